@@ -5,6 +5,7 @@ use enigma_3d::camera::Camera;
 use enigma_3d::{AppState, EventLoop, resources, example_resources, shader, material, object, texture};
 use enigma_3d::audio::AudioClip;
 use enigma_3d::material::{Material, TextureType};
+use enigma_3d::postprocessing::depth_of_field::{focus_distance_to, DepthOfField};
 
 fn enigma_ui_function(ctx: &egui::Context, app_state: &mut AppState) {
     egui::Window::new("Enigma - Chessboard Example")
@@ -76,6 +77,22 @@ fn enigma_ui_function(ctx: &egui::Context, app_state: &mut AppState) {
         });
 }
 
+// pulls the focus of the depth of field smoothly onto the first selected object
+fn focus_on_selection(app_state: &mut AppState) {
+    let target = app_state.object_selection.first()
+        .and_then(|uuid| app_state.get_object_by_uuid(uuid))
+        .map(|object| {
+            let matrix = object.transform.get_world_matrix();
+            [matrix[3][0], matrix[3][1], matrix[3][2]]
+        });
+    let (Some(target), Some(camera)) = (target, app_state.camera.clone()) else { return };
+    let blend = 1.0 - (-app_state.delta_time * 4.0).exp();
+    if let Some(depth_of_field) = app_state.get_post_process_mut::<DepthOfField>() {
+        let distance = focus_distance_to(&camera, target);
+        depth_of_field.set_focus_distance(depth_of_field.focus_distance + (distance - depth_of_field.focus_distance) * blend);
+    }
+}
+
 fn initialize_board(app_state: &mut AppState, event_loop: &EventLoop) {
     // setup materials
     let mut board_material = enigma_3d::material::Material::lit_pbr(event_loop.get_display_clone(), false);
@@ -407,11 +424,16 @@ fn main() {
     app_state.play_audio_loop("background_music");
 
     // add post processing effects
+    app_state.add_post_process(Box::new(enigma_3d::postprocessing::ssr::Ssr::new(&event_loop.display, 10.0, 64, 0.3)));
+    app_state.add_post_process(Box::new(DepthOfField::new(&event_loop.display, 4.0, 0.3, 48)));
     app_state.add_post_process(Box::new(enigma_3d::postprocessing::bloom::Bloom::new(&event_loop.display.clone(), 0.999, 15)));
     app_state.add_post_process(Box::new(enigma_3d::postprocessing::depth_fog::DepthFog::new(&event_loop.display, 0.2, 60.0, 500.0, [0.3, 0.3, 0.75], 1.0)));
     app_state.add_post_process(Box::new(enigma_3d::postprocessing::vignette::Vignette::new(&event_loop.display.clone(), 0.3, 0.3, [0.0, 0.0, 0.0], 0.8)));
     app_state.add_post_process(Box::new(enigma_3d::postprocessing::lens_dirt::LensDirt::new(&event_loop.display, resources::lens_dirt_texture(), 2.0, [800.0, 800.0], 2.0)));
 
+    // focus pull on the selected chess piece
+    app_state.inject_update_function(Arc::new(focus_on_selection));
+
     //add one ui function to the app state. multiple ui functions can be added modular
     app_state.inject_gui(Arc::new(enigma_ui_function));
 
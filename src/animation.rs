@@ -1,6 +1,7 @@
 use std::vec::Vec;
 use nalgebra::{Matrix4, Quaternion, UnitQuaternion, Vector3};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 use crate::logging::EnigmaError;
 use crate::smart_format;
 
@@ -44,6 +45,15 @@ impl Bone {
             inverse_bind_pose: Matrix4::from(serializer.inverse_bind_pose)
         }
     }
+
+    /// Transform of the bone relative to the skinned object in the bind pose.
+    pub fn get_bind_pose(&self) -> Matrix4<f32> {
+        match self.inverse_bind_pose.try_inverse() {
+            // the import scale multiplier is applied to the whole matrix, so normalizing w again
+            Some(m) if m[(3, 3)].abs() > f32::EPSILON => m / m[(3, 3)],
+            _ => Matrix4::identity(),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -78,6 +88,10 @@ impl Skeleton {
             root_transform: Matrix4::from(serializer.root_transform),
         }
     }
+    pub fn get_bone_id(&self, name: &str) -> Option<usize> {
+        self.bones.iter().position(|bone| bone.name == name)
+    }
+
     pub fn validate(&self) -> Result<(), EnigmaError> {
         for bone in self.bones.iter() {
             if let Some(parent_id) = bone.parent_id {
@@ -107,6 +121,8 @@ pub enum AnimationTransform {
     Translation([f32; 3]),
     Rotation([f32;4]),
     Scale([f32;3]),
+    /// Morph target weights of the mesh, one per target.
+    Weights(Vec<f32>),
 }
 
 #[derive(Clone)]
@@ -145,6 +161,7 @@ impl AnimationKeyframe {
             AnimationTransform::Scale(scale) => {
                 Matrix4::new_nonuniform_scaling(&Vector3::new(scale[0], scale[1], scale[2]))
             }
+            AnimationTransform::Weights(_) => Matrix4::identity(),
         }
     }
 }
@@ -155,10 +172,29 @@ pub struct AnimationKeyframeSerializer {
     pub transform: AnimationTransform,
 }
 
+/// How the values between two keyframes are calculated, matching the glTF sampler modes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum AnimationInterpolation {
+    #[default]
+    Linear,
+    Step,
+    CubicSpline,
+}
+
+/// In and out tangents of a keyframe, only used by `AnimationInterpolation::CubicSpline`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AnimationTangent {
+    pub in_tangent: AnimationTransform,
+    pub out_tangent: AnimationTransform,
+}
+
 #[derive(Clone)]
 pub struct AnimationChannel {
     pub bone_id: usize,
     pub keyframes: Vec<AnimationKeyframe>,
+    pub interpolation: AnimationInterpolation,
+    /// One entry per keyframe for cubic spline channels, empty otherwise.
+    pub tangents: Vec<AnimationTangent>,
 }
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AnimationState {
@@ -166,13 +202,209 @@ pub struct AnimationState {
     pub time: f32,
     pub speed: f32,
     pub looping: bool,
+    /// Influence of this state when blended with other states, 0.0 - 1.0.
+    #[serde(default = "default_animation_weight")]
+    pub weight: f32,
+    /// Weight change per second, used for crossfades. Fading out states are removed at weight 0.
+    #[serde(default)]
+    pub fade_speed: f32,
+}
+
+fn default_animation_weight() -> f32 {
+    1.0
+}
+
+impl AnimationState {
+    pub fn new(name: &str, looping: bool, weight: f32) -> Self {
+        Self {
+            name: name.to_string(),
+            time: 0.0,
+            speed: 1.0,
+            looping,
+            weight: weight.clamp(0.0, 1.0),
+            fade_speed: 0.0,
+        }
+    }
+
+    pub(crate) fn update_fade(&mut self, delta_time: f32) {
+        if self.fade_speed != 0.0 {
+            self.weight = (self.weight + self.fade_speed * delta_time).clamp(0.0, 1.0);
+            if self.weight >= 1.0 && self.fade_speed > 0.0 {
+                self.fade_speed = 0.0;
+            }
+        }
+    }
+
+    pub(crate) fn is_faded_out(&self) -> bool {
+        self.fade_speed < 0.0 && self.weight <= 0.0
+    }
+}
+
+/// Local translation, rotation and scale of a bone. Animations are sampled and blended as
+/// `BonePose`s and only composed into a matrix afterwards.
+#[derive(Clone, Copy, Debug)]
+pub struct BonePose {
+    pub translation: Vector3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl BonePose {
+    pub fn identity() -> Self {
+        Self {
+            translation: Vector3::zeros(),
+            rotation: UnitQuaternion::identity(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        Matrix4::new_translation(&self.translation) * self.rotation.to_homogeneous() * Matrix4::new_nonuniform_scaling(&self.scale)
+    }
+
+    /// Weighted average of the poses. The weights don't have to add up to one.
+    pub fn blend(poses: &[(BonePose, f32)]) -> BonePose {
+        let total: f32 = poses.iter().map(|(_, w)| w.max(0.0)).sum();
+        if total <= 0.0 {
+            return BonePose::identity();
+        }
+        let mut translation = Vector3::zeros();
+        let mut scale = Vector3::zeros();
+        let mut rotation = Quaternion::new(0.0, 0.0, 0.0, 0.0);
+        let reference = poses[0].0.rotation;
+        for (pose, weight) in poses.iter() {
+            let w = weight.max(0.0) / total;
+            translation += pose.translation * w;
+            scale += pose.scale * w;
+            // keep all quaternions in the same hemisphere, otherwise they cancel out
+            let q = if pose.rotation.coords.dot(&reference.coords) < 0.0 { -pose.rotation.into_inner() } else { pose.rotation.into_inner() };
+            rotation += q * w;
+        }
+        BonePose {
+            translation,
+            rotation: UnitQuaternion::try_new(rotation, f32::EPSILON).unwrap_or(reference),
+            scale,
+        }
+    }
+
+    /// Adds the difference between `pose` and `reference` on top of this pose, scaled by `weight`.
+    pub fn add(&self, pose: &BonePose, reference: &BonePose, weight: f32) -> BonePose {
+        let delta_rotation = reference.rotation.inverse() * pose.rotation;
+        let delta_scale = pose.scale.component_div(&reference.scale.map(|s| if s.abs() > f32::EPSILON { s } else { 1.0 }));
+        BonePose {
+            translation: self.translation + (pose.translation - reference.translation) * weight,
+            rotation: self.rotation * UnitQuaternion::identity().slerp(&delta_rotation, weight),
+            scale: self.scale.component_mul(&Vector3::new(1.0, 1.0, 1.0).lerp(&delta_scale, weight)),
+        }
+    }
 }
 
 impl AnimationChannel {
+    pub fn new(bone_id: usize, keyframes: Vec<AnimationKeyframe>) -> Self {
+        Self {
+            bone_id,
+            keyframes,
+            interpolation: AnimationInterpolation::Linear,
+            tangents: Vec::new(),
+        }
+    }
+
     pub fn to_serializer(&self) -> AnimationChannelSerializer {
         AnimationChannelSerializer {
             bone_id: self.bone_id.clone(),
-            keyframes: self.keyframes.iter().map(|x| x.to_serializer()).collect()
+            keyframes: self.keyframes.iter().map(|x| x.to_serializer()).collect(),
+            interpolation: self.interpolation,
+            tangents: self.tangents.clone(),
+        }
+    }
+
+    /// Evaluates the channel at `time` as described in the glTF spec. Before the first and after
+    /// the last keyframe the values of those keyframes are held.
+    pub fn sample(&self, time: f32) -> Option<AnimationTransform> {
+        let first = self.keyframes.first()?;
+        let last = self.keyframes.last()?;
+        if time <= first.time {
+            return Some(first.transform.clone());
+        }
+        if time >= last.time {
+            return Some(last.transform.clone());
+        }
+        let next_index = self.keyframes.iter().position(|k| k.time > time)?;
+        let prev_index = next_index.saturating_sub(1);
+        let prev = &self.keyframes[prev_index];
+        let next = &self.keyframes[next_index];
+        let duration = next.time - prev.time;
+        let t = if duration > 0.0 {
+            ((time - prev.time) / duration).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        match self.interpolation {
+            AnimationInterpolation::Step => Some(prev.transform.clone()),
+            AnimationInterpolation::Linear => Some(Self::interpolate_linear(&prev.transform, &next.transform, t)),
+            AnimationInterpolation::CubicSpline => match (self.tangents.get(prev_index), self.tangents.get(next_index)) {
+                (Some(prev_tangent), Some(next_tangent)) => Some(Self::interpolate_cubic(
+                    &prev.transform,
+                    &prev_tangent.out_tangent,
+                    &next.transform,
+                    &next_tangent.in_tangent,
+                    t,
+                    duration,
+                )),
+                _ => Some(Self::interpolate_linear(&prev.transform, &next.transform, t)),
+            },
+        }
+    }
+
+    fn interpolate_linear(prev: &AnimationTransform, next: &AnimationTransform, t: f32) -> AnimationTransform {
+        match (prev, next) {
+            (AnimationTransform::Translation(prev), AnimationTransform::Translation(next)) => {
+                AnimationTransform::Translation(Vector3::from(*prev).lerp(&Vector3::from(*next), t).into())
+            }
+            (AnimationTransform::Rotation(prev), AnimationTransform::Rotation(next)) => {
+                let prev_quat = UnitQuaternion::from_quaternion(Quaternion::new(prev[3], prev[0], prev[1], prev[2]));
+                let next_quat = UnitQuaternion::from_quaternion(Quaternion::new(next[3], next[0], next[1], next[2]));
+                let q = prev_quat.slerp(&next_quat, t);
+                AnimationTransform::Rotation([q.i, q.j, q.k, q.w])
+            }
+            (AnimationTransform::Scale(prev), AnimationTransform::Scale(next)) => {
+                AnimationTransform::Scale(Vector3::from(*prev).lerp(&Vector3::from(*next), t).into())
+            }
+            (AnimationTransform::Weights(prev), AnimationTransform::Weights(next)) => {
+                AnimationTransform::Weights(prev.iter().zip(next.iter()).map(|(p, n)| p + (n - p) * t).collect())
+            }
+            // mismatching keyframe types, keeping the previous one
+            _ => prev.clone(),
+        }
+    }
+
+    /// Cubic Hermite spline, the tangents are scaled by the keyframe distance `duration`.
+    fn interpolate_cubic(prev: &AnimationTransform, prev_out: &AnimationTransform, next: &AnimationTransform, next_in: &AnimationTransform, t: f32, duration: f32) -> AnimationTransform {
+        let t2 = t * t;
+        let t3 = t2 * t;
+        let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+        let h10 = t3 - 2.0 * t2 + t;
+        let h01 = -2.0 * t3 + 3.0 * t2;
+        let h11 = t3 - t2;
+        let hermite = |v0: f32, b0: f32, v1: f32, a1: f32| h00 * v0 + h10 * duration * b0 + h01 * v1 + h11 * duration * a1;
+
+        match (prev, prev_out, next, next_in) {
+            (AnimationTransform::Translation(v0), AnimationTransform::Translation(b0), AnimationTransform::Translation(v1), AnimationTransform::Translation(a1)) => {
+                AnimationTransform::Translation([0, 1, 2].map(|i| hermite(v0[i], b0[i], v1[i], a1[i])))
+            }
+            (AnimationTransform::Rotation(v0), AnimationTransform::Rotation(b0), AnimationTransform::Rotation(v1), AnimationTransform::Rotation(a1)) => {
+                let q = [0, 1, 2, 3].map(|i| hermite(v0[i], b0[i], v1[i], a1[i]));
+                let q = UnitQuaternion::from_quaternion(Quaternion::new(q[3], q[0], q[1], q[2]));
+                AnimationTransform::Rotation([q.i, q.j, q.k, q.w])
+            }
+            (AnimationTransform::Scale(v0), AnimationTransform::Scale(b0), AnimationTransform::Scale(v1), AnimationTransform::Scale(a1)) => {
+                AnimationTransform::Scale([0, 1, 2].map(|i| hermite(v0[i], b0[i], v1[i], a1[i])))
+            }
+            (AnimationTransform::Weights(v0), AnimationTransform::Weights(b0), AnimationTransform::Weights(v1), AnimationTransform::Weights(a1)) => {
+                AnimationTransform::Weights((0..v0.len().min(b0.len()).min(v1.len()).min(a1.len())).map(|i| hermite(v0[i], b0[i], v1[i], a1[i])).collect())
+            }
+            _ => Self::interpolate_linear(prev, next, t),
         }
     }
 
@@ -184,7 +416,9 @@ impl AnimationChannel {
         }
         Self {
             bone_id: serializer.bone_id,
-            keyframes
+            keyframes,
+            interpolation: serializer.interpolation,
+            tangents: serializer.tangents,
         }
     }
 }
@@ -193,21 +427,113 @@ impl AnimationChannel {
 pub struct AnimationChannelSerializer {
     pub bone_id: usize,
     pub keyframes: Vec<AnimationKeyframeSerializer>,
+    #[serde(default)]
+    pub interpolation: AnimationInterpolation,
+    #[serde(default)]
+    pub tangents: Vec<AnimationTangent>,
 }
 
+/// A named point in time of an `Animation`, e.g. a footstep or the frame a sword hits.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AnimationEvent {
+    pub name: String,
+    pub time: f32,
+}
+
+/// An `AnimationEvent` that was crossed during playback of an object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FiredAnimationEvent {
+    pub object: Uuid,
+    pub animation: String,
+    pub name: String,
+}
+
+// caps the work for tiny clips with huge time steps
+const MAX_EVENT_LAPS: usize = 64;
+
 #[derive(Clone)]
 pub struct Animation {
     pub name: String,
     pub duration: f32,
     pub channels: Vec<AnimationChannel>,
+    pub events: Vec<AnimationEvent>,
 }
 
 impl Animation {
+    pub fn new(name: &str, duration: f32, channels: Vec<AnimationChannel>) -> Self {
+        Self {
+            name: name.to_string(),
+            duration,
+            channels,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn add_event(&mut self, name: &str, time: f32) {
+        self.events.push(AnimationEvent { name: name.to_string(), time: time.clamp(0.0, self.duration.max(0.0)) });
+    }
+
+    pub fn remove_events(&mut self, name: &str) {
+        self.events.retain(|e| e.name != name);
+    }
+
+    /// Advances `time` by `delta_time` (negative to play backwards), wrapping around when
+    /// `looping` or clamping to the clip otherwise. Returns the new time and the events that were
+    /// crossed, in playback order. Events on the start of the clip fire when entering it.
+    pub fn advance(&self, time: f32, delta_time: f32, looping: bool) -> (f32, Vec<&AnimationEvent>) {
+        let mut fired = Vec::new();
+        if self.duration <= 0.0 {
+            return (0.0, fired);
+        }
+        let forward = delta_time >= 0.0;
+        let mut remaining = delta_time.abs();
+        let mut current = time.clamp(0.0, self.duration);
+        if remaining == 0.0 {
+            return (current, fired);
+        }
+        let mut laps = 0;
+        loop {
+            let room = if forward { self.duration - current } else { current };
+            if remaining <= room || !looping {
+                let next = if forward { current + remaining.min(room) } else { current - remaining.min(room) };
+                self.collect_events(current, next, forward, &mut fired);
+                current = next;
+                break;
+            }
+            let end = if forward { self.duration } else { 0.0 };
+            self.collect_events(current, end, forward, &mut fired);
+            remaining -= room;
+            current = if forward { 0.0 } else { self.duration };
+            laps += 1;
+            if laps >= MAX_EVENT_LAPS {
+                remaining %= self.duration;
+                current = if forward { remaining } else { self.duration - remaining };
+                break;
+            }
+        }
+        (current, fired)
+    }
+
+    fn collect_events<'a>(&'a self, from: f32, to: f32, forward: bool, fired: &mut Vec<&'a AnimationEvent>) {
+        // the start of the clip counts as crossed when playback leaves it
+        let start = if forward { 0.0 } else { self.duration };
+        let mut events: Vec<&AnimationEvent> = self.events.iter().filter(|e| {
+            if forward {
+                (e.time > from || (from == start && e.time == from)) && e.time <= to
+            } else {
+                (e.time < from || (from == start && e.time == from)) && e.time >= to
+            }
+        }).collect();
+        events.sort_by(|a, b| if forward { a.time.total_cmp(&b.time) } else { b.time.total_cmp(&a.time) });
+        fired.extend(events);
+    }
+
     pub fn to_serializer(&self) -> AnimationSerializer {
         AnimationSerializer {
             name: self.name.clone(),
             duration: self.duration.clone(),
-            channels: self.channels.iter().map(|x| x.to_serializer()).collect()
+            channels: self.channels.iter().map(|x| x.to_serializer()).collect(),
+            events: self.events.clone(),
         }
     }
 
@@ -220,7 +546,8 @@ impl Animation {
         Self {
             name: serializer.name,
             duration: serializer.duration,
-            channels
+            channels,
+            events: serializer.events,
         }
     }
 }
@@ -230,4 +557,189 @@ pub struct AnimationSerializer {
     pub name: String,
     pub duration: f32,
     pub channels: Vec<AnimationChannelSerializer>,
-}
\ No newline at end of file
+    #[serde(default)]
+    pub events: Vec<AnimationEvent>,
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector4;
+
+    fn pose(x: f32, yaw: f32) -> BonePose {
+        BonePose {
+            translation: Vector3::new(x, 0.0, 0.0),
+            rotation: UnitQuaternion::from_euler_angles(0.0, yaw, 0.0),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn bone_pose_blend_halfway() {
+        let blended = BonePose::blend(&[(pose(0.0, 0.0), 1.0), (pose(2.0, 1.0), 1.0)]);
+        assert!((blended.translation.x - 1.0).abs() < 1e-5);
+        assert!((blended.rotation.angle() - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn bone_pose_blend_normalizes_weights() {
+        let blended = BonePose::blend(&[(pose(0.0, 0.0), 0.2), (pose(4.0, 0.0), 0.6)]);
+        assert!((blended.translation.x - 3.0).abs() < 1e-5);
+        assert!((BonePose::blend(&[]).translation.x).abs() < 1e-5);
+    }
+
+    #[test]
+    fn bone_pose_additive_applies_delta() {
+        let base = pose(1.0, 0.0);
+        let result = base.add(&pose(3.0, 0.4), &pose(2.0, 0.0), 0.5);
+        assert!((result.translation.x - 1.5).abs() < 1e-5);
+        assert!((result.rotation.angle() - 0.2).abs() < 1e-4);
+    }
+
+    fn translation_channel(interpolation: AnimationInterpolation, values: &[(f32, f32)]) -> AnimationChannel {
+        let mut channel = AnimationChannel::new(0, values.iter().map(|(time, x)| AnimationKeyframe {
+            time: *time,
+            transform: AnimationTransform::Translation([*x, 0.0, 0.0]),
+        }).collect());
+        channel.interpolation = interpolation;
+        channel
+    }
+
+    fn sample_x(channel: &AnimationChannel, time: f32) -> f32 {
+        match channel.sample(time) {
+            Some(AnimationTransform::Translation(t)) => t[0],
+            _ => panic!("expected a translation"),
+        }
+    }
+
+    fn tangent(x_in: f32, x_out: f32) -> AnimationTangent {
+        AnimationTangent {
+            in_tangent: AnimationTransform::Translation([x_in, 0.0, 0.0]),
+            out_tangent: AnimationTransform::Translation([x_out, 0.0, 0.0]),
+        }
+    }
+
+    #[test]
+    fn channel_step_holds_previous_value() {
+        let channel = translation_channel(AnimationInterpolation::Step, &[(0.0, 0.0), (1.0, 10.0)]);
+        assert_eq!(sample_x(&channel, 0.99), 0.0);
+        assert_eq!(sample_x(&channel, 1.0), 10.0);
+    }
+
+    #[test]
+    fn channel_linear_and_clamping() {
+        let channel = translation_channel(AnimationInterpolation::Linear, &[(1.0, 0.0), (2.0, 10.0)]);
+        assert!((sample_x(&channel, 1.5) - 5.0).abs() < 1e-5);
+        assert_eq!(sample_x(&channel, 0.0), 0.0);
+        assert_eq!(sample_x(&channel, 3.0), 10.0);
+    }
+
+    #[test]
+    fn channel_cubic_spline_reproduces_cubic() {
+        // f(t) = t^3 on [0, 2]: f(0) = 0, f'(0) = 0, f(2) = 8, f'(2) = 12
+        let mut channel = translation_channel(AnimationInterpolation::CubicSpline, &[(0.0, 0.0), (2.0, 8.0)]);
+        channel.tangents = vec![tangent(0.0, 0.0), tangent(12.0, 0.0)];
+        for time in [0.5f32, 1.0, 1.5] {
+            assert!((sample_x(&channel, time) - time.powi(3)).abs() < 1e-4, "f({}) = {}", time, sample_x(&channel, time));
+        }
+    }
+
+    #[test]
+    fn channel_cubic_spline_without_tangents_is_smooth_step() {
+        let mut channel = translation_channel(AnimationInterpolation::CubicSpline, &[(0.0, 0.0), (1.0, 1.0)]);
+        channel.tangents = vec![tangent(0.0, 0.0), tangent(0.0, 0.0)];
+        assert!((sample_x(&channel, 0.5) - 0.5).abs() < 1e-5);
+        assert!((sample_x(&channel, 0.25) - 0.15625).abs() < 1e-5);
+    }
+
+    #[test]
+    fn channel_rotation_slerp_and_cubic_are_normalized() {
+        let quat = |angle: f32| {
+            let q = UnitQuaternion::from_euler_angles(0.0, angle, 0.0);
+            AnimationTransform::Rotation([q.i, q.j, q.k, q.w])
+        };
+        let mut channel = AnimationChannel::new(0, vec![
+            AnimationKeyframe { time: 0.0, transform: quat(0.0) },
+            AnimationKeyframe { time: 1.0, transform: quat(1.0) },
+        ]);
+        let angle = |channel: &AnimationChannel| match channel.sample(0.5) {
+            Some(AnimationTransform::Rotation(r)) => {
+                assert!((Vector4::new(r[0], r[1], r[2], r[3]).norm() - 1.0).abs() < 1e-5);
+                UnitQuaternion::from_quaternion(Quaternion::new(r[3], r[0], r[1], r[2])).angle()
+            }
+            _ => panic!("expected a rotation"),
+        };
+        assert!((angle(&channel) - 0.5).abs() < 1e-4);
+        let zero = AnimationTransform::Rotation([0.0, 0.0, 0.0, 0.0]);
+        channel.interpolation = AnimationInterpolation::CubicSpline;
+        channel.tangents = vec![
+            AnimationTangent { in_tangent: zero.clone(), out_tangent: zero.clone() },
+            AnimationTangent { in_tangent: zero.clone(), out_tangent: zero },
+        ];
+        angle(&channel);
+    }
+
+    fn clip_with_events() -> Animation {
+        let mut animation = Animation::new("walk", 1.0, Vec::new());
+        animation.add_event("start", 0.0);
+        animation.add_event("left", 0.25);
+        animation.add_event("right", 0.75);
+        animation
+    }
+
+    fn names(events: Vec<&AnimationEvent>) -> Vec<&str> {
+        events.into_iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn animation_events_forward() {
+        let animation = clip_with_events();
+        let (time, events) = animation.advance(0.0, 0.3, true);
+        assert!((time - 0.3).abs() < 1e-5);
+        assert_eq!(names(events), vec!["start", "left"]);
+        let (_, events) = animation.advance(0.3, 0.3, true);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn animation_events_loop_wrap() {
+        let animation = clip_with_events();
+        let (time, events) = animation.advance(0.7, 0.6, true);
+        assert!((time - 0.3).abs() < 1e-5);
+        assert_eq!(names(events), vec!["right", "start", "left"]);
+    }
+
+    #[test]
+    fn animation_events_not_looping_clamps() {
+        let animation = clip_with_events();
+        let (time, events) = animation.advance(0.7, 0.6, false);
+        assert_eq!(time, 1.0);
+        assert_eq!(names(events), vec!["right"]);
+        let (_, events) = animation.advance(1.0, 0.1, false);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn animation_events_negative_speed() {
+        let animation = clip_with_events();
+        let (time, events) = animation.advance(0.3, -0.5, true);
+        assert!((time - 0.8).abs() < 1e-5);
+        assert_eq!(names(events), vec!["left", "start"]);
+        let (time, events) = animation.advance(0.3, -0.5, false);
+        assert_eq!(time, 0.0);
+        assert_eq!(names(events), vec!["left", "start"]);
+    }
+
+    #[test]
+    fn animation_state_fades() {
+        let mut state = AnimationState::new("walk", true, 0.0);
+        state.fade_speed = 2.0;
+        state.update_fade(0.25);
+        assert!((state.weight - 0.5).abs() < 1e-5);
+        state.update_fade(1.0);
+        assert_eq!(state.weight, 1.0);
+        assert_eq!(state.fade_speed, 0.0);
+        state.fade_speed = -4.0;
+        state.update_fade(0.5);
+        assert!(state.is_faded_out());
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::animation::Animation;
+use crate::logging::EnigmaWarning;
+use crate::smart_format;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AnimationParameter {
+    Float(f32),
+    Bool(bool),
+    /// A bool that is reset as soon as a transition used it.
+    Trigger(bool),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AnimationCondition {
+    Greater(String, f32),
+    Less(String, f32),
+    Bool(String, bool),
+    Trigger(String),
+}
+
+impl AnimationCondition {
+    fn is_met(&self, parameters: &HashMap<String, AnimationParameter>) -> bool {
+        match self {
+            AnimationCondition::Greater(name, value) => matches!(parameters.get(name), Some(AnimationParameter::Float(p)) if p > value),
+            AnimationCondition::Less(name, value) => matches!(parameters.get(name), Some(AnimationParameter::Float(p)) if p < value),
+            AnimationCondition::Bool(name, value) => matches!(parameters.get(name), Some(AnimationParameter::Bool(p)) if p == value),
+            AnimationCondition::Trigger(name) => matches!(parameters.get(name), Some(AnimationParameter::Trigger(true))),
+        }
+    }
+}
+
+/// A state of the `AnimationStateMachine`, playing a clip of `Object::animations`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnimationMachineState {
+    pub name: String,
+    pub animation: String,
+    pub looping: bool,
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+}
+
+fn default_speed() -> f32 {
+    1.0
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnimationTransition {
+    /// The state this transition starts from, `None` to allow it from any state.
+    pub from: Option<String>,
+    pub to: String,
+    /// All conditions have to be met for the transition to happen.
+    #[serde(default)]
+    pub conditions: Vec<AnimationCondition>,
+    /// Crossfade duration in seconds.
+    #[serde(default)]
+    pub duration: f32,
+    /// Normalized time of the source clip (1.0 = one full play through) the transition waits for.
+    #[serde(default)]
+    pub exit_time: Option<f32>,
+}
+
+impl AnimationTransition {
+    pub fn new(from: Option<&str>, to: &str, conditions: Vec<AnimationCondition>, duration: f32, exit_time: Option<f32>) -> Self {
+        Self {
+            from: from.map(|f| f.to_string()),
+            to: to.to_string(),
+            conditions,
+            duration,
+            exit_time,
+        }
+    }
+}
+
+/// Returned by `AnimationStateMachine::update` when a new state was entered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationStateChange {
+    pub animation: String,
+    pub looping: bool,
+    pub speed: f32,
+    pub fade: f32,
+}
+
+/// Drives the animations of an `Object` from parameters. Add it as a component and set its
+/// parameters from update functions, `Object::update` takes care of the rest:
+/// ```ignore
+/// object.get_component_mut::<AnimationStateMachine>().unwrap().set_float("speed", 3.0);
+/// ```
+#[derive(Clone)]
+pub struct AnimationStateMachine {
+    states: Vec<AnimationMachineState>,
+    transitions: Vec<AnimationTransition>,
+    parameters: HashMap<String, AnimationParameter>,
+    default_state: String,
+    current_state: Option<String>,
+    state_time: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AnimationStateMachineSerializer {
+    pub states: Vec<AnimationMachineState>,
+    pub transitions: Vec<AnimationTransition>,
+    #[serde(default)]
+    pub parameters: HashMap<String, AnimationParameter>,
+    pub default_state: String,
+}
+
+impl AnimationStateMachine {
+    pub fn new(default_state: &str) -> Self {
+        Self {
+            states: Vec::new(),
+            transitions: Vec::new(),
+            parameters: HashMap::new(),
+            default_state: default_state.to_string(),
+            current_state: None,
+            state_time: 0.0,
+        }
+    }
+
+    pub fn to_serializer(&self) -> AnimationStateMachineSerializer {
+        AnimationStateMachineSerializer {
+            states: self.states.clone(),
+            transitions: self.transitions.clone(),
+            parameters: self.parameters.clone(),
+            default_state: self.default_state.clone(),
+        }
+    }
+
+    pub fn from_serializer(serializer: AnimationStateMachineSerializer) -> Self {
+        Self {
+            states: serializer.states,
+            transitions: serializer.transitions,
+            parameters: serializer.parameters,
+            default_state: serializer.default_state,
+            current_state: None,
+            state_time: 0.0,
+        }
+    }
+
+    pub fn add_state(&mut self, name: &str, animation: &str, looping: bool) {
+        self.states.retain(|s| s.name != name);
+        self.states.push(AnimationMachineState {
+            name: name.to_string(),
+            animation: animation.to_string(),
+            looping,
+            speed: 1.0,
+        });
+    }
+
+    pub fn get_state_mut(&mut self, name: &str) -> Option<&mut AnimationMachineState> {
+        self.states.iter_mut().find(|s| s.name == name)
+    }
+
+    pub fn get_states(&self) -> &Vec<AnimationMachineState> {
+        &self.states
+    }
+
+    /// Transitions are checked in the order they were added, the first one that is possible wins.
+    pub fn add_transition(&mut self, transition: AnimationTransition) {
+        self.transitions.push(transition);
+    }
+
+    pub fn get_transitions(&self) -> &Vec<AnimationTransition> {
+        &self.transitions
+    }
+
+    pub fn get_current_state(&self) -> Option<&str> {
+        self.current_state.as_deref()
+    }
+
+    /// Seconds since the current state was entered.
+    pub fn get_state_time(&self) -> f32 {
+        self.state_time
+    }
+
+    pub fn set_float(&mut self, name: &str, value: f32) {
+        self.parameters.insert(name.to_string(), AnimationParameter::Float(value));
+    }
+
+    pub fn set_bool(&mut self, name: &str, value: bool) {
+        self.parameters.insert(name.to_string(), AnimationParameter::Bool(value));
+    }
+
+    pub fn set_trigger(&mut self, name: &str) {
+        self.parameters.insert(name.to_string(), AnimationParameter::Trigger(true));
+    }
+
+    pub fn reset_trigger(&mut self, name: &str) {
+        self.parameters.insert(name.to_string(), AnimationParameter::Trigger(false));
+    }
+
+    pub fn get_float(&self, name: &str) -> Option<f32> {
+        match self.parameters.get(name) {
+            Some(AnimationParameter::Float(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        match self.parameters.get(name) {
+            Some(AnimationParameter::Bool(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn get_parameters(&self) -> &HashMap<String, AnimationParameter> {
+        &self.parameters
+    }
+
+    /// Advances the machine and returns the state to play if it changed. `animations` is used to
+    /// calculate the normalized time for exit times, states whose clip is missing are never entered.
+    pub fn update(&mut self, delta_time: f32, animations: &HashMap<String, Animation>) -> Option<AnimationStateChange> {
+        let current = match &self.current_state {
+            Some(current) => current.clone(),
+            None => {
+                let default_state = self.default_state.clone();
+                return self.enter_state(&default_state, 0.0, animations);
+            }
+        };
+        self.state_time += delta_time;
+
+        let normalized_time = self.states.iter()
+            .find(|s| s.name == current)
+            .and_then(|s| animations.get(&s.animation).map(|a| (s, a)))
+            .map(|(s, a)| if a.duration > 0.0 { self.state_time * s.speed.abs() / a.duration } else { 1.0 })
+            .unwrap_or(0.0);
+
+        let transition = self.transitions.iter()
+            .filter(|t| match &t.from {
+                Some(from) => *from == current,
+                // any-state transitions don't restart the state they lead to
+                None => t.to != current,
+            })
+            .find(|t| t.exit_time.is_none_or(|exit| normalized_time >= exit)
+                && t.conditions.iter().all(|c| c.is_met(&self.parameters)))
+            .cloned();
+
+        let transition = transition?;
+        let change = self.enter_state(&transition.to, transition.duration, animations)?;
+        for condition in transition.conditions.iter() {
+            if let AnimationCondition::Trigger(name) = condition {
+                self.reset_trigger(name);
+            }
+        }
+        Some(change)
+    }
+
+    fn enter_state(&mut self, name: &str, fade: f32, animations: &HashMap<String, Animation>) -> Option<AnimationStateChange> {
+        match self.states.iter().find(|s| s.name == name) {
+            Some(state) if !animations.contains_key(&state.animation) => {
+                EnigmaWarning::new(Some(smart_format!("Animation state machine state {} plays the missing animation {}", name, state.animation).as_str()), true).log();
+                None
+            }
+            Some(state) => {
+                self.current_state = Some(name.to_string());
+                self.state_time = 0.0;
+                Some(AnimationStateChange {
+                    animation: state.animation.clone(),
+                    looping: state.looping,
+                    speed: state.speed,
+                    fade,
+                })
+            }
+            None => {
+                EnigmaWarning::new(Some(smart_format!("Animation state machine has no state named {}", name).as_str()), true).log();
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip(name: &str, duration: f32) -> (String, Animation) {
+        (name.to_string(), Animation::new(name, duration, Vec::new()))
+    }
+
+    fn locomotion() -> (AnimationStateMachine, HashMap<String, Animation>) {
+        let animations: HashMap<String, Animation> = [clip("Idle", 2.0), clip("Walk", 1.0), clip("Attack", 0.5)].into_iter().collect();
+        let mut machine = AnimationStateMachine::new("idle");
+        machine.add_state("idle", "Idle", true);
+        machine.add_state("walk", "Walk", true);
+        machine.add_state("attack", "Attack", false);
+        machine.add_transition(AnimationTransition::new(Some("idle"), "walk", vec![AnimationCondition::Greater("speed".to_string(), 0.1)], 0.2, None));
+        machine.add_transition(AnimationTransition::new(Some("walk"), "idle", vec![AnimationCondition::Less("speed".to_string(), 0.1)], 0.2, None));
+        machine.add_transition(AnimationTransition::new(None, "attack", vec![AnimationCondition::Trigger("attack".to_string())], 0.1, None));
+        machine.add_transition(AnimationTransition::new(Some("attack"), "idle", Vec::new(), 0.1, Some(1.0)));
+        (machine, animations)
+    }
+
+    #[test]
+    fn enters_default_state() {
+        let (mut machine, animations) = locomotion();
+        let change = machine.update(0.1, &animations).unwrap();
+        assert_eq!(change.animation, "Idle");
+        assert_eq!(change.fade, 0.0);
+        assert_eq!(machine.get_current_state(), Some("idle"));
+        assert!(machine.update(0.1, &animations).is_none());
+    }
+
+    #[test]
+    fn float_conditions() {
+        let (mut machine, animations) = locomotion();
+        machine.update(0.0, &animations);
+        machine.set_float("speed", 1.0);
+        let change = machine.update(0.1, &animations).unwrap();
+        assert_eq!(change.animation, "Walk");
+        assert_eq!(change.fade, 0.2);
+        machine.set_float("speed", 0.0);
+        assert_eq!(machine.update(0.1, &animations).unwrap().animation, "Idle");
+    }
+
+    #[test]
+    fn trigger_is_consumed_and_exit_time_waits() {
+        let (mut machine, animations) = locomotion();
+        machine.update(0.0, &animations);
+        machine.set_trigger("attack");
+        assert_eq!(machine.update(0.1, &animations).unwrap().animation, "Attack");
+        // the trigger was used, so the any-state transition doesn't fire again
+        assert!(machine.update(0.2, &animations).is_none());
+        assert_eq!(machine.get_current_state(), Some("attack"));
+        // attack is 0.5 seconds long, exit time 1.0
+        assert_eq!(machine.update(0.3, &animations).unwrap().animation, "Idle");
+    }
+
+    #[test]
+    fn missing_clip_keeps_the_current_state() {
+        let (mut machine, mut animations) = locomotion();
+        machine.update(0.0, &animations);
+        animations.remove("Attack");
+        machine.set_trigger("attack");
+        assert!(machine.update(0.1, &animations).is_none());
+        assert_eq!(machine.get_current_state(), Some("idle"));
+        machine.set_float("speed", 1.0);
+        assert_eq!(machine.update(0.1, &animations).unwrap().animation, "Walk");
+    }
+
+    #[test]
+    fn serializer_round_trip() {
+        let (machine, _) = locomotion();
+        let json = serde_json::to_string(&machine.to_serializer()).unwrap();
+        let restored = AnimationStateMachine::from_serializer(serde_json::from_str(&json).unwrap());
+        assert_eq!(restored.get_states().len(), 3);
+        assert_eq!(restored.get_transitions().len(), 4);
+        assert_eq!(restored.get_transitions()[2].conditions[0], AnimationCondition::Trigger("attack".to_string()));
+    }
+}
@@ -24,6 +24,9 @@ pub struct Camera {
     pub far: f32,
     pub view: [[f32; 4]; 4],
     pub projection: [[f32; 4]; 4],
+    /// Sub-pixel offset in normalized device coordinates applied by `get_projection_matrix`, set by the
+    /// renderer while temporal anti-aliasing is on.
+    pub jitter: [f32; 2],
     components: HashMap<TypeId, Box<dyn Any>>,
 }
 
@@ -38,6 +41,7 @@ impl Clone for Camera {
             far: self.far,
             view: self.view,
             projection: self.projection,
+            jitter: self.jitter,
             components: HashMap::new(),
         }
     }
@@ -67,6 +71,7 @@ impl Camera {
             far: far.unwrap_or_else(|| 1024.0),
             view: [[0.0; 4]; 4],
             projection: [[0.0; 4]; 4],
+            jitter: [0.0, 0.0],
             components: HashMap::new(),
         };
         c.update_matrices();
@@ -87,6 +92,7 @@ impl Camera {
             far: serializer.far,
             view: serializer.view,
             projection: serializer.projection,
+            jitter: [0.0, 0.0],
             components: HashMap::new(),
         }
     }
@@ -184,7 +190,16 @@ impl Camera {
         )
     }
 
+    /// The projection including the current `jitter`.
     pub fn get_projection_matrix(&self) -> [[f32; 4]; 4] {
+        let mut projection = self.get_unjittered_projection_matrix();
+        // w is -z, so subtracting here moves every point by +jitter after the perspective divide
+        projection[2][0] -= self.jitter[0];
+        projection[2][1] -= self.jitter[1];
+        projection
+    }
+
+    pub fn get_unjittered_projection_matrix(&self) -> [[f32; 4]; 4] {
         Camera::projection_matrix(
             self.fov,
             self.width / self.height,
@@ -225,6 +240,10 @@ impl Camera {
         self.projection.clone()
     }
 
+    pub fn get_jitter(&self) -> [f32; 2] {
+        self.jitter
+    }
+
     pub fn set_position(&mut self, position: [f32; 3]) {
         self.transform.set_position(position);
         self.update_matrices();
@@ -256,6 +275,10 @@ impl Camera {
         self.update_matrices();
     }
 
+    pub fn set_jitter(&mut self, jitter: [f32; 2]) {
+        self.jitter = jitter;
+    }
+
     /// Converts a camera-local offset into a world-space position.
     ///
     /// Uses the camera's true forward direction and a XZ-projected right vector (no banking
@@ -338,4 +361,20 @@ mod tests {
         assert_eq!(cloned.get_component::<u32>(), None);
         assert_eq!(c.get_component::<u32>(), Some(&99u32));
     }
+
+    #[test]
+    fn camera_jitter_shifts_projected_points() {
+        let mut c = test_camera();
+        let point = nalgebra::Vector4::new(0.3, -0.2, -5.0, 1.0);
+        let project = |m: [[f32; 4]; 4]| {
+            let clip = nalgebra::Matrix4::from(m) * point;
+            [clip.x / clip.w, clip.y / clip.w]
+        };
+        let unjittered = project(c.get_projection_matrix());
+        c.set_jitter([0.01, -0.02]);
+        let jittered = project(c.get_projection_matrix());
+        assert!((jittered[0] - unjittered[0] - 0.01).abs() < 1e-5);
+        assert!((jittered[1] - unjittered[1] + 0.02).abs() < 1e-5);
+        assert_eq!(c.get_unjittered_projection_matrix(), Camera::new(None, None, None, None, None, None).get_projection_matrix());
+    }
 }
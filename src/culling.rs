@@ -0,0 +1,163 @@
+use crate::camera::Camera;
+use crate::geometry::{BoundingBox, InstanceAttribute};
+use crate::object::ObjectInstance;
+use crate::shadow::mat4_mul;
+
+/// Plane in the form `normal · p + distance = 0`, the normal points into the frustum.
+#[derive(Copy, Clone, Debug)]
+pub struct Plane {
+    pub normal: [f32; 3],
+    pub distance: f32,
+}
+
+impl Plane {
+    fn from_coefficients(a: f32, b: f32, c: f32, d: f32) -> Self {
+        let length = (a * a + b * b + c * c).sqrt();
+        if length <= f32::EPSILON {
+            return Plane { normal: [0.0, 0.0, 0.0], distance: 0.0 };
+        }
+        Plane { normal: [a / length, b / length, c / length], distance: d / length }
+    }
+
+    pub fn signed_distance(&self, point: [f32; 3]) -> f32 {
+        self.normal[0] * point[0] + self.normal[1] * point[1] + self.normal[2] * point[2] + self.distance
+    }
+}
+
+/// The six planes of a view or light volume, extracted from a column-major view-projection matrix.
+#[derive(Copy, Clone, Debug)]
+pub struct Frustum {
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    pub fn from_matrix(view_projection: [[f32; 4]; 4]) -> Self {
+        let m = view_projection;
+        let row = |i: usize| [m[0][i], m[1][i], m[2][i], m[3][i]];
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        let plane = |sign: f32, r: [f32; 4]| Plane::from_coefficients(
+            r3[0] + sign * r[0],
+            r3[1] + sign * r[1],
+            r3[2] + sign * r[2],
+            r3[3] + sign * r[3],
+        );
+        Frustum {
+            planes: [
+                plane(1.0, r0),  // left
+                plane(-1.0, r0), // right
+                plane(1.0, r1),  // bottom
+                plane(-1.0, r1), // top
+                plane(1.0, r2),  // near
+                plane(-1.0, r2), // far
+            ],
+        }
+    }
+
+    pub fn from_camera(camera: &Camera) -> Self {
+        Frustum::from_matrix(mat4_mul(camera.get_projection_matrix(), camera.get_view_matrix()))
+    }
+
+    /// Conservative test, boxes close to the corners of the frustum may pass although they are outside.
+    pub fn intersects_box(&self, bounding_box: &BoundingBox) -> bool {
+        let center = [bounding_box.center.x, bounding_box.center.y, bounding_box.center.z];
+        let half = [bounding_box.width / 2.0, bounding_box.height / 2.0, bounding_box.depth / 2.0];
+        self.planes.iter().all(|plane| {
+            let radius = plane.normal[0].abs() * half[0] + plane.normal[1].abs() * half[1] + plane.normal[2].abs() * half[2];
+            plane.signed_distance(center) >= -radius
+        })
+    }
+
+    pub fn intersects_sphere(&self, center: [f32; 3], radius: f32) -> bool {
+        self.planes.iter().all(|plane| plane.signed_distance(center) >= -radius)
+    }
+}
+
+/// Which instances of an `ObjectInstance` survived culling.
+pub enum InstanceVisibility {
+    All,
+    Partial(Vec<InstanceAttribute>),
+    Hidden,
+}
+
+impl InstanceVisibility {
+    /// Returns the number of (visible, culled) instances.
+    pub fn counts(&self, instance_count: usize) -> (usize, usize) {
+        match self {
+            InstanceVisibility::All => (instance_count, 0),
+            InstanceVisibility::Partial(visible) => (visible.len(), instance_count - visible.len()),
+            InstanceVisibility::Hidden => (0, instance_count),
+        }
+    }
+}
+
+/// Tests every instance of `instance` against `frustum`. Instances without bounds (skinned or morphed
+/// geometry, which can leave its bind pose bounds) are always visible.
+pub fn cull_instances(frustum: Option<&Frustum>, instance: &ObjectInstance) -> InstanceVisibility {
+    let (frustum, local_bounds) = match (frustum, &instance.local_bounds) {
+        (Some(frustum), Some(bounds)) => (frustum, bounds),
+        _ => return InstanceVisibility::All,
+    };
+    let visible: Vec<InstanceAttribute> = instance.instance_matrices.iter()
+        .filter(|matrix| frustum.intersects_box(&local_bounds.transformed(matrix)))
+        .map(|matrix| InstanceAttribute { model_matrix: *matrix })
+        .collect();
+    if visible.is_empty() {
+        InstanceVisibility::Hidden
+    } else if visible.len() == instance.instance_matrices.len() {
+        InstanceVisibility::All
+    } else {
+        InstanceVisibility::Partial(visible)
+    }
+}
+
+/// How many draws the frustum culling skipped in the last rendered frame. Objects are counted per
+/// instance, shadow casters once per shadow map (or cube face) they were tested against.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CullingStats {
+    pub objects_drawn: usize,
+    pub objects_culled: usize,
+    pub shadow_casters_drawn: usize,
+    pub shadow_casters_culled: usize,
+    pub terrain_tiles_drawn: usize,
+    pub terrain_tiles_culled: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    fn unit_box(x: f32, y: f32, z: f32) -> BoundingBox {
+        BoundingBox { center: Vector3::new(x, y, z), width: 1.0, height: 1.0, depth: 1.0 }
+    }
+
+    #[test]
+    fn camera_frustum_culls_boxes_outside() {
+        // default camera at the origin looking down -z with 90° fov
+        let frustum = Frustum::from_camera(&Camera::new(None, None, Some(90.0), None, Some(0.1), Some(100.0)));
+        assert!(frustum.intersects_box(&unit_box(0.0, 0.0, -10.0)));
+        assert!(!frustum.intersects_box(&unit_box(0.0, 0.0, 10.0)));
+        assert!(!frustum.intersects_box(&unit_box(50.0, 0.0, -10.0)));
+        assert!(!frustum.intersects_box(&unit_box(0.0, 0.0, -200.0)));
+        // partially inside the left plane
+        assert!(frustum.intersects_box(&BoundingBox { center: Vector3::new(-25.0, 0.0, -10.0), width: 40.0, height: 1.0, depth: 1.0 }));
+        assert!(frustum.intersects_sphere([0.0, 0.0, -0.5], 1.0));
+    }
+
+    #[test]
+    fn bounding_box_transformed_by_model_matrix() {
+        // rotated 90° around y and moved to x = 5
+        let matrix = [
+            [0.0, 0.0, -1.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0, 0.0],
+            [5.0, 0.0, 0.0, 1.0],
+        ];
+        let local = BoundingBox { center: Vector3::new(1.0, 0.0, 0.0), width: 4.0, height: 2.0, depth: 1.0 };
+        let world = local.transformed(&matrix);
+        assert!((world.center.x - 5.0).abs() < 1e-5);
+        assert!((world.center.z + 1.0).abs() < 1e-5);
+        assert!((world.width - 1.0).abs() < 1e-5);
+        assert!((world.depth - 4.0).abs() < 1e-5);
+    }
+}
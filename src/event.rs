@@ -43,4 +43,5 @@ impl PartialEq for EventModifiers {
     }
 }
 
-pub type EventFunction = Arc<dyn Fn(&mut AppState)>;
\ No newline at end of file
+pub type EventFunction = Arc<dyn Fn(&mut AppState)>;
+pub type AnimationEventFunction = Arc<dyn Fn(&mut AppState, &crate::animation::FiredAnimationEvent)>;
@@ -1,5 +1,6 @@
 use std::fmt::{Debug, Display, Formatter};
 use glium::implement_vertex;
+use glium::backend::Facade;
 use nalgebra::Vector3;
 use serde::{Deserialize, Serialize};
 
@@ -45,6 +46,80 @@ pub struct Vertex {
 
 glium::implement_vertex!(Vertex, position, texcoord, color, normal, bone_indices, bone_weights);
 
+/// Maximum number of morph targets the vertex shader blends per shape.
+pub const MAX_MORPH_TARGETS: usize = 8;
+// width of the texture the morph target deltas are stored in, the height grows with the data
+pub(crate) const MORPH_TEXTURE_WIDTH: u32 = 1024;
+
+/// A blend shape of a `Shape`: per vertex offsets that are added scaled by the morph weight.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MorphTarget {
+    pub name: String,
+    pub position_deltas: Vec<[f32; 3]>,
+    pub normal_deltas: Vec<[f32; 3]>,
+}
+
+/// The morph targets of a shape on the GPU. The deltas are stored in a float texture, two texels
+/// (position and normal) per vertex and target, and fetched in the vertex shader by `gl_VertexID`.
+pub struct MorphTargetBuffer {
+    pub texture: glium::texture::Texture2d,
+    pub target_count: i32,
+    pub vertex_count: i32,
+}
+
+impl MorphTargetBuffer {
+    pub fn new(display: &impl Facade, targets: &[MorphTarget], vertex_count: usize) -> Self {
+        let target_count = targets.len().min(MAX_MORPH_TARGETS);
+        if target_count == 0 || vertex_count == 0 {
+            return Self::empty(display);
+        }
+        let (data, width, height) = pack_morph_targets(&targets[..target_count], vertex_count);
+        let image = glium::texture::RawImage2d {
+            data: std::borrow::Cow::Owned(data),
+            width,
+            height,
+            format: glium::texture::ClientFormat::F32F32F32F32,
+        };
+        let texture = glium::texture::Texture2d::with_format(display, image, glium::texture::UncompressedFloatFormat::F32F32F32F32, glium::texture::MipmapsOption::NoMipmap)
+            .expect("Failed to create morph target texture");
+        Self {
+            texture,
+            target_count: target_count as i32,
+            vertex_count: vertex_count as i32,
+        }
+    }
+
+    /// A buffer without targets, for shapes that don't use morphing.
+    pub fn empty(display: &impl Facade) -> Self {
+        let texture = glium::texture::Texture2d::empty_with_format(display, glium::texture::UncompressedFloatFormat::F32F32F32F32, glium::texture::MipmapsOption::NoMipmap, 1, 1)
+            .expect("Failed to create empty morph target texture");
+        Self {
+            texture,
+            target_count: 0,
+            vertex_count: 0,
+        }
+    }
+}
+
+/// Lays out the deltas of `targets` as RGBA float texels: texel `(target * vertex_count + vertex) * 2`
+/// holds the position delta, the following one the normal delta. Missing deltas are zero.
+pub(crate) fn pack_morph_targets(targets: &[MorphTarget], vertex_count: usize) -> (Vec<f32>, u32, u32) {
+    let texels = targets.len() * vertex_count * 2;
+    let width = MORPH_TEXTURE_WIDTH;
+    let height = (texels as u32).div_ceil(width).max(1);
+    let mut data = vec![0.0f32; (width * height) as usize * 4];
+    for (t, target) in targets.iter().enumerate() {
+        for v in 0..vertex_count {
+            let texel = (t * vertex_count + v) * 2;
+            let position = target.position_deltas.get(v).copied().unwrap_or([0.0; 3]);
+            let normal = target.normal_deltas.get(v).copied().unwrap_or([0.0; 3]);
+            data[texel * 4..texel * 4 + 3].copy_from_slice(&position);
+            data[(texel + 1) * 4..(texel + 1) * 4 + 3].copy_from_slice(&normal);
+        }
+    }
+    (data, width, height)
+}
+
 
 #[derive(Serialize, Deserialize)]
 pub struct BoundingBoxSerializer {
@@ -180,6 +255,28 @@ impl BoundingBox {
         )
     }
 
+    /// The axis aligned box enclosing this box after transforming it by the column-major `matrix`,
+    /// e.g. the world space bounds of a local bounding box and a model matrix.
+    pub fn transformed(&self, matrix: &[[f32; 4]; 4]) -> BoundingBox {
+        let half = [self.width / 2.0, self.height / 2.0, self.depth / 2.0];
+        let c = [self.center.x, self.center.y, self.center.z];
+        let mut center = [0.0f32; 3];
+        let mut extent = [0.0f32; 3];
+        for row in 0..3 {
+            center[row] = matrix[3][row];
+            for col in 0..3 {
+                center[row] += matrix[col][row] * c[col];
+                extent[row] += matrix[col][row].abs() * half[col];
+            }
+        }
+        BoundingBox {
+            center: Vector3::from(center),
+            width: extent[0] * 2.0,
+            height: extent[1] * 2.0,
+            depth: extent[2] * 2.0,
+        }
+    }
+
     pub fn to_serializer(&self) -> BoundingBoxSerializer {
         BoundingBoxSerializer {
             center: [self.center.x, self.center.y, self.center.z],
@@ -197,4 +294,26 @@ impl BoundingBox {
             depth: serializer.depth,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_morph_targets_layout() {
+        let target = |name: &str, p: f32, n: f32| MorphTarget {
+            name: name.to_string(),
+            position_deltas: vec![[p, 0.0, 0.0], [0.0, p, 0.0]],
+            normal_deltas: vec![[n, 0.0, 0.0]],
+        };
+        let (data, width, height) = pack_morph_targets(&[target("smile", 1.0, 0.5), target("blink", 2.0, 0.25)], 2);
+        assert_eq!((width, height), (MORPH_TEXTURE_WIDTH, 1));
+        // target 1, vertex 1, position
+        assert_eq!(&data[6 * 4..6 * 4 + 3], &[0.0, 2.0, 0.0]);
+        // target 1, vertex 0, normal
+        assert_eq!(data[5 * 4], 0.25);
+        // missing normal deltas are zero
+        assert_eq!(&data[3 * 4..3 * 4 + 3], &[0.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,165 @@
+use std::ffi::CString;
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::time::Instant;
+use glium::backend::{Backend, Context};
+use glium::glutin::api::egl::context::PossiblyCurrentContext;
+use glium::glutin::api::egl::device::Device;
+use glium::glutin::api::egl::display::Display as EglDisplay;
+use glium::glutin::config::{ConfigSurfaceTypes, ConfigTemplateBuilder};
+use glium::glutin::context::{ContextAttributesBuilder, PossiblyCurrentGlContext};
+use glium::glutin::display::{GetGlDisplay, GlDisplay};
+use glium::texture::{MipmapsOption, RawImage2d, UncompressedFloatFormat};
+use glium::{SwapBuffersError, Texture2d};
+use image::RgbaImage;
+use crate::{profiler, AppState};
+use crate::renderer::{self, Renderer};
+
+/// Renders an `AppState` offscreen and hands back the resulting pixels instead of presenting
+/// them in a window. Useful for golden-image tests of materials and post-processing effects and
+/// for rendering on CI or build servers.
+///
+/// The GL context comes from an EGL device and is made current without any surface, so no window,
+/// display server or event loop is involved. On machines without a GPU, Mesa's software device
+/// (llvmpipe) works as well.
+pub struct HeadlessRenderer {
+    display: Rc<Context>,
+    renderer: Option<Renderer>,
+    // the renderer's color target is floating point and scaled by the render scale, the final
+    // image is presented into this one, just like the event loop presents into the window
+    output: Texture2d,
+    width: u32,
+    height: u32,
+}
+
+/// Glue between glium and a surfaceless EGL context. Everything is drawn into textures, so
+/// there is no default framebuffer to swap or resize.
+struct SurfacelessBackend {
+    context: PossiblyCurrentContext,
+    width: u32,
+    height: u32,
+}
+
+unsafe impl Backend for SurfacelessBackend {
+    fn swap_buffers(&self) -> Result<(), SwapBuffersError> {
+        Ok(())
+    }
+
+    unsafe fn get_proc_address(&self, symbol: &str) -> *const c_void {
+        let symbol = CString::new(symbol).expect("Failed to convert GL symbol name");
+        self.context.display().get_proc_address(&symbol) as *const _
+    }
+
+    fn get_framebuffer_dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn resize(&self, _new_size: (u32, u32)) {}
+
+    fn is_current(&self) -> bool {
+        self.context.is_current()
+    }
+
+    unsafe fn make_current(&self) {
+        self.context.make_current_surfaceless().expect("Failed to make headless context current");
+    }
+}
+
+impl HeadlessRenderer {
+    pub fn new(width: u32, height: u32) -> Self {
+        let device = Device::query_devices().expect("Failed to query EGL devices")
+            .next()
+            .expect("No EGL device found for headless rendering");
+        let egl_display = unsafe { EglDisplay::with_device(&device, None) }.expect("Failed to create EGL display");
+        // only offscreen framebuffers are used, so the config doesn't need to support any surface
+        let template = ConfigTemplateBuilder::new()
+            .with_surface_type(ConfigSurfaceTypes::empty())
+            .build();
+        let config = unsafe { egl_display.find_configs(template) }.expect("Failed to query EGL configs")
+            .next()
+            .expect("No EGL config found for headless rendering");
+        let context_attributes = ContextAttributesBuilder::new().build(None);
+        let context = unsafe { egl_display.create_context(&config, &context_attributes) }.expect("Failed to create headless GL context")
+            .make_current_surfaceless()
+            .expect("Failed to make headless context current");
+        let backend = SurfacelessBackend { context, width, height };
+        let display = unsafe { Context::new(backend, true, Default::default()) }.expect("Failed to create headless glium context");
+        let output = Texture2d::empty_with_format(&display, UncompressedFloatFormat::U8U8U8U8, MipmapsOption::NoMipmap, width, height).expect("Failed to create headless output texture");
+        HeadlessRenderer {
+            display,
+            renderer: None,
+            output,
+            width,
+            height,
+        }
+    }
+
+    pub fn get_display_clone(&self) -> Rc<Context> {
+        self.display.clone()
+    }
+
+    pub fn get_display_reference(&self) -> &Rc<Context> {
+        &self.display
+    }
+
+    pub fn get_size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Renders `frames` frames of `app_state` and returns the final, tonemapped and post-processed
+    /// image with the requested size, top row first.
+    /// Before every frame the app state is stepped by a fixed `1 / fps` time step, so repeated
+    /// runs of the same scene produce the same image.
+    pub fn render(&mut self, app_state: &mut AppState, frames: u32) -> RgbaImage {
+        if self.renderer.is_none() {
+            app_state.display = Some(self.display.clone());
+            let (skybox, skybox_texture) = renderer::spawn_skybox(&self.display, app_state);
+            app_state.set_skybox(skybox);
+            self.renderer = Some(Renderer::new(&self.display, app_state, self.width, self.height, skybox_texture));
+        }
+        let renderer = self.renderer.as_mut().expect("Failed to retrieve headless renderer");
+
+        let delta_time = 1.0 / app_state.fps.max(1) as f32;
+        for _ in 0..frames.max(1) {
+            let update_start = Instant::now();
+            app_state.step(delta_time);
+            app_state.get_profiler_mut().current_mut().update_ms = profiler::elapsed_ms(update_start);
+            renderer.render_frame(&self.display, app_state);
+            app_state.get_profiler_mut().end_frame();
+        }
+
+        let mut output_framebuffer = glium::framebuffer::SimpleFrameBuffer::new(&self.display, &self.output).expect("Failed to create headless output framebuffer");
+        renderer.present(&mut output_framebuffer);
+        // make sure all queued GL commands are done before reading back
+        self.display.finish();
+
+        let raw: RawImage2d<u8> = self.output.read();
+        let image = RgbaImage::from_raw(raw.width, raw.height, raw.data.into_owned())
+            .expect("Failed to convert headless render result to an image");
+        // OpenGL textures start at the bottom row
+        image::imageops::flip_vertical(&image)
+    }
+}
+
+/// GL context for the unit tests that need a real GPU. Needs an EGL device, e.g. Mesa's software
+/// rasterizer, so these tests are ignored by default: cargo test -- --ignored
+#[cfg(test)]
+pub(crate) fn test_display() -> Rc<Context> {
+    HeadlessRenderer::new(1, 1).get_display_clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+
+    #[test]
+    #[ignore]
+    fn render_returns_image_of_requested_size() {
+        let mut headless = HeadlessRenderer::new(64, 32);
+        let mut app_state = AppState::new();
+        app_state.set_camera(Camera::new(None, None, Some(60.0), Some(2.0), Some(0.01), Some(100.0)));
+        let image = headless.render(&mut app_state, 2);
+        assert_eq!(image.dimensions(), (64, 32));
+    }
+}
@@ -0,0 +1,595 @@
+use std::borrow::Cow;
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+use glium::{Surface, Texture2d};
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::backend::Facade;
+use glium::texture::{ClientFormat, CubeLayer, Cubemap, MipmapsOption, RawImage2d, UncompressedFloatFormat};
+use glium::uniforms::MagnifySamplerFilter;
+use rayon::prelude::*;
+use crate::logging::EnigmaError;
+use crate::smart_format;
+
+const CACHE_MAGIC: &[u8; 4] = b"EIBL";
+const CACHE_VERSION: u32 = 1;
+const CUBE_LAYERS: [CubeLayer; 6] = [
+    CubeLayer::PositiveX,
+    CubeLayer::NegativeX,
+    CubeLayer::PositiveY,
+    CubeLayer::NegativeY,
+    CubeLayer::PositiveZ,
+    CubeLayer::NegativeZ,
+];
+
+/// Resolutions and sample counts of the image based lighting precomputation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IblSettings {
+    /// Face size of the diffuse irradiance cubemap.
+    pub irradiance_size: u32,
+    /// Face size of the first level of the prefiltered specular cubemap, which is the unfiltered environment.
+    pub specular_size: u32,
+    /// Number of roughness levels in the specular mip chain, from roughness 0 to 1.
+    pub specular_levels: u32,
+    pub specular_samples: u32,
+    pub brdf_size: u32,
+    pub brdf_samples: u32,
+}
+
+impl Default for IblSettings {
+    fn default() -> Self {
+        Self {
+            irradiance_size: 32,
+            specular_size: 128,
+            specular_levels: 6,
+            specular_samples: 64,
+            brdf_size: 64,
+            brdf_samples: 256,
+        }
+    }
+}
+
+/// An equirectangular RGB float image, row 0 at the top.
+struct Equirect {
+    width: usize,
+    height: usize,
+    pixels: Vec<[f32; 3]>,
+}
+
+impl Equirect {
+    /// Halves the resolution with a box filter.
+    fn downsample(&self) -> Equirect {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = vec![[0.0f32; 3]; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0.0f32; 3];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (x * 2 + dx).min(self.width - 1);
+                    let sy = (y * 2 + dy).min(self.height - 1);
+                    let p = self.pixels[sy * self.width + sx];
+                    for c in 0..3 {
+                        sum[c] += p[c] * 0.25;
+                    }
+                }
+                pixels[y * width + x] = sum;
+            }
+        }
+        Equirect { width, height, pixels }
+    }
+
+    /// Bilinear lookup in the direction `dir`, using the same mapping as the sky sphere in the lit shader.
+    fn sample(&self, dir: [f32; 3]) -> [f32; 3] {
+        let u = dir[2].atan2(dir[0]) / (2.0 * PI) + 0.5;
+        let v = dir[1].clamp(-1.0, 1.0).asin() / PI + 0.5;
+        let x = u * self.width as f32 - 0.5;
+        let y = ((1.0 - v) * self.height as f32 - 0.5).clamp(0.0, (self.height - 1) as f32);
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let (fx, fy) = (x - x0, y - y0);
+        let wrap = |x: f32| (x as i64).rem_euclid(self.width as i64) as usize;
+        let (x0, x1) = (wrap(x0), wrap(x0 + 1.0));
+        let (y0, y1) = (y0 as usize, (y0 as usize + 1).min(self.height - 1));
+        let mut result = [0.0f32; 3];
+        for (c, value) in result.iter_mut().enumerate() {
+            let top = self.pixels[y0 * self.width + x0][c] * (1.0 - fx) + self.pixels[y0 * self.width + x1][c] * fx;
+            let bottom = self.pixels[y1 * self.width + x0][c] * (1.0 - fx) + self.pixels[y1 * self.width + x1][c] * fx;
+            *value = top * (1.0 - fy) + bottom * fy;
+        }
+        result
+    }
+}
+
+/// The precomputed image based lighting of an environment, ready to upload or cache on disk.
+/// Cubemap faces are stored in OpenGL order (+X, -X, +Y, -Y, +Z, -Z) as RGB floats.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IblData {
+    pub irradiance_size: u32,
+    pub irradiance: Vec<Vec<f32>>,
+    pub specular_size: u32,
+    /// One entry per roughness level, each holding the six faces.
+    pub specular: Vec<Vec<Vec<f32>>>,
+    pub brdf_size: u32,
+    /// Scale and bias of the split sum approximation, x is n·v and y is the roughness.
+    pub brdf_lut: Vec<f32>,
+}
+
+impl IblData {
+    /// Precomputes the lighting of an equirectangular environment. `pixels` are RGB floats, row 0 at the top.
+    pub fn from_equirectangular(width: u32, height: u32, pixels: &[f32], settings: &IblSettings) -> Self {
+        let source = Equirect {
+            width: width as usize,
+            height: height as usize,
+            pixels: pixels.chunks_exact(3).map(|p| [p[0], p[1], p[2]]).collect(),
+        };
+        let mut pyramid = vec![source];
+        while pyramid.last().map(|level| level.width > 1 || level.height > 1).unwrap_or(false) {
+            let next = pyramid.last().expect("pyramid is never empty").downsample();
+            pyramid.push(next);
+        }
+
+        // irradiance, convolved against a small cube of the environment
+        let source_cube_size = 16;
+        let source_level = pyramid.iter()
+            .position(|level| level.width <= (source_cube_size * 4) as usize)
+            .unwrap_or(pyramid.len() - 1);
+        let source_cube: Vec<([f32; 3], f32, [f32; 3])> = (0..6)
+            .flat_map(|face| (0..source_cube_size * source_cube_size).map(move |i| (face, i % source_cube_size, i / source_cube_size)))
+            .map(|(face, x, y)| {
+                let (dir, solid_angle) = cube_texel(face, x, y, source_cube_size);
+                (dir, solid_angle, pyramid[source_level].sample(dir))
+            })
+            .collect();
+        let irradiance = (0..6)
+            .map(|face| {
+                face_texels(face, settings.irradiance_size, |normal| {
+                    let mut sum = [0.0f32; 3];
+                    for (dir, solid_angle, radiance) in source_cube.iter() {
+                        let cos = dot(normal, *dir);
+                        if cos > 0.0 {
+                            for (total, value) in sum.iter_mut().zip(radiance) {
+                                *total += value * cos * solid_angle;
+                            }
+                        }
+                    }
+                    [sum[0] / PI, sum[1] / PI, sum[2] / PI]
+                })
+            })
+            .collect();
+
+        // specular, level 0 is the environment itself
+        let levels = settings.specular_levels.max(1);
+        let base_solid_angle = 4.0 * PI / (pyramid[0].width * pyramid[0].height) as f32;
+        let specular = (0..levels)
+            .map(|level| {
+                let size = (settings.specular_size >> level).max(1);
+                let roughness = if levels > 1 { level as f32 / (levels - 1) as f32 } else { 0.0 };
+                (0..6)
+                    .map(|face| {
+                        face_texels(face, size, |n| {
+                            if level == 0 {
+                                return pyramid[0].sample(n);
+                            }
+                            prefilter(&pyramid, n, roughness, settings.specular_samples, base_solid_angle)
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let brdf_size = settings.brdf_size.max(1);
+        let brdf_lut = (0..brdf_size * brdf_size)
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                let n_dot_v = ((i % brdf_size) as f32 + 0.5) / brdf_size as f32;
+                let roughness = ((i / brdf_size) as f32 + 0.5) / brdf_size as f32;
+                let (scale, bias) = integrate_brdf(n_dot_v, roughness, settings.brdf_samples);
+                [scale, bias]
+            })
+            .collect();
+
+        Self {
+            irradiance_size: settings.irradiance_size,
+            irradiance,
+            specular_size: settings.specular_size,
+            specular,
+            brdf_size,
+            brdf_lut,
+        }
+    }
+
+    /// Decodes an image (e.g. a Radiance `.hdr` file) and precomputes its lighting.
+    pub fn from_image_bytes(data: &[u8], settings: &IblSettings) -> Result<Self, EnigmaError> {
+        let image = match image::load_from_memory(data) {
+            Ok(image) => image.to_rgb32f(),
+            Err(e) => {
+                let error = EnigmaError::new(Some(smart_format!("Failed to decode environment image: {}", e).as_str()), true);
+                error.log();
+                return Err(error);
+            }
+        };
+        let (width, height) = image.dimensions();
+        Ok(IblData::from_equirectangular(width, height, image.as_raw(), settings))
+    }
+
+    /// Like `from_image_bytes`, but keeps the result in `cache_dir`. The cache file is named after a hash of
+    /// the image and the settings, so changing either computes the lighting again.
+    pub fn load_or_compute(data: &[u8], settings: &IblSettings, cache_dir: &Path) -> Result<Self, EnigmaError> {
+        let path = IblData::cache_path(data, settings, cache_dir);
+        if path.is_file() {
+            if let Ok(cached) = IblData::load(&path) {
+                return Ok(cached);
+            }
+        }
+        let computed = IblData::from_image_bytes(data, settings)?;
+        // a failed cache write only costs time on the next start
+        let _ = std::fs::create_dir_all(cache_dir);
+        let _ = computed.save(&path);
+        Ok(computed)
+    }
+
+    pub fn cache_path(data: &[u8], settings: &IblSettings, cache_dir: &Path) -> PathBuf {
+        let mut hash = fnv1a(0xcbf29ce484222325, data);
+        for value in [settings.irradiance_size, settings.specular_size, settings.specular_levels, settings.specular_samples, settings.brdf_size, settings.brdf_samples, CACHE_VERSION] {
+            hash = fnv1a(hash, &value.to_le_bytes());
+        }
+        cache_dir.join(format!("ibl_{:016x}.bin", hash))
+    }
+
+    /// A uniformly lit environment, mostly useful as a neutral fallback.
+    pub fn constant(color: [f32; 3]) -> Self {
+        let face = vec![color[0], color[1], color[2]];
+        Self {
+            irradiance_size: 1,
+            irradiance: vec![face.clone(); 6],
+            specular_size: 1,
+            specular: vec![vec![face; 6]],
+            brdf_size: 1,
+            brdf_lut: vec![1.0, 0.0],
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), EnigmaError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(CACHE_MAGIC);
+        for value in [CACHE_VERSION, self.irradiance_size, self.specular_size, self.specular.len() as u32, self.brdf_size] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        let floats = self.irradiance.iter()
+            .chain(self.specular.iter().flatten())
+            .chain(std::iter::once(&self.brdf_lut));
+        for data in floats {
+            for value in data {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        std::fs::write(path, bytes).map_err(|e| {
+            let error = EnigmaError::new(Some(smart_format!("Failed to write IBL cache {}: {}", path.display(), e).as_str()), true);
+            error.log();
+            error
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, EnigmaError> {
+        let invalid = |reason: &str| EnigmaError::new(Some(smart_format!("Invalid IBL cache {}: {}", path.display(), reason).as_str()), true);
+        let bytes = std::fs::read(path).map_err(|e| invalid(&e.to_string()))?;
+        if bytes.len() < 24 || &bytes[0..4] != CACHE_MAGIC {
+            return Err(invalid("missing header"));
+        }
+        let header: Vec<u32> = bytes[4..24].chunks_exact(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        if header[0] != CACHE_VERSION {
+            return Err(invalid("outdated version"));
+        }
+        let (irradiance_size, specular_size, levels, brdf_size) = (header[1], header[2], header[3], header[4]);
+        if irradiance_size == 0 || specular_size == 0 || brdf_size == 0 {
+            return Err(invalid("empty texture"));
+        }
+        // every level halves the size, down to 1x1
+        if levels == 0 || levels > specular_size.ilog2() + 1 {
+            return Err(invalid("wrong number of specular levels"));
+        }
+        let mut floats = bytes[24..].chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let mut take = |count: usize| -> Result<Vec<f32>, EnigmaError> {
+            let data: Vec<f32> = floats.by_ref().take(count).collect();
+            if data.len() != count {
+                return Err(invalid("truncated"));
+            }
+            Ok(data)
+        };
+        let texel_floats = |size: u32, channels: usize| (size as usize).checked_mul(size as usize)
+            .and_then(|texels| texels.checked_mul(channels))
+            .ok_or_else(|| invalid("texture too large"));
+        let irradiance_floats = texel_floats(irradiance_size, 3)?;
+        let irradiance = (0..6).map(|_| take(irradiance_floats)).collect::<Result<Vec<_>, _>>()?;
+        let specular = (0..levels)
+            .map(|level| {
+                let size = specular_size.checked_shr(level).unwrap_or(0).max(1);
+                let floats = texel_floats(size, 3)?;
+                (0..6).map(|_| take(floats)).collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let brdf_lut = take(texel_floats(brdf_size, 2)?)?;
+        Ok(Self { irradiance_size, irradiance, specular_size, specular, brdf_size, brdf_lut })
+    }
+}
+
+/// The GPU side of `IblData`: an irradiance cubemap for diffuse light, a prefiltered cubemap whose mip levels
+/// hold increasing roughness for specular light, and the BRDF lookup table of the split sum approximation.
+pub struct Ibl {
+    pub irradiance: Cubemap,
+    pub specular: Cubemap,
+    pub brdf_lut: Texture2d,
+    pub specular_levels: u32,
+    /// Whether the lit shader uses this environment, the fallback sphere map reflection is used otherwise.
+    pub enabled: bool,
+    pub intensity: f32,
+}
+
+impl Ibl {
+    pub fn new(display: &impl Facade, data: &IblData) -> Self {
+        let levels = data.specular.len().max(1) as u32;
+        let irradiance = Cubemap::empty_with_format(display, UncompressedFloatFormat::F16F16F16, MipmapsOption::NoMipmap, data.irradiance_size)
+            .expect("Failed to create irradiance cubemap");
+        upload_cube_level(display, &irradiance, 0, data.irradiance_size, &data.irradiance);
+
+        let specular = Cubemap::empty_with_format(display, UncompressedFloatFormat::F16F16F16, MipmapsOption::EmptyMipmapsMax(levels - 1), data.specular_size)
+            .expect("Failed to create prefiltered specular cubemap");
+        for (level, faces) in data.specular.iter().enumerate() {
+            upload_cube_level(display, &specular, level as u32, (data.specular_size >> level).max(1), faces);
+        }
+
+        let brdf_image = RawImage2d {
+            data: Cow::Borrowed(data.brdf_lut.as_slice()),
+            width: data.brdf_size,
+            height: data.brdf_size,
+            format: ClientFormat::F32F32,
+        };
+        let brdf_lut = Texture2d::with_format(display, brdf_image, UncompressedFloatFormat::F16F16, MipmapsOption::NoMipmap)
+            .expect("Failed to create BRDF lookup texture");
+
+        Self {
+            irradiance,
+            specular,
+            brdf_lut,
+            specular_levels: levels,
+            enabled: true,
+            intensity: 1.0,
+        }
+    }
+
+    /// A black, disabled environment used until one is set.
+    pub fn disabled(display: &impl Facade) -> Self {
+        let mut ibl = Ibl::new(display, &IblData::constant([0.0; 3]));
+        ibl.enabled = false;
+        ibl
+    }
+}
+
+/// Uploads six RGB float faces into mip `level` of `cubemap` by blitting them from temporary textures.
+fn upload_cube_level(display: &impl Facade, cubemap: &Cubemap, level: u32, size: u32, faces: &[Vec<f32>]) {
+    let mipmap = cubemap.mipmap(level).expect("Cubemap mip level out of range");
+    for (layer, face) in CUBE_LAYERS.iter().zip(faces.iter()) {
+        let image = RawImage2d {
+            data: Cow::Borrowed(face.as_slice()),
+            width: size,
+            height: size,
+            format: ClientFormat::F32F32F32,
+        };
+        let texture = Texture2d::with_format(display, image, UncompressedFloatFormat::F16F16F16, MipmapsOption::NoMipmap)
+            .expect("Failed to create cubemap face texture");
+        let target = SimpleFrameBuffer::new(display, mipmap.image(*layer)).expect("Failed to create cubemap face framebuffer");
+        texture.as_surface().blit_whole_color_to(
+            &target,
+            &glium::BlitTarget { left: 0, bottom: 0, width: size as i32, height: size as i32 },
+            MagnifySamplerFilter::Nearest,
+        );
+    }
+}
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = dot(v, v).sqrt();
+    [v[0] / length, v[1] / length, v[2] / length]
+}
+
+/// Direction through the center of texel (x, y) of a cube face and the solid angle the texel covers.
+/// Row 0 is the first row of the face image, as OpenGL expects it.
+fn cube_texel(face: usize, x: u32, y: u32, size: u32) -> ([f32; 3], f32) {
+    let sc = 2.0 * (x as f32 + 0.5) / size as f32 - 1.0;
+    let tc = 2.0 * (y as f32 + 0.5) / size as f32 - 1.0;
+    let dir = match face {
+        0 => [1.0, -tc, -sc],
+        1 => [-1.0, -tc, sc],
+        2 => [sc, 1.0, tc],
+        3 => [sc, -1.0, -tc],
+        4 => [sc, -tc, 1.0],
+        _ => [-sc, -tc, -1.0],
+    };
+    let solid_angle = 4.0 / (size * size) as f32 / (1.0 + sc * sc + tc * tc).powf(1.5);
+    (normalize(dir), solid_angle)
+}
+
+/// Evaluates `f` for the direction of every texel of a face, in parallel.
+fn face_texels<F: Fn([f32; 3]) -> [f32; 3] + Sync>(face: usize, size: u32, f: F) -> Vec<f32> {
+    (0..size * size)
+        .into_par_iter()
+        .flat_map_iter(|i| f(cube_texel(face, i % size, i / size, size).0))
+        .collect()
+}
+
+fn hammersley(i: u32, count: u32) -> (f32, f32) {
+    (i as f32 / count as f32, i.reverse_bits() as f32 * 2.328_306_4e-10)
+}
+
+fn importance_sample_ggx(xi: (f32, f32), n: [f32; 3], roughness: f32) -> [f32; 3] {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.0;
+    let cos_theta = ((1.0 - xi.1) / (1.0 + (a * a - 1.0) * xi.1)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let h = [phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta];
+    let up = if n[2].abs() < 0.999 { [0.0, 0.0, 1.0] } else { [1.0, 0.0, 0.0] };
+    let tangent = normalize(cross(up, n));
+    let bitangent = cross(n, tangent);
+    normalize([
+        tangent[0] * h[0] + bitangent[0] * h[1] + n[0] * h[2],
+        tangent[1] * h[0] + bitangent[1] * h[1] + n[1] * h[2],
+        tangent[2] * h[0] + bitangent[2] * h[1] + n[2] * h[2],
+    ])
+}
+
+/// GGX prefiltered radiance around `n`, assuming the view direction equals the normal. Samples are taken from
+/// coarser pyramid levels where the sample density is low, which keeps rough levels free of fireflies.
+fn prefilter(pyramid: &[Equirect], n: [f32; 3], roughness: f32, samples: u32, base_solid_angle: f32) -> [f32; 3] {
+    let a2 = roughness.powi(4);
+    let mut sum = [0.0f32; 3];
+    let mut weight = 0.0f32;
+    for i in 0..samples.max(1) {
+        let h = importance_sample_ggx(hammersley(i, samples.max(1)), n, roughness);
+        let n_dot_h = dot(n, h).max(0.0);
+        let l = [2.0 * n_dot_h * h[0] - n[0], 2.0 * n_dot_h * h[1] - n[1], 2.0 * n_dot_h * h[2] - n[2]];
+        let n_dot_l = dot(n, l);
+        if n_dot_l <= 0.0 {
+            continue;
+        }
+        let d = a2 / (PI * (n_dot_h * n_dot_h * (a2 - 1.0) + 1.0).powi(2));
+        let pdf = d / 4.0;
+        let sample_solid_angle = 1.0 / (samples as f32 * pdf + 0.0001);
+        let lod = (0.5 * (sample_solid_angle / base_solid_angle).log2() + 1.0).max(0.0);
+        let level = (lod.round() as usize).min(pyramid.len() - 1);
+        let radiance = pyramid[level].sample(l);
+        for c in 0..3 {
+            sum[c] += radiance[c] * n_dot_l;
+        }
+        weight += n_dot_l;
+    }
+    if weight <= 0.0 {
+        return pyramid[0].sample(n);
+    }
+    [sum[0] / weight, sum[1] / weight, sum[2] / weight]
+}
+
+/// Scale and bias to F0 of the specular BRDF integrated over the hemisphere.
+fn integrate_brdf(n_dot_v: f32, roughness: f32, samples: u32) -> (f32, f32) {
+    let v = [(1.0 - n_dot_v * n_dot_v).max(0.0).sqrt(), 0.0, n_dot_v];
+    let n = [0.0, 0.0, 1.0];
+    let k = roughness * roughness / 2.0;
+    let geometry = |n_dot_x: f32| n_dot_x / (n_dot_x * (1.0 - k) + k);
+    let (mut scale, mut bias) = (0.0f32, 0.0f32);
+    for i in 0..samples.max(1) {
+        let h = importance_sample_ggx(hammersley(i, samples.max(1)), n, roughness);
+        let v_dot_h = dot(v, h);
+        let l = [2.0 * v_dot_h * h[0] - v[0], 2.0 * v_dot_h * h[1] - v[1], 2.0 * v_dot_h * h[2] - v[2]];
+        let n_dot_l = l[2].max(0.0);
+        let n_dot_h = h[2].max(0.0);
+        let v_dot_h = v_dot_h.max(0.0);
+        if n_dot_l > 0.0 {
+            let g = geometry(n_dot_l) * geometry(n_dot_v);
+            let g_vis = g * v_dot_h / (n_dot_h * n_dot_v).max(0.0001);
+            let fc = (1.0 - v_dot_h).powi(5);
+            scale += (1.0 - fc) * g_vis;
+            bias += fc * g_vis;
+        }
+    }
+    (scale / samples.max(1) as f32, bias / samples.max(1) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_settings() -> IblSettings {
+        IblSettings {
+            irradiance_size: 4,
+            specular_size: 8,
+            specular_levels: 3,
+            specular_samples: 16,
+            brdf_size: 8,
+            brdf_samples: 32,
+        }
+    }
+
+    #[test]
+    fn cube_face_centers() {
+        let expected = [[1.0, 0.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0], [0.0, 0.0, -1.0]];
+        for (face, direction) in expected.iter().enumerate() {
+            // size 1 has its only texel at the center
+            let (dir, solid_angle) = cube_texel(face, 0, 0, 1);
+            assert!(dot(dir, *direction) > 0.999, "face {} points to {:?}", face, dir);
+            assert!((solid_angle - 4.0).abs() < 1e-5);
+        }
+        // all texels of a cube cover the sphere
+        let size = 16;
+        let total: f32 = (0..6).flat_map(|f| (0..size * size).map(move |i| cube_texel(f, i % size, i / size, size).1)).sum();
+        assert!((total - 4.0 * PI).abs() < 0.05, "total solid angle {}", total);
+    }
+
+    #[test]
+    fn constant_environment_stays_constant() {
+        let pixels = vec![0.5f32; 16 * 8 * 3];
+        let data = IblData::from_equirectangular(16, 8, &pixels, &small_settings());
+        assert_eq!(data.irradiance.len(), 6);
+        assert_eq!(data.specular.len(), 3);
+        assert_eq!(data.specular[2][0].len(), 2 * 2 * 3);
+        for value in data.irradiance.iter().flatten() {
+            assert!((value - 0.5).abs() < 0.02, "irradiance {}", value);
+        }
+        for value in data.specular.iter().flatten().flatten() {
+            assert!((value - 0.5).abs() < 1e-3, "specular {}", value);
+        }
+    }
+
+    #[test]
+    fn brdf_of_smooth_surface_at_normal_incidence() {
+        let (scale, bias) = integrate_brdf(1.0, 0.0, 64);
+        assert!((scale - 1.0).abs() < 1e-3);
+        assert!(bias.abs() < 1e-3);
+        // rough surfaces at grazing angles reflect less
+        let (rough_scale, _) = integrate_brdf(0.1, 1.0, 256);
+        assert!(rough_scale < scale);
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let pixels: Vec<f32> = (0..16 * 8 * 3).map(|i| (i % 7) as f32 * 0.25).collect();
+        let data = IblData::from_equirectangular(16, 8, &pixels, &small_settings());
+        let dir = std::env::temp_dir().join(format!("enigma_ibl_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ibl.bin");
+        data.save(&path).unwrap();
+        assert_eq!(IblData::load(&path).unwrap(), data);
+        std::fs::write(&path, b"nope").unwrap();
+        assert!(IblData::load(&path).is_err());
+        // a corrupt header has to fail instead of overflowing
+        let header = |values: [u32; 4]| {
+            let mut bytes = CACHE_MAGIC.to_vec();
+            for value in [CACHE_VERSION].into_iter().chain(values) {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            bytes
+        };
+        for values in [[u32::MAX, 1, 1, 1], [1, 4, 40, 1], [1, 4, 0, 1], [1, 0, 1, 1], [1, 1, 1, u32::MAX]] {
+            std::fs::write(&path, header(values)).unwrap();
+            assert!(IblData::load(&path).is_err());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let other = IblSettings { specular_samples: 32, ..small_settings() };
+        assert_ne!(IblData::cache_path(b"a", &small_settings(), &dir), IblData::cache_path(b"a", &other, &dir));
+        assert_ne!(IblData::cache_path(b"a", &small_settings(), &dir), IblData::cache_path(b"b", &small_settings(), &dir));
+    }
+}
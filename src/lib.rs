@@ -1,30 +1,36 @@
 use std::any::Any;
 use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use egui_glium::EguiGlium;
 use winit::window::Window;
+use glium::backend::{Context, Facade};
 use glium::glutin::surface::WindowSurface;
-use glium::{Display, Surface, Texture2d, uniform};
-use glium::uniforms::UniformBuffer;
+use glium::Display;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use winit::event::{DeviceEvent, Event, WindowEvent};
 use winit::window::CursorGrabMode;
 use winit::event_loop::{ControlFlow};
 use crate::audio::{AudioClip, AudioEngine};
-use crate::shadow::ShadowMaps;
-use crate::shadow::{directional_light_space_matrix, view_matrix, perspective_90_matrix, mat4_mul, CUBE_FACE_DIRS, face_viewport};
 use crate::camera::{Camera, CameraSerializer};
 use crate::collision_world::MouseState;
+use crate::culling::CullingStats;
+use crate::profiler::{FrameProfiler, FrameStats};
 use crate::data::AppStateData;
 use crate::event::EventModifiers;
-use crate::geometry::BoneTransforms;
 use crate::light::{Light, LightEmissionType};
-use crate::logging::{EnigmaError, EnigmaMessage, EnigmaWarning};
+use crate::lighting::ClusterGrid;
+use crate::ibl::{IblData, IblSettings};
+use crate::logging::{EnigmaError, EnigmaMessage};
 use crate::material::Material;
-use crate::object::{Object, ObjectInstance};
-use crate::postprocessing::PostProcessingEffect;
+use crate::mesh_cache::MeshCache;
+use crate::object::Object;
+use crate::postprocessing::{PostProcessEntry, PostProcessEntrySerializer, PostProcessingEffect};
+use crate::postprocessing::taa::TaaSettings;
+use crate::postprocessing::tonemapping::{AutoExposure, Exposure, TonemapOperator};
+use crate::renderer::Renderer;
 use crate::texture::Texture;
 
 pub mod shader;
@@ -44,10 +50,20 @@ pub mod resources;
 pub mod data;
 pub mod example_resources;
 pub mod animation;
+pub mod animation_state_machine;
 pub mod logging;
 pub mod audio;
 pub mod shadow;
 pub mod terrain;
+pub mod renderer;
+// headless rendering needs an EGL device, which glutin doesn't provide on macOS
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+pub mod headless;
+pub mod mesh_cache;
+pub mod culling;
+pub mod profiler;
+pub mod lighting;
+pub mod ibl;
 
 pub fn init_default(app_state: &mut AppState) {
     app_state.set_renderscale(1);
@@ -122,6 +138,9 @@ pub struct AppStateSerializer {
     pub skybox_texture: Option<texture::TextureSerializer>,
     pub objects: Vec<object::ObjectSerializer>,
     pub object_selection: Vec<String>,
+    /// `None` for scenes saved without post-processing, loading them keeps the current stack.
+    #[serde(default)]
+    pub post_processes: Option<Vec<PostProcessEntrySerializer>>,
 }
 
 pub struct AppState {
@@ -137,9 +156,11 @@ pub struct AppState {
     pub event_injections: Vec<(event::EventCharacteristic, event::EventFunction, event::EventModifiers)>,
     pub start_injections: Vec<event::EventFunction>,
     pub update_injections: Vec<event::EventFunction>,
+    pub fixed_update_injections: Vec<event::EventFunction>,
+    pub animation_event_injections: Vec<(String, event::AnimationEventFunction)>,
     pub gui_injections: Vec<ui::GUIDrawFunction>,
-    pub post_processes: Vec<Box<dyn PostProcessingEffect>>,
-    pub display: Option<glium::Display<WindowSurface>>,
+    pub post_processes: Vec<PostProcessEntry>,
+    pub display: Option<Rc<Context>>,
     pub time: f32,
     pub delta_time: f32,
     pub render_scale: u32,
@@ -147,16 +168,34 @@ pub struct AppState {
     mouse_state: MouseState,
     last_event_time: Instant,
     last_frame_time: Instant,
+    started: bool,
     is_mouse_down: bool,
     pub state_data: Vec<AppStateData>,
     audio_engine: AudioEngine,
     audio_clips:  HashMap<String, AudioClip>,
     pub shadow_resolution: u32,
     pub shadow_distance: f32,
+    pub shadow_cascades: usize,
+    pub shadow_cascade_lambda: f32,
     pub modifiers: EventModifiers,
     pub held_keys: HashSet<event::VirtualKeyCode>,
     pub cursor_locked: bool,
+    pub fixed_tick_rate: u32,
+    pub max_fixed_steps: u32,
+    fixed_accumulator: f32,
+    interpolation_alpha: f32,
     pub terrain: Option<terrain::Terrain>,
+    pub frustum_culling: bool,
+    pub light_clusters: ClusterGrid,
+    pub environment_lighting: Option<Arc<IblData>>,
+    pub environment_intensity: f32,
+    pub tonemap_operator: TonemapOperator,
+    pub exposure: Exposure,
+    pub tonemap_position: usize,
+    current_exposure: f32,
+    pub taa: Option<TaaSettings>,
+    culling_stats: CullingStats,
+    profiler: FrameProfiler,
 }
 
 pub struct EventLoop {
@@ -181,6 +220,8 @@ impl AppState {
             ambient_light: None,
             event_injections: Vec::new(),
             update_injections: Vec::new(),
+            fixed_update_injections: Vec::new(),
+            animation_event_injections: Vec::new(),
             start_injections: Vec::new(),
             post_processes: Vec::new(),
             display: None,
@@ -193,15 +234,33 @@ impl AppState {
             state_data: Vec::new(),
             last_event_time: Instant::now(),
             last_frame_time: Instant::now(),
+            started: false,
             is_mouse_down: false,
             audio_engine: AudioEngine::new(),
             audio_clips: HashMap::new(),
             shadow_resolution: 1024,
             shadow_distance: 50.0,
+            shadow_cascades: 3,
+            shadow_cascade_lambda: 0.75,
             modifiers: EventModifiers::default(),
             held_keys: HashSet::new(),
             cursor_locked: false,
+            fixed_tick_rate: 60,
+            max_fixed_steps: 5,
+            fixed_accumulator: 0.0,
+            interpolation_alpha: 0.0,
             terrain: None,
+            frustum_culling: true,
+            light_clusters: ClusterGrid::default(),
+            environment_lighting: None,
+            environment_intensity: 1.0,
+            tonemap_operator: TonemapOperator::default(),
+            exposure: Exposure::default(),
+            tonemap_position: 0,
+            current_exposure: 1.0,
+            taa: None,
+            culling_stats: CullingStats::default(),
+            profiler: FrameProfiler::new(300),
         }
     }
 
@@ -233,69 +292,21 @@ impl AppState {
         self.audio_engine.set_clip_volume(name, volume);
     }
 
-    fn setup_skybox_instance(&self, display: &Display<WindowSurface>, sky_box_matrix: &Option<[[f32; 4]; 4]>) -> Option<(Uuid, object::ObjectInstance)> {
-        match &self.skybox {
-            Some(skybox) => {
-                let mut instance = ObjectInstance::new(display);
-                let model_matrix = sky_box_matrix.unwrap_or_else(|| {
-                    [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]]
-                });
-                instance.set_vertex_buffers(skybox.get_vertex_buffers(display));
-                instance.set_index_buffers(skybox.get_index_buffers(display));
-                instance.instance_matrices.push(model_matrix);
-                let data = instance.instance_matrices
-                    .iter()
-                    .map(|i| geometry::InstanceAttribute {
-                        model_matrix: *i,
-                    })
-                    .collect::<Vec<_>>();
-                instance.instance_attributes = glium::vertex::VertexBuffer::dynamic(display, &data).unwrap();
-                Some((skybox.get_unique_id(), instance))
-            }
-            None => None
-        }
+    fn setup_skybox_instance(&self, display: &impl Facade, sky_box_matrix: &Option<[[f32; 4]; 4]>, mesh_cache: &mut MeshCache) {
+        mesh_cache.update_skybox(display, self.skybox.as_ref(), sky_box_matrix);
     }
 
-    fn setup_instances(&mut self, display: &Display<WindowSurface>, model_matrices: &HashMap<Uuid, [[f32; 4]; 4]>) -> HashMap<Uuid, object::ObjectInstance> {
-        let mut instances = HashMap::new();
+    fn setup_instances(&mut self, display: &impl Facade, model_matrices: &HashMap<Uuid, [[f32; 4]; 4]>, mesh_cache: &mut MeshCache) {
         // sort objects for transparent rendering
         let cam_pos = self.camera.as_ref().expect("failed to retrieve camera").transform.get_position();
         self.objects.sort_by(|a, b| {
-            let distance_a = (cam_pos - a.transform.get_position()).len();
-            let distance_b = (cam_pos - b.transform.get_position()).len();
+            let distance_a = (cam_pos - a.transform.get_world_position()).len();
+            let distance_b = (cam_pos - b.transform.get_world_position()).len();
             distance_b.partial_cmp(&distance_a).unwrap()
         });
 
-        // iterating over the objects, making instances
-        for object in self.objects.iter() {
-            let instance_id = object.get_instance_id();
-            let model_matrix = model_matrices.get(&object.get_unique_id()).unwrap_or_else(|| {
-                &[[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]]
-            });
-            if !instances.contains_key(&instance_id) {
-                let mut object_instance = ObjectInstance::new(display);
-                object_instance.set_vertex_buffers(object.get_vertex_buffers(display));
-                object_instance.set_index_buffers(object.get_index_buffers(display));
-                instances.insert(instance_id, object_instance);
-            }
-            instances.get_mut(&instance_id).expect("No instance of this uuid found. which is weird, because we just added it above").add_instance(*model_matrix);
-
-
-            //updating instance attributes
-            match instances.get_mut(&instance_id) {
-                Some(instance) => {
-                    let data = instance.instance_matrices
-                        .iter()
-                        .map(|i| geometry::InstanceAttribute {
-                            model_matrix: *i,
-                        })
-                        .collect::<Vec<_>>();
-                    instance.instance_attributes = glium::vertex::VertexBuffer::dynamic(display, &data).unwrap();
-                }
-                None => panic!("Something went wrong, when adding the instance")
-            }
-        }
-        instances
+        // geometry is only uploaded for new or changed objects, see `MeshCache`
+        mesh_cache.update(display, &self.objects, model_matrices);
     }
 
     pub fn to_serializer(&self) -> AppStateSerializer {
@@ -320,6 +331,7 @@ impl AppState {
         let objects = self.objects.iter().map(|o| o.to_serializer()).collect();
         let materials = self.materials.iter().map(|o| o.to_serializer()).collect();
         let object_selection = self.object_selection.iter().map(|o| o.to_string()).collect();
+        let post_processes = self.post_processes.iter().filter_map(|p| p.to_serializer()).collect();
         AppStateSerializer {
             camera,
             light,
@@ -329,10 +341,11 @@ impl AppState {
             objects,
             materials,
             object_selection,
+            post_processes: Some(post_processes),
         }
     }
 
-    pub fn inject_serializer(&mut self, serializer: AppStateSerializer, display: Display<WindowSurface>, additive: bool) {
+    pub fn inject_serializer(&mut self, serializer: AppStateSerializer, display: impl Facade, additive: bool) {
         self.camera = match serializer.camera {
             Some(camera) => Some(Camera::from_serializer(camera)),
             None => None,
@@ -363,12 +376,21 @@ impl AppState {
         for o in serializer.objects {
             self.add_object(Object::from_serializer(o));
         }
+        self.update_hierarchy();
         for m in serializer.materials {
             self.add_material(Material::from_serializer(m, &display));
         }
         for o in serializer.object_selection {
             self.object_selection.push(Uuid::parse_str(&o).unwrap());
         }
+        if let Some(post_processes) = serializer.post_processes {
+            self.post_processes.clear();
+            for p in post_processes {
+                let entry = PostProcessEntry::from_serializer(p, &display);
+                let name = self.unique_post_process_name(&entry.name);
+                self.post_processes.push(PostProcessEntry { name, ..entry });
+            }
+        }
     }
 
     pub fn add_state_data(&mut self, name: &str, data: Box<dyn Any>) {
@@ -436,18 +458,103 @@ impl AppState {
         self.gui_injections.push(function);
     }
 
-    pub fn add_post_process(&mut self, post_process: Box<dyn PostProcessingEffect>) {
-        self.post_processes.push(post_process);
+    /// Appends `post_process` to the stack, named after its type, and returns the name.
+    pub fn add_post_process(&mut self, post_process: Box<dyn PostProcessingEffect>) -> String {
+        let name = post_process.to_serializer()
+            .map(|settings| settings.type_name())
+            .unwrap_or("PostProcess");
+        self.insert_post_process(self.post_processes.len(), name, post_process)
+    }
+
+    /// Appends `post_process` under `name` and returns the name, which gets a number appended if it is taken.
+    pub fn add_named_post_process(&mut self, name: &str, post_process: Box<dyn PostProcessingEffect>) -> String {
+        self.insert_post_process(self.post_processes.len(), name, post_process)
     }
 
-    pub fn get_post_processes(&self) -> &Vec<Box<dyn PostProcessingEffect>> {
+    /// Inserts `post_process` at `index` of the stack, see `add_named_post_process`.
+    pub fn insert_post_process(&mut self, index: usize, name: &str, post_process: Box<dyn PostProcessingEffect>) -> String {
+        let unique_name = self.unique_post_process_name(name);
+        let index = index.min(self.post_processes.len());
+        self.post_processes.insert(index, PostProcessEntry::new(&unique_name, post_process));
+        unique_name
+    }
+
+    pub fn remove_post_process(&mut self, name: &str) -> Option<Box<dyn PostProcessingEffect>> {
+        let index = self.post_processes.iter().position(|p| p.name == name)?;
+        Some(self.post_processes.remove(index).effect)
+    }
+
+    /// Moves the effect called `name` to `index`, returns false if there is no such effect.
+    pub fn move_post_process(&mut self, name: &str, index: usize) -> bool {
+        match self.post_processes.iter().position(|p| p.name == name) {
+            Some(current) => {
+                let entry = self.post_processes.remove(current);
+                let index = index.min(self.post_processes.len());
+                self.post_processes.insert(index, entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns false if there is no effect called `name`.
+    pub fn set_post_process_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.get_post_process_entry_mut(name) {
+            Some(entry) => {
+                entry.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets how much of the effect called `name` is mixed into the image, returns false if there is no such effect.
+    pub fn set_post_process_weight(&mut self, name: &str, weight: f32) -> bool {
+        match self.get_post_process_entry_mut(name) {
+            Some(entry) => {
+                entry.weight = weight.clamp(0.0, 1.0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_post_process_entry(&self, name: &str) -> Option<&PostProcessEntry> {
+        self.post_processes.iter().find(|p| p.name == name)
+    }
+
+    pub fn get_post_process_entry_mut(&mut self, name: &str) -> Option<&mut PostProcessEntry> {
+        self.post_processes.iter_mut().find(|p| p.name == name)
+    }
+
+    /// The names of the effects in the order they are rendered.
+    pub fn get_post_process_names(&self) -> Vec<String> {
+        self.post_processes.iter().map(|p| p.name.clone()).collect()
+    }
+
+    pub fn get_post_processes(&self) -> &Vec<PostProcessEntry> {
         &self.post_processes
     }
 
-    pub fn get_post_processes_mut(&mut self) -> &mut Vec<Box<dyn PostProcessingEffect>> {
+    pub fn get_post_processes_mut(&mut self) -> &mut Vec<PostProcessEntry> {
         &mut self.post_processes
     }
 
+    /// The first post-processing effect of type `T`, e.g. to animate its settings from an update function.
+    pub fn get_post_process_mut<T: PostProcessingEffect + 'static>(&mut self) -> Option<&mut T> {
+        self.post_processes.iter_mut()
+            .filter_map(|p| p.effect.as_any_mut())
+            .find_map(|process| process.downcast_mut::<T>())
+    }
+
+    fn unique_post_process_name(&self, name: &str) -> String {
+        let taken = |candidate: &str| self.post_processes.iter().any(|p| p.name == candidate);
+        if !taken(name) {
+            return name.to_string();
+        }
+        (2..).map(|i| format!("{}_{}", name, i)).find(|candidate| !taken(candidate)).expect("Ran out of post process names")
+    }
+
     pub fn get_mouse_state(&self) -> &MouseState {
         &self.mouse_state
     }
@@ -464,8 +571,170 @@ impl AppState {
         self.objects.push(object);
     }
 
+    /// Removes the objects and all of their children.
     pub fn remove_objects(&mut self, uuids: &[Uuid]) {
-        self.objects.retain(|o| !uuids.contains(&o.get_unique_id()));
+        let mut removed: HashSet<Uuid> = uuids.iter().copied().collect();
+        loop {
+            let children: Vec<Uuid> = self.objects.iter()
+                .filter(|o| !removed.contains(&o.get_unique_id()) && o.get_parent().is_some_and(|p| removed.contains(&p)))
+                .map(|o| o.get_unique_id())
+                .collect();
+            if children.is_empty() {
+                break;
+            }
+            removed.extend(children);
+        }
+        self.objects.retain(|o| !removed.contains(&o.get_unique_id()));
+    }
+
+    /// Removes the objects but keeps their children. The children are attached to the next
+    /// ancestor that is not removed and keep their world transform.
+    pub fn remove_objects_keep_children(&mut self, uuids: &[Uuid]) {
+        self.update_hierarchy();
+        let removed: HashSet<Uuid> = uuids.iter().copied().collect();
+        let parents: HashMap<Uuid, Option<Uuid>> = self.objects.iter().map(|o| (o.get_unique_id(), o.get_parent())).collect();
+        let children: Vec<Uuid> = self.objects.iter()
+            .filter(|o| !removed.contains(&o.get_unique_id()) && o.get_parent().is_some_and(|p| removed.contains(&p)))
+            .map(|o| o.get_unique_id())
+            .collect();
+        for child in children {
+            let mut new_parent = parents.get(&child).copied().flatten();
+            let mut depth = 0;
+            while let Some(p) = new_parent {
+                if !removed.contains(&p) || depth > parents.len() {
+                    break;
+                }
+                new_parent = parents.get(&p).copied().flatten();
+                depth += 1;
+            }
+            self.reparent_keep_world(child, new_parent.filter(|p| !removed.contains(p)));
+        }
+        self.objects.retain(|o| !removed.contains(&o.get_unique_id()));
+    }
+
+    /// Attaches `child` to `parent`, or detaches it when `parent` is `None`. The child keeps its
+    /// current world transform. Fails if one of the objects doesn't exist or the parent is a
+    /// descendant of the child.
+    pub fn set_parent(&mut self, child: Uuid, parent: Option<Uuid>) -> Result<(), EnigmaError> {
+        self.validate_parent(child, parent)?;
+        self.update_hierarchy();
+        self.reparent_keep_world(child, parent);
+        self.update_hierarchy();
+        Ok(())
+    }
+
+    /// Attaches `child` to the bone `bone_name` of `parent`, so it follows the animation of the
+    /// parent. The current transform of the child becomes its offset relative to the bone.
+    pub fn attach_to_bone(&mut self, child: Uuid, parent: Uuid, bone_name: &str) -> Result<(), EnigmaError> {
+        self.validate_parent(child, Some(parent))?;
+        let has_bone = self.get_object_by_uuid(&parent)
+            .and_then(|o| o.get_skeleton().as_ref())
+            .is_some_and(|skeleton| skeleton.get_bone_id(bone_name).is_some());
+        if !has_bone {
+            return Err(EnigmaError::new(Some(smart_format!("Cannot attach {} to bone {}, the object {} has no bone with that name", child, bone_name, parent).as_str()), true));
+        }
+        if let Some(object) = self.get_object_by_uuid_mut(child) {
+            object.set_parent(Some(parent));
+            object.set_parent_bone(Some(bone_name.to_string()));
+        }
+        self.update_hierarchy();
+        Ok(())
+    }
+
+    fn validate_parent(&self, child: Uuid, parent: Option<Uuid>) -> Result<(), EnigmaError> {
+        if self.get_object_by_uuid(&child).is_none() {
+            return Err(EnigmaError::new(Some(smart_format!("Cannot set parent, no object with id {} found", child).as_str()), true));
+        }
+        if let Some(parent) = parent {
+            if self.get_object_by_uuid(&parent).is_none() {
+                return Err(EnigmaError::new(Some(smart_format!("Cannot set parent, no object with id {} found", parent).as_str()), true));
+            }
+            let mut current = Some(parent);
+            let mut depth = 0;
+            while let Some(id) = current {
+                depth += 1;
+                if id == child || depth > self.objects.len() {
+                    return Err(EnigmaError::new(Some(smart_format!("Cannot parent {} to {}, this would create a cycle", child, parent).as_str()), true));
+                }
+                current = self.get_object_by_uuid(&id).and_then(|o| o.get_parent());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_children(&self, uuid: &Uuid) -> Vec<Uuid> {
+        self.objects.iter()
+            .filter(|o| o.get_parent() == Some(*uuid))
+            .map(|o| o.get_unique_id())
+            .collect()
+    }
+
+    fn reparent_keep_world(&mut self, child: Uuid, parent: Option<Uuid>) {
+        let parent_world = parent
+            .and_then(|p| self.get_object_by_uuid(&p))
+            .map(|p| p.transform.get_world_matrix_object())
+            .unwrap_or_else(nalgebra::Matrix4::identity);
+        if let Some(object) = self.get_object_by_uuid_mut(child) {
+            let world = object.transform.get_world_matrix_object();
+            let local = parent_world.try_inverse().unwrap_or_else(nalgebra::Matrix4::identity) * world;
+            object.transform.set_from_matrix(local);
+            object.set_parent(parent);
+            object.transform.parent_matrix = parent_world;
+        }
+    }
+
+    /// Propagates the world matrices from parents to children. Called every step and before
+    /// rendering, call it manually after moving parents if you need up to date world positions.
+    pub fn update_hierarchy(&mut self) {
+        // objects attached to a bone are offset by the current pose of that bone
+        let bone_offsets: HashMap<Uuid, nalgebra::Matrix4<f32>> = self.objects.iter()
+            .filter_map(|o| {
+                let bone_name = o.get_parent_bone()?;
+                let parent = self.get_object_by_uuid(&o.get_parent()?)?;
+                Some((o.get_unique_id(), parent.get_bone_model_matrix(bone_name)?))
+            })
+            .collect();
+        let locals: HashMap<Uuid, (Option<Uuid>, nalgebra::Matrix4<f32>)> = self.objects.iter_mut()
+            .map(|o| {
+                let offset = bone_offsets.get(&o.get_unique_id()).copied().unwrap_or_else(nalgebra::Matrix4::identity);
+                (o.get_unique_id(), (o.get_parent(), offset * o.transform.get_matrix_object()))
+            })
+            .collect();
+        let mut worlds: HashMap<Uuid, nalgebra::Matrix4<f32>> = HashMap::new();
+        for object in self.objects.iter_mut() {
+            let parent_matrix = match object.get_parent() {
+                Some(parent) => {
+                    let offset = bone_offsets.get(&object.get_unique_id()).copied().unwrap_or_else(nalgebra::Matrix4::identity);
+                    Self::world_matrix_of(parent, &locals, &mut worlds) * offset
+                }
+                None => nalgebra::Matrix4::identity(),
+            };
+            object.transform.parent_matrix = parent_matrix;
+        }
+    }
+
+    fn world_matrix_of(uuid: Uuid, locals: &HashMap<Uuid, (Option<Uuid>, nalgebra::Matrix4<f32>)>, worlds: &mut HashMap<Uuid, nalgebra::Matrix4<f32>>) -> nalgebra::Matrix4<f32> {
+        // walking up until a known world matrix or a root, a missing parent counts as root
+        let mut chain = Vec::new();
+        let mut current = Some(uuid);
+        while let Some(id) = current {
+            if worlds.contains_key(&id) || chain.contains(&id) {
+                break;
+            }
+            match locals.get(&id) {
+                Some((parent, _)) => {
+                    chain.push(id);
+                    current = *parent;
+                }
+                None => break,
+            }
+        }
+        let mut world = current.and_then(|id| worlds.get(&id).copied()).unwrap_or_else(nalgebra::Matrix4::identity);
+        for id in chain.iter().rev() {
+            world *= locals[id].1;
+            worlds.insert(*id, world);
+        }
+        world
     }
 
     /// Returns the UUID of the object in `candidates` most aligned with the camera forward
@@ -478,7 +747,7 @@ impl AppState {
         let mut result = None;
         for uuid in candidates {
             if let Some(obj) = self.get_object_by_uuid(uuid) {
-                let pos = obj.transform.get_position();
+                let pos = obj.transform.get_world_position();
                 let to = [pos.x - cam_pos[0], pos.y - cam_pos[1], pos.z - cam_pos[2]];
                 let len = (to[0]*to[0] + to[1]*to[1] + to[2]*to[2]).sqrt();
                 if len < 0.01 { continue; }
@@ -629,10 +898,134 @@ impl AppState {
         self.update_injections.push(function);
     }
 
+    /// Injects a function that runs at the fixed tick rate, independent of the frame rate.
+    /// While it runs, `delta_time` holds the fixed time step.
+    pub fn inject_fixed_update_function(&mut self, function: event::EventFunction) {
+        self.fixed_update_injections.push(function);
+    }
+
+    /// Injects a function that runs whenever an object crosses the animation event `event_name`
+    /// during playback. It runs at the end of the step, after all objects were updated.
+    pub fn inject_animation_event_function(&mut self, event_name: &str, function: event::AnimationEventFunction) {
+        self.animation_event_injections.push((event_name.to_string(), function));
+    }
+
+    fn run_animation_events(&mut self) {
+        let fired: Vec<animation::FiredAnimationEvent> = self.objects.iter_mut().flat_map(|o| o.take_fired_animation_events()).collect();
+        if fired.is_empty() {
+            return;
+        }
+        let functions = self.animation_event_injections.clone();
+        for event in fired.iter() {
+            for (name, function) in functions.iter() {
+                if *name == event.name {
+                    function(self, event);
+                }
+            }
+        }
+    }
+
+    pub fn set_fixed_tick_rate(&mut self, ticks_per_second: u32) {
+        self.fixed_tick_rate = ticks_per_second.max(1);
+    }
+
+    pub fn get_fixed_tick_rate(&self) -> u32 {
+        self.fixed_tick_rate
+    }
+
+    pub fn get_fixed_delta_time(&self) -> f32 {
+        1.0 / self.fixed_tick_rate.max(1) as f32
+    }
+
+    /// Limits how many fixed ticks a single frame may run to catch up. Time beyond that is dropped,
+    /// so a slow frame can't snowball into ever slower frames.
+    pub fn set_max_fixed_steps(&mut self, max_steps: u32) {
+        self.max_fixed_steps = max_steps.max(1);
+    }
+
+    pub fn get_max_fixed_steps(&self) -> u32 {
+        self.max_fixed_steps
+    }
+
+    /// How far the current frame lies between the last and the next fixed tick (0.0–1.0).
+    /// Use it with `Transform::lerp` to smooth rendering of objects moved in fixed updates.
+    pub fn get_interpolation_alpha(&self) -> f32 {
+        self.interpolation_alpha
+    }
+
+    fn run_fixed_updates(&mut self, delta_time: f32) {
+        let fixed_delta_time = self.get_fixed_delta_time();
+        self.fixed_accumulator += delta_time;
+
+        let fixed_injections = self.fixed_update_injections.clone();
+        let mut steps = 0;
+        while self.fixed_accumulator >= fixed_delta_time && steps < self.max_fixed_steps {
+            self.delta_time = fixed_delta_time;
+            for function in fixed_injections.iter() {
+                function(self);
+            }
+            self.fixed_accumulator -= fixed_delta_time;
+            steps += 1;
+        }
+        if self.fixed_accumulator >= fixed_delta_time {
+            // drop the time we could not catch up on
+            self.fixed_accumulator %= fixed_delta_time;
+        }
+        self.delta_time = delta_time;
+        self.interpolation_alpha = self.fixed_accumulator / fixed_delta_time;
+    }
+
     pub fn inject_start_function(&mut self, function: event::EventFunction) {
         self.start_injections.push(function);
     }
 
+    /// Runs the start injections. Only the first call has an effect, later calls are ignored.
+    pub fn start(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        let functions = std::mem::take(&mut self.start_injections);
+        for function in functions.iter() {
+            function(self);
+        }
+        self.start_injections = functions;
+    }
+
+    pub fn has_started(&self) -> bool {
+        self.started
+    }
+
+    /// Advances the simulation by `delta_time` seconds without touching the GPU: runs the start
+    /// injections once, accounts time, runs the due fixed updates and the update injections and
+    /// updates materials, objects and their animations. `EventLoop::run` calls this once per frame with the wall clock delta,
+    /// tests and tools can call it with a fixed time step to get deterministic results.
+    pub fn step(&mut self, delta_time: f32) {
+        self.start();
+        self.delta_time = delta_time;
+        self.time += delta_time;
+
+        // executing fixed update functions
+        self.run_fixed_updates(delta_time);
+
+        // executing update functions
+        let update_injections = self.update_injections.clone();
+        for function in update_injections.iter() {
+            function(self);
+        }
+
+        // updating materials
+        for material in self.materials.iter_mut() {
+            material.update();
+        }
+        // updating objects
+        for object in self.objects.iter_mut() {
+            object.update(delta_time);
+        }
+        self.update_hierarchy();
+        self.run_animation_events();
+    }
+
     pub fn set_skybox(&mut self, skybox: object::Object) {
         self.skybox = Some(skybox);
     }
@@ -677,6 +1070,164 @@ impl AppState {
         self.shadow_distance
     }
 
+    /// Sets how many cascades the shadow map of the first shadow casting directional light is split into,
+    /// between 1 and `shadow::MAX_CASCADES`. The cascades together cover the shadow distance.
+    pub fn set_shadow_cascades(&mut self, cascades: usize) {
+        self.shadow_cascades = cascades.clamp(1, shadow::MAX_CASCADES);
+    }
+
+    pub fn get_shadow_cascades(&self) -> usize {
+        self.shadow_cascades
+    }
+
+    /// Blends the cascade splits between uniform (0.0) and logarithmic (1.0) distribution.
+    pub fn set_shadow_cascade_lambda(&mut self, lambda: f32) {
+        self.shadow_cascade_lambda = lambda.clamp(0.0, 1.0);
+    }
+
+    pub fn get_shadow_cascade_lambda(&self) -> f32 {
+        self.shadow_cascade_lambda
+    }
+
+    /// Enables culling of objects, shadow casters and terrain tiles outside the camera or light volumes.
+    pub fn set_frustum_culling(&mut self, enabled: bool) {
+        self.frustum_culling = enabled;
+    }
+
+    pub fn get_frustum_culling(&self) -> bool {
+        self.frustum_culling
+    }
+
+    /// Sets how many clusters the view is divided into for lights that don't get one of the shadow slots.
+    pub fn set_light_clusters(&mut self, grid: ClusterGrid) {
+        self.light_clusters = grid;
+    }
+
+    pub fn get_light_clusters(&self) -> ClusterGrid {
+        self.light_clusters
+    }
+
+    /// Lights the PBR materials with precomputed image based lighting. `None` falls back to the skybox reflection.
+    pub fn set_environment_lighting(&mut self, data: Option<IblData>) {
+        self.environment_lighting = data.map(Arc::new);
+    }
+
+    /// Precomputes image based lighting from an equirectangular HDR image, for example
+    /// `example_resources::skybox_texture_hdr()`. With a `cache_dir` the result is stored on disk and
+    /// loaded from there on the next start.
+    pub fn set_environment_from_hdr(&mut self, data: &[u8], cache_dir: Option<&str>) -> Result<(), EnigmaError> {
+        let settings = IblSettings::default();
+        let ibl = match cache_dir {
+            Some(dir) => IblData::load_or_compute(data, &settings, std::path::Path::new(dir))?,
+            None => IblData::from_image_bytes(data, &settings)?,
+        };
+        self.set_environment_lighting(Some(ibl));
+        Ok(())
+    }
+
+    pub fn get_environment_lighting(&self) -> Option<&IblData> {
+        self.environment_lighting.as_deref()
+    }
+
+    pub fn set_environment_intensity(&mut self, intensity: f32) {
+        self.environment_intensity = intensity.max(0.0);
+    }
+
+    pub fn get_environment_intensity(&self) -> f32 {
+        self.environment_intensity
+    }
+
+    /// Sets the curve that maps the HDR scene into the displayable range.
+    pub fn set_tonemap_operator(&mut self, operator: TonemapOperator) {
+        self.tonemap_operator = operator;
+    }
+
+    pub fn get_tonemap_operator(&self) -> TonemapOperator {
+        self.tonemap_operator
+    }
+
+    /// Uses a fixed exposure multiplier.
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = Exposure::Manual(exposure.max(0.0));
+    }
+
+    /// Adapts the exposure to the scene luminance every frame.
+    pub fn set_auto_exposure(&mut self, settings: AutoExposure) {
+        self.exposure = Exposure::Auto(settings);
+    }
+
+    pub fn get_exposure(&self) -> Exposure {
+        self.exposure
+    }
+
+    /// The exposure the last frame was tonemapped with, which is the adapted value when auto exposure is on.
+    pub fn get_current_exposure(&self) -> f32 {
+        self.current_exposure
+    }
+
+    pub(crate) fn set_current_exposure(&mut self, exposure: f32) {
+        self.current_exposure = exposure;
+    }
+
+    /// Sets how many post-processing effects run on the HDR scene before tonemapping. 0 tonemaps before all
+    /// effects, anything at or beyond the number of effects tonemaps after all of them.
+    pub fn set_tonemap_position(&mut self, position: usize) {
+        self.tonemap_position = position;
+    }
+
+    pub fn get_tonemap_position(&self) -> usize {
+        self.tonemap_position
+    }
+
+    /// Enables temporal anti-aliasing with `Some` settings. The camera is jittered and the resolved frame
+    /// is fed to the post-processing effects, so it combines with `Fxaa` but rarely needs it.
+    pub fn set_taa(&mut self, taa: Option<TaaSettings>) {
+        self.taa = taa;
+    }
+
+    pub fn get_taa(&self) -> Option<TaaSettings> {
+        self.taa
+    }
+
+    /// Culling results of the last rendered frame.
+    pub fn get_culling_stats(&self) -> CullingStats {
+        self.culling_stats
+    }
+
+    pub(crate) fn set_culling_stats(&mut self, stats: CullingStats) {
+        self.culling_stats = stats;
+    }
+
+    pub fn get_profiler(&self) -> &FrameProfiler {
+        &self.profiler
+    }
+
+    pub fn get_profiler_mut(&mut self) -> &mut FrameProfiler {
+        &mut self.profiler
+    }
+
+    /// Stats of the last finished frame, see `FrameStats`.
+    pub fn get_frame_stats(&self) -> &FrameStats {
+        self.profiler.get_last_frame()
+    }
+
+    /// How many frames `get_profiler().get_history()` keeps, 300 by default.
+    pub fn set_frame_history_length(&mut self, frames: usize) {
+        self.profiler.set_history_length(frames);
+    }
+
+    /// Writes the frame history as CSV to `path`, one row per frame.
+    pub fn write_frame_history_csv(&self, path: &str) -> Result<(), EnigmaError> {
+        match std::fs::write(path, self.profiler.to_csv()) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let error = EnigmaError::new(Some(smart_format!("Failed to write frame history to {}: {}", path, e).as_str()), true);
+                error.log();
+                Err(error)
+            }
+        }
+    }
+
     pub fn set_terrain(&mut self, terrain: terrain::Terrain) {
         self.terrain = Some(terrain);
     }
@@ -714,46 +1265,7 @@ impl EventLoop {
     }
 
     pub fn spawn_skybox(&mut self, app_state: &mut AppState) -> (crate::object::Object, texture::Texture) {
-        if let Some(current_skybox_object) = app_state.get_skybox().clone() {
-            // If we have an existing skybox, try to get its texture
-            if let Some(texture_uuid) = current_skybox_object.get_materials().first() {
-                if let Some(material) = app_state.get_material(texture_uuid) {
-                    if let Some(texture) = &material.albedo {
-                        // Successfully found texture, clone it and return with the existing object
-                        return (
-                            current_skybox_object,
-                            texture.get_texture_clone(self.get_display_reference())
-                        );
-                    }
-                }
-            }
-
-            // If we reached here, we couldn't get the texture from the existing skybox
-            let mut logger = EnigmaWarning::new(None, true);
-            logger.extent("Failed to get texture from existing skybox. Creating default skybox...");
-            logger.log();
-        }
-
-        let mut material = crate::material::Material::unlit(self.display.clone(), false);
-        material.set_name("INTERNAL::SkyBox");
-
-        material.set_texture_from_resource(resources::skybox_texture(), crate::material::TextureType::Albedo);
-
-        // create a default object
-        let mut object = Object::load_from_gltf_resource(resources::skybox(), None);
-
-        // set the material
-        object.add_material(material.uuid);
-        object.get_shapes_mut()[0].set_material_from_object_list(0);
-
-        object.name = "Skybox".to_string();
-
-        object.transform.set_scale([1.0, 1.0, 1.0]);
-
-        app_state.add_material(material);
-        // skybox texture
-        let skybox_texture = texture::Texture::from_resource(&self.display, resources::skybox_texture());
-        (object, skybox_texture)
+        renderer::spawn_skybox(&self.display, app_state)
     }
 
     pub fn set_icon_from_path(&self, path: &str) {
@@ -775,7 +1287,7 @@ impl EventLoop {
     // This is just the render loop . an actual event loop still needs to be set up
     pub fn run(mut self, app_state: Arc<Mutex<AppState>>) {
         let mut temp_app_state = app_state.lock().unwrap();
-        temp_app_state.display = Some(self.display.clone());
+        temp_app_state.display = Some(self.display.get_context().clone());
 
         //spawning skybox
         let (skybox, skybox_texture) = self.spawn_skybox(&mut temp_app_state);
@@ -787,34 +1299,8 @@ impl EventLoop {
         let nanos = 1_000_000_000 / temp_app_state.fps;
         let frame_duration = Duration::from_nanos(nanos); // 60 FPS (1,000,000,000 ns / 60)
 
-        let mut texture = Texture2d::empty(&self.display, self.window.inner_size().width * temp_app_state.render_scale, self.window.inner_size().height * temp_app_state.render_scale).expect("Failed to create texture");
-        let mut depth_texture = glium::texture::DepthTexture2d::empty(&self.display, self.window.inner_size().width * temp_app_state.render_scale, self.window.inner_size().height * temp_app_state.render_scale).expect("Failed to create depth texture");
-
-        let mut buffer_textures: Vec<Texture2d> = Vec::new();
-        for _ in 0..temp_app_state.max_buffers {
-            buffer_textures.push(Texture2d::empty(&self.display, self.window.inner_size().width * temp_app_state.render_scale, self.window.inner_size().height * temp_app_state.render_scale).expect("Failed to create texture"));
-        }
-
-        let mut shadow_maps = ShadowMaps::new(&self.display, temp_app_state.shadow_resolution);
-
-        let shadow_dir_program = glium::Program::from_source(
-            &self.display,
-            resources::shadow_depth_vert_shader(),
-            resources::shadow_depth_dir_frag_shader(),
-            None,
-        ).expect("Failed to compile directional shadow shader");
-
-        let shadow_point_program = glium::Program::from_source(
-            &self.display,
-            resources::shadow_depth_vert_shader(),
-            resources::shadow_depth_point_frag_shader(),
-            None,
-        ).expect("Failed to compile point shadow shader");
-
-        // prepare post processing
-        let screen_vert_rect = postprocessing::get_screen_vert_rect(&self.display);
-        let screen_indices_rect = postprocessing::get_screen_indices_rect(&self.display);
-        let screen_program = postprocessing::get_screen_program(&self.display);
+        let window_size = self.window.inner_size();
+        let mut renderer = Renderer::new(&self.display, &temp_app_state, window_size.width, window_size.height, skybox_texture);
 
         //initializing GUI
         match self.gui_renderer {
@@ -826,11 +1312,8 @@ impl EventLoop {
         }
 
         // running start events
-        let functions = std::mem::take(&mut temp_app_state.start_injections);
-        for function in functions.iter() {
-            function(&mut temp_app_state);
-        }
-        temp_app_state.start_injections = functions;
+        temp_app_state.start();
+        temp_app_state.last_frame_time = Instant::now();
 
         //dropping modified appstate at the end after all the preparation before happened
         drop(temp_app_state);
@@ -839,25 +1322,12 @@ impl EventLoop {
         self.event_loop.run(move |event, _window_target, control_flow| {
             // unpacking appstate
             let mut app_state = app_state.lock().unwrap();
-            let light = app_state.light.clone();
-            let ambient_light = app_state.ambient_light.clone();
-            let camera = app_state.camera.clone();
             let event_injections = app_state.event_injections.clone();
-            let update_injections = app_state.update_injections.clone();
             let gui_injections = app_state.gui_injections.clone();
 
             *control_flow = ControlFlow::WaitUntil(next_frame_time);
             next_frame_time = Instant::now() + frame_duration;
 
-            // passing framebuffer
-            let texture = &mut texture;
-            let depth_texture = &mut depth_texture;
-            let buffer_textures = &mut buffer_textures;
-            let mut framebuffer = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(&self.display, &*texture, &*depth_texture).expect("Failed to create framebuffer");
-
-            // passing skybox
-            let skybox_texture = &skybox_texture;
-
             match event {
                 Event::DeviceEvent { event: DeviceEvent::MouseMotion { delta }, .. } => {
                     app_state.get_mouse_state_mut().add_raw_delta(delta.0, delta.1);
@@ -869,9 +1339,6 @@ impl EventLoop {
                         if !response.consumed {
                             app_state.camera.as_mut().expect("failed to retrieve camera").set_aspect(new_size.width as f32, new_size.height as f32);
                             self.display.resize(new_size.into());
-                            if let Some(app_state_display) = app_state.display.as_mut() {
-                                app_state_display.resize(new_size.into());
-                            }
                         }
                     }
                     WindowEvent::ModifiersChanged(modifiers) => {
@@ -933,336 +1400,16 @@ impl EventLoop {
                     }
                 }
                 Event::RedrawRequested(_) => {
-                    let current_time = Instant::now();
-                    app_state.delta_time = (current_time - app_state.last_frame_time).as_secs_f32();
-                    app_state.last_frame_time = current_time;
-                    app_state.time += app_state.delta_time;
-                    // updating materials
-                    for material in app_state.materials.iter_mut() {
-                        material.update();
-                    }
-                    // updating objects
-                    let deltatime = app_state.delta_time;
-                    for object in app_state.objects.iter_mut() {
-                        object.update(deltatime);
-                    }
-
-                    let render_target = &mut framebuffer;
-                    render_target.clear_color_and_depth((0.0, 0.0, 0.0, 1.0), 1.0);
-                    let model_matrices: std::collections::HashMap<Uuid, [[f32; 4]; 4]> = app_state.objects.iter_mut().map(|x| (x.get_unique_id(), x.transform.get_matrix())).collect();
-                    let bone_uniform_buffers: std::collections::HashMap<Uuid, UniformBuffer<BoneTransforms>> = app_state.objects.iter_mut().map(|x| (x.get_unique_id(), x.get_bone_transform_buffer(&self.display))).collect();
-                    let object_instances = app_state.setup_instances(&self.display, &model_matrices);
-
-                    // --- Shadow pass ---
-                    if shadow_maps.resolution != app_state.shadow_resolution {
-                        shadow_maps = ShadowMaps::new(&self.display, app_state.shadow_resolution);
-                    }
-                    shadow_maps.clear();
-
-                    let shadow_draw_params = glium::DrawParameters {
-                        depth: glium::Depth {
-                            test: glium::draw_parameters::DepthTest::IfLess,
-                            write: true,
-                            ..Default::default()
-                        },
-                        backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
-                        ..Default::default()
-                    };
-
-                    let cam_pos: [f32; 3] = match camera {
-                        Some(ref c) => { let p = c.transform.get_position(); [p.x, p.y, p.z] }
-                        None => [0.0, 0.0, 0.0],
-                    };
-
-                    for (light_index, light_item) in light.iter().enumerate().take(4) {
-                        if !light_item.cast_shadow { continue; }
-
-                        if light_item.is_directional() {
-                            // --- Directional shadow map ---
-                            let half = app_state.shadow_distance;
-                            let lsm = directional_light_space_matrix(light_item.direction, cam_pos, half);
-                            shadow_maps.light_space_matrices[light_index] = lsm;
-
-                            let shadow_tex = glium::texture::Texture2d::empty_with_format(
-                                &self.display,
-                                glium::texture::UncompressedFloatFormat::F32,
-                                glium::texture::MipmapsOption::NoMipmap,
-                                shadow_maps.resolution,
-                                shadow_maps.resolution,
-                            ).expect("Failed to create directional shadow texture");
-
-                            {
-                                let mut fb = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
-                                    &self.display,
-                                    &shadow_tex,
-                                    &shadow_maps.dir_depth_rb,
-                                ).expect("Failed to create directional shadow framebuffer");
-                                fb.clear_color_and_depth((1.0, 0.0, 0.0, 1.0), 1.0);
-
-                                for (instance_id, object_instance) in object_instances.iter() {
-                                    if let Some(object) = app_state.get_object_by_uuid(instance_id) {
-                                        if object.get_materials().is_empty() { continue; }
-                                        let has_skeleton = object.get_skeleton().is_some();
-                                        let bone_transform = bone_uniform_buffers.get(&object.get_unique_id())
-                                            .expect("Missing bone transforms in shadow pass");
-                                        for ((buffer, _mat_index), indices) in object_instance.vertex_buffers.iter()
-                                            .zip(object_instance.index_buffers.iter())
-                                        {
-                                            let uniforms = glium::uniform! {
-                                                light_space_matrix: lsm,
-                                                has_skeleton: has_skeleton,
-                                                BoneTransforms: bone_transform,
-                                            };
-                                            fb.draw(
-                                                (buffer, object_instance.instance_attributes.per_instance().unwrap()),
-                                                indices,
-                                                &shadow_dir_program,
-                                                &uniforms,
-                                                &shadow_draw_params,
-                                            ).expect("Failed to draw shadow pass");
-                                        }
-                                    }
-                                }
-                            }
-                            shadow_maps.directional_maps[light_index] = Some(shadow_tex);
-
-                        } else {
-                            // --- Point light shadow map (atlas) ---
-                            let far_plane = 100.0f32;
-                            shadow_maps.point_far_planes[light_index] = far_plane;
-                            let res = shadow_maps.resolution;
-
-                            let atlas_tex = glium::texture::Texture2d::empty_with_format(
-                                &self.display,
-                                glium::texture::UncompressedFloatFormat::F32,
-                                glium::texture::MipmapsOption::NoMipmap,
-                                res * 2,
-                                res * 3,
-                            ).expect("Failed to create point shadow atlas texture");
-
-                            // Clear entire atlas to 1.0 (no shadow) before rendering faces
-                            {
-                                let mut clear_fb = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
-                                    &self.display,
-                                    &atlas_tex,
-                                    &shadow_maps.point_depth_rb,
-                                ).expect("Failed to create atlas clear framebuffer");
-                                clear_fb.clear_color_and_depth((1.0, 0.0, 0.0, 1.0), 1.0);
-                            }
-
-                            let near = 0.1f32;
-                            let proj = perspective_90_matrix(near, far_plane);
-                            let lp = light_item.position;
-
-                            for face in 0..6usize {
-                                let (dir, up) = CUBE_FACE_DIRS[face];
-                                let view = view_matrix(&lp, &dir, &up);
-                                let lsm = mat4_mul(proj, view);
-                                let viewport = face_viewport(face, res);
-
-                                let face_draw_params = glium::DrawParameters {
-                                    depth: glium::Depth {
-                                        test: glium::draw_parameters::DepthTest::IfLess,
-                                        write: true,
-                                        ..Default::default()
-                                    },
-                                    backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
-                                    viewport: Some(viewport),
-                                    ..Default::default()
-                                };
-
-                                let mut fb = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
-                                    &self.display,
-                                    &atlas_tex,
-                                    &shadow_maps.point_depth_rb,
-                                ).expect("Failed to create point shadow framebuffer");
-
-                                for (instance_id, object_instance) in object_instances.iter() {
-                                    if let Some(object) = app_state.get_object_by_uuid(instance_id) {
-                                        if object.get_materials().is_empty() { continue; }
-                                        let has_skeleton = object.get_skeleton().is_some();
-                                        let bone_transform = bone_uniform_buffers.get(&object.get_unique_id())
-                                            .expect("Missing bone transforms in shadow pass");
-                                        for ((buffer, _mat_index), indices) in object_instance.vertex_buffers.iter()
-                                            .zip(object_instance.index_buffers.iter())
-                                        {
-                                            let uniforms = glium::uniform! {
-                                                light_space_matrix: lsm,
-                                                has_skeleton: has_skeleton,
-                                                BoneTransforms: bone_transform,
-                                                light_pos: lp,
-                                                far_plane: far_plane,
-                                            };
-                                            fb.draw(
-                                                (buffer, object_instance.instance_attributes.per_instance().unwrap()),
-                                                indices,
-                                                &shadow_point_program,
-                                                &uniforms,
-                                                &face_draw_params,
-                                            ).expect("Failed to draw shadow pass");
-                                        }
-                                    }
-                                }
-                            }
-                            shadow_maps.point_maps[light_index] = Some(atlas_tex);
-                        }
-                    }
-                    // --- End shadow pass ---
-
-                    // render objects opaque
-                    let opaque_rendering_parameter = glium::DrawParameters {
-                        depth: glium::Depth {
-                            test: glium::draw_parameters::DepthTest::IfLess,
-                            write: true,
-                            ..Default::default()
-                        },
-                        backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
-                        ..Default::default()
-                    };
-
-                    for (instance_id, object_instance) in object_instances.iter() {
-                        let object_option = app_state.get_object_by_uuid(&instance_id);
-                        match object_option {
-                            Some(object) => {
-                                let closest_lights = object.get_closest_lights(&light);
-                                let has_skeleton = object.get_skeleton().is_some();
-                                let bone_transform = bone_uniform_buffers.get(&object.get_unique_id()).expect("Missing Bone Transform Uniforms for Object");
-                                for ((buffer, mat_index), indices) in object_instance.vertex_buffers.iter().zip(object_instance.index_buffers.iter()) {
-                                    let mat_uuid: &Uuid = &object.get_materials()[*mat_index];
-                                    match app_state.get_material(mat_uuid) {
-                                        Some(material) => {
-                                            if material.render_transparent {
-                                                continue;
-                                            }
-                                            let uniforms = &material.get_uniforms(&closest_lights, ambient_light.as_ref(), camera.as_ref(), &bone_transform, has_skeleton, skybox_texture, &shadow_maps);
-                                            render_target.draw((buffer, object_instance.instance_attributes.per_instance().expect("Error, unwrapping per instance in opaque draw")), indices, &material.program, uniforms, &opaque_rendering_parameter).expect("Failed to draw object");
-                                        }
-                                        None => ()
-                                    }
-                                }
-                            }
-                            None => EnigmaError::new(Some(smart_format!("Error, instancing the Object Instance with the instance id {}, because no Object with that Id could be found", instance_id).as_str()), true).log()
-                        }
-                    }
-
-                    // render terrain
-                    if let Some(terrain) = &app_state.terrain {
-                        if let Some(cam) = camera.as_ref() {
-                            terrain.draw(
-                                render_target,
-                                cam,
-                                &light,
-                                ambient_light.as_ref(),
-                                skybox_texture,
-                                &shadow_maps,
-                            );
-                        }
-                    }
-
-                    // render skybox
-                    let skybox_rendering_parameter = glium::DrawParameters {
-                        depth: glium::Depth {
-                            test: glium::draw_parameters::DepthTest::IfLess,
-                            write: false,
-                            ..Default::default()
-                        },
-                        backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
-                        ..Default::default()
-                    };
-
-                    //First get the matrix outside of the closure
-                    let skybox_model_matrix = match app_state.get_skybox_mut() {
-                        Some(obj) => {
-                            // skybox should always be relative to the camera
-                            obj.transform.set_position(cam_pos);
-                            Some(obj.transform.get_matrix().clone())
-                        },
-                        None => None
-                    };
-                    let skybox_instance = app_state.setup_skybox_instance(&self.display, &skybox_model_matrix);
-
-                    match skybox_instance {
-                        Some((skybox_id, instance)) => {
-                            let object_option = app_state.get_skybox();
-                            match object_option {
-                                Some(skybox) => {
-                                    let closest_lights = skybox.get_closest_lights(&light);
-                                    let skybox_bone_buffer = skybox.get_bone_transform_buffer(&self.display);
-                                    for ((buffer, mat_index), indices) in instance.vertex_buffers.iter().zip(instance.index_buffers.iter()) {
-                                        let mat_uuid: &Uuid = &skybox.get_materials()[*mat_index];
-                                        match app_state.get_material(mat_uuid) {
-                                            Some(material) => {
-                                                let uniforms = &material.get_uniforms(&closest_lights, ambient_light.as_ref(), camera.as_ref(), &skybox_bone_buffer, false, skybox_texture, &shadow_maps);
-                                                render_target.draw((buffer, instance.instance_attributes.per_instance().expect("Error, unwrapping per instance in skybox draw")), indices, &material.program, uniforms, &skybox_rendering_parameter).expect("Failed to draw object");
-                                            }
-                                            None => ()
-                                        }
-                                    }
-                                }
-                                None => EnigmaError::new(Some(smart_format!("Error, instancing the Skybox Instance with the instance id {}, because no Object with that Id could be found", skybox_id).as_str()), true).log()
-                            }
-                        }
-                        None => {}
-                    }
-
-                    // render objects transparent
-                    let transparent_rendering_parameter = glium::DrawParameters {
-                        blend: glium::Blend::alpha_blending(),
-                        ..opaque_rendering_parameter
-                    };
-                    for (instance_id, object_instance) in object_instances.iter() {
-                        let object_option = app_state.get_object_by_uuid(&instance_id);
-                        match object_option {
-                            Some(object) => {
-                                let closest_lights = object.get_closest_lights(&light);
-                                let has_skeleton = object.get_skeleton().is_some();
-                                let bone_transform = bone_uniform_buffers.get(&object.get_unique_id()).expect("Missing Bone Transform Uniforms for Object");
-                                for ((buffer, mat_index), indices) in object_instance.vertex_buffers.iter().zip(object_instance.index_buffers.iter()) {
-                                    let mat_uuid: &Uuid = &object.get_materials()[*mat_index];
-                                    match app_state.get_material(mat_uuid) {
-                                        Some(material) => {
-                                            if !material.render_transparent {
-                                                continue;
-                                            }
-                                            let uniforms = &material.get_uniforms(&closest_lights, ambient_light.as_ref(), camera.as_ref(), &bone_transform, has_skeleton, skybox_texture, &shadow_maps);
-                                            render_target.draw((buffer, object_instance.instance_attributes.per_instance().expect("Error, unwrapping per instance in transparent draw")), indices, &material.program, uniforms, &transparent_rendering_parameter).expect("Failed to draw object");
-                                        }
-                                        None => ()
-                                    }
-                                }
-                            }
-                            None => EnigmaError::new(Some(smart_format!("Error, instancing the Transparent Object Instance with the instance id {}, because no Object with that Id could be found", instance_id).as_str()), true).log()
-                        }
-                    }
-
-                    // execute post processing
-                    // Each effect reads from a ping-pong buffer (not from `texture` directly),
-                    // because `framebuffer` is backed by `texture` — sampling from a texture
-                    // that is simultaneously attached as a render target is undefined in OpenGL.
-                    let pp_src_idx = buffer_textures.len() - 1;
-                    for process in app_state.get_post_processes() {
-                        {
-                            let mut pp_fb = glium::framebuffer::SimpleFrameBuffer::new(&self.display, &buffer_textures[pp_src_idx]).expect("Failed to create post-process ping-pong framebuffer");
-                            let copy_uniforms = uniform! { scene: &*texture };
-                            pp_fb.draw(&screen_vert_rect, &screen_indices_rect, &screen_program, &copy_uniforms, &Default::default()).expect("Failed to copy to ping-pong buffer");
-                        }
-                        process.render(&app_state, &screen_vert_rect, &screen_indices_rect, &mut framebuffer, &buffer_textures[pp_src_idx], &depth_texture, &buffer_textures);
-                    }
+                    renderer.render_frame(&self.display, &mut app_state);
 
                     // drawing to screen
+                    let present_start = Instant::now();
                     let mut screen_target = self.display.draw();
-                    let screen_uniforms = uniform! {
-                        scene: &*texture,
-                    };
-                    screen_target.draw(
-                        &screen_vert_rect,
-                        &screen_indices_rect,
-                        &screen_program,
-                        &screen_uniforms,
-                        &Default::default(),
-                    ).expect("Failed to draw screen");
+                    renderer.present(&mut screen_target);
+                    let mut present_ms = profiler::elapsed_ms(present_start);
 
                     // drawing GUI
+                    let gui_start = Instant::now();
                     let gui_renderer = self.gui_renderer.as_mut().expect("Failed to retrieve gui renderer");
                     gui_renderer.run(&self.window, |egui_context| {
                         for function in gui_injections.iter() {
@@ -1270,9 +1417,19 @@ impl EventLoop {
                         }
                     });
                     gui_renderer.paint(&self.display, &mut screen_target);
+                    let gui_ms = profiler::elapsed_ms(gui_start);
+
+                    let swap_start = Instant::now();
                     screen_target.finish().expect("Failed to swap buffers");
+                    present_ms += profiler::elapsed_ms(swap_start);
+
+                    let frame_stats = app_state.get_profiler_mut().current_mut();
+                    frame_stats.present_ms = present_ms;
+                    frame_stats.gui_ms = gui_ms;
+                    app_state.get_profiler_mut().end_frame();
                 }
                 Event::MainEventsCleared => {
+                    let input_start = Instant::now();
 
                     // executing mouse down events
                     if app_state.is_mouse_down && app_state.last_event_time.elapsed() >= Duration::from_millis(100) {
@@ -1298,10 +1455,14 @@ impl EventLoop {
                         }
                     }
 
-                    // executing update functions
-                    for function in update_injections {
-                        function(&mut app_state);
-                    }
+                    app_state.get_profiler_mut().current_mut().input_ms = profiler::elapsed_ms(input_start);
+
+                    // advancing the simulation
+                    let current_time = Instant::now();
+                    let delta_time = (current_time - app_state.last_frame_time).as_secs_f32();
+                    app_state.last_frame_time = current_time;
+                    app_state.step(delta_time);
+                    app_state.get_profiler_mut().current_mut().update_ms = profiler::elapsed_ms(current_time);
 
                     // sync cursor lock state
                     if app_state.cursor_locked {
@@ -1344,5 +1505,299 @@ mod tests {
         assert_eq!(s.get_shadow_resolution(), 2048);
         s.set_shadow_distance(75.0);
         assert_eq!(s.get_shadow_distance(), 75.0);
+        s.set_shadow_cascades(9);
+        assert_eq!(s.get_shadow_cascades(), shadow::MAX_CASCADES);
+        s.set_shadow_cascades(0);
+        assert_eq!(s.get_shadow_cascades(), 1);
+        s.set_shadow_cascade_lambda(2.0);
+        assert_eq!(s.get_shadow_cascade_lambda(), 1.0);
+    }
+
+    fn count_calls(app_state: &mut AppState, name: &str) {
+        let count = app_state.get_state_data_value::<u32>(name).copied().unwrap_or(0);
+        app_state.set_state_data_value(name, Box::new(count + 1));
+    }
+
+    #[test]
+    fn appstate_step_runs_start_injections_once() {
+        let mut s = AppState::new();
+        s.inject_start_function(Arc::new(|a: &mut AppState| count_calls(a, "start")));
+        assert!(!s.has_started());
+        s.step(0.1);
+        s.step(0.1);
+        s.step(0.1);
+        assert!(s.has_started());
+        assert_eq!(s.get_state_data_value::<u32>("start"), Some(&1));
+    }
+
+    #[test]
+    fn appstate_step_runs_update_injections_every_step() {
+        let mut s = AppState::new();
+        s.inject_update_function(Arc::new(|a: &mut AppState| count_calls(a, "update")));
+        for _ in 0..5 {
+            s.step(1.0 / 60.0);
+        }
+        assert_eq!(s.get_state_data_value::<u32>("update"), Some(&5));
+    }
+
+    #[test]
+    fn appstate_step_accounts_time() {
+        let mut s = AppState::new();
+        for _ in 0..4 {
+            s.step(0.25);
+        }
+        assert!((s.time - 1.0).abs() < 1e-6);
+        assert_eq!(s.delta_time, 0.25);
+    }
+
+    #[test]
+    fn appstate_fixed_update_runs_at_tick_rate() {
+        let mut s = AppState::new();
+        s.set_fixed_tick_rate(50);
+        s.inject_fixed_update_function(Arc::new(|a: &mut AppState| {
+            assert!((a.delta_time - 0.02).abs() < 1e-6);
+            count_calls(a, "fixed");
+        }));
+        // 30 frames of 1/30 s = one second of simulated time at a variable frame rate
+        for _ in 0..30 {
+            s.step(1.0 / 30.0);
+        }
+        let ticks = *s.get_state_data_value::<u32>("fixed").unwrap();
+        assert!(ticks == 49 || ticks == 50, "ticks = {}", ticks);
+        // the variable delta time is restored after the fixed ticks
+        assert!((s.delta_time - 1.0 / 30.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn appstate_fixed_update_respects_max_steps() {
+        let mut s = AppState::new();
+        s.set_fixed_tick_rate(10);
+        s.set_max_fixed_steps(3);
+        s.inject_fixed_update_function(Arc::new(|a: &mut AppState| count_calls(a, "fixed")));
+        s.step(1.05);
+        assert_eq!(s.get_state_data_value::<u32>("fixed"), Some(&3));
+        // the dropped time does not leak into the next frame
+        s.step(0.0);
+        assert_eq!(s.get_state_data_value::<u32>("fixed"), Some(&3));
+        assert!(s.get_interpolation_alpha() < 1.0);
+    }
+
+    #[test]
+    fn appstate_interpolation_alpha() {
+        let mut s = AppState::new();
+        s.set_fixed_tick_rate(10);
+        s.step(0.125);
+        assert!((s.get_interpolation_alpha() - 0.25).abs() < 1e-4);
+        s.step(0.05);
+        assert!((s.get_interpolation_alpha() - 0.75).abs() < 1e-4);
+    }
+
+    #[test]
+    fn appstate_step_updates_object_transforms() {
+        let mut s = AppState::new();
+        s.inject_update_function(Arc::new(|a: &mut AppState| {
+            let dt = a.delta_time;
+            if let Some(o) = a.get_object_mut("mover") {
+                o.transform.move_dir_array([dt, 0.0, 0.0]);
+            }
+        }));
+        s.add_object(Object::new(Some("mover".to_string())));
+        for _ in 0..10 {
+            s.step(0.1);
+        }
+        let object = s.get_object_mut("mover").unwrap();
+        assert!((object.transform.get_position().x - 1.0).abs() < 1e-5);
+        // the model matrix is rebuilt during the step
+        assert!((object.transform.matrix[(0, 3)] - 1.0).abs() < 1e-5);
+    }
+
+    fn hierarchy_scene() -> (AppState, Uuid, Uuid, Uuid) {
+        let mut s = AppState::new();
+        let mut root = Object::new(Some("root".to_string()));
+        root.transform.set_position([10.0, 0.0, 0.0]);
+        let mut middle = Object::new(Some("middle".to_string()));
+        middle.transform.set_position([1.0, 0.0, 0.0]);
+        middle.set_parent(Some(root.get_unique_id()));
+        let mut leaf = Object::new(Some("leaf".to_string()));
+        leaf.transform.set_position([0.0, 1.0, 0.0]);
+        leaf.set_parent(Some(middle.get_unique_id()));
+        let ids = (root.get_unique_id(), middle.get_unique_id(), leaf.get_unique_id());
+        // children added before their parents on purpose
+        s.add_object(leaf);
+        s.add_object(middle);
+        s.add_object(root);
+        s.update_hierarchy();
+        (s, ids.0, ids.1, ids.2)
+    }
+
+    #[test]
+    fn hierarchy_propagates_world_matrices() {
+        let (s, _, _, leaf) = hierarchy_scene();
+        let p = s.get_object_by_uuid(&leaf).unwrap().transform.get_world_position();
+        assert!((p.x - 11.0).abs() < 1e-5 && (p.y - 1.0).abs() < 1e-5, "{:?}", p);
+    }
+
+    #[test]
+    fn hierarchy_remove_objects_cascades() {
+        let (mut s, root, _, _) = hierarchy_scene();
+        s.add_object(Object::new(Some("other".to_string())));
+        s.remove_objects(&[root]);
+        assert_eq!(s.get_objects().len(), 1);
+        assert!(s.get_object("other").is_some());
+    }
+
+    #[test]
+    fn hierarchy_remove_keep_children_preserves_world_position() {
+        let (mut s, root, middle, leaf) = hierarchy_scene();
+        s.remove_objects_keep_children(&[middle]);
+        let leaf_object = s.get_object_by_uuid(&leaf).unwrap();
+        assert_eq!(leaf_object.get_parent(), Some(root));
+        s.update_hierarchy();
+        let p = s.get_object_by_uuid(&leaf).unwrap().transform.get_world_position();
+        assert!((p.x - 11.0).abs() < 1e-4 && (p.y - 1.0).abs() < 1e-4, "{:?}", p);
+    }
+
+    #[test]
+    fn hierarchy_set_parent_rejects_cycles() {
+        let (mut s, root, _, leaf) = hierarchy_scene();
+        assert!(s.set_parent(root, Some(leaf)).is_err());
+        assert!(s.set_parent(leaf, None).is_ok());
+        let leaf_object = s.get_object_by_uuid(&leaf).unwrap();
+        assert_eq!(leaf_object.get_parent(), None);
+        assert!((leaf_object.transform.get_position().x - 11.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn hierarchy_survives_serialization() {
+        let (s, root, middle, _) = hierarchy_scene();
+        let mut restored = AppState::new();
+        for object in s.to_serializer().objects {
+            restored.add_object(Object::from_serializer(object));
+        }
+        assert_eq!(restored.get_children(&root), vec![middle]);
+    }
+
+    #[test]
+    fn hierarchy_attach_to_bone_follows_animation() {
+        let mut s = AppState::new();
+        let mut knight = Object::new(Some("knight".to_string()));
+        *knight.get_skeleton_mut() = Some(animation::Skeleton {
+            bones: vec![animation::Bone {
+                name: "hand".to_string(),
+                id: 0,
+                node_index: 0,
+                parent_id: None,
+                inverse_bind_pose: nalgebra::Matrix4::identity(),
+            }],
+            root_transform: nalgebra::Matrix4::identity(),
+        });
+        let keyframe = |time: f32, y: f32| animation::AnimationKeyframe {
+            time,
+            transform: animation::AnimationTransform::Translation([0.0, y, 0.0]),
+        };
+        knight.get_animations_mut().insert("raise".to_string(), animation::Animation::new("raise", 1.0, vec![
+            animation::AnimationChannel::new(0, vec![keyframe(0.0, 0.0), keyframe(1.0, 2.0)]),
+        ]));
+        knight.play_animation("raise", false);
+        let knight_id = knight.get_unique_id();
+        let mut sword = Object::new(Some("sword".to_string()));
+        sword.transform.set_position([0.5, 0.0, 0.0]);
+        let sword_id = sword.get_unique_id();
+        s.add_object(knight);
+        s.add_object(sword);
+
+        assert!(s.attach_to_bone(sword_id, knight_id, "foot").is_err());
+        assert!(s.attach_to_bone(sword_id, knight_id, "hand").is_ok());
+        s.step(0.5);
+        let p = s.get_object_by_uuid(&sword_id).unwrap().transform.get_world_position();
+        assert!((p.x - 0.5).abs() < 1e-5 && (p.y - 1.0).abs() < 1e-5, "{:?}", p);
+    }
+
+    #[test]
+    fn appstate_runs_animation_event_functions() {
+        let mut s = AppState::new();
+        let mut knight = Object::new(Some("knight".to_string()));
+        *knight.get_skeleton_mut() = Some(animation::Skeleton { bones: Vec::new(), root_transform: nalgebra::Matrix4::identity() });
+        let mut walk = animation::Animation::new("walk", 1.0, Vec::new());
+        walk.add_event("footstep", 0.25);
+        walk.add_event("footstep", 0.75);
+        knight.get_animations_mut().insert("walk".to_string(), walk);
+        knight.play_animation("walk", true);
+        s.add_object(knight);
+        s.inject_animation_event_function("footstep", Arc::new(|a: &mut AppState, _event: &animation::FiredAnimationEvent| count_calls(a, "footsteps")));
+        for _ in 0..10 {
+            s.step(0.1);
+        }
+        assert_eq!(*s.get_state_data_value::<u32>("footsteps").unwrap(), 2);
+    }
+
+    struct TestEffect(f32);
+
+    impl PostProcessingEffect for TestEffect {
+        fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+            Some(self)
+        }
+
+        fn to_serializer(&self) -> Option<postprocessing::PostProcessingEffectSerializer> {
+            Some(postprocessing::PostProcessingEffectSerializer::Vignette { intensity: self.0, falloff: 0.5, color: [0.0, 0.0, 0.0], opacity: 1.0 })
+        }
+    }
+
+    struct UnserializableEffect;
+
+    impl PostProcessingEffect for UnserializableEffect {}
+
+    #[test]
+    fn appstate_post_process_stack() {
+        let mut s = AppState::new();
+        assert_eq!(s.add_post_process(Box::new(TestEffect(0.1))), "Vignette");
+        assert_eq!(s.add_post_process(Box::new(TestEffect(0.2))), "Vignette_2");
+        assert_eq!(s.add_post_process(Box::new(UnserializableEffect)), "PostProcess");
+        assert_eq!(s.insert_post_process(0, "first", Box::new(TestEffect(0.3))), "first");
+        assert_eq!(s.get_post_process_names(), vec!["first", "Vignette", "Vignette_2", "PostProcess"]);
+
+        assert!(s.move_post_process("first", 10));
+        assert!(s.move_post_process("Vignette_2", 0));
+        assert!(!s.move_post_process("missing", 0));
+        assert_eq!(s.get_post_process_names(), vec!["Vignette_2", "Vignette", "PostProcess", "first"]);
+
+        assert!(s.set_post_process_enabled("Vignette", false));
+        assert!(s.set_post_process_weight("first", 2.0));
+        assert!(!s.get_post_process_entry("Vignette").unwrap().enabled);
+        assert_eq!(s.get_post_process_entry("first").unwrap().weight, 1.0);
+
+        assert!(s.remove_post_process("PostProcess").is_some());
+        assert!(s.remove_post_process("PostProcess").is_none());
+        s.get_post_process_mut::<TestEffect>().unwrap().0 = 0.9;
+        assert_eq!(s.get_post_process_entry_mut("Vignette_2").unwrap().effect.as_any_mut().unwrap().downcast_ref::<TestEffect>().unwrap().0, 0.9);
+    }
+
+    #[test]
+    fn appstate_post_process_serialization() {
+        let mut s = AppState::new();
+        s.add_named_post_process("look", Box::new(TestEffect(0.4)));
+        s.add_post_process(Box::new(UnserializableEffect));
+        s.set_post_process_weight("look", 0.5);
+        s.set_post_process_enabled("look", false);
+
+        let entries: Vec<PostProcessEntrySerializer> = s.get_post_processes().iter().filter_map(|p| p.to_serializer()).collect();
+        assert_eq!(entries.len(), 1);
+        let json = serde_json::to_string(&entries).unwrap();
+        let restored: Vec<PostProcessEntrySerializer> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, entries);
+        assert_eq!(restored[0].name, "look");
+        assert_eq!(restored[0].weight, 0.5);
+        assert!(!restored[0].enabled);
+        assert_eq!(restored[0].effect.type_name(), "Vignette");
+
+        // scenes saved before the stack was serialized still load
+        let old_scene = r#"{"camera":null,"light":[],"ambient_light":null,"skybox":null,"materials":[],"skybox_texture":null,"objects":[],"object_selection":[]}"#;
+        let serializer: AppStateSerializer = serde_json::from_str(old_scene).unwrap();
+        assert!(serializer.post_processes.is_none());
+
+        // an empty stack is saved as such and clears the current one
+        let json = serde_json::to_string(&AppState::new().to_serializer()).unwrap();
+        let serializer: AppStateSerializer = serde_json::from_str(&json).unwrap();
+        assert_eq!(serializer.post_processes, Some(Vec::new()));
     }
 }
@@ -28,6 +28,50 @@ impl ShadowResolution {
     }
 }
 
+/// How the edges of a light's shadow are filtered.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Default)]
+pub enum ShadowFilter {
+    /// One shadow map sample, hard edges.
+    #[default]
+    None,
+    /// Percentage closer filtering over a `kernel_size` x `kernel_size` texel grid.
+    Pcf { kernel_size: u32 },
+    /// Percentage closer soft shadows, the penumbra widens with the distance between caster and receiver.
+    /// `light_size` is the size of the light in shadow map texels.
+    Pcss { light_size: f32 },
+}
+
+impl ShadowFilter {
+    /// Mode and size as passed to the shaders.
+    pub fn uniform_values(&self) -> (i32, f32) {
+        match self {
+            ShadowFilter::None => (0, 0.0),
+            ShadowFilter::Pcf { kernel_size } => (1, (*kernel_size).clamp(1, 15) as f32),
+            ShadowFilter::Pcss { light_size } => (2, light_size.max(1.0)),
+        }
+    }
+}
+
+pub const DEFAULT_SHADOW_DEPTH_BIAS: f32 = 0.005;
+
+fn default_shadow_depth_bias() -> f32 {
+    DEFAULT_SHADOW_DEPTH_BIAS
+}
+
+/// Cone of a spot light. Both angles are in degrees, measured from the light direction to the edge of the cone.
+/// Inside `inner_angle` the light has full intensity, between the angles it fades out.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct SpotCone {
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+}
+
+impl SpotCone {
+    pub fn new(inner_angle: f32, outer_angle: f32) -> Self {
+        Self { inner_angle, outer_angle }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct LightSerializer {
     pub position: [f32; 3],
@@ -35,14 +79,36 @@ pub struct LightSerializer {
     pub intensity: f32,
     pub direction: [f32; 3],
     pub cast_shadow: bool,
+    #[serde(default)]
+    pub range: f32,
+    #[serde(default)]
+    pub spot: Option<SpotCone>,
+    #[serde(default)]
+    pub shadow_filter: ShadowFilter,
+    #[serde(default = "default_shadow_depth_bias")]
+    pub shadow_depth_bias: f32,
+    #[serde(default)]
+    pub shadow_normal_bias: f32,
 }
 
+/// Below this intensity a light without explicit range is considered to have no effect.
+const LIGHT_CUTOFF: f32 = 0.01;
+
 pub struct Light {
     pub position: [f32; 3],
     pub color: [f32; 3],
     pub intensity: f32,
     pub direction: [f32; 3],
     pub cast_shadow: bool,
+    /// Distance at which a point light has faded out completely, `0.0` derives it from the intensity.
+    pub range: f32,
+    /// Turns the light into a spot light shining along `direction`.
+    pub spot: Option<SpotCone>,
+    pub shadow_filter: ShadowFilter,
+    /// Slope scaled depth offset against shadow acne, in shadow map depth units.
+    pub shadow_depth_bias: f32,
+    /// Offset along the surface normal before the shadow map lookup, in world units.
+    pub shadow_normal_bias: f32,
     components: HashMap<TypeId, Box<dyn Any>>,
 }
 
@@ -54,6 +120,11 @@ impl Clone for Light {
             intensity: self.intensity,
             direction: self.direction,
             cast_shadow: self.cast_shadow,
+            range: self.range,
+            spot: self.spot,
+            shadow_filter: self.shadow_filter,
+            shadow_depth_bias: self.shadow_depth_bias,
+            shadow_normal_bias: self.shadow_normal_bias,
             components: HashMap::new(),
         }
     }
@@ -67,16 +138,57 @@ impl Light {
             intensity,
             direction : direction.unwrap_or_else(|| [0.0, 0.0, 0.0]),
             cast_shadow,
+            range: 0.0,
+            spot: None,
+            shadow_filter: ShadowFilter::None,
+            shadow_depth_bias: DEFAULT_SHADOW_DEPTH_BIAS,
+            shadow_normal_bias: 0.0,
             components: HashMap::new(),
         }
     }
 
+    /// A spot light at `position` shining along `direction` within `cone`.
+    pub fn new_spot(position: [f32; 3], direction: [f32; 3], color: [f32; 3], intensity: f32, cone: SpotCone, range: f32, cast_shadow: bool) -> Self {
+        let mut light = Light::new(position, color, intensity, Some(direction), cast_shadow);
+        light.range = range;
+        light.spot = Some(cone);
+        light
+    }
+
     pub fn default() -> Self {
         Light::new([0.0, 0.0, 0.0], [1.0, 1.0, 1.0], 1.0, None, true)
     }
 
     pub fn is_directional(&self) -> bool {
-        self.direction != [0.0, 0.0, 0.0]
+        self.direction != [0.0, 0.0, 0.0] && self.spot.is_none()
+    }
+
+    pub fn is_spot(&self) -> bool {
+        self.spot.is_some()
+    }
+
+    /// Cosines of the inner and outer cone angle, as used by the shaders. The inner angle is clamped to the outer one.
+    pub fn get_spot_cosines(&self) -> [f32; 2] {
+        match self.spot {
+            Some(cone) => {
+                let outer = cone.outer_angle.clamp(0.0, 89.0);
+                let inner = cone.inner_angle.clamp(0.0, outer);
+                [inner.to_radians().cos(), outer.to_radians().cos()]
+            }
+            None => [-1.0, -1.0],
+        }
+    }
+
+    /// The distance used to assign the light to clusters. Without an explicit `range` this is
+    /// where the inverse square falloff drops below 1% of the intensity. Directional lights reach everywhere.
+    pub fn get_effective_range(&self) -> f32 {
+        if self.is_directional() {
+            return f32::INFINITY;
+        }
+        if self.range > 0.0 {
+            return self.range;
+        }
+        (self.intensity.max(0.0) / LIGHT_CUTOFF).sqrt()
     }
 
     pub fn from_serializer(serializer: LightSerializer) -> Self {
@@ -86,6 +198,11 @@ impl Light {
             intensity: serializer.intensity,
             direction: serializer.direction,
             cast_shadow: serializer.cast_shadow,
+            range: serializer.range,
+            spot: serializer.spot,
+            shadow_filter: serializer.shadow_filter,
+            shadow_depth_bias: serializer.shadow_depth_bias,
+            shadow_normal_bias: serializer.shadow_normal_bias,
             components: HashMap::new(),
         }
     }
@@ -97,6 +214,11 @@ impl Light {
             intensity: self.intensity,
             direction: self.direction,
             cast_shadow: self.cast_shadow,
+            range: self.range,
+            spot: self.spot,
+            shadow_filter: self.shadow_filter,
+            shadow_depth_bias: self.shadow_depth_bias,
+            shadow_normal_bias: self.shadow_normal_bias,
         }
     }
 
@@ -185,6 +307,68 @@ mod tests {
         assert_eq!(l.get_component::<u32>(), None);
     }
 
+    #[test]
+    fn effective_range() {
+        let mut light = Light::new([0.0; 3], [1.0; 3], 4.0, None, false);
+        assert!((light.get_effective_range() - 20.0).abs() < 1e-4);
+        light.range = 5.0;
+        assert_eq!(light.get_effective_range(), 5.0);
+        let sun = Light::new([0.0; 3], [1.0; 3], 1.0, Some([0.0, -1.0, 0.0]), true);
+        assert!(sun.get_effective_range().is_infinite());
+    }
+
+    #[test]
+    fn spot_light_round_trip() {
+        let spot = Light::new_spot([1.0, 2.0, 3.0], [0.0, -1.0, 0.0], [1.0; 3], 5.0, SpotCone::new(20.0, 30.0), 12.0, true);
+        assert!(spot.is_spot());
+        assert!(!spot.is_directional());
+        assert_eq!(spot.get_effective_range(), 12.0);
+        let json = serde_json::to_string(&spot.to_serializer()).unwrap();
+        let restored = Light::from_serializer(serde_json::from_str(&json).unwrap());
+        assert_eq!(restored.spot, Some(SpotCone { inner_angle: 20.0, outer_angle: 30.0 }));
+        assert_eq!(restored.direction, [0.0, -1.0, 0.0]);
+        assert_eq!(restored.range, 12.0);
+        let [inner, outer] = restored.get_spot_cosines();
+        assert!(inner > outer);
+        assert!((outer - 30f32.to_radians().cos()).abs() < 1e-6);
+
+        // lights saved before spot lights existed still load
+        let old = r#"{"position":[0,0,0],"color":[1,1,1],"intensity":1,"direction":[0,-1,0],"cast_shadow":false}"#;
+        let light = Light::from_serializer(serde_json::from_str(old).unwrap());
+        assert!(light.is_directional());
+    }
+
+    #[test]
+    fn shadow_filter_round_trip() {
+        let mut light = test_light();
+        light.shadow_filter = ShadowFilter::Pcss { light_size: 6.0 };
+        light.shadow_depth_bias = 0.002;
+        light.shadow_normal_bias = 0.05;
+        let json = serde_json::to_string(&light.to_serializer()).unwrap();
+        let restored = Light::from_serializer(serde_json::from_str(&json).unwrap());
+        assert_eq!(restored.shadow_filter, ShadowFilter::Pcss { light_size: 6.0 });
+        assert_eq!(restored.shadow_depth_bias, 0.002);
+        assert_eq!(restored.shadow_normal_bias, 0.05);
+
+        // older files get the defaults
+        let old = r#"{"position":[0,0,0],"color":[1,1,1],"intensity":1,"direction":[0,0,0],"cast_shadow":true}"#;
+        let light = Light::from_serializer(serde_json::from_str(old).unwrap());
+        assert_eq!(light.shadow_filter, ShadowFilter::None);
+        assert_eq!(light.shadow_depth_bias, DEFAULT_SHADOW_DEPTH_BIAS);
+        assert_eq!(light.shadow_normal_bias, 0.0);
+    }
+
+    #[test]
+    fn shadow_filter_uniform_values_are_clamped() {
+        assert_eq!(ShadowFilter::None.uniform_values(), (0, 0.0));
+        assert_eq!(ShadowFilter::Pcf { kernel_size: 5 }.uniform_values(), (1, 5.0));
+        assert_eq!(ShadowFilter::Pcf { kernel_size: 0 }.uniform_values(), (1, 1.0));
+        assert_eq!(ShadowFilter::Pcf { kernel_size: 99 }.uniform_values(), (1, 15.0));
+        assert_eq!(ShadowFilter::Pcss { light_size: 6.0 }.uniform_values(), (2, 6.0));
+        assert_eq!(ShadowFilter::Pcss { light_size: 0.2 }.uniform_values(), (2, 1.0));
+        assert_eq!(ShadowFilter::Pcss { light_size: -3.0 }.uniform_values(), (2, 1.0));
+    }
+
     #[test]
     fn light_component_clone_isolation() {
         let mut l = test_light();
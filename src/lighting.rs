@@ -0,0 +1,471 @@
+use std::borrow::Cow;
+use glium::backend::Facade;
+use glium::Rect;
+use glium::texture::{ClientFormat, MipmapsOption, RawImage2d, Texture2d, UncompressedFloatFormat};
+use crate::camera::Camera;
+use crate::ibl::Ibl;
+use crate::light::Light;
+use crate::shadow::ShadowMaps;
+use crate::texture::Texture;
+
+/// Lights that are passed to the shaders as plain uniforms. Shadow casting lights always get a
+/// slot, so this is also the maximum number of shadow casting lights.
+pub const LIGHT_SLOTS: usize = 4;
+/// Texels per light in the clustered light texture: position + range, color * intensity, direction + light type
+/// (0 point, 1 directional, 2 spot) and the spot cone cosines.
+pub(crate) const LIGHT_TEXELS: u32 = 4;
+pub(crate) const INDEX_TEXTURE_WIDTH: u32 = 1024;
+
+/// Number of clusters the view frustum is divided into. The depth is sliced exponentially,
+/// so clusters close to the camera are small.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClusterGrid {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+}
+
+impl ClusterGrid {
+    pub fn new(x: u32, y: u32, z: u32) -> Self {
+        Self { x: x.max(1), y: y.max(1), z: z.max(1) }
+    }
+
+    pub fn cluster_count(&self) -> usize {
+        (self.x * self.y * self.z) as usize
+    }
+
+    pub fn cluster_index(&self, x: u32, y: u32, z: u32) -> usize {
+        (x + y * self.x + z * self.x * self.y) as usize
+    }
+}
+
+impl Default for ClusterGrid {
+    fn default() -> Self {
+        ClusterGrid::new(16, 9, 24)
+    }
+}
+
+/// Picks the lights that get one of the `LIGHT_SLOTS` uniform slots: shadow casters first, then
+/// directional lights, both in the order of `lights`. Returns indices into `lights`. Shadow casters
+/// that don't fit are lit through the clusters without shadows.
+pub fn select_slot_lights(lights: &[Light]) -> Vec<usize> {
+    let shadow_casters = lights.iter().enumerate().filter(|(_, l)| l.cast_shadow).map(|(i, _)| i);
+    let directional = lights.iter().enumerate().filter(|(_, l)| !l.cast_shadow && l.is_directional()).map(|(i, _)| i);
+    shadow_casters.chain(directional).take(LIGHT_SLOTS).collect()
+}
+
+/// Which clustered lights affect which cluster. `clusters` holds (offset, count) into `indices` per
+/// cluster, `indices` refers to the lights passed to `assign_lights`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterAssignment {
+    pub grid: ClusterGrid,
+    pub clusters: Vec<(u32, u32)>,
+    pub indices: Vec<u32>,
+}
+
+/// The parts of the camera the cluster grid is built from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClusterView {
+    pub view: [[f32; 4]; 4],
+    /// `projection[0][0]` and `projection[1][1]` of a perspective projection.
+    pub scale_x: f32,
+    pub scale_y: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl ClusterView {
+    pub fn from_camera(camera: &Camera) -> Self {
+        // the clusters must not move with the TAA jitter, the shaders look them up by screen position
+        let projection = camera.get_unjittered_projection_matrix();
+        Self {
+            view: camera.get_view_matrix(),
+            scale_x: projection[0][0],
+            scale_y: projection[1][1],
+            near: camera.get_near(),
+            far: camera.get_far(),
+        }
+    }
+
+    fn slice_depth(&self, slice: f32, grid: &ClusterGrid) -> f32 {
+        self.near * (self.far / self.near).powf(slice / grid.z as f32)
+    }
+
+    fn slice_of(&self, depth: f32, grid: &ClusterGrid) -> u32 {
+        let slice = ((depth / self.near).ln() / (self.far / self.near).ln() * grid.z as f32).floor();
+        (slice.max(0.0) as u32).min(grid.z - 1)
+    }
+
+    /// View space min/max corners of cluster (x, y, z).
+    fn cluster_bounds(&self, x: u32, y: u32, z: u32, grid: &ClusterGrid) -> ([f32; 3], [f32; 3]) {
+        let near = self.slice_depth(z as f32, grid);
+        let far = self.slice_depth(z as f32 + 1.0, grid);
+        let ndc = |i: u32, n: u32| -1.0 + 2.0 * i as f32 / n as f32;
+        let (x0, x1) = (ndc(x, grid.x), ndc(x + 1, grid.x));
+        let (y0, y1) = (ndc(y, grid.y), ndc(y + 1, grid.y));
+        let xs = [x0 * near / self.scale_x, x1 * near / self.scale_x, x0 * far / self.scale_x, x1 * far / self.scale_x];
+        let ys = [y0 * near / self.scale_y, y1 * near / self.scale_y, y0 * far / self.scale_y, y1 * far / self.scale_y];
+        let min = |v: [f32; 4]| v.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = |v: [f32; 4]| v.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        ([min(xs), min(ys), -far], [max(xs), max(ys), -near])
+    }
+
+    fn view_position(&self, p: [f32; 3]) -> [f32; 3] {
+        let m = self.view;
+        [
+            m[0][0] * p[0] + m[1][0] * p[1] + m[2][0] * p[2] + m[3][0],
+            m[0][1] * p[0] + m[1][1] * p[1] + m[2][1] * p[2] + m[3][1],
+            m[0][2] * p[0] + m[1][2] * p[1] + m[2][2] * p[2] + m[3][2],
+        ]
+    }
+}
+
+/// View space bounds of every cluster. They only depend on the projection and the grid, so they are
+/// kept between frames and only built again when `matches` fails.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterBounds {
+    pub grid: ClusterGrid,
+    scale_x: f32,
+    scale_y: f32,
+    near: f32,
+    far: f32,
+    bounds: Vec<([f32; 3], [f32; 3])>,
+}
+
+impl ClusterBounds {
+    pub fn new(view: &ClusterView, grid: ClusterGrid) -> Self {
+        let mut bounds = Vec::with_capacity(grid.cluster_count());
+        for z in 0..grid.z {
+            for y in 0..grid.y {
+                for x in 0..grid.x {
+                    bounds.push(view.cluster_bounds(x, y, z, &grid));
+                }
+            }
+        }
+        Self { grid, scale_x: view.scale_x, scale_y: view.scale_y, near: view.near, far: view.far, bounds }
+    }
+
+    /// Whether these bounds were built for the projection of `view` and `grid`.
+    pub fn matches(&self, view: &ClusterView, grid: ClusterGrid) -> bool {
+        self.grid == grid && self.scale_x == view.scale_x && self.scale_y == view.scale_y && self.near == view.near && self.far == view.far
+    }
+
+    /// View space min/max corners of cluster (x, y, z).
+    pub fn get(&self, x: u32, y: u32, z: u32) -> ([f32; 3], [f32; 3]) {
+        self.bounds[self.grid.cluster_index(x, y, z)]
+    }
+}
+
+/// Assigns every light to the clusters its range touches. Directional lights touch all clusters.
+/// `bounds` has to match the projection of `view`.
+pub fn assign_lights(lights: &[&Light], view: &ClusterView, bounds: &ClusterBounds) -> ClusterAssignment {
+    let grid = bounds.grid;
+    let mut per_cluster: Vec<Vec<u32>> = vec![Vec::new(); grid.cluster_count()];
+    for (light_index, light) in lights.iter().enumerate() {
+        let range = light.get_effective_range();
+        if range.is_infinite() {
+            per_cluster.iter_mut().for_each(|c| c.push(light_index as u32));
+            continue;
+        }
+        let center = view.view_position(light.position);
+        let depth = -center[2];
+        if depth + range < view.near || depth - range > view.far {
+            continue;
+        }
+        let first = view.slice_of((depth - range).max(view.near), &grid);
+        let last = view.slice_of((depth + range).min(view.far), &grid);
+        for z in first..=last {
+            for y in 0..grid.y {
+                for x in 0..grid.x {
+                    let (min, max) = bounds.get(x, y, z);
+                    let distance_squared: f32 = (0..3)
+                        .map(|i| {
+                            let closest = center[i].clamp(min[i], max[i]);
+                            (center[i] - closest) * (center[i] - closest)
+                        })
+                        .sum();
+                    if distance_squared <= range * range {
+                        per_cluster[grid.cluster_index(x, y, z)].push(light_index as u32);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut clusters = Vec::with_capacity(per_cluster.len());
+    let mut indices = Vec::new();
+    for cluster in per_cluster {
+        clusters.push((indices.len() as u32, cluster.len() as u32));
+        indices.extend(cluster);
+    }
+    ClusterAssignment { grid, clusters, indices }
+}
+
+/// The light type as the shaders encode it in the w component of the light direction.
+pub(crate) fn light_type(light: &Light) -> f32 {
+    if light.is_spot() {
+        2.0
+    } else if light.is_directional() {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// `LIGHT_TEXELS` RGBA texels per light, one light per row.
+pub(crate) fn pack_lights(lights: &[&Light]) -> Vec<f32> {
+    let mut data = Vec::with_capacity(lights.len() * LIGHT_TEXELS as usize * 4);
+    for light in lights {
+        let range = if light.is_directional() { 0.0 } else { light.get_effective_range() };
+        let color = [light.color[0] * light.intensity, light.color[1] * light.intensity, light.color[2] * light.intensity];
+        data.extend_from_slice(&[light.position[0], light.position[1], light.position[2], range]);
+        data.extend_from_slice(&[color[0], color[1], color[2], light.intensity]);
+        data.extend_from_slice(&[light.direction[0], light.direction[1], light.direction[2], light_type(light)]);
+        let [inner, outer] = light.get_spot_cosines();
+        data.extend_from_slice(&[inner, outer, 0.0, 0.0]);
+    }
+    data
+}
+
+/// Light indices, four per texel, `INDEX_TEXTURE_WIDTH` texels per row.
+pub(crate) fn pack_indices(indices: &[u32]) -> (Vec<f32>, u32, u32) {
+    let texels = (indices.len() as u32).div_ceil(4);
+    let height = texels.div_ceil(INDEX_TEXTURE_WIDTH).max(1);
+    let mut data = vec![0.0f32; (INDEX_TEXTURE_WIDTH * height * 4) as usize];
+    for (i, index) in indices.iter().enumerate() {
+        data[i] = *index as f32;
+    }
+    (data, INDEX_TEXTURE_WIDTH, height)
+}
+
+fn float_texture(display: &impl Facade, width: u32, height: u32) -> Texture2d {
+    Texture2d::empty_with_format(display, UncompressedFloatFormat::F32F32F32F32, MipmapsOption::NoMipmap, width, height)
+        .expect("Failed to create clustered lighting texture")
+}
+
+/// Uploads RGBA `data` into the bottom `width` x `data.len() / 4 / width` texels of `texture`.
+fn write_rows(texture: &Texture2d, data: Vec<f32>, width: u32) {
+    let height = data.len() as u32 / 4 / width;
+    let image = RawImage2d {
+        data: Cow::Owned(data),
+        width,
+        height,
+        format: ClientFormat::F32F32F32F32,
+    };
+    texture.write(Rect { left: 0, bottom: 0, width, height }, image);
+}
+
+/// Everything of a frame a lit material samples besides its own textures, see `Material::get_uniforms`.
+pub struct SceneLighting<'a> {
+    pub lighting: &'a LightingData,
+    pub ambient_light: Option<&'a Light>,
+    pub skybox: &'a Texture,
+    pub ibl: &'a Ibl,
+    pub shadow_maps: &'a ShadowMaps,
+}
+
+/// The lights of the current frame, prepared for the shaders. The `LIGHT_SLOTS` slot lights are passed as
+/// uniforms and may cast shadows, all other lights are stored in float textures and looked up per
+/// fragment through the cluster grid.
+pub struct LightingData {
+    pub slot_lights: Vec<Light>,
+    pub light_texture: Texture2d,
+    pub cluster_texture: Texture2d,
+    pub index_texture: Texture2d,
+    pub clustered_light_count: i32,
+    pub grid: ClusterGrid,
+    pub near: f32,
+    pub far: f32,
+    pub screen_size: [f32; 2],
+    bounds: ClusterBounds,
+}
+
+impl LightingData {
+    /// Empty lighting, the textures are allocated here and filled by `update` every frame.
+    pub fn new(display: &impl Facade, grid: ClusterGrid, screen_size: [f32; 2]) -> Self {
+        let view = ClusterView::from_camera(&Camera::default());
+        Self {
+            slot_lights: Vec::new(),
+            light_texture: float_texture(display, LIGHT_TEXELS, 1),
+            cluster_texture: float_texture(display, grid.x, grid.y * grid.z),
+            index_texture: float_texture(display, INDEX_TEXTURE_WIDTH, 1),
+            clustered_light_count: 0,
+            grid,
+            near: view.near,
+            far: view.far,
+            screen_size,
+            bounds: ClusterBounds::new(&view, grid),
+        }
+    }
+
+    /// Picks the slot lights of `lights`, assigns the others to the clusters of `camera` and uploads them.
+    /// The textures are only allocated again when they are too small or the grid changed, the cluster
+    /// bounds only when the projection or the grid changed.
+    pub fn update(&mut self, display: &impl Facade, lights: &[Light], camera: Option<&Camera>, grid: ClusterGrid, screen_size: [f32; 2]) {
+        let slots = select_slot_lights(lights);
+        let clustered: Vec<&Light> = lights.iter().enumerate()
+            .filter(|(i, _)| !slots.contains(i))
+            .map(|(_, l)| l)
+            .collect();
+        let view = match camera {
+            Some(camera) => ClusterView::from_camera(camera),
+            None => ClusterView::from_camera(&Camera::default()),
+        };
+        if !self.bounds.matches(&view, grid) {
+            self.bounds = ClusterBounds::new(&view, grid);
+        }
+        let assignment = assign_lights(&clustered, &view, &self.bounds);
+
+        if !clustered.is_empty() {
+            if self.light_texture.height() < clustered.len() as u32 {
+                self.light_texture = float_texture(display, LIGHT_TEXELS, (clustered.len() as u32).next_power_of_two());
+            }
+            write_rows(&self.light_texture, pack_lights(&clustered), LIGHT_TEXELS);
+        }
+        if self.grid != grid {
+            self.cluster_texture = float_texture(display, grid.x, grid.y * grid.z);
+        }
+        let cluster_data: Vec<f32> = assignment.clusters.iter()
+            .flat_map(|(offset, count)| [*offset as f32, *count as f32, 0.0, 0.0])
+            .collect();
+        write_rows(&self.cluster_texture, cluster_data, grid.x);
+        let (index_data, index_width, index_height) = pack_indices(&assignment.indices);
+        if self.index_texture.height() < index_height {
+            self.index_texture = float_texture(display, index_width, index_height.next_power_of_two());
+        }
+        write_rows(&self.index_texture, index_data, index_width);
+
+        self.slot_lights = slots.iter().map(|i| lights[*i].clone()).collect();
+        self.clustered_light_count = clustered.len() as i32;
+        self.grid = grid;
+        self.near = view.near;
+        self.far = view.far;
+        self.screen_size = screen_size;
+    }
+
+    pub fn get_slot_lights(&self) -> &Vec<Light> {
+        &self.slot_lights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::SpotCone;
+
+    fn point(position: [f32; 3], intensity: f32) -> Light {
+        Light::new(position, [1.0, 1.0, 1.0], intensity, None, false)
+    }
+
+    fn view() -> ClusterView {
+        // camera at the origin looking down -z
+        ClusterView::from_camera(&Camera::new(None, None, Some(90.0), Some(1.0), Some(0.1), Some(100.0)))
+    }
+
+    #[test]
+    fn slots_prefer_shadow_casters_then_directional() {
+        let mut lights: Vec<Light> = (0..6).map(|i| point([i as f32, 0.0, 0.0], 1.0)).collect();
+        lights[1].cast_shadow = true;
+        lights[4].cast_shadow = true;
+        lights[5].direction = [0.0, -1.0, 0.0];
+        assert_eq!(select_slot_lights(&lights), vec![1, 4, 5]);
+        for light in lights.iter_mut() {
+            light.cast_shadow = true;
+        }
+        assert_eq!(select_slot_lights(&lights).len(), LIGHT_SLOTS);
+    }
+
+    #[test]
+    fn point_light_only_touches_nearby_clusters() {
+        let mut near_light = point([0.0, 0.0, -5.0], 1.0);
+        near_light.range = 1.0;
+        let behind = point([0.0, 0.0, 10.0], 1.0);
+        let lights = vec![&near_light, &behind];
+        let grid = ClusterGrid::new(4, 4, 8);
+        let assignment = assign_lights(&lights, &view(), &ClusterBounds::new(&view(), grid));
+        assert_eq!(assignment.clusters.len(), grid.cluster_count());
+        assert!(!assignment.indices.is_empty());
+        // the light behind the camera is in no cluster
+        assert!(assignment.indices.iter().all(|i| *i == 0));
+        // the light sits in the middle of the screen, so the corner clusters don't see it
+        let (_, count) = assignment.clusters[grid.cluster_index(0, 0, 4)];
+        assert_eq!(count, 0);
+        let touched = assignment.clusters.iter().filter(|(_, count)| *count > 0).count();
+        assert!(touched < grid.cluster_count() / 4, "touched {} clusters", touched);
+    }
+
+    #[test]
+    fn directional_light_touches_every_cluster() {
+        let sun = Light::new([0.0; 3], [1.0; 3], 1.0, Some([0.0, -1.0, 0.0]), false);
+        let grid = ClusterGrid::new(2, 2, 2);
+        let assignment = assign_lights(&[&sun], &view(), &ClusterBounds::new(&view(), grid));
+        assert!(assignment.clusters.iter().all(|(_, count)| *count == 1));
+        assert_eq!(assignment.clusters[3], (3, 1));
+    }
+
+    #[test]
+    fn cluster_bounds_follow_projection_and_grid() {
+        let mut camera = Camera::new(None, None, Some(90.0), Some(1.0), Some(0.1), Some(100.0));
+        let grid = ClusterGrid::new(4, 4, 8);
+        let bounds = ClusterBounds::new(&ClusterView::from_camera(&camera), grid);
+        assert_eq!(bounds.get(1, 2, 3), view().cluster_bounds(1, 2, 3, &grid));
+
+        // the bounds are in view space and ignore the TAA jitter, so moving or jittering the camera keeps them
+        camera.set_position([3.0, 0.0, 1.0]);
+        camera.set_jitter([0.001, -0.002]);
+        assert!(bounds.matches(&ClusterView::from_camera(&camera), grid));
+        assert!(!bounds.matches(&ClusterView::from_camera(&camera), ClusterGrid::new(4, 4, 9)));
+        camera.set_fov(60.0);
+        assert!(!bounds.matches(&ClusterView::from_camera(&camera), grid));
+    }
+
+    #[test]
+    fn packed_indices_are_padded() {
+        let (data, width, height) = pack_indices(&[3, 1, 2, 7, 5]);
+        assert_eq!((width, height), (INDEX_TEXTURE_WIDTH, 1));
+        assert_eq!(&data[..6], &[3.0, 1.0, 2.0, 7.0, 5.0, 0.0]);
+        assert_eq!(pack_lights(&[&point([1.0, 2.0, 3.0], 2.0)]).len(), (LIGHT_TEXELS * 4) as usize);
+        let spot = Light::new_spot([0.0; 3], [0.0, -1.0, 0.0], [1.0; 3], 1.0, SpotCone::new(10.0, 20.0), 5.0, false);
+        let packed = pack_lights(&[&spot]);
+        assert_eq!(packed[3], 5.0);
+        assert_eq!(packed[11], 2.0);
+        assert!(packed[12] > packed[13]);
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "macos", target_os = "ios"))))]
+mod gl_tests {
+    use super::*;
+    use glium::GlObject;
+    use crate::headless::test_display;
+
+    #[test]
+    #[ignore]
+    fn textures_grow_and_keep_the_lights() {
+        let display = test_display();
+        let grid = ClusterGrid::new(4, 4, 8);
+        let mut lighting = LightingData::new(&display, grid, [64.0, 64.0]);
+        let lights: Vec<Light> = (0..12).map(|i| Light::new([i as f32, 0.0, -5.0], [1.0; 3], 1.0, None, false)).collect();
+
+        lighting.update(&display, &lights[..6], None, grid, [64.0, 64.0]);
+        let light_texture = lighting.light_texture.get_id();
+        // none of the lights casts shadows or is directional, so all of them are clustered
+        assert_eq!(lighting.clustered_light_count, 6);
+        // fewer lights fit into the same texture
+        lighting.update(&display, &lights[..5], None, grid, [64.0, 64.0]);
+        assert_eq!(lighting.light_texture.get_id(), light_texture);
+        lighting.update(&display, &lights, None, grid, [64.0, 64.0]);
+        assert_eq!(lighting.clustered_light_count, 12);
+        assert!(lighting.light_texture.height() >= 12);
+
+        let image = lighting.light_texture.first_layer().main_level().into_image(None).unwrap();
+        let texels: Vec<Vec<(f32, f32, f32, f32)>> = image.raw_read(&Rect { left: 0, bottom: 0, width: LIGHT_TEXELS, height: 12 });
+        for (row, light) in lights.iter().enumerate() {
+            assert_eq!(texels[row][0].0, light.position[0]);
+        }
+
+        let other_grid = ClusterGrid::new(2, 2, 2);
+        lighting.update(&display, &lights, None, other_grid, [64.0, 64.0]);
+        assert_eq!(lighting.cluster_texture.dimensions(), (2, 4));
+    }
+}
@@ -1,15 +1,16 @@
+use std::rc::Rc;
 use glium::uniforms::UniformBuffer;
-use glium::Display;
-use glium::glutin::surface::WindowSurface;
+use glium::backend::{Context, Facade};
 use glium::texture::RawImage2d;
-use glium::uniforms::SamplerWrapFunction;
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use crate::{resources, shader, texture};
 use crate::camera::Camera;
-use crate::geometry::BoneTransforms;
+use crate::geometry::{BoneTransforms, MorphTargetBuffer, MAX_MORPH_TARGETS};
 use crate::light::{Light, LightBlock};
-use crate::shadow::ShadowMaps;
+use crate::lighting::{self, SceneLighting};
+use crate::shadow::CASCADE_BLEND;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MaterialSerializer {
@@ -50,7 +51,7 @@ pub struct Material {
     _tex_gray: glium::texture::SrgbTexture2d,
     _tex_normal: glium::texture::SrgbTexture2d,
     //this should be a raw image
-    pub display: glium::Display<WindowSurface>,
+    pub display: Rc<Context>,
     pub program: glium::Program,
     pub time: f32,
     pub matrix: [[f32; 4]; 4],
@@ -121,34 +122,34 @@ impl Clone for Material {
 }
 
 impl Material {
-    pub fn default(shader: shader::Shader, display: &glium::Display<WindowSurface>) -> Self {
-        Material::new(shader, display.clone(), None, None, None, None, None, None, None, None, None, None)
+    pub fn default(shader: shader::Shader, display: &impl Facade) -> Self {
+        Material::new(shader, display.get_context().clone(), None, None, None, None, None, None, None, None, None, None)
     }
 
-    pub fn from_serializer(serializer: MaterialSerializer, display: &glium::Display<WindowSurface>) -> Self {
+    pub fn from_serializer(serializer: MaterialSerializer, display: &impl Facade) -> Self {
         let shader = shader::Shader::from_serializer(serializer.shader);
         let albedo = match serializer.albedo {
-            Some(albedo) => Some(texture::Texture::from_serializer(albedo, &display)),
+            Some(albedo) => Some(texture::Texture::from_serializer(albedo, display)),
             None => None,
         };
         let normal = match serializer.normal {
-            Some(normal) => Some(texture::Texture::from_serializer(normal, &display)),
+            Some(normal) => Some(texture::Texture::from_serializer(normal, display)),
             None => None,
         };
         let roughness = match serializer.roughness {
-            Some(roughness) => Some(texture::Texture::from_serializer(roughness, &display)),
+            Some(roughness) => Some(texture::Texture::from_serializer(roughness, display)),
             None => None,
         };
         let metallic = match serializer.metallic {
-            Some(metallic) => Some(texture::Texture::from_serializer(metallic, &display)),
+            Some(metallic) => Some(texture::Texture::from_serializer(metallic, display)),
             None => None,
         };
         let emissive = match serializer.emissive {
-            Some(emissive) => Some(texture::Texture::from_serializer(emissive, &display)),
+            Some(emissive) => Some(texture::Texture::from_serializer(emissive, display)),
             None => None,
         };
 
-        let mut mat = Material::new(shader, display.clone(), Some(serializer.color), albedo, normal, Some(serializer.normal_strength), roughness, Some(serializer.roughness_strength), metallic, Some(serializer.metallic_strength), emissive, Some(serializer.emissive_strength));
+        let mut mat = Material::new(shader, display.get_context().clone(), Some(serializer.color), albedo, normal, Some(serializer.normal_strength), roughness, Some(serializer.roughness_strength), metallic, Some(serializer.metallic_strength), emissive, Some(serializer.emissive_strength));
         mat.name = serializer.name;
         mat.matrix = serializer.matrix;
         mat.set_transparency_strength(serializer.transparency);
@@ -195,7 +196,7 @@ impl Material {
 
     pub fn new(
         shader: shader::Shader,
-        display: glium::Display<WindowSurface>,
+        display: impl Facade,
         color: Option<[f32; 3]>,
         albedo: Option<texture::Texture>,
         normal: Option<texture::Texture>,
@@ -233,7 +234,7 @@ impl Material {
         Self {
             name: "New Material".to_string(),
             shader,
-            display,
+            display: display.get_context().clone(),
             color: color.unwrap_or_else(|| [1.0, 1.0, 1.0]),
             albedo: match albedo {
                 Some(albedo) => Some(albedo),
@@ -364,13 +365,19 @@ impl Material {
         }
     }
 
-    pub fn lit_pbr(display: Display<WindowSurface>, transparency: bool) -> Self {
+    pub fn lit_pbr(display: impl Facade, transparency: bool) -> Self {
         let mut mat = Material::default(shader::Shader::from_strings(resources::vertex_shader(), resources::fragment_shader(), None), &display);
         mat.set_transparency(transparency);
         mat
     }
 
-    pub fn unlit(display: Display<WindowSurface>, transparency: bool) -> Self {
+    /// Whether the shader of this material can write the surface buffer for screen-space effects,
+    /// which is true for `lit_pbr` and any custom shader declaring `uniform bool surface_pass`.
+    pub fn supports_surface_pass(&self) -> bool {
+        self.program.get_uniform("surface_pass").is_some()
+    }
+
+    pub fn unlit(display: impl Facade, transparency: bool) -> Self {
         let mut mat = Material::default(shader::Shader::from_strings(resources::vertex_shader(), resources::fragment_unlit_shader(), None), &display);
         mat.set_transparency(transparency);
         mat
@@ -393,14 +400,7 @@ impl Material {
                 light_position[i] = [lights[i].position[0], lights[i].position[1], lights[i].position[2], 0.0];
                 light_color[i] = [lights[i].color[0], lights[i].color[1], lights[i].color[2], 0.0];
                 light_intensity[i] = lights[i].intensity;
-                light_direction[i] = {
-                    let direction = lights[i].direction;
-                    if direction == [0.0, 0.0, 0.0] {
-                        [0.0, 0.0, 0.0, 0.0]
-                    } else {
-                        [lights[i].direction[0], lights[i].direction[1], lights[i].direction[2], 1.0]
-                    }
-                };
+                light_direction[i] = [lights[i].direction[0], lights[i].direction[1], lights[i].direction[2], lighting::light_type(&lights[i])];
                 cast_shadow[i] = if lights[i].cast_shadow { 1 } else { 0 };
             }
         }
@@ -423,8 +423,24 @@ impl Material {
         }
     }
 
-    pub fn get_uniforms<'a>(&'a self, lights: &Vec<Light>, ambient_light: Option<&'a Light>, camera: Option<&'a Camera>, bone_transforms: &'a UniformBuffer<BoneTransforms>, has_skeleton: bool, skybox: &'a texture::Texture, shadow_maps: &'a ShadowMaps) -> impl glium::uniforms::Uniforms + 'a {
+    pub fn get_uniforms<'a>(&'a self, scene: &SceneLighting<'a>, camera: Option<&'a Camera>, bone_transforms: &'a UniformBuffer<BoneTransforms>, has_skeleton: bool, morph_targets: &'a MorphTargetBuffer, morph_weights: [f32; MAX_MORPH_TARGETS]) -> impl glium::uniforms::Uniforms + 'a {
+        let SceneLighting { lighting, ambient_light, skybox, ibl, shadow_maps } = *scene;
+        let lights = lighting.get_slot_lights();
         let light_block = Material::light_block_from_vec(lights, ambient_light);
+        let mut light_range = [0.0f32; 4];
+        let mut light_cone_inner = [-1.0f32; 4];
+        let mut light_cone_outer = [-1.0f32; 4];
+        let mut shadow_filter = [0i32; 4];
+        let mut shadow_filter_size = [0.0f32; 4];
+        let mut shadow_depth_bias = [0.0f32; 4];
+        let mut shadow_normal_bias = [0.0f32; 4];
+        for (i, light) in lights.iter().take(4).enumerate() {
+            (shadow_filter[i], shadow_filter_size[i]) = light.shadow_filter.uniform_values();
+            shadow_depth_bias[i] = light.shadow_depth_bias;
+            shadow_normal_bias[i] = light.shadow_normal_bias;
+            light_range[i] = if light.is_directional() { 0.0 } else { light.get_effective_range() };
+            [light_cone_inner[i], light_cone_outer[i]] = light.get_spot_cosines();
+        }
 
         let cast_shadow_vec: [f32; 4] = [
             if lights.get(0).map(|l| l.cast_shadow).unwrap_or(false) { 1.0 } else { 0.0 },
@@ -516,8 +532,20 @@ impl Material {
             ambient_light_color: light_block.ambient_color,
             ambient_light_intensity: light_block.ambient_intensity,
             skybox: &skybox.texture,
+            surface_pass: false,
+            ibl_enabled: ibl.enabled,
+            ibl_intensity: ibl.intensity,
+            ibl_irradiance: ibl.irradiance.sampled().minify_filter(MinifySamplerFilter::Linear).magnify_filter(MagnifySamplerFilter::Linear),
+            ibl_specular: ibl.specular.sampled().minify_filter(MinifySamplerFilter::LinearMipmapLinear).magnify_filter(MagnifySamplerFilter::Linear),
+            ibl_specular_max_lod: (ibl.specular_levels - 1) as f32,
+            ibl_brdf_lut: ibl.brdf_lut.sampled().wrap_function(SamplerWrapFunction::Clamp).minify_filter(MinifySamplerFilter::Linear).magnify_filter(MagnifySamplerFilter::Linear),
             BoneTransforms: bone_transforms,
             has_skeleton: has_skeleton,
+            morph_targets: morph_targets.texture.sampled().minify_filter(MinifySamplerFilter::Nearest).magnify_filter(MagnifySamplerFilter::Nearest),
+            morph_target_count: morph_targets.target_count,
+            morph_vertex_count: morph_targets.vertex_count,
+            morph_weights_0: [morph_weights[0], morph_weights[1], morph_weights[2], morph_weights[3]],
+            morph_weights_1: [morph_weights[4], morph_weights[5], morph_weights[6], morph_weights[7]],
             shadow_map_0: shadow_maps.directional_maps[0].as_ref().unwrap_or(&shadow_maps.dummy).sampled(),
             shadow_map_1: shadow_maps.directional_maps[1].as_ref().unwrap_or(&shadow_maps.dummy).sampled(),
             shadow_map_2: shadow_maps.directional_maps[2].as_ref().unwrap_or(&shadow_maps.dummy).sampled(),
@@ -531,7 +559,30 @@ impl Material {
             shadow_light_space_2: shadow_maps.light_space_matrices[2],
             shadow_light_space_3: shadow_maps.light_space_matrices[3],
             shadow_far_planes: shadow_maps.point_far_planes,
-            light_cast_shadow: cast_shadow_vec
+            light_cast_shadow: cast_shadow_vec,
+            light_range: light_range,
+            light_shadow_filter: shadow_filter,
+            light_shadow_filter_size: shadow_filter_size,
+            light_shadow_depth_bias: shadow_depth_bias,
+            light_shadow_normal_bias: shadow_normal_bias,
+            shadow_cascade_slot: shadow_maps.cascade_slot.map(|slot| slot as i32).unwrap_or(-1),
+            shadow_cascade_count: shadow_maps.cascade_count as i32,
+            shadow_cascade_splits: shadow_maps.cascade_splits,
+            shadow_cascade_blend: CASCADE_BLEND,
+            shadow_cascade_0: shadow_maps.cascade_matrices[0],
+            shadow_cascade_1: shadow_maps.cascade_matrices[1],
+            shadow_cascade_2: shadow_maps.cascade_matrices[2],
+            shadow_cascade_3: shadow_maps.cascade_matrices[3],
+            light_cone_inner: light_cone_inner,
+            light_cone_outer: light_cone_outer,
+            clustered_lights: lighting.light_texture.sampled().minify_filter(MinifySamplerFilter::Nearest).magnify_filter(MagnifySamplerFilter::Nearest),
+            light_clusters: lighting.cluster_texture.sampled().minify_filter(MinifySamplerFilter::Nearest).magnify_filter(MagnifySamplerFilter::Nearest),
+            light_indices: lighting.index_texture.sampled().minify_filter(MinifySamplerFilter::Nearest).magnify_filter(MagnifySamplerFilter::Nearest),
+            clustered_light_count: lighting.clustered_light_count,
+            cluster_grid: [lighting.grid.x as i32, lighting.grid.y as i32, lighting.grid.z as i32],
+            cluster_near: lighting.near,
+            cluster_far: lighting.far,
+            cluster_screen_size: lighting.screen_size
         }
     }
     fn tex_raw_from_array(color: [f32; 4]) -> RawImage2d<'static, u8> {
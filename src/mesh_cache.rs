@@ -0,0 +1,124 @@
+use std::collections::{HashMap, HashSet};
+use glium::backend::Facade;
+use uuid::Uuid;
+use crate::object::{Object, ObjectInstance};
+
+const IDENTITY: [[f32; 4]; 4] = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+
+/// Keeps the GPU buffers of all object instances alive between frames, keyed by instance id.
+/// Geometry is only uploaded again when `Object::get_geometry_revision` changes, and the instance
+/// attribute buffers are reused and only grow when more instances are needed.
+pub struct MeshCache {
+    instances: HashMap<Uuid, ObjectInstance>,
+    skybox: Option<(Uuid, ObjectInstance)>,
+    uploads: usize,
+}
+
+impl MeshCache {
+    pub fn new() -> Self {
+        Self {
+            instances: HashMap::new(),
+            skybox: None,
+            uploads: 0,
+        }
+    }
+
+    /// Updates the instances for `objects`. Instances of objects that are gone are dropped.
+    pub fn update(&mut self, display: &impl Facade, objects: &[Object], model_matrices: &HashMap<Uuid, [[f32; 4]; 4]>) {
+        // the object with the highest revision of each instance provides the geometry. This stays the same
+        // when objects change their order, so the cache doesn't rebuild because of transparency sorting
+        let mut sources: HashMap<Uuid, &Object> = HashMap::new();
+        for object in objects.iter() {
+            let source = sources.entry(object.get_instance_id()).or_insert(object);
+            if object.get_geometry_revision() > source.get_geometry_revision() {
+                *source = object;
+            }
+        }
+
+        let used: HashSet<Uuid> = sources.keys().copied().collect();
+        self.instances.retain(|id, _| used.contains(id));
+
+        for (instance_id, object) in sources.iter() {
+            let instance = self.instances.entry(*instance_id).or_insert_with(|| ObjectInstance::new(display));
+            if instance.geometry_revision != object.get_geometry_revision() {
+                instance.upload_geometry(display, object);
+                self.uploads += 1;
+            }
+            instance.clear_instances();
+        }
+
+        for object in objects.iter() {
+            let model_matrix = model_matrices.get(&object.get_unique_id()).unwrap_or(&IDENTITY);
+            self.instances.get_mut(&object.get_instance_id())
+                .expect("No instance of this uuid found. which is weird, because we just added it above")
+                .add_instance(*model_matrix);
+        }
+
+        for instance in self.instances.values_mut() {
+            instance.update_instance_attributes(display);
+        }
+    }
+
+    /// Updates the skybox instance, which is kept apart from the scene objects.
+    pub fn update_skybox(&mut self, display: &impl Facade, skybox: Option<&Object>, model_matrix: &Option<[[f32; 4]; 4]>) {
+        let skybox = match skybox {
+            Some(skybox) => skybox,
+            None => {
+                self.skybox = None;
+                return;
+            }
+        };
+        let reuse = matches!(&self.skybox, Some((id, instance)) if *id == skybox.get_unique_id() && instance.geometry_revision == skybox.get_geometry_revision());
+        if !reuse {
+            let mut instance = ObjectInstance::new(display);
+            instance.upload_geometry(display, skybox);
+            self.skybox = Some((skybox.get_unique_id(), instance));
+            self.uploads += 1;
+        }
+        let (_, instance) = self.skybox.as_mut().expect("Skybox instance was just created");
+        instance.clear_instances();
+        instance.add_instance(model_matrix.unwrap_or(IDENTITY));
+        instance.update_instance_attributes(display);
+    }
+
+    pub fn get(&self, instance_id: &Uuid) -> Option<&ObjectInstance> {
+        self.instances.get(instance_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Uuid, &ObjectInstance)> {
+        self.instances.iter()
+    }
+
+    pub fn get_skybox(&self) -> Option<(&Uuid, &ObjectInstance)> {
+        self.skybox.as_ref().map(|(id, instance)| (id, instance))
+    }
+
+    /// Forces the geometry of `instance_id` to be uploaded again next frame.
+    pub fn invalidate(&mut self, instance_id: &Uuid) {
+        self.instances.remove(instance_id);
+    }
+
+    pub fn clear(&mut self) {
+        self.instances.clear();
+        self.skybox = None;
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    /// Number of geometry uploads since the cache was created.
+    pub fn get_upload_count(&self) -> usize {
+        self.uploads
+    }
+}
+
+impl Default for MeshCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
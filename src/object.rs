@@ -1,9 +1,9 @@
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::vec::Vec;
-use glium::Display;
-use glium::glutin::surface::WindowSurface;
-use crate::geometry::{BoneTransforms, BoundingBox, Vertex};
+use glium::backend::Facade;
+use crate::geometry::{BoneTransforms, BoundingBox, MorphTarget, MorphTargetBuffer, Vertex, MAX_MORPH_TARGETS};
 use nalgebra::{Vector3, Matrix4, Translation3, UnitQuaternion, Point3};
 use crate::{animation, debug_geo, geometry, smart_format};
 use uuid::Uuid;
@@ -15,14 +15,27 @@ use glium::uniforms::UniformBuffer;
 use nalgebra_glm::normalize;
 use obj::{load_obj, Obj};
 use serde::{Deserialize, Serialize};
-use crate::animation::{AnimationState, MAX_BONES};
-use crate::logging::{EnigmaError, EnigmaMessage};
+use crate::animation::{AnimationState, BonePose, FiredAnimationEvent, MAX_BONES};
+use crate::animation_state_machine::{AnimationStateMachine, AnimationStateMachineSerializer};
+use crate::logging::{EnigmaError, EnigmaMessage, EnigmaWarning};
+
+static GEOMETRY_REVISION: AtomicU64 = AtomicU64::new(1);
+
+fn next_geometry_revision() -> u64 {
+    GEOMETRY_REVISION.fetch_add(1, Ordering::Relaxed)
+}
 
 pub struct ObjectInstance {
     pub vertex_buffers: Vec<(glium::vertex::VertexBufferAny, usize)>,
     pub index_buffers: Vec<glium::IndexBuffer<u32>>,
     pub instance_matrices: Vec<[[f32; 4]; 4]>,
+    /// Grows on demand and is reused across frames, only the first `instance_matrices.len()` entries are valid.
     pub instance_attributes: glium::VertexBuffer<geometry::InstanceAttribute>,
+    pub morph_buffers: Vec<MorphTargetBuffer>,
+    /// `Object::get_geometry_revision` of the object the buffers were uploaded from.
+    pub geometry_revision: u64,
+    /// Bounds used for frustum culling, `None` if the instance must never be culled.
+    pub local_bounds: Option<BoundingBox>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -36,6 +49,18 @@ pub struct ObjectSerializer {
     cloned_id: String,
     animations: HashMap<String, animation::AnimationSerializer>,
     skeleton: Option<animation::SkeletonSerializer>,
+    #[serde(default)]
+    parent: Option<String>,
+    #[serde(default)]
+    parent_bone: Option<String>,
+    #[serde(default)]
+    state_machine: Option<AnimationStateMachineSerializer>,
+    #[serde(default)]
+    morph_target_names: Vec<String>,
+    #[serde(default)]
+    morph_weights: Vec<f32>,
+    #[serde(default)]
+    morph_target_nodes: Vec<usize>,
 }
 
 pub struct Object {
@@ -50,7 +75,17 @@ pub struct Object {
     animations: HashMap<String, animation::Animation>,
     skeleton: Option<animation::Skeleton>,
     current_animation: Option<AnimationState>,
+    blended_animations: Vec<AnimationState>,
+    additive_animations: Vec<AnimationState>,
+    fired_animation_events: Vec<FiredAnimationEvent>,
+    morph_target_names: Vec<String>,
+    morph_weights: Vec<f32>,
+    // glTF nodes of the mesh that owns the morph targets, only their weight channels drive `morph_weights`
+    morph_target_nodes: Vec<usize>,
     components: HashMap<TypeId, Box<dyn Any>>,
+    parent: Option<Uuid>,
+    parent_bone: Option<String>,
+    geometry_revision: u64,
 }
 
 impl Clone for Object {
@@ -69,6 +104,7 @@ impl Clone for Object {
             new_shape.vertices = shape.vertices.clone();
             new_shape.indices = shape.indices.clone();
             new_shape.material_index = shape.material_index;
+            new_shape.morph_targets = shape.morph_targets.clone();
             new_object.add_shape(new_shape);
         }
 
@@ -80,7 +116,15 @@ impl Clone for Object {
         new_object.cloned_id = self.unique_id;
         new_object.animations = self.animations.clone();
         new_object.skeleton = self.skeleton.clone();
+        new_object.morph_target_names = self.morph_target_names.clone();
+        new_object.morph_weights = self.morph_weights.clone();
+        new_object.morph_target_nodes = self.morph_target_nodes.clone();
         new_object.components = HashMap::new();
+        new_object.parent = self.parent;
+        new_object.parent_bone = self.parent_bone.clone();
+        new_object.transform.parent_matrix = self.transform.parent_matrix;
+        // same geometry, so clones can share the uploaded buffers
+        new_object.geometry_revision = self.geometry_revision;
         new_object
     }
 }
@@ -90,6 +134,8 @@ pub struct Shape {
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
     pub material_index: usize,
+    #[serde(default)]
+    pub morph_targets: Vec<MorphTarget>,
 }
 
 impl Clone for Shape {
@@ -98,6 +144,7 @@ impl Clone for Shape {
             vertices: self.vertices.clone(),
             indices: self.indices.clone(),
             material_index: self.material_index,
+            morph_targets: self.morph_targets.clone(),
         }
     }
 }
@@ -108,6 +155,7 @@ impl Shape {
             vertices: Vec::new(),
             indices: Vec::new(),
             material_index: 0,
+            morph_targets: Vec::new(),
         }
     }
 
@@ -116,6 +164,7 @@ impl Shape {
             vertices,
             indices,
             material_index: 0,
+            morph_targets: Vec::new(),
         }
     }
 
@@ -129,11 +178,11 @@ impl Shape {
         shape
     }
 
-    pub fn get_vertex_buffer(&self, display: Display<WindowSurface>) -> glium::VertexBuffer<Vertex> {
+    pub fn get_vertex_buffer(&self, display: impl Facade) -> glium::VertexBuffer<Vertex> {
         glium::VertexBuffer::new(&display, &self.vertices).unwrap()
     }
 
-    pub fn get_index_buffer(&self, display: Display<WindowSurface>) -> glium::IndexBuffer<u32> {
+    pub fn get_index_buffer(&self, display: impl Facade) -> glium::IndexBuffer<u32> {
         glium::IndexBuffer::new(&display, glium::index::PrimitiveType::TrianglesList, &self.indices).unwrap()
     }
 
@@ -142,20 +191,74 @@ impl Shape {
     }
 }
 
+fn instance_buffer_capacity(required: usize) -> usize {
+    required.next_power_of_two().max(16)
+}
+
 impl ObjectInstance {
-    pub fn new(display: &Display<WindowSurface>) -> Self {
+    pub fn new(display: &impl Facade) -> Self {
         Self {
             vertex_buffers: Vec::new(),
             index_buffers: Vec::new(),
             instance_matrices: Vec::new(),
             instance_attributes: glium::vertex::VertexBuffer::dynamic(display, &Vec::new()).expect("Building ObjectInstance, Per Instance Attribute could not be created"),
+            morph_buffers: Vec::new(),
+            geometry_revision: 0,
+            local_bounds: None,
+        }
+    }
+
+    /// Uploads vertex, index and morph target buffers of `object`.
+    pub fn upload_geometry(&mut self, display: &impl Facade, object: &Object) {
+        self.set_vertex_buffers(object.get_vertex_buffers(display));
+        self.set_index_buffers(object.get_index_buffers(display));
+        self.set_morph_buffers(object.get_morph_target_buffers(display));
+        self.geometry_revision = object.get_geometry_revision();
+        // skinned and morphed geometry can leave its bind pose bounds, so it is never culled
+        self.local_bounds = match object.get_skeleton().is_some() || !object.get_morph_target_names().is_empty() {
+            true => None,
+            false => object.get_local_bounding_box(),
+        };
+    }
+
+    pub fn clear_instances(&mut self) {
+        self.instance_matrices.clear();
+    }
+
+    /// Writes `instance_matrices` into the instance attribute buffer. The buffer is only recreated
+    /// when it is too small, growing to the next power of two.
+    pub fn update_instance_attributes(&mut self, display: &impl Facade) {
+        let data = self.instance_matrices
+            .iter()
+            .map(|i| geometry::InstanceAttribute {
+                model_matrix: *i,
+            })
+            .collect::<Vec<_>>();
+        if data.is_empty() {
+            return;
+        }
+        if self.instance_attributes.len() < data.len() {
+            self.instance_attributes = glium::vertex::VertexBuffer::empty_dynamic(display, instance_buffer_capacity(data.len()))
+                .expect("Growing ObjectInstance, Per Instance Attribute could not be created");
         }
+        self.instance_attributes.slice_mut(0..data.len()).expect("Instance attribute buffer is too small").write(&data);
+    }
+
+    /// The valid part of the instance attribute buffer. Bind it to a local and draw with its `per_instance()`,
+    /// the per instance source borrows the slice.
+    pub fn instance_slice(&self) -> glium::vertex::VertexBufferSlice<'_, geometry::InstanceAttribute> {
+        self.instance_attributes.slice(0..self.instance_matrices.len())
+            .expect("Instance attribute buffer is smaller than the instance count")
     }
 
     pub fn set_vertex_buffers(&mut self, buffers: Vec<(glium::vertex::VertexBufferAny, usize)>) {
         self.vertex_buffers = buffers;
     }
 
+    pub fn set_morph_buffers(&mut self, buffers: Vec<MorphTargetBuffer>) {
+        self.morph_buffers = buffers;
+    }
+
     pub fn set_index_buffers(&mut self, buffers: Vec<glium::IndexBuffer<u32>>) {
         self.index_buffers = buffers;
     }
@@ -180,7 +283,16 @@ impl Object {
             animations: HashMap::new(),
             skeleton: None,
             current_animation: None,
+            blended_animations: Vec::new(),
+            additive_animations: Vec::new(),
+            fired_animation_events: Vec::new(),
+            morph_target_names: Vec::new(),
+            morph_weights: Vec::new(),
+            morph_target_nodes: Vec::new(),
             components: HashMap::new(),
+            parent: None,
+            parent_bone: None,
+            geometry_revision: next_geometry_revision(),
         };
         object.calculate_bounding_box();
         object
@@ -210,6 +322,12 @@ impl Object {
                 Some(skeleton) => Some(skeleton.to_serializer()),
                 None => None
             },
+            parent: self.parent.map(|p| p.to_string()),
+            parent_bone: self.parent_bone.clone(),
+            state_machine: self.get_component::<AnimationStateMachine>().map(|m| m.to_serializer()),
+            morph_target_names: self.morph_target_names.clone(),
+            morph_weights: self.morph_weights.clone(),
+            morph_target_nodes: self.morph_target_nodes.clone(),
         }
     }
 
@@ -235,6 +353,14 @@ impl Object {
             Some(s) => Some(animation::Skeleton::from_serializer(s)),
             None => None
         };
+        object.parent = serializer.parent.map(|p| Uuid::parse_str(p.as_str()).expect("failed to parse parent uuid"));
+        object.parent_bone = serializer.parent_bone;
+        object.morph_target_names = serializer.morph_target_names;
+        object.morph_weights = serializer.morph_weights;
+        object.morph_target_nodes = serializer.morph_target_nodes;
+        if let Some(state_machine) = serializer.state_machine {
+            object.set_component(AnimationStateMachine::from_serializer(state_machine));
+        }
         object
     }
 
@@ -284,6 +410,33 @@ impl Object {
         self.cloned_id = self.unique_id;
     }
 
+    /// The object this object is attached to. Its transform is then relative to the parent.
+    /// Use `AppState::set_parent` to attach objects that are already part of the scene,
+    /// it checks for cycles and keeps the world transform.
+    pub fn get_parent(&self) -> Option<Uuid> {
+        self.parent
+    }
+
+    /// Also detaches the object from a parent bone, see `set_parent_bone`.
+    pub fn set_parent(&mut self, parent: Option<Uuid>) {
+        self.parent = parent;
+        self.parent_bone = None;
+        if parent.is_none() {
+            self.transform.parent_matrix = Matrix4::identity();
+        }
+    }
+
+    /// The bone of the parent object this object follows, if any.
+    pub fn get_parent_bone(&self) -> Option<&str> {
+        self.parent_bone.as_deref()
+    }
+
+    /// Makes the object follow the named bone of its parent. The transform of the object is then
+    /// relative to the bone. Use `AppState::attach_to_bone` for objects already in the scene.
+    pub fn set_parent_bone(&mut self, bone_name: Option<String>) {
+        self.parent_bone = bone_name;
+    }
+
     fn calculate_bounding_box(&mut self) -> BoundingBox {
         let mut min_x = f32::INFINITY;
         let mut min_y = f32::INFINITY;
@@ -312,7 +465,7 @@ impl Object {
             (min_point.z + max_point.z) / 2.0,
         );
         self.transform.update();
-        let transformed_center = self.transform.matrix.transform_point(&center);
+        let transformed_center = self.transform.get_world_matrix_object().transform_point(&center);
         let transformed_width = (max_x - min_x) * self.transform.get_scale().x;
         let transformed_height = (max_y - min_y) * self.transform.get_scale().y;
         let transformed_depth = (max_z - min_z) * self.transform.get_scale().z;
@@ -413,126 +566,221 @@ impl Object {
     }
 
     fn update_animation_internal(&mut self, delta_time: f32) {
-        if let Some(anim_state) = &mut self.current_animation {
-            if let Some(animation) = self.animations.get(&anim_state.name) {
-                anim_state.time += delta_time * anim_state.speed;
-                if anim_state.time > animation.duration {
-                    if anim_state.looping {
-                        anim_state.time %= animation.duration;
-                    } else {
-                        anim_state.time = animation.duration;
-                    }
+        let object = self.unique_id;
+        let animations = &self.animations;
+        let fired_events = &mut self.fired_animation_events;
+        let states = self.current_animation.iter_mut()
+            .chain(self.blended_animations.iter_mut())
+            .chain(self.additive_animations.iter_mut());
+        for anim_state in states {
+            anim_state.update_fade(delta_time);
+            if let Some(animation) = animations.get(&anim_state.name) {
+                let (time, events) = animation.advance(anim_state.time, delta_time * anim_state.speed, anim_state.looping);
+                anim_state.time = time;
+                // clips that are fully faded out don't fire events
+                if anim_state.weight > 0.0 {
+                    fired_events.extend(events.into_iter().map(|e| FiredAnimationEvent {
+                        object,
+                        animation: anim_state.name.clone(),
+                        name: e.name.clone(),
+                    }));
                 }
             }
         }
+        self.blended_animations.retain(|s| !s.is_faded_out());
+    }
+
+    /// Returns and clears the animation events fired since the last call. `AppState::step` does
+    /// this for all objects and runs the functions injected with `inject_animation_event_function`.
+    pub fn take_fired_animation_events(&mut self) -> Vec<FiredAnimationEvent> {
+        std::mem::take(&mut self.fired_animation_events)
     }
 
     pub fn has_skeletal_animation(&self) -> bool {
         self.skeleton.is_some() && !self.animations.is_empty()
     }
 
-    pub fn get_bone_transform_buffer(&self, display: &Display<WindowSurface>) -> UniformBuffer<BoneTransforms> {
+    pub fn get_bone_transform_buffer(&self, display: &impl Facade) -> UniformBuffer<BoneTransforms> {
         let identity = [[1.0f32, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
         let mut bone_transform_data = BoneTransforms {
             bone_transforms: [identity; MAX_BONES],
         };
 
-        if let (Some(skeleton), Some(anim_state)) = (&self.skeleton, &self.current_animation) {
+        if let Some(skeleton) = &self.skeleton {
+            if self.is_playing_animation() {
+                let global_transforms = self.get_bone_global_transforms();
+                for (i, bone) in skeleton.bones.iter().enumerate() {
+                    let final_transform: Matrix4<f32> = global_transforms[i] * bone.inverse_bind_pose;
+                    bone_transform_data.bone_transforms[i] = final_transform.into();
+                }
+            }
+        }
+
+        UniformBuffer::new(display, bone_transform_data).expect("Failed to create BoneTransform Buffer")
+    }
+
+    fn is_playing_animation(&self) -> bool {
+        self.current_animation.iter()
+            .chain(self.blended_animations.iter())
+            .chain(self.additive_animations.iter())
+            .any(|anim_state| self.animations.contains_key(anim_state.name.as_str()))
+    }
+
+    /// Blends the local pose of a bone from all playing animation states: the current and
+    /// blended states are averaged by weight, the additive layers are applied on top.
+    fn evaluate_bone_pose(&self, node_index: usize) -> BonePose {
+        let mut poses = Vec::new();
+        for anim_state in self.current_animation.iter().chain(self.blended_animations.iter()) {
+            if anim_state.weight <= 0.0 { continue; }
             if let Some(animation) = self.animations.get(anim_state.name.as_str()) {
-                let mut global_transforms = vec![Matrix4::identity(); skeleton.bones.len()];
+                poses.push((self.sample_bone(animation, node_index, anim_state.time), anim_state.weight));
+            }
+        }
+        let mut pose = BonePose::blend(&poses);
+        for anim_state in self.additive_animations.iter() {
+            if anim_state.weight <= 0.0 { continue; }
+            if let Some(animation) = self.animations.get(anim_state.name.as_str()) {
+                // additive clips are relative to their first frame
+                let reference = self.sample_bone(animation, node_index, 0.0);
+                let additive = self.sample_bone(animation, node_index, anim_state.time);
+                pose = pose.add(&additive, &reference, anim_state.weight);
+            }
+        }
+        pose
+    }
 
+    /// Transforms of all bones relative to the object in the current pose, indexed by bone id.
+    /// Without a playing animation the bind pose is returned.
+    pub fn get_bone_global_transforms(&self) -> Vec<Matrix4<f32>> {
+        let skeleton = match &self.skeleton {
+            Some(skeleton) => skeleton,
+            None => return Vec::new(),
+        };
+        match self.is_playing_animation() {
+            true => {
+                let mut global_transforms = vec![Matrix4::identity(); skeleton.bones.len()];
                 for (i, bone) in skeleton.bones.iter().enumerate() {
-                    let local_transform = self.interpolate_bone(animation, bone.node_index, anim_state.time);
+                    let local_transform = self.evaluate_bone_pose(bone.node_index).to_matrix();
                     let parent_transform: Matrix4<f32> = bone.parent_id
                         .map(|id| global_transforms[id])
                         .unwrap_or(skeleton.root_transform);
-
                     global_transforms[i] = parent_transform * local_transform;
-                    let final_transform: Matrix4<f32> = global_transforms[i] * bone.inverse_bind_pose;
-                    bone_transform_data.bone_transforms[i] = final_transform.into();
                 }
+                global_transforms
             }
+            false => skeleton.bones.iter().map(|bone| bone.get_bind_pose()).collect(),
         }
+    }
 
-        UniformBuffer::new(display, bone_transform_data).expect("Failed to create BoneTransform Buffer")
+    /// Transform of the named bone relative to the object in the current pose.
+    pub fn get_bone_model_matrix(&self, bone_name: &str) -> Option<Matrix4<f32>> {
+        let bone_id = self.skeleton.as_ref()?.get_bone_id(bone_name)?;
+        self.get_bone_global_transforms().get(bone_id).copied()
+    }
+
+    /// World transform of the named bone in the current pose. Useful to place objects into the
+    /// hand of a character, see `AppState::attach_to_bone` to let them follow the bone.
+    pub fn get_bone_world_matrix(&self, bone_name: &str) -> Option<[[f32; 4]; 4]> {
+        self.get_bone_world_matrix_object(bone_name).map(|m| m.into())
+    }
+
+    pub fn get_bone_world_matrix_object(&self, bone_name: &str) -> Option<Matrix4<f32>> {
+        self.get_bone_model_matrix(bone_name).map(|bone| self.transform.get_world_matrix_object() * bone)
     }
 
-    fn interpolate_bone(&self, animation: &animation::Animation, node_index: usize, time: f32) -> Matrix4<f32> {
-        let mut translation = Matrix4::identity();
-        let mut rotation = Matrix4::identity();
-        let mut scale = Matrix4::identity();
+    fn sample_bone(&self, animation: &animation::Animation, node_index: usize, time: f32) -> BonePose {
+        let mut pose = BonePose::identity();
 
         for channel in animation.channels.iter().filter(|c| c.bone_id == node_index) {
-            if channel.keyframes.is_empty() { continue; }
-            let m = Self::interpolate_channel(channel, time);
-            match &channel.keyframes[0].transform {
-                animation::AnimationTransform::Translation(_) => translation = m,
-                animation::AnimationTransform::Rotation(_) => rotation = m,
-                animation::AnimationTransform::Scale(_) => scale = m,
+            match channel.sample(time) {
+                Some(animation::AnimationTransform::Translation(t)) => pose.translation = Vector3::from(t),
+                Some(animation::AnimationTransform::Rotation(r)) => pose.rotation = UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(r[3], r[0], r[1], r[2])),
+                Some(animation::AnimationTransform::Scale(s)) => pose.scale = Vector3::from(s),
+                Some(animation::AnimationTransform::Weights(_)) | None => {}
             }
         }
 
-        translation * rotation * scale
+        pose
     }
 
-    fn interpolate_channel(channel: &animation::AnimationChannel, time: f32) -> Matrix4<f32> {
-        let mut prev_keyframe = &channel.keyframes[0];
-        let mut next_keyframe = prev_keyframe;
-
-        for keyframe in &channel.keyframes {
-            if keyframe.time > time {
-                next_keyframe = keyframe;
-                break;
-            }
-            prev_keyframe = keyframe;
+    pub fn play_animation(&mut self, name: &str, looping: bool) {
+        if let Some(_) = self.animations.get(name) {
+            self.current_animation = Some(AnimationState::new(name, looping, 1.0));
+            self.blended_animations.clear();
         }
+    }
 
-        let duration = next_keyframe.time - prev_keyframe.time;
-        let t = if duration > 0.0 {
-            ((time - prev_keyframe.time) / duration).clamp(0.0, 1.0)
-        } else {
-            0.0
-        };
+    /// Crossfades from the currently playing animations to `name` over `fade_seconds`.
+    pub fn play_animation_blended(&mut self, name: &str, fade_seconds: f32, looping: bool) {
+        if !self.animations.contains_key(name) {
+            return;
+        }
+        if fade_seconds <= 0.0 {
+            self.play_animation(name, looping);
+            return;
+        }
+        let fading_out = self.current_animation.take().into_iter().chain(self.blended_animations.drain(..)).collect::<Vec<_>>();
+        for mut anim_state in fading_out {
+            anim_state.fade_speed = -1.0 / fade_seconds;
+            self.blended_animations.push(anim_state);
+        }
+        let mut anim_state = AnimationState::new(name, looping, 0.0);
+        anim_state.fade_speed = 1.0 / fade_seconds;
+        self.current_animation = Some(anim_state);
+    }
 
-        match (&prev_keyframe.transform, &next_keyframe.transform) {
-            (animation::AnimationTransform::Translation(prev), animation::AnimationTransform::Translation(next)) => {
-                let interpolated = Vector3::new(
-                    prev[0] + (next[0] - prev[0]) * t,
-                    prev[1] + (next[1] - prev[1]) * t,
-                    prev[2] + (next[2] - prev[2]) * t,
-                );
-                Matrix4::new_translation(&interpolated)
-            }
-            (animation::AnimationTransform::Rotation(prev), animation::AnimationTransform::Rotation(next)) => {
-                let prev_quat = UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(prev[3], prev[0], prev[1], prev[2]));
-                let next_quat = UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(next[3], next[0], next[1], next[2]));
-                prev_quat.slerp(&next_quat, t).to_homogeneous()
-            }
-            (animation::AnimationTransform::Scale(prev), animation::AnimationTransform::Scale(next)) => {
-                let interpolated = Vector3::new(
-                    prev[0] + (next[0] - prev[0]) * t,
-                    prev[1] + (next[1] - prev[1]) * t,
-                    prev[2] + (next[2] - prev[2]) * t,
-                );
-                Matrix4::new_nonuniform_scaling(&interpolated)
+    /// Plays `name` next to the current animation, weighted by `weight`. Calling it again for the
+    /// same animation only updates the weight.
+    pub fn blend_animation(&mut self, name: &str, weight: f32, looping: bool) {
+        if !self.animations.contains_key(name) {
+            return;
+        }
+        match self.blended_animations.iter_mut().find(|s| s.name == name) {
+            Some(anim_state) => {
+                anim_state.weight = weight.clamp(0.0, 1.0);
+                anim_state.fade_speed = 0.0;
             }
-            _ => Matrix4::identity(),
+            None => self.blended_animations.push(AnimationState::new(name, looping, weight)),
         }
     }
 
-    pub fn play_animation(&mut self, name: &str, looping: bool) {
-        if let Some(_) = self.animations.get(name) {
-            self.current_animation = Some(AnimationState {
-                name: name.to_string(),
-                time: 0.0,
-                speed: 1.0,
-                looping,
-            });
+    /// Plays `name` as an additive layer on top of the blended animations. The difference of
+    /// the clip to its first frame is added, e.g. for breathing or recoil on top of a walk.
+    pub fn add_additive_animation(&mut self, name: &str, weight: f32, looping: bool) {
+        if !self.animations.contains_key(name) {
+            return;
         }
+        self.additive_animations.retain(|s| s.name != name);
+        self.additive_animations.push(AnimationState::new(name, looping, weight));
+    }
+
+    pub fn remove_additive_animation(&mut self, name: &str) {
+        self.additive_animations.retain(|s| s.name != name);
+    }
+
+    /// Sets the weight of every playing state of the animation `name`.
+    pub fn set_animation_weight(&mut self, name: &str, weight: f32) {
+        let states = self.current_animation.iter_mut()
+            .chain(self.blended_animations.iter_mut())
+            .chain(self.additive_animations.iter_mut());
+        for anim_state in states.filter(|s| s.name == name) {
+            anim_state.weight = weight.clamp(0.0, 1.0);
+            anim_state.fade_speed = 0.0;
+        }
+    }
+
+    pub fn get_blended_animations(&self) -> &Vec<AnimationState> {
+        &self.blended_animations
+    }
+
+    pub fn get_additive_animations(&self) -> &Vec<AnimationState> {
+        &self.additive_animations
     }
 
     pub fn stop_animation(&mut self) {
         self.current_animation = None;
+        self.blended_animations.clear();
+        self.additive_animations.clear();
     }
 
     pub fn get_current_animation(&self) -> &Option<AnimationState> {
@@ -541,43 +789,127 @@ impl Object {
 
     pub fn update(&mut self, delta_time: f32) {
         self.transform.update();
-        if self.skeleton.is_some() && self.current_animation.is_some() {
+        self.update_state_machine(delta_time);
+        if (self.skeleton.is_some() || !self.morph_target_names.is_empty()) && self.is_playing_animation() {
             self.update_animation_internal(delta_time);
+            if let Some(weights) = self.evaluate_morph_weights() {
+                for (i, weight) in weights.into_iter().enumerate() {
+                    self.set_morph_weight_index(i, weight);
+                }
+            }
+        }
+    }
+
+    fn update_state_machine(&mut self, delta_time: f32) {
+        let change = match self.components.get_mut(&TypeId::of::<AnimationStateMachine>()).and_then(|b| b.downcast_mut::<AnimationStateMachine>()) {
+            Some(state_machine) => state_machine.update(delta_time, &self.animations),
+            None => None,
+        };
+        if let Some(change) = change {
+            self.play_animation_blended(&change.animation, change.fade, change.looping);
+            if let Some(anim_state) = &mut self.current_animation {
+                anim_state.speed = change.speed;
+            }
+        }
+    }
+
+    pub fn get_morph_target_names(&self) -> &Vec<String> {
+        &self.morph_target_names
+    }
+
+    pub fn get_morph_weights(&self) -> &Vec<f32> {
+        &self.morph_weights
+    }
+
+    pub fn get_morph_weight(&self, name: &str) -> Option<f32> {
+        let index = self.morph_target_names.iter().position(|n| n == name)?;
+        Some(self.morph_weights.get(index).copied().unwrap_or(0.0))
+    }
+
+    /// Sets the weight of the morph target `name`. Animations with weight channels overwrite it
+    /// while they play.
+    pub fn set_morph_weight(&mut self, name: &str, weight: f32) {
+        match self.morph_target_names.iter().position(|n| n == name) {
+            Some(index) => self.set_morph_weight_index(index, weight),
+            None => EnigmaWarning::new(Some(smart_format!("Object {} has no morph target named {}", self.name, name).as_str()), true).log(),
+        }
+    }
+
+    pub fn set_morph_weight_index(&mut self, index: usize, weight: f32) {
+        if self.morph_weights.len() <= index {
+            self.morph_weights.resize(index + 1, 0.0);
         }
+        self.morph_weights[index] = weight;
+    }
+
+    pub fn get_morph_target_nodes(&self) -> &Vec<usize> {
+        &self.morph_target_nodes
     }
 
-    pub fn get_closest_lights(&self, lights: &Vec<crate::light::Light>) -> Vec<crate::light::Light> {
-        let mut closest_lights = Vec::new();
-
-        //collect the four closest lights to the object
-        for light in lights.iter() {
-            let light_pos = light.position;
-            let object_pos = self.transform.get_position();
-            let distance = (Vector3::from(light_pos) - object_pos).magnitude();
-            if closest_lights.len() < 4 {
-                closest_lights.push((light.clone(), distance));
-            } else {
-                let mut max_distance = 0.0;
-                let mut max_index = 0;
-                for (index, (_, distance)) in closest_lights.iter().enumerate() {
-                    if *distance > max_distance {
-                        max_distance = *distance;
-                        max_index = index;
+    /// Restricts the weight channels that animate the morph targets to the ones targeting these
+    /// glTF nodes. With no nodes, e.g. for objects built in code, every weight channel applies.
+    pub fn set_morph_target_nodes(&mut self, nodes: Vec<usize>) {
+        self.morph_target_nodes = nodes;
+    }
+
+    pub(crate) fn get_morph_weight_uniforms(&self) -> [f32; MAX_MORPH_TARGETS] {
+        let mut weights = [0.0; MAX_MORPH_TARGETS];
+        for (w, weight) in weights.iter_mut().zip(self.morph_weights.iter()) {
+            *w = *weight;
+        }
+        weights
+    }
+
+    /// Weighted blend of the morph weight channels of the playing animations that target the
+    /// morph target nodes, `None` if none of them animates morph weights.
+    fn evaluate_morph_weights(&self) -> Option<Vec<f32>> {
+        let mut result: Vec<f32> = Vec::new();
+        let mut total = 0.0;
+        for anim_state in self.current_animation.iter().chain(self.blended_animations.iter()) {
+            if anim_state.weight <= 0.0 { continue; }
+            let animation = match self.animations.get(anim_state.name.as_str()) {
+                Some(animation) => animation,
+                None => continue,
+            };
+            let morph_channels = animation.channels.iter()
+                .filter(|channel| self.morph_target_nodes.is_empty() || self.morph_target_nodes.contains(&channel.bone_id));
+            for channel in morph_channels {
+                if let Some(animation::AnimationTransform::Weights(weights)) = channel.sample(anim_state.time) {
+                    if result.len() < weights.len() {
+                        result.resize(weights.len(), 0.0);
                     }
-                }
-                if distance < max_distance {
-                    closest_lights[max_index] = (light.clone(), distance.clone());
+                    for (r, w) in result.iter_mut().zip(weights.iter()) {
+                        *r += w * anim_state.weight;
+                    }
+                    total += anim_state.weight;
+                    break;
                 }
             }
         }
-        closest_lights.iter().map(|(light, _)| light.clone()).collect()
+        if total <= 0.0 {
+            return None;
+        }
+        Some(result.into_iter().map(|w| w / total).collect())
     }
 
     pub fn add_shape(&mut self, shape: Shape) {
         self.shapes.push(shape);
+        self.geometry_revision = next_geometry_revision();
+    }
+
+    /// Changes whenever the shapes of this object might have changed. Used to decide if the
+    /// buffers on the GPU have to be uploaded again.
+    pub fn get_geometry_revision(&self) -> u64 {
+        self.geometry_revision
+    }
+
+    pub fn get_morph_target_buffers(&self, display: &impl Facade) -> Vec<MorphTargetBuffer> {
+        self.get_shapes().iter()
+            .map(|shape| MorphTargetBuffer::new(display, &shape.morph_targets, shape.vertices.len()))
+            .collect()
     }
 
-    pub fn get_vertex_buffers(&self, display: &Display<WindowSurface>) -> Vec<(glium::vertex::VertexBufferAny, usize)> {
+    pub fn get_vertex_buffers(&self, display: &impl Facade) -> Vec<(glium::vertex::VertexBufferAny, usize)> {
         let shapes = self.get_shapes();
         let mut buffer = Vec::new();
         for shape in shapes.iter() {
@@ -587,7 +919,7 @@ impl Object {
         buffer
     }
 
-    pub fn get_index_buffers(&self, display: &Display<WindowSurface>) -> Vec<glium::IndexBuffer<u32>> {
+    pub fn get_index_buffers(&self, display: &impl Facade) -> Vec<glium::IndexBuffer<u32>> {
         let shapes = self.get_shapes();
         let mut buffer = Vec::new();
         for shape in shapes.iter() {
@@ -600,6 +932,20 @@ impl Object {
         self.calculate_bounding_box()
     }
 
+    /// The bounds of all shapes in object space, `None` if the object has no vertices.
+    pub fn get_local_bounding_box(&self) -> Option<BoundingBox> {
+        let mut vertices = self.shapes.iter().flat_map(|shape| shape.vertices.iter());
+        let first = vertices.next()?.position;
+        let (min, max) = vertices.fold((first, first), |(mut min, mut max), vertex| {
+            for i in 0..3 {
+                min[i] = min[i].min(vertex.position[i]);
+                max[i] = max[i].max(vertex.position[i]);
+            }
+            (min, max)
+        });
+        Some(BoundingBox::new(min, max))
+    }
+
     pub fn get_materials(&self) -> &Vec<Uuid> {
         &self.materials
     }
@@ -617,6 +963,7 @@ impl Object {
     }
 
     pub fn get_shapes_mut(&mut self) -> &mut Vec<Shape> {
+        self.geometry_revision = next_geometry_revision();
         &mut self.shapes
     }
 
@@ -695,10 +1042,37 @@ impl Object {
         for mesh in gltf.meshes() {
             let mut vertices = Vec::new();
             let mut indices = Vec::new();
+            let mut morph_targets: Vec<MorphTarget> = Vec::new();
+            // glTF requires the same targets on every primitive of a mesh
+            let mut primitive_target_count = None;
             for primitive in mesh.primitives() {
                 let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
 
                 let positions: Vec<[f32; 3]> = reader.read_positions().unwrap().collect();
+
+                let target_count = primitive.morph_targets().len();
+                match primitive_target_count {
+                    Some(count) if count != target_count => EnigmaWarning::new(Some(smart_format!("Primitives of mesh {} have {} and {} morph targets, missing targets are left undeformed", mesh.name().unwrap_or("mesh"), count, target_count).as_str()), true).log(),
+                    _ => primitive_target_count = Some(target_count),
+                }
+
+                // morph targets, appended per primitive so they line up with the merged vertices
+                for (target_index, (target_positions, target_normals, _)) in reader.read_morph_targets().enumerate() {
+                    if morph_targets.len() <= target_index {
+                        morph_targets.push(MorphTarget {
+                            name: format!("morph_{}", target_index),
+                            position_deltas: vec![[0.0; 3]; vertices.len()],
+                            normal_deltas: vec![[0.0; 3]; vertices.len()],
+                        });
+                    }
+                    let target = &mut morph_targets[target_index];
+                    let mut target_positions: Vec<[f32; 3]> = target_positions.map(|p| p.collect()).unwrap_or_default();
+                    let mut target_normals: Vec<[f32; 3]> = target_normals.map(|n| n.collect()).unwrap_or_default();
+                    target_positions.resize(positions.len(), [0.0; 3]);
+                    target_normals.resize(positions.len(), [0.0; 3]);
+                    target.position_deltas.extend(target_positions);
+                    target.normal_deltas.extend(target_normals);
+                }
                 let normals: Vec<[f32; 3]> = reader.read_normals().unwrap().collect();
                 let raw_tex_coords: Vec<[f32; 2]> = reader.read_tex_coords(0)
                     .map(|tc| tc.into_f32().collect())
@@ -733,8 +1107,36 @@ impl Object {
                 }
 
                 indices.extend(prim_indices);
+                // primitives without some of the targets of the mesh
+                for target in morph_targets.iter_mut() {
+                    target.position_deltas.resize(vertices.len(), [0.0; 3]);
+                    target.normal_deltas.resize(vertices.len(), [0.0; 3]);
+                }
             }
-            let shape = Shape::from_vertices_indices(vertices, indices);
+            if !morph_targets.is_empty() {
+                let names = Object::load_morph_target_names_internal(&mesh);
+                for (i, target) in morph_targets.iter_mut().enumerate() {
+                    if let Some(name) = names.get(i) {
+                        target.name = name.clone();
+                    }
+                }
+                let target_names: Vec<String> = morph_targets.iter().map(|t| t.name.clone()).collect();
+                let mesh_nodes = gltf.nodes().filter(|node| node.mesh().is_some_and(|m| m.index() == mesh.index())).map(|node| node.index());
+                if object.morph_target_names.is_empty() {
+                    object.morph_target_names = target_names;
+                    object.morph_weights = mesh.weights().map(|w| w.to_vec()).unwrap_or_else(|| vec![0.0; morph_targets.len()]);
+                    object.morph_target_nodes = mesh_nodes.collect();
+                } else if object.morph_target_names == target_names {
+                    // same targets split over several meshes, they share the weights
+                    object.morph_target_nodes.extend(mesh_nodes);
+                } else {
+                    // all shapes of an object are driven by one set of weights
+                    EnigmaWarning::new(Some(smart_format!("Mesh {} has other morph targets than the first morphed mesh of {}, its morph targets are ignored", mesh.name().unwrap_or("mesh"), object.name).as_str()), true).log();
+                    morph_targets.clear();
+                }
+            }
+            let mut shape = Shape::from_vertices_indices(vertices, indices);
+            shape.morph_targets = morph_targets;
             object.add_shape(shape);
         }
 
@@ -827,59 +1229,108 @@ impl Object {
         for channel in anim.channels() {
             let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
             let bone_id = channel.target().node().index();
+            let interpolation = match channel.sampler().interpolation() {
+                gltf::animation::Interpolation::Linear => animation::AnimationInterpolation::Linear,
+                gltf::animation::Interpolation::Step => animation::AnimationInterpolation::Step,
+                gltf::animation::Interpolation::CubicSpline => animation::AnimationInterpolation::CubicSpline,
+            };
             let mut keyframes = Vec::new();
+            let mut tangents = Vec::new();
             if let (Some(times), Some(outputs)) = (reader.read_inputs(), reader.read_outputs()) {
                 let times: Vec<f32> = times.collect();
                 // Update max_time
                 if let Some(&channel_duration) = times.iter().max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal)) {
                     duration = duration.max(channel_duration);
                 }
-                match outputs {
-                    gltf::animation::util::ReadOutputs::Translations(translations) => {
-                        for (i, translation) in translations.enumerate() {
-                            let translation = Vector3::from(translation);
-                            keyframes.push(animation::AnimationKeyframe {
-                                time: times[i],
-                                transform: animation::AnimationTransform::Translation((translation * multiplier).into()),
-                            });
+                // tangents are scaled like the values, but rotation tangents must not be normalized
+                let values: Vec<animation::AnimationTransform> = match outputs {
+                    gltf::animation::util::ReadOutputs::Translations(translations) => translations
+                        .map(|t| animation::AnimationTransform::Translation((Vector3::from(t) * multiplier).into()))
+                        .collect(),
+                    gltf::animation::util::ReadOutputs::Rotations(rotations) => rotations.into_f32()
+                        .map(animation::AnimationTransform::Rotation)
+                        .collect(),
+                    gltf::animation::util::ReadOutputs::Scales(scales) => scales
+                        .map(|s| animation::AnimationTransform::Scale((Vector3::from(s) * multiplier).into()))
+                        .collect(),
+                    gltf::animation::util::ReadOutputs::MorphTargetWeights(weights) => {
+                        let weights: Vec<f32> = weights.into_f32().collect();
+                        // one value per morph target and output, cubic splines have three outputs per keyframe
+                        let outputs = match interpolation {
+                            animation::AnimationInterpolation::CubicSpline => times.len() * 3,
+                            _ => times.len(),
+                        };
+                        let target_count = weights.len().checked_div(outputs).unwrap_or(0);
+                        if target_count == 0 {
+                            Vec::new()
+                        } else {
+                            weights.chunks_exact(target_count).map(|w| animation::AnimationTransform::Weights(w.to_vec())).collect()
                         }
                     }
-                    gltf::animation::util::ReadOutputs::Rotations(rotations) => {
-                        for (i, rotation) in rotations.into_f32().enumerate() {
-                            let rotation = UnitQuaternion::from_quaternion(
-                                nalgebra::Quaternion::new(rotation[3], rotation[0], rotation[1], rotation[2])
-                            );
-                            keyframes.push(animation::AnimationKeyframe {
-                                time: times[i],
-                                transform: animation::AnimationTransform::Rotation([rotation[0], rotation[1], rotation[2], rotation[3]]),
-                            });
-                        }
+                };
+                let normalized = |transform: &animation::AnimationTransform| match transform {
+                    animation::AnimationTransform::Rotation(r) => {
+                        let rotation = UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(r[3], r[0], r[1], r[2]));
+                        animation::AnimationTransform::Rotation([rotation[0], rotation[1], rotation[2], rotation[3]])
                     }
-                    gltf::animation::util::ReadOutputs::Scales(scales) => {
-                        for (i, scale) in scales.enumerate() {
-                            let scale = Vector3::from(scale);
-                            keyframes.push(animation::AnimationKeyframe {
-                                time: times[i],
-                                transform: animation::AnimationTransform::Scale((scale * multiplier).into()),
-                            });
+                    other => other.clone(),
+                };
+                match interpolation {
+                    // cubic spline outputs are stored as in tangent, value, out tangent per keyframe
+                    animation::AnimationInterpolation::CubicSpline => {
+                        for (time, chunk) in times.iter().zip(values.chunks_exact(3)) {
+                            keyframes.push(animation::AnimationKeyframe { time: *time, transform: normalized(&chunk[1]) });
+                            tangents.push(animation::AnimationTangent { in_tangent: chunk[0].clone(), out_tangent: chunk[2].clone() });
                         }
                     }
-                    gltf::animation::util::ReadOutputs::MorphTargetWeights(_) => {
-                        // Handle morph target weights if needed
-                        // For now, we'll just ignore these
+                    _ => {
+                        for (time, value) in times.iter().zip(values.iter()) {
+                            keyframes.push(animation::AnimationKeyframe { time: *time, transform: normalized(value) });
+                        }
                     }
                 }
             }
 
             if !keyframes.is_empty() {
-                channels.push(animation::AnimationChannel { bone_id, keyframes });
+                channels.push(animation::AnimationChannel { bone_id, keyframes, interpolation, tangents });
             }
         }
 
-        animation::Animation {
-            name,
-            duration,
-            channels,
+        let mut animation = animation::Animation::new(name.as_str(), duration, channels);
+        animation.events = Object::load_animation_events_internal(anim);
+        animation
+    }
+
+    /// Blender and most other exporters store the morph target names in the mesh extras.
+    fn load_morph_target_names_internal(mesh: &gltf::Mesh) -> Vec<String> {
+        #[derive(Deserialize)]
+        struct MeshExtras {
+            #[serde(default, rename = "targetNames")]
+            target_names: Vec<String>,
+        }
+        mesh.extras().as_ref()
+            .and_then(|extras| serde_json::from_str::<MeshExtras>(extras.get()).ok())
+            .map(|extras| extras.target_names)
+            .unwrap_or_default()
+    }
+
+    /// Reads events from the extras of a glTF animation, e.g. custom properties exported from
+    /// Blender: `{"events": [{"name": "footstep", "time": 0.25}]}`.
+    fn load_animation_events_internal(anim: &gltf::Animation) -> Vec<animation::AnimationEvent> {
+        #[derive(Deserialize)]
+        struct AnimationExtras {
+            #[serde(default)]
+            events: Vec<animation::AnimationEvent>,
+        }
+        match anim.extras() {
+            Some(extras) => match serde_json::from_str::<AnimationExtras>(extras.get()) {
+                Ok(extras) => extras.events,
+                Err(e) => {
+                    EnigmaWarning::new(Some(smart_format!("Could not read animation events from the extras of {}: {}", anim.name().unwrap_or("animation"), e).as_str()), true).log();
+                    Vec::new()
+                }
+            },
+            None => Vec::new(),
         }
     }
 }
@@ -898,6 +1349,9 @@ pub struct Transform {
     // radian angles
     pub scale: Vector3<f32>,
     pub matrix: Matrix4<f32>,
+    /// World matrix of the parent object, identity for objects without a parent.
+    /// Kept up to date by `AppState::update_hierarchy`.
+    pub parent_matrix: Matrix4<f32>,
 }
 
 impl Transform {
@@ -907,6 +1361,7 @@ impl Transform {
             rotation: Vector3::new(0.0, 0.0, 0.0),
             scale: Vector3::new(1.0, 1.0, 1.0),
             matrix: Matrix4::identity(),
+            parent_matrix: Matrix4::identity(),
         }
     }
 
@@ -948,11 +1403,33 @@ impl Transform {
     }
 
     pub fn update(&mut self) {
+        self.matrix = self.compute_local_matrix();
+    }
+
+    fn compute_local_matrix(&self) -> Matrix4<f32> {
         let scale_matrix = Matrix4::new_nonuniform_scaling(&self.scale);
         let rotation_matrix = UnitQuaternion::from_euler_angles(self.rotation.x, self.rotation.y, self.rotation.z).to_homogeneous();
         let translation_matrix = Translation3::from(self.position).to_homogeneous();
         // Scale, then rotate, then translate
-        self.matrix = translation_matrix * rotation_matrix * scale_matrix;
+        translation_matrix * rotation_matrix * scale_matrix
+    }
+
+    /// Sets position, rotation and scale from an affine matrix without shear.
+    pub fn set_from_matrix(&mut self, matrix: Matrix4<f32>) {
+        let column = |i: usize| Vector3::new(matrix[(0, i)], matrix[(1, i)], matrix[(2, i)]);
+        let scale = Vector3::new(column(0).magnitude(), column(1).magnitude(), column(2).magnitude());
+        let safe = |s: f32| if s.abs() > f32::EPSILON { s } else { 1.0 };
+        let rotation = nalgebra::Matrix3::from_columns(&[
+            column(0) / safe(scale.x),
+            column(1) / safe(scale.y),
+            column(2) / safe(scale.z),
+        ]);
+        let rotation = UnitQuaternion::from_rotation_matrix(&nalgebra::Rotation3::from_matrix_unchecked(rotation));
+        let (roll, pitch, yaw) = rotation.euler_angles();
+        self.position = column(3);
+        self.rotation = Vector3::new(roll, pitch, yaw);
+        self.scale = scale;
+        self.update();
     }
 
 
@@ -1011,6 +1488,24 @@ impl Transform {
         self.matrix
     }
 
+    /// Same as `get_matrix`, the matrix relative to the parent.
+    pub fn get_local_matrix(&mut self) -> [[f32; 4]; 4] {
+        self.get_matrix()
+    }
+
+    pub fn get_world_matrix(&self) -> [[f32; 4]; 4] {
+        self.get_world_matrix_object().into()
+    }
+
+    pub fn get_world_matrix_object(&self) -> Matrix4<f32> {
+        self.parent_matrix * self.compute_local_matrix()
+    }
+
+    pub fn get_world_position(&self) -> Vector3<f32> {
+        let world = self.get_world_matrix_object();
+        Vector3::new(world[(0, 3)], world[(1, 3)], world[(2, 3)])
+    }
+
     pub fn lerp(&self, other: &Self, t: f32) -> Self {
         let position = self.get_position().lerp(&other.get_position(), t);
         let scale = self.get_scale().lerp(&other.get_scale(), t);
@@ -1027,6 +1522,7 @@ impl Transform {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::animation_state_machine::{AnimationCondition, AnimationTransition};
 
     #[test]
     fn cube_vertex_count() {
@@ -1134,6 +1630,255 @@ mod tests {
         assert_eq!(obj.get_component::<&str>(), Some(&"hello"));
     }
 
+    #[test]
+    fn transform_world_matrix_without_parent_is_local() {
+        let mut t = Transform::new();
+        t.set_position([1.0, 2.0, 3.0]);
+        t.set_rotation([0.0, 90.0, 0.0]);
+        assert_eq!(t.get_world_matrix(), t.get_local_matrix());
+    }
+
+    #[test]
+    fn transform_world_position_applies_parent() {
+        let mut parent = Transform::new();
+        parent.set_position([10.0, 0.0, 0.0]);
+        parent.set_scale([2.0, 2.0, 2.0]);
+        let mut child = Transform::new();
+        child.set_position([1.0, 0.0, 0.0]);
+        child.parent_matrix = parent.get_world_matrix_object();
+        let p = child.get_world_position();
+        assert!((p.x - 12.0).abs() < 1e-5, "x = {}", p.x);
+        // the local position is untouched
+        assert_eq!(child.get_position().x, 1.0);
+    }
+
+    #[test]
+    fn transform_set_from_matrix_round_trip() {
+        let mut t = Transform::new();
+        t.set_position([1.0, -2.0, 3.0]);
+        t.set_rotation([10.0, 20.0, 30.0]);
+        t.set_scale([1.0, 2.0, 3.0]);
+        let m = t.get_matrix_object();
+        let mut r = Transform::new();
+        r.set_from_matrix(m);
+        let rm = r.get_matrix_object();
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((m[(i, j)] - rm[(i, j)]).abs() < 1e-4, "{} != {}", m[(i, j)], rm[(i, j)]);
+            }
+        }
+    }
+
+    #[test]
+    fn object_serializer_keeps_parent() {
+        let parent = Object::new(None);
+        let mut child = Object::new(None);
+        child.set_parent(Some(parent.get_unique_id()));
+        let restored = Object::from_serializer(child.to_serializer());
+        assert_eq!(restored.get_parent(), Some(parent.get_unique_id()));
+    }
+
+    fn two_bone_object() -> Object {
+        let mut object = Object::new(Some("skinned".to_string()));
+        let bone = |name: &str, id: usize, parent_id: Option<usize>, y: f32| animation::Bone {
+            name: name.to_string(),
+            id,
+            node_index: id,
+            parent_id,
+            inverse_bind_pose: Matrix4::new_translation(&Vector3::new(0.0, -y, 0.0)),
+        };
+        *object.get_skeleton_mut() = Some(animation::Skeleton {
+            bones: vec![bone("root", 0, None, 0.0), bone("hand", 1, Some(0), 1.0)],
+            root_transform: Matrix4::identity(),
+        });
+        let keyframe = |time: f32, x: f32| animation::AnimationKeyframe {
+            time,
+            transform: animation::AnimationTransform::Translation([x, 0.0, 0.0]),
+        };
+        object.get_animations_mut().insert("move".to_string(), animation::Animation::new("move", 1.0, vec![
+            animation::AnimationChannel::new(0, vec![keyframe(0.0, 0.0), keyframe(1.0, 2.0)]),
+            animation::AnimationChannel::new(1, vec![animation::AnimationKeyframe { time: 0.0, transform: animation::AnimationTransform::Translation([0.0, 1.0, 0.0]) }]),
+        ]));
+        object
+    }
+
+    #[test]
+    fn bone_world_matrix_bind_pose() {
+        let object = two_bone_object();
+        let hand = object.get_bone_world_matrix_object("hand").unwrap();
+        assert!((hand[(1, 3)] - 1.0).abs() < 1e-5);
+        assert!(object.get_bone_world_matrix("missing").is_none());
+    }
+
+    #[test]
+    fn bone_world_matrix_follows_animation_and_object() {
+        let mut object = two_bone_object();
+        object.transform.set_position([0.0, 0.0, 5.0]);
+        object.play_animation("move", false);
+        object.update(0.5);
+        let hand = object.get_bone_world_matrix_object("hand").unwrap();
+        assert!((hand[(0, 3)] - 1.0).abs() < 1e-5, "x = {}", hand[(0, 3)]);
+        assert!((hand[(1, 3)] - 1.0).abs() < 1e-5);
+        assert!((hand[(2, 3)] - 5.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn crossfade_blends_between_clips() {
+        let mut object = two_bone_object();
+        let mut still = object.get_animations()["move"].clone();
+        still.name = "still".to_string();
+        still.channels.remove(0);
+        object.get_animations_mut().insert("still".to_string(), still);
+
+        object.play_animation("move", true);
+        object.update(0.5);
+        object.play_animation_blended("still", 1.0, true);
+        object.update(0.5);
+        // move is at t = 1.0 (x = 2.0) with half weight, still has no root motion
+        let root = object.get_bone_model_matrix("root").unwrap();
+        assert!((root[(0, 3)] - 1.0).abs() < 1e-4, "x = {}", root[(0, 3)]);
+        object.update(0.6);
+        assert!(object.get_blended_animations().is_empty());
+        assert_eq!(object.get_current_animation().as_ref().unwrap().weight, 1.0);
+    }
+
+    #[test]
+    fn additive_layer_adds_on_top() {
+        let mut object = two_bone_object();
+        object.play_animation("move", false);
+        object.add_additive_animation("move", 0.5, false);
+        object.update(0.5);
+        // base x = 1.0, plus half of the additive delta of 1.0
+        let root = object.get_bone_model_matrix("root").unwrap();
+        assert!((root[(0, 3)] - 1.5).abs() < 1e-4, "x = {}", root[(0, 3)]);
+    }
+
+    #[test]
+    fn state_machine_drives_animation() {
+        let mut object = two_bone_object();
+        let mut machine = AnimationStateMachine::new("moving");
+        machine.add_state("moving", "move", true);
+        object.set_component(machine);
+        object.update(0.1);
+        assert_eq!(object.get_current_animation().as_ref().unwrap().name, "move");
+
+        let restored = Object::from_serializer(object.to_serializer());
+        let restored_machine = restored.get_component::<AnimationStateMachine>().unwrap();
+        assert_eq!(restored_machine.get_states()[0].animation, "move");
+    }
+
+    #[test]
+    fn state_machine_keeps_clip_speed_for_missing_animation() {
+        let mut object = two_bone_object();
+        let mut machine = AnimationStateMachine::new("moving");
+        machine.add_state("moving", "move", true);
+        machine.add_state("broken", "missing", true);
+        machine.get_state_mut("broken").unwrap().speed = 3.0;
+        machine.add_transition(AnimationTransition::new(Some("moving"), "broken", vec![AnimationCondition::Trigger("break".to_string())], 0.0, None));
+        object.set_component(machine);
+        object.update(0.1);
+        object.get_component_mut::<AnimationStateMachine>().unwrap().set_trigger("break");
+        object.update(0.1);
+        let current = object.get_current_animation().as_ref().unwrap();
+        assert_eq!(current.name, "move");
+        assert_eq!(current.speed, 1.0);
+    }
+
+    #[test]
+    fn animation_events_are_collected_on_update() {
+        let mut object = two_bone_object();
+        object.get_animations_mut().get_mut("move").unwrap().add_event("hit", 0.5);
+        object.play_animation("move", true);
+        object.update(0.4);
+        assert!(object.take_fired_animation_events().is_empty());
+        object.update(0.2);
+        let events = object.take_fired_animation_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "hit");
+        assert_eq!(events[0].object, object.get_unique_id());
+        assert!(object.take_fired_animation_events().is_empty());
+    }
+
+    #[test]
+    fn geometry_revision_changes_with_shapes() {
+        let mut cube = Object::cube(1.0);
+        let revision = cube.get_geometry_revision();
+        assert_eq!(cube.clone().get_geometry_revision(), revision);
+        cube.name = "renamed".to_string();
+        assert_eq!(cube.get_geometry_revision(), revision);
+        cube.get_shapes_mut()[0].vertices.pop();
+        assert_ne!(cube.get_geometry_revision(), revision);
+        assert_ne!(Object::cube(1.0).get_geometry_revision(), cube.get_geometry_revision());
+    }
+
+    #[test]
+    fn local_bounding_box_ignores_transform() {
+        let mut cube = Object::cube(1.0);
+        cube.transform.set_position([10.0, 0.0, 0.0]);
+        let bounds = cube.get_local_bounding_box().unwrap();
+        assert!(bounds.center.x.abs() < 1e-5);
+        assert!((bounds.width - 2.0).abs() < 1e-5);
+        assert!(Object::new(None).get_local_bounding_box().is_none());
+    }
+
+    #[test]
+    fn instance_buffer_grows_in_powers_of_two() {
+        assert_eq!(instance_buffer_capacity(1), 16);
+        assert_eq!(instance_buffer_capacity(16), 16);
+        assert_eq!(instance_buffer_capacity(17), 32);
+        assert_eq!(instance_buffer_capacity(300), 512);
+    }
+
+    fn morph_object() -> Object {
+        let mut object = Object::new(Some("face".to_string()));
+        object.morph_target_names = vec!["smile".to_string(), "blink".to_string()];
+        object.morph_weights = vec![0.0, 0.0];
+        let keyframe = |time: f32, smile: f32| animation::AnimationKeyframe {
+            time,
+            transform: animation::AnimationTransform::Weights(vec![smile, 1.0]),
+        };
+        object.get_animations_mut().insert("talk".to_string(), animation::Animation::new("talk", 1.0, vec![
+            animation::AnimationChannel::new(0, vec![keyframe(0.0, 0.0), keyframe(1.0, 1.0)]),
+        ]));
+        object
+    }
+
+    #[test]
+    fn morph_weight_by_name() {
+        let mut object = morph_object();
+        object.set_morph_weight("blink", 0.75);
+        assert_eq!(object.get_morph_weight("blink"), Some(0.75));
+        assert_eq!(object.get_morph_weight("missing"), None);
+        let uniforms = object.get_morph_weight_uniforms();
+        assert_eq!(uniforms[1], 0.75);
+        assert_eq!(uniforms[MAX_MORPH_TARGETS - 1], 0.0);
+    }
+
+    #[test]
+    fn morph_weights_follow_animation() {
+        let mut object = morph_object();
+        object.play_animation("talk", false);
+        object.update(0.25);
+        assert!((object.get_morph_weight("smile").unwrap() - 0.25).abs() < 1e-5);
+        assert_eq!(object.get_morph_weight("blink"), Some(1.0));
+    }
+
+    #[test]
+    fn morph_weights_ignore_channels_of_other_nodes() {
+        let mut object = morph_object();
+        object.set_morph_target_nodes(vec![2]);
+        let keyframe = |time: f32, blink: f32| animation::AnimationKeyframe {
+            time,
+            transform: animation::AnimationTransform::Weights(vec![0.5, blink]),
+        };
+        object.get_animations_mut().get_mut("talk").unwrap().channels
+            .push(animation::AnimationChannel::new(2, vec![keyframe(0.0, 0.0), keyframe(1.0, 0.0)]));
+        object.play_animation("talk", false);
+        object.update(0.25);
+        assert_eq!(object.get_morph_weight("smile"), Some(0.5));
+        assert_eq!(object.get_morph_weight("blink"), Some(0.0));
+    }
+
     #[test]
     fn component_clone_isolation() {
         let mut obj = Object::new(None);
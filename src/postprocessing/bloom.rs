@@ -1,9 +1,10 @@
+use std::any::Any;
 use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
 use glium::framebuffer::SimpleFrameBuffer;
-use glium::glutin::surface::WindowSurface;
+use glium::backend::Facade;
 use glium::texture::DepthTexture2d;
 use crate::geometry::Vertex;
-use crate::postprocessing::PostProcessingEffect;
+use crate::postprocessing::{PostProcessingEffect, PostProcessingEffectSerializer};
 use crate::{AppState, postprocessing, resources, shader};
 
 pub struct Bloom {
@@ -16,14 +17,14 @@ pub struct Bloom {
 }
 
 impl Bloom {
-    pub fn new(display: &glium::Display<WindowSurface>, threshold: f32, iterations: i32) -> Self {
+    pub fn new(display: &impl Facade, threshold: f32, iterations: i32) -> Self {
         let extract_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_bloom_extract_fragment(), None);
         let blur_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_bloom_blur_fragment(), None);
         let combine_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_bloom_combine_fragment(), None);
         let program_extract = glium::Program::from_source(display, &extract_shader.get_vertex_shader(), &extract_shader.get_fragment_shader(), None).expect("Failed to compile shader program");
         let program_blur = glium::Program::from_source(display, &blur_shader.get_vertex_shader(), &blur_shader.get_fragment_shader(), None).expect("Failed to compile shader program");
         let program_combine = glium::Program::from_source(display, &combine_shader.get_vertex_shader(), &combine_shader.get_fragment_shader(), None).expect("Failed to compile shader program");
-        let program_copy = postprocessing::get_screen_program(&display);
+        let program_copy = postprocessing::get_screen_program(display);
 
         Self {
             program_extract,
@@ -107,4 +108,12 @@ impl PostProcessingEffect for Bloom {
         };
         target.draw(vertex_buffer, index_buffer, &self.program_combine, &uniforms, &params).unwrap();
     }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::Bloom { threshold: self.threshold, iterations: self.iterations })
+    }
 }
\ No newline at end of file
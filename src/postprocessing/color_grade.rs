@@ -0,0 +1,433 @@
+use std::any::Any;
+use std::cell::Cell;
+use glium::backend::Facade;
+use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::{DepthTexture2d, MipmapsOption, Texture3d, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction};
+use crate::{AppState, resources, shader, smart_format};
+use crate::geometry::Vertex;
+use crate::logging::EnigmaError;
+use crate::postprocessing::PostProcessingEffect;
+
+pub const LUT_MIN_SIZE: u32 = 2;
+pub const LUT_MAX_SIZE: u32 = 256;
+
+/// A 3D color lookup table. Entry `r + g * size + b * size * size` holds the output color for the input at
+/// grid position (r, g, b), the same order as the data of a `.cube` file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lut {
+    pub title: Option<String>,
+    pub size: u32,
+    pub data: Vec<[f32; 3]>,
+    /// Input colors that map to the first and last grid position.
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+}
+
+impl Lut {
+    /// A LUT that returns every color unchanged.
+    pub fn identity(size: u32) -> Self {
+        let size = size.clamp(LUT_MIN_SIZE, LUT_MAX_SIZE);
+        let scale = 1.0 / (size - 1) as f32;
+        let data = (0..size * size * size)
+            .map(|i| [(i % size) as f32 * scale, (i / size % size) as f32 * scale, (i / (size * size)) as f32 * scale])
+            .collect();
+        Self {
+            title: None,
+            size,
+            data,
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+        }
+    }
+
+    /// Parses the text of an Adobe/Resolve `.cube` file. Only 3D LUTs are supported, unknown keywords are ignored.
+    pub fn parse_cube(source: &str) -> Result<Self, EnigmaError> {
+        let invalid = |line: usize, reason: &str| EnigmaError::new(Some(smart_format!("Invalid .cube LUT, line {}: {}", line, reason).as_str()), true);
+        let floats = |line: usize, values: &[&str], count: usize| -> Result<Vec<f32>, EnigmaError> {
+            if values.len() != count {
+                return Err(invalid(line, &smart_format!("expected {} values, found {}", count, values.len())));
+            }
+            values.iter().map(|v| v.parse::<f32>().map_err(|_| invalid(line, &smart_format!("{} is not a number", v)))).collect()
+        };
+
+        let mut title = None;
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut data = Vec::new();
+        for (index, raw_line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let (keyword, values) = (parts[0], &parts[1..]);
+            if keyword.parse::<f32>().is_ok() {
+                let color = floats(line_number, &parts[..], 3)?;
+                data.push([color[0], color[1], color[2]]);
+                continue;
+            }
+            match keyword {
+                "TITLE" => title = Some(line["TITLE".len()..].trim().trim_matches('"').to_string()),
+                "LUT_3D_SIZE" => {
+                    let value = match values {
+                        [value] => value.parse::<u32>().map_err(|_| invalid(line_number, "LUT_3D_SIZE is not a whole number"))?,
+                        _ => return Err(invalid(line_number, "LUT_3D_SIZE expects one value")),
+                    };
+                    if !(LUT_MIN_SIZE..=LUT_MAX_SIZE).contains(&value) {
+                        return Err(invalid(line_number, &smart_format!("LUT_3D_SIZE must be between {} and {}", LUT_MIN_SIZE, LUT_MAX_SIZE)));
+                    }
+                    size = Some(value);
+                }
+                "LUT_1D_SIZE" => return Err(invalid(line_number, "1D LUTs are not supported")),
+                "DOMAIN_MIN" => {
+                    let v = floats(line_number, values, 3)?;
+                    domain_min = [v[0], v[1], v[2]];
+                }
+                "DOMAIN_MAX" => {
+                    let v = floats(line_number, values, 3)?;
+                    domain_max = [v[0], v[1], v[2]];
+                }
+                // Resolve writes the domain as one range for all channels
+                "LUT_3D_INPUT_RANGE" => {
+                    let v = floats(line_number, values, 2)?;
+                    domain_min = [v[0]; 3];
+                    domain_max = [v[1]; 3];
+                }
+                _ => {}
+            }
+        }
+
+        let size = size.ok_or_else(|| EnigmaError::new(Some("Invalid .cube LUT: missing LUT_3D_SIZE"), true))?;
+        let expected = (size * size * size) as usize;
+        if data.len() != expected {
+            return Err(EnigmaError::new(Some(smart_format!("Invalid .cube LUT: expected {} entries for size {}, found {}", expected, size, data.len()).as_str()), true));
+        }
+        if (0..3).any(|i| domain_max[i] <= domain_min[i]) {
+            return Err(EnigmaError::new(Some("Invalid .cube LUT: DOMAIN_MAX must be larger than DOMAIN_MIN"), true));
+        }
+        Ok(Self { title, size, data, domain_min, domain_max })
+    }
+
+    pub fn from_cube_file(path: &str) -> Result<Self, EnigmaError> {
+        let result = std::fs::read_to_string(path)
+            .map_err(|e| EnigmaError::new(Some(smart_format!("Failed to read LUT file {}: {}", path, e).as_str()), true))
+            .and_then(|source| Lut::parse_cube(&source));
+        if let Err(error) = &result {
+            error.log();
+        }
+        result
+    }
+
+    /// Builds a LUT from the RGB pixels of a strip image, as exported by Photoshop, Resolve or Unreal: `size`
+    /// squares side by side, one per blue step, with red growing to the right and green downwards. Strips
+    /// with the squares stacked vertically work as well.
+    pub fn from_strip_pixels(width: u32, height: u32, pixels: &[f32]) -> Result<Self, EnigmaError> {
+        let invalid = |reason: String| EnigmaError::new(Some(smart_format!("Invalid LUT strip of {}x{}: {}", width, height, reason).as_str()), true);
+        // squared in u64, so huge images are rejected instead of overflowing
+        let (width_64, height_64) = (u64::from(width), u64::from(height));
+        let (size, horizontal) = if width_64 == height_64 * height_64 {
+            (height, true)
+        } else if height_64 == width_64 * width_64 {
+            (width, false)
+        } else {
+            return Err(invalid("the width must be the height squared, or the other way round".to_string()));
+        };
+        if !(LUT_MIN_SIZE..=LUT_MAX_SIZE).contains(&size) {
+            return Err(invalid(smart_format!("the LUT size must be between {} and {}", LUT_MIN_SIZE, LUT_MAX_SIZE)));
+        }
+        if pixels.len() != (width * height * 3) as usize {
+            return Err(invalid(smart_format!("expected {} values, found {}", width * height * 3, pixels.len())));
+        }
+        let data = (0..size * size * size)
+            .map(|i| {
+                let (r, g, b) = (i % size, i / size % size, i / (size * size));
+                let (x, y) = if horizontal { (r + b * size, g) } else { (r, g + b * size) };
+                let offset = ((y * width + x) * 3) as usize;
+                [pixels[offset], pixels[offset + 1], pixels[offset + 2]]
+            })
+            .collect();
+        Ok(Self {
+            title: None,
+            size,
+            data,
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+        })
+    }
+
+    /// Decodes a strip image, see `from_strip_pixels`. 16 bit PNGs keep their precision.
+    pub fn from_strip_bytes(data: &[u8]) -> Result<Self, EnigmaError> {
+        let result = image::load_from_memory(data)
+            .map_err(|e| EnigmaError::new(Some(smart_format!("Failed to decode LUT image: {}", e).as_str()), true))
+            .and_then(|image| {
+                let image = image.to_rgb32f();
+                Lut::from_strip_pixels(image.width(), image.height(), image.as_raw())
+            });
+        if let Err(error) = &result {
+            error.log();
+        }
+        result
+    }
+
+    pub fn from_strip_file(path: &str) -> Result<Self, EnigmaError> {
+        match std::fs::read(path) {
+            Ok(data) => Lut::from_strip_bytes(&data),
+            Err(e) => {
+                let error = EnigmaError::new(Some(smart_format!("Failed to read LUT file {}: {}", path, e).as_str()), true);
+                error.log();
+                Err(error)
+            }
+        }
+    }
+
+    /// Looks up `color` with trilinear interpolation, like the GPU does.
+    pub fn sample(&self, color: [f32; 3]) -> [f32; 3] {
+        let size = self.size as usize;
+        let mut base = [0usize; 3];
+        let mut fraction = [0.0f32; 3];
+        for i in 0..3 {
+            let range = (self.domain_max[i] - self.domain_min[i]).max(1e-5);
+            let position = ((color[i] - self.domain_min[i]) / range).clamp(0.0, 1.0) * (size - 1) as f32;
+            base[i] = (position.floor() as usize).min(size - 2);
+            fraction[i] = position - base[i] as f32;
+        }
+        let fetch = |r: usize, g: usize, b: usize| self.data[(base[0] + r) + (base[1] + g) * size + (base[2] + b) * size * size];
+        let lerp = |a: [f32; 3], b: [f32; 3], t: f32| [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t];
+        let [fr, fg, fb] = fraction;
+        let g0 = lerp(lerp(fetch(0, 0, 0), fetch(1, 0, 0), fr), lerp(fetch(0, 1, 0), fetch(1, 1, 0), fr), fg);
+        let g1 = lerp(lerp(fetch(0, 0, 1), fetch(1, 0, 1), fr), lerp(fetch(0, 1, 1), fetch(1, 1, 1), fr), fg);
+        lerp(g0, g1, fb)
+    }
+}
+
+/// A `Lut` uploaded as a 3D texture.
+struct LutTexture {
+    texture: Texture3d,
+    size: f32,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+}
+
+impl LutTexture {
+    fn new(display: &impl Facade, lut: &Lut) -> Self {
+        let size = lut.size as usize;
+        // depth is blue, rows are green and columns red
+        let data: Vec<Vec<Vec<(f32, f32, f32)>>> = (0..size)
+            .map(|b| (0..size)
+                .map(|g| (0..size)
+                    .map(|r| {
+                        let color = lut.data[r + g * size + b * size * size];
+                        (color[0], color[1], color[2])
+                    })
+                    .collect())
+                .collect())
+            .collect();
+        Self {
+            texture: Texture3d::with_format(display, data, UncompressedFloatFormat::F16F16F16, MipmapsOption::NoMipmap).expect("Failed to create LUT texture"),
+            size: lut.size as f32,
+            domain_min: lut.domain_min,
+            domain_max: lut.domain_max,
+        }
+    }
+}
+
+/// Color grading with 3D LUTs, e.g. looks exported from Resolve or Photoshop. LUTs expect display colors,
+/// so run it after tonemapping (see `AppState::set_tonemap_position`). `blend_to` crossfades to another LUT
+/// over time. The LUTs are not kept on the CPU, so it is left out of scene files.
+pub struct ColorGrade {
+    /// Mix between the original colors at 0 and the graded colors at 1.
+    pub intensity: f32,
+    program: glium::Program,
+    current: LutTexture,
+    next: Option<LutTexture>,
+    blend_duration: f32,
+    blend: Cell<f32>,
+}
+
+impl ColorGrade {
+    pub fn new(display: &impl Facade, lut: &Lut, intensity: f32) -> Self {
+        let grade_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_color_grade_fragment(), None);
+        let program = glium::Program::from_source(display, &grade_shader.get_vertex_shader(), &grade_shader.get_fragment_shader(), None).expect("Failed to compile color grade shader program");
+
+        Self {
+            intensity: intensity.clamp(0.0, 1.0),
+            program,
+            current: LutTexture::new(display, lut),
+            next: None,
+            blend_duration: 0.0,
+            blend: Cell::new(0.0),
+        }
+    }
+
+    pub fn set_intensity(&mut self, intensity: f32) {
+        self.intensity = intensity.clamp(0.0, 1.0);
+    }
+
+    /// Replaces the LUT right away and stops a running blend.
+    pub fn set_lut(&mut self, display: &impl Facade, lut: &Lut) {
+        self.current = LutTexture::new(display, lut);
+        self.next = None;
+        self.blend.set(0.0);
+    }
+
+    /// Crossfades from the current LUT to `lut` over `duration` seconds. A blend that is still running is
+    /// finished first. A `duration` of 0 leaves the progress to `set_blend`.
+    pub fn blend_to(&mut self, display: &impl Facade, lut: &Lut, duration: f32) {
+        if let Some(next) = self.next.take() {
+            self.current = next;
+        }
+        self.next = Some(LutTexture::new(display, lut));
+        self.blend_duration = duration.max(0.0);
+        self.blend.set(0.0);
+    }
+
+    /// Sets the progress of the blend started with `blend_to`, 0 is the old LUT and 1 the new one.
+    pub fn set_blend(&mut self, blend: f32) {
+        self.blend.set(blend.clamp(0.0, 1.0));
+    }
+
+    pub fn get_blend(&self) -> f32 {
+        self.blend.get()
+    }
+
+    pub fn is_blending(&self) -> bool {
+        self.next.is_some() && self.blend.get() < 1.0
+    }
+}
+
+impl PostProcessingEffect for ColorGrade {
+    fn render(&self, app_state: &AppState, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, target: &mut SimpleFrameBuffer, source: &Texture2d, _depth_source: &DepthTexture2d, _buffer_textures: &Vec<Texture2d>) {
+        if self.next.is_some() && self.blend_duration > 0.0 {
+            self.blend.set((self.blend.get() + app_state.delta_time / self.blend_duration).min(1.0));
+        }
+        let next = self.next.as_ref().unwrap_or(&self.current);
+
+        let uniforms = uniform! {
+            scene: source.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            lut_a: self.current.texture.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Linear)
+                .magnify_filter(MagnifySamplerFilter::Linear),
+            lut_a_size: self.current.size,
+            lut_a_domain_min: self.current.domain_min,
+            lut_a_domain_max: self.current.domain_max,
+            lut_b: next.texture.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Linear)
+                .magnify_filter(MagnifySamplerFilter::Linear),
+            lut_b_size: next.size,
+            lut_b_domain_min: next.domain_min,
+            lut_b_domain_max: next.domain_max,
+            blend: if self.next.is_some() { self.blend.get() } else { 0.0 },
+            intensity: self.intensity,
+        };
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::DepthTest::Overwrite,
+                write: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        target.draw(vertex_buffer, index_buffer, &self.program, &uniforms, &params).expect("Failed to draw color grade pass");
+    }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WARM_CUBE: &str = r#"# Created by a test
+TITLE "Warm look"
+LUT_3D_SIZE 2
+DOMAIN_MIN 0.0 0.0 0.0
+DOMAIN_MAX 1.0 1.0 1.0
+
+0.1 0.0 0.0
+1.0 0.0 0.0
+0.1 1.0 0.0
+1.0 1.0 0.0
+0.1 0.0 0.8
+1.0 0.0 0.8
+0.1 1.0 0.8
+1.0 1.0 0.8
+"#;
+
+    fn assert_color(actual: [f32; 3], expected: [f32; 3]) {
+        assert!((0..3).all(|i| (actual[i] - expected[i]).abs() < 1e-5), "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn parses_cube_files() {
+        let lut = Lut::parse_cube(WARM_CUBE).unwrap();
+        assert_eq!(lut.title.as_deref(), Some("Warm look"));
+        assert_eq!(lut.size, 2);
+        assert_eq!(lut.data.len(), 8);
+        // red changes fastest
+        assert_eq!(lut.data[1], [1.0, 0.0, 0.0]);
+        assert_eq!(lut.data[4], [0.1, 0.0, 0.8]);
+        assert_color(lut.sample([0.0, 0.0, 0.0]), [0.1, 0.0, 0.0]);
+        assert_color(lut.sample([1.0, 1.0, 1.0]), [1.0, 1.0, 0.8]);
+        assert_color(lut.sample([0.5, 0.5, 0.5]), [0.55, 0.5, 0.4]);
+    }
+
+    #[test]
+    fn parses_input_range_and_ignores_unknown_keywords() {
+        let source = "LUT_3D_INPUT_RANGE 0.0 2.0\nVENDOR_KEYWORD something\nLUT_3D_SIZE 2\n".to_string()
+            + &Lut::identity(2).data.iter().map(|c| format!("{} {} {}\n", c[0], c[1], c[2])).collect::<String>();
+        let lut = Lut::parse_cube(&source).unwrap();
+        assert_eq!(lut.domain_max, [2.0; 3]);
+        // an input of 1.0 is the middle of the domain
+        assert_color(lut.sample([1.0, 1.0, 1.0]), [0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn rejects_invalid_cube_files() {
+        assert!(Lut::parse_cube("0 0 0\n1 1 1\n").is_err(), "missing size");
+        assert!(Lut::parse_cube("LUT_3D_SIZE 2\n0 0 0\n1 1 1\n").is_err(), "too few entries");
+        assert!(Lut::parse_cube("LUT_1D_SIZE 2\n0 0 0\n1 1 1\n").is_err(), "1D LUT");
+        assert!(Lut::parse_cube("LUT_3D_SIZE 1\n0 0 0\n").is_err(), "size out of range");
+        assert!(Lut::parse_cube(&WARM_CUBE.replace("1.0 0.0 0.8", "1.0 zero 0.8")).is_err(), "bad number");
+        assert!(Lut::parse_cube(&WARM_CUBE.replace("0.1 1.0 0.8", "0.1 1.0")).is_err(), "missing channel");
+        assert!(Lut::parse_cube(&WARM_CUBE.replace("DOMAIN_MAX 1.0 1.0 1.0", "DOMAIN_MAX 0.0 1.0 1.0")).is_err(), "empty domain");
+    }
+
+    #[test]
+    fn identity_lut_keeps_colors() {
+        let lut = Lut::identity(17);
+        for color in [[0.0, 0.0, 0.0], [1.0, 1.0, 1.0], [0.25, 0.6, 0.9], [0.03, 0.5, 0.77]] {
+            assert_color(lut.sample(color), color);
+        }
+    }
+
+    #[test]
+    fn strips_match_the_cube_layout() {
+        let size = 4u32;
+        let identity = Lut::identity(size);
+        // horizontal strip: blue selects the square, red grows to the right and green downwards
+        let (width, height) = (size * size, size);
+        let mut pixels = vec![0.0; (width * height * 3) as usize];
+        for (i, color) in identity.data.iter().enumerate() {
+            let (r, g, b) = (i as u32 % size, i as u32 / size % size, i as u32 / (size * size));
+            let offset = ((g * width + r + b * size) * 3) as usize;
+            pixels[offset..offset + 3].copy_from_slice(color);
+        }
+        assert_eq!(Lut::from_strip_pixels(width, height, &pixels).unwrap(), identity);
+        assert!(Lut::from_strip_pixels(width, height + 1, &pixels).is_err());
+        assert!(Lut::from_strip_pixels(width, height, &pixels[3..]).is_err());
+        // 65536 squared doesn't fit into u32
+        assert!(Lut::from_strip_pixels(0, 65536, &[]).is_err());
+        assert!(Lut::from_strip_pixels(u32::MAX, 65536, &[]).is_err());
+    }
+}
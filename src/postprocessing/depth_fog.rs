@@ -1,10 +1,11 @@
-use glium::glutin::surface::WindowSurface;
+use std::any::Any;
+use glium::backend::Facade;
 use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
 use glium::framebuffer::SimpleFrameBuffer;
 use glium::texture::DepthTexture2d;
 use crate::{AppState, resources, shader};
 use crate::geometry::Vertex;
-use crate::postprocessing::PostProcessingEffect;
+use crate::postprocessing::{PostProcessingEffect, PostProcessingEffectSerializer};
 
 pub struct DepthFog {
     pub min_depth: f32,
@@ -16,7 +17,7 @@ pub struct DepthFog {
 }
 
 impl DepthFog {
-    pub fn new(display: &glium::Display<WindowSurface>, min_depth: f32, max_depth: f32, fog_cutoff: f32, color: [f32; 3], opacity: f32) -> Self {
+    pub fn new(display: &impl Facade, min_depth: f32, max_depth: f32, fog_cutoff: f32, color: [f32; 3], opacity: f32) -> Self {
         let fog_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_depth_fog_fragment(), None);
 
         let program = glium::Program::from_source(display, &fog_shader.get_vertex_shader(), &fog_shader.get_fragment_shader(), None).expect("Failed to compile shader program");
@@ -64,4 +65,12 @@ impl PostProcessingEffect for DepthFog {
             &params,
         ).unwrap();
     }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::DepthFog { min_depth: self.min_depth, max_depth: self.max_depth, fog_cutoff: self.fog_cutoff, color: self.color, opacity: self.opacity })
+    }
 }
\ No newline at end of file
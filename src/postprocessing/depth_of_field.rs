@@ -0,0 +1,132 @@
+use std::any::Any;
+use glium::backend::Facade;
+use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::DepthTexture2d;
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction};
+use crate::{AppState, resources, shader};
+use crate::camera::Camera;
+use crate::geometry::Vertex;
+use crate::logging::EnigmaWarning;
+use crate::postprocessing::{PostProcessingEffect, PostProcessingEffectSerializer};
+
+pub const DOF_MIN_BOKEH_QUALITY: u32 = 8;
+pub const DOF_MAX_BOKEH_QUALITY: u32 = 128;
+
+/// Distance of `point` to the camera along its viewing direction, which is what the depth of field focuses on.
+pub fn focus_distance_to(camera: &Camera, point: [f32; 3]) -> f32 {
+    let position = camera.get_position();
+    let direction = camera.calculate_direction_vector();
+    let length = (direction[0] * direction[0] + direction[1] * direction[1] + direction[2] * direction[2]).sqrt().max(f32::EPSILON);
+    ((point[0] - position[0]) * direction[0] + (point[1] - position[1]) * direction[1] + (point[2] - position[2]) * direction[2]) / length
+}
+
+/// Cinematic depth of field. Everything away from the focus distance is blurred with a round bokeh, the
+/// further away the blurrier, up to `max_blur` pixels.
+pub struct DepthOfField {
+    /// Distance from the camera in world units that is perfectly sharp.
+    pub focus_distance: f32,
+    /// Strength of the blur, 0 keeps everything sharp.
+    pub aperture: f32,
+    /// Number of samples of the bokeh disc, higher is smoother and slower.
+    pub bokeh_quality: u32,
+    /// Largest blur radius in pixels.
+    pub max_blur: f32,
+    program: glium::Program,
+}
+
+impl DepthOfField {
+    pub fn new(display: &impl Facade, focus_distance: f32, aperture: f32, bokeh_quality: u32) -> Self {
+        let dof_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_depth_of_field_fragment(), None);
+        let program = glium::Program::from_source(display, &dof_shader.get_vertex_shader(), &dof_shader.get_fragment_shader(), None).expect("Failed to compile depth of field shader program");
+
+        Self {
+            focus_distance: focus_distance.max(0.0),
+            aperture: aperture.max(0.0),
+            bokeh_quality: bokeh_quality.clamp(DOF_MIN_BOKEH_QUALITY, DOF_MAX_BOKEH_QUALITY),
+            max_blur: 12.0,
+            program,
+        }
+    }
+
+    pub fn set_focus_distance(&mut self, focus_distance: f32) {
+        self.focus_distance = focus_distance.max(0.0);
+    }
+
+    /// Focuses on a world space `point`, e.g. the position of the selected object.
+    pub fn focus_on(&mut self, camera: &Camera, point: [f32; 3]) {
+        self.set_focus_distance(focus_distance_to(camera, point));
+    }
+
+    pub fn set_aperture(&mut self, aperture: f32) {
+        self.aperture = aperture.max(0.0);
+    }
+
+    pub fn set_bokeh_quality(&mut self, bokeh_quality: u32) {
+        self.bokeh_quality = bokeh_quality.clamp(DOF_MIN_BOKEH_QUALITY, DOF_MAX_BOKEH_QUALITY);
+    }
+
+    pub fn set_max_blur(&mut self, max_blur: f32) {
+        self.max_blur = max_blur.max(0.0);
+    }
+}
+
+impl PostProcessingEffect for DepthOfField {
+    fn render(&self, app_state: &AppState, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, target: &mut SimpleFrameBuffer, source: &Texture2d, depth_source: &DepthTexture2d, _buffer_textures: &Vec<Texture2d>) {
+        let camera = match app_state.camera.as_ref() {
+            Some(camera) => camera,
+            None => {
+                EnigmaWarning::new(Some("DepthOfField needs a camera to linearize the depth buffer, skipping the effect."), true).log();
+                return;
+            }
+        };
+        let uniforms = uniform! {
+            scene: source.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Linear)
+                .magnify_filter(MagnifySamplerFilter::Linear),
+            depth: depth_source.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            near: camera.near,
+            far: camera.far,
+            focus_distance: self.focus_distance,
+            aperture: self.aperture,
+            max_blur: self.max_blur,
+            sample_count: self.bokeh_quality as i32,
+        };
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::DepthTest::Overwrite,
+                write: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        target.draw(vertex_buffer, index_buffer, &self.program, &uniforms, &params).expect("Failed to draw depth of field pass");
+    }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::DepthOfField { focus_distance: self.focus_distance, aperture: self.aperture, bokeh_quality: self.bokeh_quality, max_blur: self.max_blur })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn focus_distance_is_measured_along_the_view_direction() {
+        // the default camera looks down -z
+        let camera = Camera::new(Some([0.0, 1.0, 2.0]), None, None, None, None, None);
+        assert!((focus_distance_to(&camera, [0.0, 1.0, -3.0]) - 5.0).abs() < 1e-5);
+        // sideways offsets don't change the depth
+        assert!((focus_distance_to(&camera, [4.0, -2.0, -3.0]) - 5.0).abs() < 1e-5);
+        assert!(focus_distance_to(&camera, [0.0, 1.0, 4.0]) < 0.0);
+    }
+}
@@ -1,8 +1,9 @@
-use glium::glutin::surface::WindowSurface;
+use std::any::Any;
+use glium::backend::Facade;
 use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
 use glium::framebuffer::SimpleFrameBuffer;
 use glium::texture::DepthTexture2d;
-use crate::postprocessing::PostProcessingEffect;
+use crate::postprocessing::{PostProcessingEffect, PostProcessingEffectSerializer};
 use crate::{AppState, resources, shader};
 use crate::geometry::Vertex;
 
@@ -13,7 +14,7 @@ pub struct Edge {
 }
 
 impl Edge {
-    pub fn new(display: &glium::Display<WindowSurface>, threshold: f32, color: [f32; 3]) -> Self {
+    pub fn new(display: &impl Facade, threshold: f32, color: [f32; 3]) -> Self {
         let edge_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_edge_fragment(), None);
 
         let program = glium::Program::from_source(display, &edge_shader.get_vertex_shader(), &edge_shader.get_fragment_shader(), None).expect("Failed to compile shader program");
@@ -56,4 +57,12 @@ impl PostProcessingEffect for Edge {
             &params,
         ).unwrap();
     }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::Edge { threshold: self.threshold, color: self.color })
+    }
 }
\ No newline at end of file
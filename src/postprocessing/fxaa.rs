@@ -0,0 +1,78 @@
+use std::any::Any;
+use glium::backend::Facade;
+use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::DepthTexture2d;
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction};
+use crate::{AppState, resources, shader};
+use crate::geometry::Vertex;
+use crate::postprocessing::{PostProcessingEffect, PostProcessingEffectSerializer};
+
+/// Fast approximate anti-aliasing. Smooths edges found in the luminance of the image in a single pass, so it
+/// works best after tonemapping. Cheap, but it can't restore detail that was lost between pixels like TAA does.
+pub struct Fxaa {
+    program: glium::Program,
+    /// Minimum local contrast relative to the brightest pixel that counts as an edge, lower finds more edges.
+    pub edge_threshold: f32,
+    /// Absolute minimum contrast, keeps dark areas from being smoothed.
+    pub edge_threshold_min: f32,
+    /// How much single pixel details like thin lines are smoothed.
+    pub subpixel_quality: f32,
+}
+
+impl Fxaa {
+    pub fn new(display: &impl Facade, edge_threshold: f32, edge_threshold_min: f32, subpixel_quality: f32) -> Self {
+        let fxaa_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_fxaa_fragment(), None);
+        let program = glium::Program::from_source(display, &fxaa_shader.get_vertex_shader(), &fxaa_shader.get_fragment_shader(), None).expect("Failed to compile fxaa shader program");
+
+        Self {
+            program,
+            edge_threshold: edge_threshold.clamp(0.0, 1.0),
+            edge_threshold_min: edge_threshold_min.clamp(0.0, 1.0),
+            subpixel_quality: subpixel_quality.clamp(0.0, 1.0),
+        }
+    }
+
+    pub fn set_edge_threshold(&mut self, edge_threshold: f32) {
+        self.edge_threshold = edge_threshold.clamp(0.0, 1.0);
+    }
+
+    pub fn set_edge_threshold_min(&mut self, edge_threshold_min: f32) {
+        self.edge_threshold_min = edge_threshold_min.clamp(0.0, 1.0);
+    }
+
+    pub fn set_subpixel_quality(&mut self, subpixel_quality: f32) {
+        self.subpixel_quality = subpixel_quality.clamp(0.0, 1.0);
+    }
+}
+
+impl PostProcessingEffect for Fxaa {
+    fn render(&self, _app_state: &AppState, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, target: &mut SimpleFrameBuffer, source: &Texture2d, _depth_source: &DepthTexture2d, _buffer_textures: &Vec<Texture2d>) {
+        let uniforms = uniform! {
+            scene: source.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Linear)
+                .magnify_filter(MagnifySamplerFilter::Linear),
+            edge_threshold: self.edge_threshold,
+            edge_threshold_min: self.edge_threshold_min,
+            subpixel_quality: self.subpixel_quality,
+        };
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::DepthTest::Overwrite,
+                write: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        target.draw(vertex_buffer, index_buffer, &self.program, &uniforms, &params).expect("Failed to draw fxaa pass");
+    }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::Fxaa { edge_threshold: self.edge_threshold, edge_threshold_min: self.edge_threshold_min, subpixel_quality: self.subpixel_quality })
+    }
+}
@@ -1,10 +1,11 @@
+use std::any::Any;
 use glium::framebuffer::SimpleFrameBuffer;
-use glium::glutin::surface::WindowSurface;
+use glium::backend::Facade;
 use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
 use glium::texture::DepthTexture2d;
 use crate::geometry::Vertex;
 use crate::{AppState, resources, shader};
-use crate::postprocessing::PostProcessingEffect;
+use crate::postprocessing::{PostProcessingEffect, PostProcessingEffectSerializer};
 
 pub struct GrayScale {
     program: glium::Program,
@@ -32,10 +33,18 @@ impl PostProcessingEffect for GrayScale {
             &params,
         ).unwrap();
     }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::GrayScale)
+    }
 }
 
 impl GrayScale {
-    pub fn new(display: &glium::Display<WindowSurface>) -> Self {
+    pub fn new(display: &impl Facade) -> Self {
         let shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_grayscale_fragment(), None);
         let program = glium::Program::from_source(display, &shader.get_vertex_shader(), &shader.get_fragment_shader(), None).expect("Failed to compile shader program");
         Self {
@@ -1,14 +1,17 @@
-use glium::glutin::surface::WindowSurface;
+use std::any::Any;
+use glium::backend::Facade;
 use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
 use glium::framebuffer::SimpleFrameBuffer;
 use glium::texture::DepthTexture2d;
 use crate::{AppState, resources, shader};
 use crate::geometry::Vertex;
-use crate::postprocessing::PostProcessingEffect;
+use crate::postprocessing::{PostProcessingEffect, PostProcessingEffectSerializer};
 
 pub struct LensDirt {
     program: glium::Program,
     dirt_texture: Texture2d,
+    // the encoded image, kept for serialization
+    dirt_texture_data: Vec<u8>,
     intensity: f32,
     tile_scale: [f32; 2],
     light_sensitivity: f32,
@@ -16,7 +19,7 @@ pub struct LensDirt {
 
 impl LensDirt {
     pub fn new(
-        display: &glium::Display<WindowSurface>,
+        display: &impl Facade,
         dirt_texture_data: &[u8],
         intensity: f32,
         tile_scale: [f32; 2],
@@ -48,6 +51,7 @@ impl LensDirt {
         Self {
             program,
             dirt_texture,
+            dirt_texture_data: dirt_texture_data.to_vec(),
             intensity,
             tile_scale,
             light_sensitivity,
@@ -83,4 +87,12 @@ impl PostProcessingEffect for LensDirt {
             &params,
         ).unwrap();
     }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::LensDirt { dirt_texture: self.dirt_texture_data.clone(), intensity: self.intensity, tile_scale: self.tile_scale, light_sensitivity: self.light_sensitivity })
+    }
 }
\ No newline at end of file
@@ -1,7 +1,9 @@
-use glium::{Display, IndexBuffer, Texture2d, VertexBuffer};
+use std::any::Any;
+use glium::{IndexBuffer, Texture2d, VertexBuffer};
 use glium::framebuffer::SimpleFrameBuffer;
-use glium::glutin::surface::WindowSurface;
+use glium::backend::Facade;
 use glium::texture::DepthTexture2d;
+use serde::{Deserialize, Serialize};
 use crate::AppState;
 use crate::geometry::Vertex;
 use crate::logging::EnigmaWarning;
@@ -12,14 +14,164 @@ pub mod edge;
 pub mod depth_fog;
 pub mod vignette;
 pub mod lens_dirt;
+pub mod tonemapping;
+pub mod ssao;
+pub mod ssr;
+pub mod fxaa;
+pub mod taa;
+pub mod depth_of_field;
+pub mod motion_blur;
+pub mod color_grade;
+
+/// The textures of the frame a post-processing pass reads from.
+pub struct PostProcessSources<'a> {
+    /// The frame before the pass.
+    pub source: &'a Texture2d,
+    pub depth: &'a DepthTexture2d,
+    /// See `PostProcessingEffect::needs_surface_buffer`.
+    pub surface: &'a Texture2d,
+    pub buffer_textures: &'a Vec<Texture2d>,
+}
 
 pub trait PostProcessingEffect {
     fn render(&self, _app_state: &AppState, _vertex_buffer: &VertexBuffer<Vertex>, _index_buffer: &IndexBuffer<u32>, _target: &mut SimpleFrameBuffer, _source: &Texture2d, _depth_source: &DepthTexture2d, _buffer_textures: &Vec<Texture2d>) {
         EnigmaWarning::new(Some("PostProcessingEffect::render() not implemented. Please implement this trait in your postprocessing struct."), true).log();
     }
+
+    /// Effects returning true get the surface buffer passed to `render_with_surface`. It holds the view space
+    /// normal in xy, the roughness in z and the linear depth in w of every opaque object with a lit material,
+    /// and costs an extra draw of those objects per frame.
+    fn needs_surface_buffer(&self) -> bool {
+        false
+    }
+
+    /// Called by the renderer instead of `render`, forwards to `render` unless the effect needs the surface buffer.
+    fn render_with_surface(&self, app_state: &AppState, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, target: &mut SimpleFrameBuffer, sources: &PostProcessSources) {
+        self.render(app_state, vertex_buffer, index_buffer, target, sources.source, sources.depth, sources.buffer_textures);
+    }
+
+    /// Gives access to the concrete effect, see `AppState::get_post_process_mut`. Effects returning `None`
+    /// can't be changed after they were added.
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        None
+    }
+
+    /// The settings of the effect for scene files. Effects returning `None` are left out when the `AppState`
+    /// is serialized.
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        None
+    }
+}
+
+/// The settings of a built-in effect, enough to create it again with `into_effect`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PostProcessingEffectSerializer {
+    Bloom { threshold: f32, iterations: i32 },
+    DepthFog { min_depth: f32, max_depth: f32, fog_cutoff: f32, color: [f32; 3], opacity: f32 },
+    Vignette { intensity: f32, falloff: f32, color: [f32; 3], opacity: f32 },
+    Edge { threshold: f32, color: [f32; 3] },
+    /// Keeps the encoded dirt image, like `TextureSerializer` keeps binary data.
+    LensDirt { dirt_texture: Vec<u8>, intensity: f32, tile_scale: [f32; 2], light_sensitivity: f32 },
+    GrayScale,
+    Fxaa { edge_threshold: f32, edge_threshold_min: f32, subpixel_quality: f32 },
+    Ssao(ssao::SsaoSettings),
+    Ssr(ssr::SsrSettings),
+    DepthOfField { focus_distance: f32, aperture: f32, bokeh_quality: u32, max_blur: f32 },
+    MotionBlur { intensity: f32, samples: u32, max_blur: f32 },
+}
+
+impl PostProcessingEffectSerializer {
+    /// The name of the effect type, used for entries added without a name.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            PostProcessingEffectSerializer::Bloom { .. } => "Bloom",
+            PostProcessingEffectSerializer::DepthFog { .. } => "DepthFog",
+            PostProcessingEffectSerializer::Vignette { .. } => "Vignette",
+            PostProcessingEffectSerializer::Edge { .. } => "Edge",
+            PostProcessingEffectSerializer::LensDirt { .. } => "LensDirt",
+            PostProcessingEffectSerializer::GrayScale => "GrayScale",
+            PostProcessingEffectSerializer::Fxaa { .. } => "Fxaa",
+            PostProcessingEffectSerializer::Ssao(_) => "Ssao",
+            PostProcessingEffectSerializer::Ssr(_) => "Ssr",
+            PostProcessingEffectSerializer::DepthOfField { .. } => "DepthOfField",
+            PostProcessingEffectSerializer::MotionBlur { .. } => "MotionBlur",
+        }
+    }
+
+    pub fn into_effect(self, display: &impl Facade) -> Box<dyn PostProcessingEffect> {
+        match self {
+            PostProcessingEffectSerializer::Bloom { threshold, iterations } => Box::new(bloom::Bloom::new(display, threshold, iterations)),
+            PostProcessingEffectSerializer::DepthFog { min_depth, max_depth, fog_cutoff, color, opacity } => Box::new(depth_fog::DepthFog::new(display, min_depth, max_depth, fog_cutoff, color, opacity)),
+            PostProcessingEffectSerializer::Vignette { intensity, falloff, color, opacity } => Box::new(vignette::Vignette::new(display, intensity, falloff, color, opacity)),
+            PostProcessingEffectSerializer::Edge { threshold, color } => Box::new(edge::Edge::new(display, threshold, color)),
+            PostProcessingEffectSerializer::LensDirt { dirt_texture, intensity, tile_scale, light_sensitivity } => Box::new(lens_dirt::LensDirt::new(display, &dirt_texture, intensity, tile_scale, light_sensitivity)),
+            PostProcessingEffectSerializer::GrayScale => Box::new(grayscale::GrayScale::new(display)),
+            PostProcessingEffectSerializer::Fxaa { edge_threshold, edge_threshold_min, subpixel_quality } => Box::new(fxaa::Fxaa::new(display, edge_threshold, edge_threshold_min, subpixel_quality)),
+            PostProcessingEffectSerializer::Ssao(settings) => Box::new(ssao::Ssao::from_settings(display, settings)),
+            PostProcessingEffectSerializer::Ssr(settings) => Box::new(ssr::Ssr::from_settings(display, settings)),
+            PostProcessingEffectSerializer::DepthOfField { focus_distance, aperture, bokeh_quality, max_blur } => {
+                let mut depth_of_field = depth_of_field::DepthOfField::new(display, focus_distance, aperture, bokeh_quality);
+                depth_of_field.set_max_blur(max_blur);
+                Box::new(depth_of_field)
+            }
+            PostProcessingEffectSerializer::MotionBlur { intensity, samples, max_blur } => {
+                let mut motion_blur = motion_blur::MotionBlur::new(display, intensity, samples);
+                motion_blur.set_max_blur(max_blur);
+                Box::new(motion_blur)
+            }
+        }
+    }
+}
+
+/// An effect in the post-processing stack of the `AppState`, addressed by its unique name.
+pub struct PostProcessEntry {
+    pub name: String,
+    /// Disabled effects are skipped, but keep their place in the stack.
+    pub enabled: bool,
+    /// Mix between the image before the effect at 0 and the full effect at 1.
+    pub weight: f32,
+    pub effect: Box<dyn PostProcessingEffect>,
+}
+
+impl PostProcessEntry {
+    pub fn new(name: &str, effect: Box<dyn PostProcessingEffect>) -> Self {
+        Self {
+            name: name.to_string(),
+            enabled: true,
+            weight: 1.0,
+            effect,
+        }
+    }
+
+    /// `None` if the effect has no serialization, see `PostProcessingEffect::to_serializer`.
+    pub fn to_serializer(&self) -> Option<PostProcessEntrySerializer> {
+        self.effect.to_serializer().map(|effect| PostProcessEntrySerializer {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            weight: self.weight,
+            effect,
+        })
+    }
+
+    pub fn from_serializer(serializer: PostProcessEntrySerializer, display: &impl Facade) -> Self {
+        Self {
+            name: serializer.name,
+            enabled: serializer.enabled,
+            weight: serializer.weight.clamp(0.0, 1.0),
+            effect: serializer.effect.into_effect(display),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PostProcessEntrySerializer {
+    pub name: String,
+    pub enabled: bool,
+    pub weight: f32,
+    pub effect: PostProcessingEffectSerializer,
 }
 
-pub fn get_screen_vert_rect(display: &Display<WindowSurface>) -> glium::VertexBuffer<Vertex> {
+pub fn get_screen_vert_rect(display: &impl Facade) -> glium::VertexBuffer<Vertex> {
     let vertices = vec![
         Vertex { position: [-1.0, -1.0, 0.0], texcoord: [0.0, 0.0], color: [1.0, 1.0, 1.0], normal: [0.0, 0.0, 1.0], bone_indices: [0, 0, 0, 0], bone_weights: [0.0, 0.0, 0.0, 0.0] },
         Vertex { position: [-1.0, 1.0, 0.0], texcoord: [0.0, 1.0], color: [1.0, 1.0, 1.0], normal: [0.0, 0.0, 1.0], bone_indices: [0, 0, 0, 0], bone_weights: [0.0, 0.0, 0.0, 0.0] },
@@ -29,12 +181,12 @@ pub fn get_screen_vert_rect(display: &Display<WindowSurface>) -> glium::VertexBu
     glium::VertexBuffer::new(display, &vertices).unwrap()
 }
 
-pub fn get_screen_indices_rect(display: &Display<WindowSurface>) -> glium::IndexBuffer<u32> {
+pub fn get_screen_indices_rect(display: &impl Facade) -> glium::IndexBuffer<u32> {
     let indices: Vec<u32> = vec![0, 1, 2, 0, 2, 3];
     glium::IndexBuffer::new(display, glium::index::PrimitiveType::TrianglesList, &indices).unwrap()
 }
 
-pub fn get_screen_program(display: &Display<WindowSurface>) -> glium::Program {
+pub fn get_screen_program(display: &impl Facade) -> glium::Program {
     let vertex_shader_src = r#"
         #version 140
 
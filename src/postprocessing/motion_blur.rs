@@ -0,0 +1,144 @@
+use std::any::Any;
+use std::cell::Cell;
+use glium::backend::Facade;
+use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::DepthTexture2d;
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction};
+use crate::{AppState, resources, shader};
+use crate::geometry::Vertex;
+use crate::logging::EnigmaWarning;
+use crate::postprocessing::{PostProcessingEffect, PostProcessingEffectSerializer};
+use crate::shadow::mat4_mul;
+
+pub const MOTION_BLUR_MAX_SAMPLES: u32 = 64;
+
+/// Matrix that takes a clip space position of the current frame to where the same world position was in the
+/// previous frame, or `None` if `view_projection` can't be inverted.
+pub fn reprojection_matrix(view_projection: [[f32; 4]; 4], previous_view_projection: [[f32; 4]; 4]) -> Option<[[f32; 4]; 4]> {
+    let inverse = nalgebra::Matrix4::from(view_projection).try_inverse()?;
+    Some(mat4_mul(previous_view_projection, inverse.into()))
+}
+
+/// Camera motion blur. Every pixel is reprojected with the depth buffer into the previous frame and blurred
+/// along the way it moved on screen, so only the camera movement blurs, not moving objects.
+pub struct MotionBlur {
+    /// Fraction of the frame the virtual shutter is open, 1.0 blurs over the whole movement of one frame.
+    pub intensity: f32,
+    pub samples: u32,
+    /// Longest blur as a fraction of the screen.
+    pub max_blur: f32,
+    program: glium::Program,
+    previous_view_projection: Cell<Option<[[f32; 4]; 4]>>,
+}
+
+impl MotionBlur {
+    pub fn new(display: &impl Facade, intensity: f32, samples: u32) -> Self {
+        let motion_blur_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_motion_blur_fragment(), None);
+        let program = glium::Program::from_source(display, &motion_blur_shader.get_vertex_shader(), &motion_blur_shader.get_fragment_shader(), None).expect("Failed to compile motion blur shader program");
+
+        Self {
+            intensity: intensity.max(0.0),
+            samples: samples.clamp(2, MOTION_BLUR_MAX_SAMPLES),
+            max_blur: 0.05,
+            program,
+            previous_view_projection: Cell::new(None),
+        }
+    }
+
+    pub fn set_intensity(&mut self, intensity: f32) {
+        self.intensity = intensity.max(0.0);
+    }
+
+    pub fn set_samples(&mut self, samples: u32) {
+        self.samples = samples.clamp(2, MOTION_BLUR_MAX_SAMPLES);
+    }
+
+    pub fn set_max_blur(&mut self, max_blur: f32) {
+        self.max_blur = max_blur.clamp(0.0, 1.0);
+    }
+
+    /// Forgets the last camera, so a camera cut doesn't smear the next frame.
+    pub fn reset(&mut self) {
+        self.previous_view_projection.set(None);
+    }
+}
+
+impl PostProcessingEffect for MotionBlur {
+    fn render(&self, app_state: &AppState, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, target: &mut SimpleFrameBuffer, source: &Texture2d, depth_source: &DepthTexture2d, _buffer_textures: &Vec<Texture2d>) {
+        let camera = match app_state.camera.as_ref() {
+            Some(camera) => camera,
+            None => {
+                EnigmaWarning::new(Some("MotionBlur needs a camera to reproject the depth buffer, skipping the effect."), true).log();
+                return;
+            }
+        };
+        let view_projection = mat4_mul(camera.get_unjittered_projection_matrix(), camera.get_view_matrix());
+        let previous_view_projection = self.previous_view_projection.replace(Some(view_projection)).unwrap_or(view_projection);
+        let identity = nalgebra::Matrix4::<f32>::identity().into();
+        let reprojection = reprojection_matrix(view_projection, previous_view_projection).unwrap_or(identity);
+
+        let uniforms = uniform! {
+            scene: source.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Linear)
+                .magnify_filter(MagnifySamplerFilter::Linear),
+            depth: depth_source.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            reprojection: reprojection,
+            intensity: self.intensity,
+            max_blur: self.max_blur,
+            sample_count: self.samples as i32,
+        };
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::DepthTest::Overwrite,
+                write: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        target.draw(vertex_buffer, index_buffer, &self.program, &uniforms, &params).expect("Failed to draw motion blur pass");
+    }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::MotionBlur { intensity: self.intensity, samples: self.samples, max_blur: self.max_blur })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+
+    fn project(matrix: [[f32; 4]; 4], point: [f32; 4]) -> [f32; 2] {
+        let clip = nalgebra::Matrix4::from(matrix) * nalgebra::Vector4::from(point);
+        [clip.x / clip.w, clip.y / clip.w]
+    }
+
+    #[test]
+    fn reprojection_follows_the_camera() {
+        let mut camera = Camera::new(None, None, None, None, None, None);
+        let previous = mat4_mul(camera.get_projection_matrix(), camera.get_view_matrix());
+        camera.set_position([0.5, 0.0, 0.0]);
+        let current = mat4_mul(camera.get_projection_matrix(), camera.get_view_matrix());
+
+        let world = [0.2, 0.3, -4.0, 1.0];
+        let clip = nalgebra::Matrix4::from(current) * nalgebra::Vector4::from(world);
+        let reprojection = reprojection_matrix(current, previous).unwrap();
+        let reprojected = project(reprojection, [clip.x / clip.w, clip.y / clip.w, clip.z / clip.w, 1.0]);
+        let expected = project(previous, world);
+        assert!((reprojected[0] - expected[0]).abs() < 1e-4 && (reprojected[1] - expected[1]).abs() < 1e-4);
+
+        // without camera movement nothing moves
+        let still = reprojection_matrix(current, current).unwrap();
+        let same = project(still, [0.1, -0.2, 0.5, 1.0]);
+        assert!((same[0] - 0.1).abs() < 1e-4 && (same[1] + 0.2).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,265 @@
+use std::any::Any;
+use glium::backend::Facade;
+use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::{DepthTexture2d, MipmapsOption, Texture1d, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction};
+use serde::{Deserialize, Serialize};
+use crate::{AppState, resources, shader};
+use crate::geometry::Vertex;
+use crate::logging::EnigmaWarning;
+use crate::postprocessing::{PostProcessingEffect, PostProcessingEffectSerializer};
+
+pub const SSAO_MAX_SAMPLES: u32 = 64;
+pub const SSAO_MAX_BLUR_RADIUS: i32 = 8;
+const GOLDEN_ANGLE: f32 = 2.399_963;
+
+/// Parameters of the screen-space ambient occlusion, see `clamped` for their valid ranges.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SsaoSettings {
+    /// World space radius around each pixel that is searched for occluders.
+    pub radius: f32,
+    /// Depth bias against self occlusion on flat surfaces.
+    pub bias: f32,
+    pub samples: u32,
+    /// Half size of the depth aware blur in pixels, 0 disables the blur.
+    pub blur_radius: i32,
+    pub intensity: f32,
+    /// Only removes light up to the ambient light of the app state instead of darkening the whole color.
+    pub ambient_only: bool,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            bias: 0.025,
+            samples: 16,
+            blur_radius: 2,
+            intensity: 1.0,
+            ambient_only: false,
+        }
+    }
+}
+
+impl SsaoSettings {
+    /// The settings with every value moved into the range the shaders can handle.
+    pub fn clamped(self) -> Self {
+        Self {
+            radius: self.radius.max(0.0),
+            bias: self.bias.max(0.0),
+            samples: self.samples.clamp(1, SSAO_MAX_SAMPLES),
+            blur_radius: self.blur_radius.clamp(0, SSAO_MAX_BLUR_RADIUS),
+            intensity: self.intensity.clamp(0.0, 1.0),
+            ambient_only: self.ambient_only,
+        }
+    }
+}
+
+/// Screen-space ambient occlusion. Normals are reconstructed from the depth buffer, so it works with every
+/// material. Uses the first two buffer textures for the occlusion and its blurred version.
+pub struct Ssao {
+    settings: SsaoSettings,
+    program_ao: glium::Program,
+    program_blur: glium::Program,
+    program_combine: glium::Program,
+    kernel: Texture1d,
+}
+
+impl Ssao {
+    pub fn new(display: &impl Facade, radius: f32, bias: f32, samples: u32, blur_radius: i32) -> Self {
+        Self::from_settings(display, SsaoSettings { radius, bias, samples, blur_radius, ..Default::default() })
+    }
+
+    pub fn from_settings(display: &impl Facade, settings: SsaoSettings) -> Self {
+        let ao_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_ssao_fragment(), None);
+        let blur_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_ssao_blur_fragment(), None);
+        let combine_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_ssao_combine_fragment(), None);
+        let program_ao = glium::Program::from_source(display, &ao_shader.get_vertex_shader(), &ao_shader.get_fragment_shader(), None).expect("Failed to compile ssao shader program");
+        let program_blur = glium::Program::from_source(display, &blur_shader.get_vertex_shader(), &blur_shader.get_fragment_shader(), None).expect("Failed to compile ssao blur shader program");
+        let program_combine = glium::Program::from_source(display, &combine_shader.get_vertex_shader(), &combine_shader.get_fragment_shader(), None).expect("Failed to compile ssao combine shader program");
+
+        Self {
+            settings: settings.clamped(),
+            program_ao,
+            program_blur,
+            program_combine,
+            kernel: Texture1d::with_format(display, Self::sample_kernel(SSAO_MAX_SAMPLES), UncompressedFloatFormat::F32F32F32, MipmapsOption::NoMipmap).expect("Failed to create ssao kernel texture"),
+        }
+    }
+
+    /// Sample offsets in the hemisphere around +z, at most 1 long and denser close to the center.
+    /// Directions are cosine weighted and follow a golden angle spiral, the distances come from a
+    /// radical inverse sequence, so every prefix of the kernel covers the hemisphere evenly and
+    /// `samples` can pick any count from the same kernel.
+    pub fn sample_kernel(count: u32) -> Vec<(f32, f32, f32)> {
+        (0..count).map(|i| {
+            // van der Corput sequence, in (0, 1) for i + 1
+            let t = (i + 1).reverse_bits() as f32 / 4_294_967_296.0;
+            let phi = i as f32 * GOLDEN_ANGLE;
+            let sin_theta = t.sqrt();
+            let scale = 0.1 + 0.9 * t * t;
+            (phi.cos() * sin_theta * scale, phi.sin() * sin_theta * scale, (1.0 - t).sqrt() * scale)
+        }).collect()
+    }
+
+    pub fn get_settings(&self) -> SsaoSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: SsaoSettings) {
+        self.settings = settings.clamped();
+    }
+
+    pub fn set_radius(&mut self, radius: f32) {
+        self.set_settings(SsaoSettings { radius, ..self.settings });
+    }
+
+    pub fn set_bias(&mut self, bias: f32) {
+        self.set_settings(SsaoSettings { bias, ..self.settings });
+    }
+
+    pub fn set_samples(&mut self, samples: u32) {
+        self.set_settings(SsaoSettings { samples, ..self.settings });
+    }
+
+    pub fn set_blur_radius(&mut self, blur_radius: i32) {
+        self.set_settings(SsaoSettings { blur_radius, ..self.settings });
+    }
+
+    pub fn set_intensity(&mut self, intensity: f32) {
+        self.set_settings(SsaoSettings { intensity, ..self.settings });
+    }
+
+    pub fn set_ambient_only(&mut self, ambient_only: bool) {
+        self.set_settings(SsaoSettings { ambient_only, ..self.settings });
+    }
+}
+
+impl PostProcessingEffect for Ssao {
+    fn render(&self, app_state: &AppState, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, target: &mut SimpleFrameBuffer, source: &Texture2d, depth_source: &DepthTexture2d, buffer_textures: &Vec<Texture2d>) {
+        let camera = match app_state.camera.as_ref() {
+            Some(camera) => camera,
+            None => {
+                EnigmaWarning::new(Some("Ssao needs a camera to reconstruct positions from the depth buffer, skipping the effect."), true).log();
+                return;
+            }
+        };
+        let display = app_state.display.as_ref().expect("Ssao needs the app state display");
+        let mut ao_framebuffer = SimpleFrameBuffer::new(display, &buffer_textures[0]).expect("Failed to create ssao framebuffer");
+        let mut blur_framebuffer = SimpleFrameBuffer::new(display, &buffer_textures[1]).expect("Failed to create ssao blur framebuffer");
+
+        // occlusion in r, linear depth in g for the depth aware blur
+        let uniforms = uniform! {
+            depth: depth_source.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            projection_matrix: camera.get_projection_matrix(),
+            near: camera.near,
+            far: camera.far,
+            radius: self.settings.radius,
+            bias: self.settings.bias,
+            sample_count: self.settings.samples as i32,
+            kernel: self.kernel.sampled()
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+        };
+        ao_framebuffer.draw(vertex_buffer, index_buffer, &self.program_ao, &uniforms, &Default::default()).expect("Failed to draw ssao pass");
+
+        let uniforms = uniform! {
+            ao: buffer_textures[0].sampled()
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            blur_radius: self.settings.blur_radius,
+        };
+        blur_framebuffer.draw(vertex_buffer, index_buffer, &self.program_blur, &uniforms, &Default::default()).expect("Failed to draw ssao blur pass");
+
+        let ambient_light = match &app_state.ambient_light {
+            Some(light) => [light.color[0] * light.intensity, light.color[1] * light.intensity, light.color[2] * light.intensity],
+            None => [0.0, 0.0, 0.0],
+        };
+        let uniforms = uniform! {
+            scene: source,
+            ao: buffer_textures[1].sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Linear)
+                .magnify_filter(MagnifySamplerFilter::Linear),
+            intensity: self.settings.intensity,
+            ambient_only: self.settings.ambient_only,
+            ambient_light: ambient_light,
+        };
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::DepthTest::Overwrite,
+                write: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        target.draw(vertex_buffer, index_buffer, &self.program_combine, &uniforms, &params).expect("Failed to draw ssao combine pass");
+    }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::Ssao(self.settings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_kernel_stays_in_the_hemisphere() {
+        let kernel = Ssao::sample_kernel(SSAO_MAX_SAMPLES);
+        assert_eq!(kernel.len(), SSAO_MAX_SAMPLES as usize);
+        for &(x, y, z) in &kernel {
+            let length = (x * x + y * y + z * z).sqrt();
+            assert!(z > 0.0);
+            assert!((0.1..=1.0).contains(&length));
+        }
+        // fewer samples use the start of the same kernel
+        assert_eq!(Ssao::sample_kernel(8), kernel[..8]);
+    }
+
+    #[test]
+    fn sample_kernel_prefixes_cover_the_hemisphere() {
+        for count in [8, 16, SSAO_MAX_SAMPLES] {
+            let kernel = Ssao::sample_kernel(count);
+            // a few samples already point in every direction around the normal
+            let quadrants = kernel.iter().fold([false; 4], |mut seen, &(x, y, _)| {
+                seen[(x < 0.0) as usize * 2 + (y < 0.0) as usize] = true;
+                seen
+            });
+            assert!(quadrants.iter().all(|&seen| seen), "{count} samples miss a quadrant");
+            // and reach from close to the center up to the radius
+            let lengths: Vec<f32> = kernel.iter().map(|&(x, y, z)| (x * x + y * y + z * z).sqrt()).collect();
+            assert!(lengths.iter().any(|&length| length < 0.3));
+            assert!(lengths.iter().any(|&length| length > 0.5));
+        }
+    }
+
+    #[test]
+    fn settings_are_clamped() {
+        let settings = SsaoSettings { radius: -1.0, bias: -0.1, samples: 0, blur_radius: -3, intensity: -0.5, ambient_only: true }.clamped();
+        assert_eq!(settings, SsaoSettings { radius: 0.0, bias: 0.0, samples: 1, blur_radius: 0, intensity: 0.0, ambient_only: true });
+
+        let settings = SsaoSettings { samples: SSAO_MAX_SAMPLES + 1, blur_radius: SSAO_MAX_BLUR_RADIUS + 1, intensity: 1.5, ..Default::default() }.clamped();
+        assert_eq!(settings.samples, SSAO_MAX_SAMPLES);
+        assert_eq!(settings.blur_radius, SSAO_MAX_BLUR_RADIUS);
+        assert_eq!(settings.intensity, 1.0);
+        assert_eq!(SsaoSettings::default().clamped(), SsaoSettings::default());
+    }
+
+    #[test]
+    fn serializer_round_trip_keeps_the_settings() {
+        let serializer = PostProcessingEffectSerializer::Ssao(SsaoSettings { radius: 0.8, bias: 0.03, samples: 24, blur_radius: 3, intensity: 0.6, ambient_only: true });
+        let json = serde_json::to_string(&serializer).unwrap();
+        assert_eq!(serde_json::from_str::<PostProcessingEffectSerializer>(&json).unwrap(), serializer);
+    }
+}
@@ -0,0 +1,204 @@
+use std::any::Any;
+use glium::backend::Facade;
+use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::{DepthTexture2d, SrgbTexture2d};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction};
+use serde::{Deserialize, Serialize};
+use crate::{AppState, resources, shader};
+use crate::geometry::Vertex;
+use crate::logging::EnigmaWarning;
+use crate::postprocessing::{PostProcessSources, PostProcessingEffect, PostProcessingEffectSerializer};
+
+pub const SSR_MAX_STEPS: i32 = 256;
+
+/// Parameters of the screen-space reflections, see `clamped` for their valid ranges.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SsrSettings {
+    /// Longest reflection ray in world units.
+    pub max_distance: f32,
+    pub max_steps: i32,
+    /// How far a ray may be behind the depth buffer and still count as a hit.
+    pub thickness: f32,
+    pub intensity: f32,
+    /// Surfaces rougher than this get no reflection.
+    pub max_roughness: f32,
+}
+
+impl Default for SsrSettings {
+    fn default() -> Self {
+        Self {
+            max_distance: 10.0,
+            max_steps: 64,
+            thickness: 0.3,
+            intensity: 1.0,
+            max_roughness: 0.6,
+        }
+    }
+}
+
+impl SsrSettings {
+    /// The settings with every value moved into the range the shader can handle.
+    pub fn clamped(self) -> Self {
+        Self {
+            max_distance: self.max_distance.max(0.0),
+            max_steps: self.max_steps.clamp(1, SSR_MAX_STEPS),
+            thickness: self.thickness.max(0.0),
+            intensity: self.intensity.max(0.0),
+            max_roughness: self.max_roughness.clamp(0.01, 1.0),
+        }
+    }
+}
+
+/// Screen-space reflections. Ray-marches the depth buffer along the reflection of every pixel of an opaque,
+/// lit object and replaces the sky reflection with what it hits. Rough surfaces get blurrier and weaker
+/// reflections, surfaces above `max_roughness` none. Rays that leave the screen keep the sky reflection.
+pub struct Ssr {
+    settings: SsrSettings,
+    program: glium::Program,
+    // bound in place of the sky when the app state has no skybox
+    dummy_sky: SrgbTexture2d,
+}
+
+impl Ssr {
+    pub fn new(display: &impl Facade, max_distance: f32, max_steps: i32, thickness: f32) -> Self {
+        Self::from_settings(display, SsrSettings { max_distance, max_steps, thickness, ..Default::default() })
+    }
+
+    pub fn from_settings(display: &impl Facade, settings: SsrSettings) -> Self {
+        let ssr_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_ssr_fragment(), None);
+        let program = glium::Program::from_source(display, &ssr_shader.get_vertex_shader(), &ssr_shader.get_fragment_shader(), None).expect("Failed to compile ssr shader program");
+
+        Self {
+            settings: settings.clamped(),
+            program,
+            dummy_sky: SrgbTexture2d::empty(display, 1, 1).expect("Failed to create ssr dummy sky texture"),
+        }
+    }
+
+    pub fn get_settings(&self) -> SsrSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: SsrSettings) {
+        self.settings = settings.clamped();
+    }
+
+    pub fn set_max_distance(&mut self, max_distance: f32) {
+        self.set_settings(SsrSettings { max_distance, ..self.settings });
+    }
+
+    pub fn set_max_steps(&mut self, max_steps: i32) {
+        self.set_settings(SsrSettings { max_steps, ..self.settings });
+    }
+
+    pub fn set_thickness(&mut self, thickness: f32) {
+        self.set_settings(SsrSettings { thickness, ..self.settings });
+    }
+
+    pub fn set_intensity(&mut self, intensity: f32) {
+        self.set_settings(SsrSettings { intensity, ..self.settings });
+    }
+
+    pub fn set_max_roughness(&mut self, max_roughness: f32) {
+        self.set_settings(SsrSettings { max_roughness, ..self.settings });
+    }
+}
+
+impl PostProcessingEffect for Ssr {
+    fn render(&self, _app_state: &AppState, _vertex_buffer: &VertexBuffer<Vertex>, _index_buffer: &IndexBuffer<u32>, _target: &mut SimpleFrameBuffer, _source: &Texture2d, _depth_source: &DepthTexture2d, _buffer_textures: &Vec<Texture2d>) {
+        EnigmaWarning::new(Some("Ssr needs the surface buffer, render it with render_with_surface()."), true).log();
+    }
+
+    fn needs_surface_buffer(&self) -> bool {
+        true
+    }
+
+    fn render_with_surface(&self, app_state: &AppState, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, target: &mut SimpleFrameBuffer, sources: &PostProcessSources) {
+        let camera = match app_state.camera.as_ref() {
+            Some(camera) => camera,
+            None => {
+                EnigmaWarning::new(Some("Ssr needs a camera to trace reflections, skipping the effect."), true).log();
+                return;
+            }
+        };
+        // the sky the lit shader reflects, i.e. the albedo of the skybox material
+        let skybox = app_state.get_skybox().as_ref()
+            .and_then(|skybox| skybox.get_materials().first())
+            .and_then(|uuid| app_state.get_material(uuid))
+            .and_then(|material| material.albedo.as_ref());
+
+        let uniforms = uniform! {
+            scene: sources.source.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Linear)
+                .magnify_filter(MagnifySamplerFilter::Linear),
+            depth: sources.depth.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            surface: sources.surface.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            skybox: match skybox {
+                Some(texture) => texture.texture.sampled(),
+                None => self.dummy_sky.sampled(),
+            },
+            has_skybox: skybox.is_some(),
+            projection_matrix: camera.get_projection_matrix(),
+            view_matrix: camera.get_view_matrix(),
+            near: camera.near,
+            far: camera.far,
+            max_distance: self.settings.max_distance,
+            max_steps: self.settings.max_steps,
+            thickness: self.settings.thickness,
+            intensity: self.settings.intensity,
+            max_roughness: self.settings.max_roughness,
+        };
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::DepthTest::Overwrite,
+                write: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        target.draw(vertex_buffer, index_buffer, &self.program, &uniforms, &params).expect("Failed to draw ssr pass");
+    }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::Ssr(self.settings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_are_clamped() {
+        let settings = SsrSettings { max_distance: -1.0, max_steps: 0, thickness: -0.5, intensity: -2.0, max_roughness: 0.0 }.clamped();
+        assert_eq!(settings, SsrSettings { max_distance: 0.0, max_steps: 1, thickness: 0.0, intensity: 0.0, max_roughness: 0.01 });
+
+        let settings = SsrSettings { max_steps: SSR_MAX_STEPS + 1, max_roughness: 3.0, ..Default::default() }.clamped();
+        assert_eq!(settings.max_steps, SSR_MAX_STEPS);
+        assert_eq!(settings.max_roughness, 1.0);
+        assert_eq!(SsrSettings::default().clamped(), SsrSettings::default());
+    }
+
+    #[test]
+    fn serializer_round_trip_keeps_the_settings() {
+        let serializer = PostProcessingEffectSerializer::Ssr(SsrSettings { max_distance: 12.0, max_steps: 48, thickness: 0.3, intensity: 0.7, max_roughness: 0.4 });
+        let json = serde_json::to_string(&serializer).unwrap();
+        assert_eq!(serde_json::from_str::<PostProcessingEffectSerializer>(&json).unwrap(), serializer);
+        // scenes saved before a setting existed get its default
+        let loaded: PostProcessingEffectSerializer = serde_json::from_str(r#"{"Ssr":{"max_distance":5.0}}"#).unwrap();
+        assert_eq!(loaded, PostProcessingEffectSerializer::Ssr(SsrSettings { max_distance: 5.0, ..Default::default() }));
+    }
+}
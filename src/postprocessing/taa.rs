@@ -0,0 +1,187 @@
+use glium::{implement_vertex, IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::backend::Facade;
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction};
+use crate::geometry::Vertex;
+use crate::postprocessing::PostProcessSources;
+use crate::shadow::mat4_mul;
+use crate::{resources, shader};
+
+/// Number of jitter positions before the sequence repeats.
+pub const TAA_JITTER_SAMPLES: u32 = 8;
+
+/// Settings of the temporal anti-aliasing, enabled with `AppState::set_taa`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TaaSettings {
+    /// How much of the history is kept each frame, higher is smoother but ghosts more.
+    pub feedback: f32,
+    /// Scales the sub-pixel camera jitter, 1.0 jitters across one pixel.
+    pub jitter_scale: f32,
+}
+
+impl Default for TaaSettings {
+    fn default() -> Self {
+        Self {
+            feedback: 0.9,
+            jitter_scale: 1.0,
+        }
+    }
+}
+
+/// Element `index` of the Halton low discrepancy sequence in `base`, in [0, 1).
+pub fn halton(mut index: u32, base: u32) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0 / base as f32;
+    while index > 0 {
+        result += (index % base) as f32 * fraction;
+        index /= base;
+        fraction /= base as f32;
+    }
+    result
+}
+
+/// Sub-pixel offset of `frame` in normalized device coordinates, for a target of `width` x `height` pixels.
+pub fn jitter_offset(frame: u32, width: u32, height: u32, scale: f32) -> [f32; 2] {
+    // Halton starts at 0, skip it so the first frame is jittered as well
+    let index = frame % TAA_JITTER_SAMPLES + 1;
+    [
+        (halton(index, 2) - 0.5) * 2.0 / width.max(1) as f32 * scale,
+        (halton(index, 3) - 0.5) * 2.0 / height.max(1) as f32 * scale,
+    ]
+}
+
+/// Per instance data of the motion vector pass.
+#[derive(Copy, Clone, Debug)]
+pub struct MotionInstanceAttribute {
+    pub model_matrix: [[f32; 4]; 4],
+    pub previous_model_matrix: [[f32; 4]; 4],
+}
+implement_vertex!(MotionInstanceAttribute, model_matrix, previous_model_matrix);
+
+/// GPU state of the temporal anti-aliasing: the motion vector target, the history of resolved frames and
+/// the view-projection of the last frame. Owned by the `Renderer`.
+pub struct TemporalResolve {
+    pub velocity_texture: Texture2d,
+    history_texture: Texture2d,
+    history_valid: bool,
+    previous_view_projection: Option<[[f32; 4]; 4]>,
+    pub frame: u32,
+    pub motion_program: glium::Program,
+    resolve_program: glium::Program,
+    copy_program: glium::Program,
+}
+
+impl TemporalResolve {
+    pub fn new(display: &impl Facade, width: u32, height: u32) -> Self {
+        let motion_program = glium::Program::from_source(display, resources::taa_motion_vertex(), resources::taa_motion_fragment(), None)
+            .expect("Failed to compile motion vector shader program");
+        let resolve_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_taa_fragment(), None);
+        let resolve_program = glium::Program::from_source(display, &resolve_shader.get_vertex_shader(), &resolve_shader.get_fragment_shader(), None)
+            .expect("Failed to compile taa resolve shader program");
+        Self {
+            velocity_texture: Texture2d::empty_with_format(display, UncompressedFloatFormat::F16F16F16F16, MipmapsOption::NoMipmap, width, height)
+                .expect("Failed to create velocity texture"),
+            history_texture: Texture2d::empty_with_format(display, UncompressedFloatFormat::F16F16F16F16, MipmapsOption::NoMipmap, width, height)
+                .expect("Failed to create taa history texture"),
+            history_valid: false,
+            previous_view_projection: None,
+            frame: 0,
+            motion_program,
+            resolve_program,
+            copy_program: crate::postprocessing::get_screen_program(display),
+        }
+    }
+
+    /// The unjittered view-projection of the last resolved frame, or `current` on the first frame.
+    pub fn previous_view_projection(&self, current: [[f32; 4]; 4]) -> [[f32; 4]; 4] {
+        self.previous_view_projection.unwrap_or(current)
+    }
+
+    /// Forgets the history, e.g. after a camera cut or when TAA was switched off.
+    pub fn reset(&mut self) {
+        self.history_valid = false;
+        self.previous_view_projection = None;
+    }
+
+    /// Blends the source of `sources` with the reprojected history into `target`. `view_projection` is the
+    /// unjittered view-projection of this frame. Call `store_history` with the texture behind `target` afterwards.
+    pub fn resolve(&mut self, settings: &TaaSettings, view_projection: [[f32; 4]; 4], vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, target: &mut SimpleFrameBuffer, sources: &PostProcessSources) {
+        let previous_view_projection = self.previous_view_projection(view_projection);
+        let inverse_view_projection = nalgebra::Matrix4::from(view_projection)
+            .try_inverse()
+            .map(|m| m.into())
+            .unwrap_or(view_projection);
+        // reprojects a current clip space position straight into the previous frame
+        let reprojection = mat4_mul(previous_view_projection, inverse_view_projection);
+
+        let uniforms = uniform! {
+            scene: sources.source.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            history: self.history_texture.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Linear)
+                .magnify_filter(MagnifySamplerFilter::Linear),
+            velocity: self.velocity_texture.sampled()
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            depth: sources.depth.sampled()
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            reprojection: reprojection,
+            feedback: if self.history_valid { settings.feedback.clamp(0.0, 0.98) } else { 0.0 },
+        };
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::DepthTest::Overwrite,
+                write: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        target.draw(vertex_buffer, index_buffer, &self.resolve_program, &uniforms, &params).expect("Failed to draw taa resolve pass");
+
+        self.history_valid = true;
+        self.previous_view_projection = Some(view_projection);
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    /// Copies the resolved image into the history for the next frame.
+    pub fn store_history(&self, display: &impl Facade, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, resolved: &Texture2d) {
+        let mut history_framebuffer = SimpleFrameBuffer::new(display, &self.history_texture).expect("Failed to create taa history framebuffer");
+        let uniforms = uniform! { scene: resolved };
+        history_framebuffer.draw(vertex_buffer, index_buffer, &self.copy_program, &uniforms, &Default::default()).expect("Failed to copy taa history");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halton_sequence() {
+        assert_eq!(halton(0, 2), 0.0);
+        assert_eq!(halton(1, 2), 0.5);
+        assert_eq!(halton(2, 2), 0.25);
+        assert_eq!(halton(3, 2), 0.75);
+        assert!((halton(1, 3) - 1.0 / 3.0).abs() < 1e-6);
+        assert!((halton(5, 3) - 7.0 / 9.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn jitter_stays_within_one_pixel_and_repeats() {
+        let (width, height) = (1920, 1080);
+        let mut offsets = Vec::new();
+        for frame in 0..TAA_JITTER_SAMPLES {
+            let [x, y] = jitter_offset(frame, width, height, 1.0);
+            // one pixel is 2 / size in normalized device coordinates
+            assert!(x.abs() <= 1.0 / width as f32 && y.abs() <= 1.0 / height as f32);
+            assert!(x != 0.0 || y != 0.0);
+            offsets.push([x, y]);
+        }
+        assert_eq!(jitter_offset(TAA_JITTER_SAMPLES, width, height, 1.0), offsets[0]);
+        assert_eq!(jitter_offset(3, width, height, 0.0), [0.0, 0.0]);
+    }
+}
@@ -0,0 +1,267 @@
+use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::pixel_buffer::PixelBuffer;
+use glium::backend::Facade;
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction};
+use serde::{Deserialize, Serialize};
+use crate::geometry::Vertex;
+use crate::{AppState, resources, shader};
+
+/// Size of the downsampled log luminance image the auto exposure histogram is built from.
+const LUMINANCE_SIZE: u32 = 64;
+const HISTOGRAM_BINS: usize = 64;
+
+/// The curve that maps the HDR scene color into the displayable range.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TonemapOperator {
+    /// Only applies the exposure and clips, which looks like the old 8-bit pipeline.
+    #[default]
+    None,
+    Reinhard,
+    Aces,
+    AgX,
+}
+
+impl TonemapOperator {
+    fn uniform_value(&self) -> i32 {
+        match self {
+            TonemapOperator::None => 0,
+            TonemapOperator::Reinhard => 1,
+            TonemapOperator::Aces => 2,
+            TonemapOperator::AgX => 3,
+        }
+    }
+}
+
+/// Automatic exposure from a histogram of the scene luminance.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoExposure {
+    /// Range of the histogram in log2 luminance, darker and brighter pixels land in the first and last bin.
+    pub min_log_luminance: f32,
+    pub max_log_luminance: f32,
+    /// Fraction of the darkest and brightest pixels ignored for the average, so a few very bright or dark pixels don't pump the exposure.
+    pub low_percentile: f32,
+    pub high_percentile: f32,
+    /// Exposure compensation in stops.
+    pub compensation: f32,
+    /// Adaptation speeds towards a brighter and a darker scene, higher is faster.
+    pub speed_up: f32,
+    pub speed_down: f32,
+}
+
+impl Default for AutoExposure {
+    fn default() -> Self {
+        Self {
+            min_log_luminance: -8.0,
+            max_log_luminance: 4.0,
+            low_percentile: 0.5,
+            high_percentile: 0.95,
+            compensation: 0.0,
+            speed_up: 3.0,
+            speed_down: 1.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Exposure {
+    /// A fixed multiplier applied to the scene color before tonemapping.
+    Manual(f32),
+    Auto(AutoExposure),
+}
+
+impl Default for Exposure {
+    fn default() -> Self {
+        Exposure::Manual(1.0)
+    }
+}
+
+/// Counts log2 luminance values into `bins` equally sized bins between `min_log` and `max_log`.
+pub fn luminance_histogram(log_luminances: &[f32], bins: usize, min_log: f32, max_log: f32) -> Vec<u32> {
+    let mut histogram = vec![0u32; bins.max(1)];
+    let range = (max_log - min_log).max(f32::EPSILON);
+    for value in log_luminances {
+        let t = ((value - min_log) / range).clamp(0.0, 1.0);
+        let bin = ((t * bins as f32) as usize).min(histogram.len() - 1);
+        histogram[bin] += 1;
+    }
+    histogram
+}
+
+/// Average log2 luminance of the histogram, ignoring the pixels below `low_percentile` and above `high_percentile`.
+pub fn histogram_average(histogram: &[u32], min_log: f32, max_log: f32, low_percentile: f32, high_percentile: f32) -> f32 {
+    let total: u32 = histogram.iter().sum();
+    if total == 0 {
+        return min_log;
+    }
+    let low = total as f32 * low_percentile.clamp(0.0, 1.0);
+    let high = total as f32 * high_percentile.clamp(low_percentile, 1.0);
+    let bin_size = (max_log - min_log) / histogram.len() as f32;
+    let (mut sum, mut weight, mut seen) = (0.0f32, 0.0f32, 0.0f32);
+    for (bin, count) in histogram.iter().enumerate() {
+        let start = seen;
+        seen += *count as f32;
+        // the part of this bin between the percentiles
+        let used = (seen.min(high) - start.max(low)).max(0.0);
+        sum += used * (min_log + (bin as f32 + 0.5) * bin_size);
+        weight += used;
+    }
+    if weight <= 0.0 {
+        return min_log;
+    }
+    sum / weight
+}
+
+/// Exposure that maps the average luminance to middle gray.
+pub fn exposure_from_log_luminance(average_log_luminance: f32, compensation: f32) -> f32 {
+    0.18 * 2f32.powf(compensation - average_log_luminance)
+}
+
+/// Moves `current` towards `target` exposure in stops, frame rate independent.
+pub fn adapt_exposure(current: f32, target: f32, settings: &AutoExposure, delta_time: f32) -> f32 {
+    let current_ev = current.max(f32::EPSILON).log2();
+    let target_ev = target.max(f32::EPSILON).log2();
+    // a lower exposure means the scene got brighter
+    let speed = if target_ev < current_ev { settings.speed_up } else { settings.speed_down };
+    let t = 1.0 - (-delta_time.max(0.0) * speed).exp();
+    2f32.powf(current_ev + (target_ev - current_ev) * t)
+}
+
+/// The final tonemapping stage of the `Renderer`. It keeps the adapted exposure between frames.
+pub struct ToneMapper {
+    program: glium::Program,
+    luminance_program: glium::Program,
+    luminance_texture: Texture2d,
+    // the luminance of the last frame, read back a frame late so the CPU doesn't wait for the GPU
+    luminance_readback: Option<PixelBuffer<(u8, u8, u8, u8)>>,
+    exposure: f32,
+}
+
+impl ToneMapper {
+    pub fn new(display: &impl Facade) -> Self {
+        let tonemap_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_tonemapping_fragment(), None);
+        let luminance_shader = shader::Shader::from_strings(resources::post_processing_vertex(), resources::post_processing_luminance_fragment(), None);
+        let program = glium::Program::from_source(display, &tonemap_shader.get_vertex_shader(), &tonemap_shader.get_fragment_shader(), None).expect("Failed to compile tonemapping shader program");
+        let luminance_program = glium::Program::from_source(display, &luminance_shader.get_vertex_shader(), &luminance_shader.get_fragment_shader(), None).expect("Failed to compile luminance shader program");
+        // the log luminance is stored normalized to the histogram range, 8 bits are plenty for the histogram bins
+        let luminance_texture = Texture2d::empty(display, LUMINANCE_SIZE, LUMINANCE_SIZE).expect("Failed to create luminance texture");
+        Self {
+            program,
+            luminance_program,
+            luminance_texture,
+            luminance_readback: None,
+            exposure: 1.0,
+        }
+    }
+
+    /// The exposure used for the last tonemapped frame.
+    pub fn get_exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    /// Updates the exposure from `source` and tonemaps it into `target`, with the operator and exposure of the app state.
+    pub fn render(&mut self, app_state: &AppState, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, target: &mut SimpleFrameBuffer, source: &Texture2d) {
+        self.exposure = match app_state.exposure {
+            Exposure::Manual(value) => value,
+            Exposure::Auto(settings) => {
+                let display = app_state.display.as_ref().expect("Auto exposure needs the app state display");
+                match self.measure_exposure(display, &settings, vertex_buffer, index_buffer, source) {
+                    Some(target_exposure) => adapt_exposure(self.exposure, target_exposure, &settings, app_state.delta_time),
+                    None => self.exposure,
+                }
+            }
+        };
+
+        let uniforms = uniform! {
+            scene: source.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest),
+            exposure: self.exposure,
+            tonemap_operator: app_state.tonemap_operator.uniform_value(),
+        };
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::DepthTest::Overwrite,
+                write: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        target.draw(vertex_buffer, index_buffer, &self.program, &uniforms, &params).expect("Failed to draw tonemapping pass");
+    }
+
+    /// Renders the log luminance of `source` into a small texture and starts copying it into a pixel buffer.
+    /// The histogram is built from the copy of the previous frame, which the GPU has finished by now, so the
+    /// exposure lags one frame behind. Returns `None` until the first copy is available.
+    fn measure_exposure(&mut self, display: &impl Facade, settings: &AutoExposure, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u32>, source: &Texture2d) -> Option<f32> {
+        let previous = self.luminance_readback.take();
+        {
+            let mut luminance_framebuffer = SimpleFrameBuffer::new(display, &self.luminance_texture).expect("Failed to create luminance framebuffer");
+            let uniforms = uniform! {
+                scene: source.sampled()
+                    .wrap_function(SamplerWrapFunction::Clamp)
+                    .minify_filter(MinifySamplerFilter::Linear)
+                    .magnify_filter(MagnifySamplerFilter::Linear),
+                min_log_luminance: settings.min_log_luminance,
+                max_log_luminance: settings.max_log_luminance,
+            };
+            luminance_framebuffer.draw(vertex_buffer, index_buffer, &self.luminance_program, &uniforms, &Default::default()).expect("Failed to draw luminance pass");
+        }
+        self.luminance_readback = Some(self.luminance_texture.read_to_pixel_buffer());
+
+        let pixels: Vec<Vec<(u8, u8, u8, u8)>> = previous?.read_as_texture_2d().expect("Failed to read luminance pixel buffer");
+        let range = settings.max_log_luminance - settings.min_log_luminance;
+        let log_luminances: Vec<f32> = pixels.iter().flatten()
+            .map(|p| settings.min_log_luminance + p.0 as f32 / 255.0 * range)
+            .collect();
+        let histogram = luminance_histogram(&log_luminances, HISTOGRAM_BINS, settings.min_log_luminance, settings.max_log_luminance);
+        let average = histogram_average(&histogram, settings.min_log_luminance, settings.max_log_luminance, settings.low_percentile, settings.high_percentile);
+        Some(exposure_from_log_luminance(average, settings.compensation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_bins_and_clamps() {
+        let histogram = luminance_histogram(&[-20.0, -8.0, 0.0, 3.99, 100.0], 4, -8.0, 4.0);
+        assert_eq!(histogram, vec![2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn histogram_average_ignores_outliers() {
+        // 90 pixels at log luminance -1, 10 very bright ones
+        let mut values = vec![-1.0; 90];
+        values.extend(vec![3.9; 10]);
+        let histogram = luminance_histogram(&values, 120, -8.0, 4.0);
+        let average = histogram_average(&histogram, -8.0, 4.0, 0.0, 0.9);
+        assert!((average + 1.0).abs() < 0.1, "average {}", average);
+        let with_outliers = histogram_average(&histogram, -8.0, 4.0, 0.0, 1.0);
+        assert!(with_outliers > average);
+        assert_eq!(histogram_average(&[0; 8], -8.0, 4.0, 0.0, 1.0), -8.0);
+    }
+
+    #[test]
+    fn exposure_maps_average_to_middle_gray() {
+        let average = 0.5f32;
+        let exposure = exposure_from_log_luminance(average.log2(), 0.0);
+        assert!((average * exposure - 0.18).abs() < 1e-5);
+        // one stop of compensation doubles the exposure
+        assert!((exposure_from_log_luminance(average.log2(), 1.0) - exposure * 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn adaptation_converges_and_respects_direction() {
+        let settings = AutoExposure { speed_up: 10.0, speed_down: 1.0, ..Default::default() };
+        let brighter = adapt_exposure(1.0, 0.25, &settings, 0.1);
+        let darker = adapt_exposure(1.0, 4.0, &settings, 0.1);
+        // adapting to a brighter scene is faster
+        assert!((brighter.log2() + 2.0).abs() < (darker.log2() - 2.0).abs());
+        assert_eq!(adapt_exposure(1.0, 4.0, &settings, 0.0), 1.0);
+        assert!((adapt_exposure(1.0, 4.0, &settings, 100.0) - 4.0).abs() < 1e-3);
+    }
+}
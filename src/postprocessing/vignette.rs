@@ -1,8 +1,9 @@
-use glium::glutin::surface::WindowSurface;
+use std::any::Any;
+use glium::backend::Facade;
 use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
 use glium::framebuffer::SimpleFrameBuffer;
 use glium::texture::DepthTexture2d;
-use crate::postprocessing::PostProcessingEffect;
+use crate::postprocessing::{PostProcessingEffect, PostProcessingEffectSerializer};
 use crate::{AppState, resources, shader};
 use crate::geometry::Vertex;
 
@@ -15,7 +16,7 @@ pub struct Vignette {
 }
 
 impl Vignette {
-    pub fn new(display: &glium::Display<WindowSurface>, intensity: f32, falloff: f32, color: [f32; 3], opacity: f32) -> Self {
+    pub fn new(display: &impl Facade, intensity: f32, falloff: f32, color: [f32; 3], opacity: f32) -> Self {
         let vignette_shader = shader::Shader::from_strings(
             resources::post_processing_vertex(),
             resources::post_processing_vignette_fragment(),
@@ -82,4 +83,12 @@ impl PostProcessingEffect for Vignette {
             &params,
         ).unwrap();
     }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+
+    fn to_serializer(&self) -> Option<PostProcessingEffectSerializer> {
+        Some(PostProcessingEffectSerializer::Vignette { intensity: self.intensity, falloff: self.falloff, color: self.color, opacity: self.opacity })
+    }
 }
\ No newline at end of file
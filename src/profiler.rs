@@ -0,0 +1,376 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Instant;
+use glium::backend::Facade;
+use glium::draw_parameters::TimeElapsedQuery;
+use crate::culling::CullingStats;
+use crate::ui::GUIDrawFunction;
+use crate::AppState;
+
+/// What one frame cost. CPU times are in milliseconds and measured per phase of the frame,
+/// `gpu_ms` is the GPU time of the renderer's own draws (shadows, scene, skybox and the post-processing copies)
+/// if timer queries are supported. Terrain and post-processing effects draw with their own parameters and
+/// aren't part of it. GPU results arrive one or two frames late so the CPU never waits for them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    pub frame: u64,
+    pub draw_calls: usize,
+    pub triangles: usize,
+    pub instances: usize,
+    pub shadow_passes: usize,
+    pub post_process_passes: usize,
+    pub culling: CullingStats,
+    pub input_ms: f32,
+    pub update_ms: f32,
+    pub shadow_ms: f32,
+    pub scene_ms: f32,
+    pub post_process_ms: f32,
+    pub present_ms: f32,
+    pub gui_ms: f32,
+    pub frame_ms: f32,
+    pub gpu_ms: Option<f32>,
+}
+
+impl FrameStats {
+    /// Counts one draw call of `index_count` triangle list indices, drawn `instances` times.
+    pub fn add_draw(&mut self, index_count: usize, instances: usize) {
+        self.draw_calls += 1;
+        self.instances += instances;
+        self.triangles += index_count / 3 * instances;
+    }
+
+    /// Copies everything the renderer measures from `render` into these stats.
+    pub(crate) fn merge_render(&mut self, render: &FrameStats) {
+        self.draw_calls = render.draw_calls;
+        self.triangles = render.triangles;
+        self.instances = render.instances;
+        self.shadow_passes = render.shadow_passes;
+        self.post_process_passes = render.post_process_passes;
+        self.culling = render.culling;
+        self.shadow_ms = render.shadow_ms;
+        self.scene_ms = render.scene_ms;
+        self.post_process_ms = render.post_process_ms;
+        self.gpu_ms = render.gpu_ms;
+    }
+
+    pub fn csv_header() -> &'static str {
+        "frame,draw_calls,triangles,instances,shadow_passes,post_process_passes,objects_culled,shadow_casters_culled,terrain_tiles_culled,input_ms,update_ms,shadow_ms,scene_ms,post_process_ms,present_ms,gui_ms,frame_ms,gpu_ms"
+    }
+
+    pub fn to_csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{}",
+            self.frame, self.draw_calls, self.triangles, self.instances, self.shadow_passes, self.post_process_passes,
+            self.culling.objects_culled, self.culling.shadow_casters_culled, self.culling.terrain_tiles_culled,
+            self.input_ms, self.update_ms, self.shadow_ms, self.scene_ms, self.post_process_ms, self.present_ms, self.gui_ms, self.frame_ms,
+            self.gpu_ms.map(|ms| format!("{:.3}", ms)).unwrap_or_default(),
+        )
+    }
+}
+
+/// Frames whose GPU time is still unknown after this many frames are dropped.
+const MAX_PENDING_GPU_FRAMES: usize = 3;
+
+/// Collects `FrameStats` while a frame is running and keeps a rolling history of finished frames.
+pub struct FrameProfiler {
+    current: FrameStats,
+    last: FrameStats,
+    history: VecDeque<FrameStats>,
+    history_length: usize,
+    frame_start: Option<Instant>,
+    frame: u64,
+    // one timer query per GPU pass of the frame being rendered, the last one belongs to the running pass
+    gpu_passes: Vec<TimeElapsedQuery>,
+    // the passes of earlier frames, oldest first, read once the GPU is done with them
+    pending_gpu_frames: VecDeque<Vec<TimeElapsedQuery>>,
+    gpu_pass_ended: bool,
+    gpu_timer_supported: bool,
+}
+
+impl FrameProfiler {
+    pub fn new(history_length: usize) -> Self {
+        Self {
+            current: FrameStats::default(),
+            last: FrameStats::default(),
+            history: VecDeque::new(),
+            history_length,
+            frame_start: None,
+            frame: 0,
+            gpu_passes: Vec::new(),
+            pending_gpu_frames: VecDeque::new(),
+            gpu_pass_ended: false,
+            gpu_timer_supported: true,
+        }
+    }
+
+    /// The stats of the frame that is currently being built.
+    pub fn current_mut(&mut self) -> &mut FrameStats {
+        &mut self.current
+    }
+
+    /// Finishes the current frame, moves it into the history and starts a new one.
+    pub fn end_frame(&mut self) {
+        let now = Instant::now();
+        if let Some(start) = self.frame_start {
+            self.current.frame_ms = (now - start).as_secs_f32() * 1000.0;
+        }
+        self.frame_start = Some(now);
+        self.current.frame = self.frame;
+        self.frame += 1;
+
+        let finished = std::mem::take(&mut self.current);
+        self.history.push_back(finished.clone());
+        while self.history.len() > self.history_length {
+            self.history.pop_front();
+        }
+        self.last = finished;
+    }
+
+    /// Starts timing the GPU passes of a new frame and returns the GPU time of the latest earlier
+    /// frame the GPU has finished, in milliseconds.
+    pub(crate) fn begin_gpu_frame(&mut self, display: &impl Facade) -> Option<f32> {
+        if !self.gpu_passes.is_empty() {
+            self.pending_gpu_frames.push_back(std::mem::take(&mut self.gpu_passes));
+        }
+        let mut gpu_ms = None;
+        while let Some(frame) = self.pending_gpu_frames.front() {
+            // the GPU finishes queries in order, so once a query of the next frame is done, the queries
+            // of this frame that aren't ready were never used by a draw and count as zero
+            let next_started = self.pending_gpu_frames.get(1).is_some_and(|next| next.iter().any(|query| query.is_ready()));
+            if !next_started && !frame.iter().all(|query| query.is_ready()) {
+                break;
+            }
+            let frame = self.pending_gpu_frames.pop_front().expect("Failed to take finished GPU frame");
+            let nanoseconds: u64 = frame.into_iter().map(|query| query.get() as u64).sum();
+            gpu_ms = Some(nanoseconds as f32 / 1_000_000.0);
+        }
+        while self.pending_gpu_frames.len() > MAX_PENDING_GPU_FRAMES {
+            self.pending_gpu_frames.pop_front();
+        }
+        self.begin_gpu_pass(display);
+        gpu_ms
+    }
+
+    fn begin_gpu_pass(&mut self, display: &impl Facade) {
+        self.gpu_pass_ended = false;
+        if !self.gpu_timer_supported {
+            return;
+        }
+        match TimeElapsedQuery::new(display) {
+            Ok(query) => self.gpu_passes.push(query),
+            Err(_) => self.gpu_timer_supported = false,
+        }
+    }
+
+    /// Marks the running GPU pass as done. Any draw without its query, like terrain or post-processing
+    /// effects, ends the query and it can't be used again.
+    pub(crate) fn end_gpu_pass(&mut self) {
+        self.gpu_pass_ended = true;
+    }
+
+    /// Starts a new GPU pass if the running one was ended, so a query is only created right before a draw uses it.
+    pub(crate) fn resume_gpu_pass(&mut self, display: &impl Facade) {
+        if self.gpu_pass_ended {
+            self.begin_gpu_pass(display);
+        }
+    }
+
+    /// The timer query of the running GPU pass, `None` without timer query support.
+    pub(crate) fn get_gpu_query(&self) -> Option<&TimeElapsedQuery> {
+        self.gpu_passes.last()
+    }
+
+    /// The last finished frame.
+    pub fn get_last_frame(&self) -> &FrameStats {
+        &self.last
+    }
+
+    pub fn get_history(&self) -> &VecDeque<FrameStats> {
+        &self.history
+    }
+
+    pub fn set_history_length(&mut self, length: usize) {
+        self.history_length = length;
+        while self.history.len() > self.history_length {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn get_history_length(&self) -> usize {
+        self.history_length
+    }
+
+    /// Average frame time over the history in milliseconds.
+    pub fn get_average_frame_ms(&self) -> f32 {
+        if self.history.is_empty() {
+            return 0.0;
+        }
+        self.history.iter().map(|f| f.frame_ms).sum::<f32>() / self.history.len() as f32
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::new();
+        let _ = writeln!(csv, "{}", FrameStats::csv_header());
+        for frame in self.history.iter() {
+            let _ = writeln!(csv, "{}", frame.to_csv_row());
+        }
+        csv
+    }
+}
+
+pub(crate) fn elapsed_ms(start: Instant) -> f32 {
+    start.elapsed().as_secs_f32() * 1000.0
+}
+
+/// A built-in overlay showing the stats of the last frame and a frame time graph. Add it with
+/// `app_state.inject_gui(profiler::stats_overlay())`.
+pub fn stats_overlay() -> GUIDrawFunction {
+    Arc::new(|ctx: &egui::Context, app_state: &mut AppState| {
+        let profiler = app_state.get_profiler();
+        let stats = profiler.get_last_frame().clone();
+        let frame_times: Vec<f32> = profiler.get_history().iter().map(|f| f.frame_ms).collect();
+        let average = profiler.get_average_frame_ms();
+        let mut dump = false;
+
+        egui::Window::new("Frame Stats")
+            .default_width(240.0)
+            .show(ctx, |ui| {
+                ui.label(format!("Frame: {:.2} ms (avg {:.2} ms, {:.0} fps)", stats.frame_ms, average, if average > 0.0 { 1000.0 / average } else { 0.0 }));
+                match stats.gpu_ms {
+                    Some(gpu_ms) => ui.label(format!("GPU: {:.2} ms", gpu_ms)),
+                    None => ui.label("GPU: not available"),
+                };
+                ui.separator();
+                ui.label(format!("Draw calls: {}", stats.draw_calls));
+                ui.label(format!("Triangles: {}", stats.triangles));
+                ui.label(format!("Instances: {}", stats.instances));
+                ui.label(format!("Shadow passes: {}", stats.shadow_passes));
+                ui.label(format!("Post process passes: {}", stats.post_process_passes));
+                ui.label(format!("Culled: {} objects, {} shadow casters, {} terrain tiles",
+                    stats.culling.objects_culled, stats.culling.shadow_casters_culled, stats.culling.terrain_tiles_culled));
+                ui.separator();
+                ui.label(format!("Input: {:.2} ms", stats.input_ms));
+                ui.label(format!("Update: {:.2} ms", stats.update_ms));
+                ui.label(format!("Shadows: {:.2} ms", stats.shadow_ms));
+                ui.label(format!("Scene: {:.2} ms", stats.scene_ms));
+                ui.label(format!("Post processing: {:.2} ms", stats.post_process_ms));
+                ui.label(format!("Present: {:.2} ms", stats.present_ms));
+                ui.label(format!("GUI: {:.2} ms", stats.gui_ms));
+
+                // frame time graph, 33ms at the top
+                let (rect, _) = ui.allocate_exact_size(egui::vec2(ui.available_width(), 60.0), egui::Sense::hover());
+                let painter = ui.painter();
+                painter.rect_filled(rect, 0.0, egui::Color32::from_black_alpha(120));
+                if !frame_times.is_empty() {
+                    let bar_width = rect.width() / frame_times.len() as f32;
+                    for (i, ms) in frame_times.iter().enumerate() {
+                        let height = (ms / 33.3).min(1.0) * rect.height();
+                        let x = rect.left() + i as f32 * bar_width;
+                        let bar = egui::Rect::from_min_max(egui::pos2(x, rect.bottom() - height), egui::pos2(x + bar_width, rect.bottom()));
+                        let color = if *ms > 16.7 { egui::Color32::LIGHT_RED } else { egui::Color32::LIGHT_GREEN };
+                        painter.rect_filled(bar, 0.0, color);
+                    }
+                }
+
+                if ui.button("Dump history to frame_stats.csv").clicked() {
+                    dump = true;
+                }
+            });
+
+        if dump {
+            let _ = app_state.write_frame_history_csv("frame_stats.csv");
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_draw_counts_instanced_triangles() {
+        let mut stats = FrameStats::default();
+        stats.add_draw(36, 10);
+        stats.add_draw(6, 1);
+        assert_eq!(stats.draw_calls, 2);
+        assert_eq!(stats.instances, 11);
+        assert_eq!(stats.triangles, 122);
+    }
+
+    #[test]
+    fn history_is_rolling() {
+        let mut profiler = FrameProfiler::new(3);
+        for draws in 0..5 {
+            profiler.current_mut().draw_calls = draws;
+            profiler.end_frame();
+        }
+        assert_eq!(profiler.get_history().len(), 3);
+        assert_eq!(profiler.get_history()[0].draw_calls, 2);
+        assert_eq!(profiler.get_last_frame().draw_calls, 4);
+        assert_eq!(profiler.get_last_frame().frame, 4);
+        // the next frame starts empty
+        assert_eq!(profiler.current_mut().draw_calls, 0);
+    }
+
+    #[test]
+    fn csv_has_header_and_one_row_per_frame() {
+        let mut profiler = FrameProfiler::new(10);
+        profiler.current_mut().triangles = 12;
+        profiler.current_mut().gpu_ms = Some(1.5);
+        profiler.end_frame();
+        profiler.end_frame();
+        let csv = profiler.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], FrameStats::csv_header());
+        let columns = FrameStats::csv_header().split(',').count();
+        assert_eq!(lines[1].split(',').count(), columns);
+        assert!(lines[1].starts_with("0,0,12,"));
+        assert!(lines[1].ends_with(",1.500"));
+        // no gpu time is an empty column
+        assert!(lines[2].ends_with(','));
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "macos", target_os = "ios"))))]
+mod gl_tests {
+    use super::*;
+    use glium::{uniform, Surface, Texture2d};
+    use glium::framebuffer::SimpleFrameBuffer;
+    use crate::headless::test_display;
+    use crate::postprocessing;
+
+    #[test]
+    #[ignore]
+    fn gpu_time_survives_draws_without_the_query() {
+        let display = test_display();
+        let vertex_buffer = postprocessing::get_screen_vert_rect(&display);
+        let index_buffer = postprocessing::get_screen_indices_rect(&display);
+        let program = postprocessing::get_screen_program(&display);
+        let source = Texture2d::empty(&display, 16, 16).unwrap();
+        let target = Texture2d::empty(&display, 16, 16).unwrap();
+        let mut profiler = FrameProfiler::new(10);
+
+        let mut gpu_ms = None;
+        for _ in 0..4 {
+            gpu_ms = profiler.begin_gpu_frame(&display).or(gpu_ms);
+            let mut framebuffer = SimpleFrameBuffer::new(&display, &target).unwrap();
+            let draw = |framebuffer: &mut SimpleFrameBuffer, query: Option<&TimeElapsedQuery>| {
+                let params = glium::DrawParameters { time_elapsed_query: query, ..Default::default() };
+                framebuffer.draw(&vertex_buffer, &index_buffer, &program, &uniform! { scene: &source }, &params).unwrap();
+            };
+            draw(&mut framebuffer, profiler.get_gpu_query());
+            // e.g. a custom effect, ends the running query
+            draw(&mut framebuffer, None);
+            profiler.end_gpu_pass();
+            profiler.resume_gpu_pass(&display);
+            draw(&mut framebuffer, profiler.get_gpu_query());
+            // a pass without any draw
+            profiler.end_gpu_pass();
+            profiler.resume_gpu_pass(&display);
+            display.finish();
+        }
+        assert!(gpu_ms.is_some());
+    }
+}
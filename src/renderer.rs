@@ -0,0 +1,871 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use glium::{IndexBuffer, Surface, Texture2d, uniform, VertexBuffer};
+use glium::backend::Facade;
+use glium::draw_parameters::TimeElapsedQuery;
+use glium::texture::{DepthTexture2d, MipmapsOption, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, UniformBuffer, UniformValue, Uniforms};
+use uuid::Uuid;
+use crate::{postprocessing, resources, smart_format, AppState};
+use crate::culling::{self, Frustum, InstanceVisibility};
+use crate::geometry::{BoneTransforms, InstanceAttribute, Vertex};
+use crate::ibl::{Ibl, IblData};
+use crate::lighting::{LightingData, SceneLighting};
+use crate::logging::{EnigmaError, EnigmaWarning};
+use crate::mesh_cache::MeshCache;
+use crate::object::{Object, ObjectInstance};
+use crate::postprocessing::taa::{jitter_offset, MotionInstanceAttribute, TemporalResolve};
+use crate::postprocessing::tonemapping::ToneMapper;
+use crate::postprocessing::PostProcessSources;
+use crate::profiler::{self, FrameStats};
+use crate::shadow::ShadowMaps;
+use crate::shadow::{cascade_light_space_matrix, cascade_splits, directional_light_space_matrix, spot_light_space_matrix, MAX_CASCADES, view_matrix, perspective_90_matrix, mat4_mul, CUBE_FACE_DIRS, face_viewport};
+use crate::texture::Texture;
+
+const HDR_FORMAT: UncompressedFloatFormat = UncompressedFloatFormat::F16F16F16F16;
+
+/// Spot light shadows end at the light range, but never further than point light shadows reach.
+const SPOT_SHADOW_MAX_DISTANCE: f32 = 100.0;
+
+/// The light space matrix of one shadow map render and the part of the shadow texture it draws into.
+type ShadowPass = ([[f32; 4]; 4], Option<glium::Rect>);
+
+/// Owns every GPU resource needed to turn an `AppState` into a finished image: the scene color
+/// and depth targets, the post-processing ping-pong buffers, the shadow maps and the internal
+/// programs. It is shared by the windowed `EventLoop` and the `HeadlessRenderer`, so both go
+/// through exactly the same shadow, opaque, terrain, skybox, transparent and post-processing passes.
+pub struct Renderer {
+    texture: Texture2d,
+    depth_texture: DepthTexture2d,
+    // view space normals, roughness and linear depth for screen-space effects, see `PostProcessingEffect::needs_surface_buffer`
+    surface_texture: Texture2d,
+    buffer_textures: Vec<Texture2d>,
+    shadow_maps: ShadowMaps,
+    shadow_dir_program: glium::Program,
+    shadow_point_program: glium::Program,
+    screen_vert_rect: VertexBuffer<Vertex>,
+    screen_indices_rect: IndexBuffer<u32>,
+    screen_program: glium::Program,
+    tone_mapper: ToneMapper,
+    // temporal anti-aliasing, only used while the app state has TAA settings
+    temporal: TemporalResolve,
+    previous_model_matrices: HashMap<Uuid, Vec<[[f32; 4]; 4]>>,
+    motion_instances: VertexBuffer<MotionInstanceAttribute>,
+    skybox_texture: Texture,
+    // image based lighting of the app state's environment, uploaded again when the environment changes
+    ibl: Ibl,
+    ibl_source: Option<Arc<IblData>>,
+    mesh_cache: MeshCache,
+    // instances that survived culling when only some instances of an object are visible
+    culled_instances: VertexBuffer<InstanceAttribute>,
+    // lights and cluster textures, kept between frames and only reallocated when they need to grow
+    lighting: LightingData,
+}
+
+/// Creates the skybox object and texture for `app_state`. If the app state already holds a skybox,
+/// its albedo texture is reused, otherwise the internal default skybox is created.
+pub(crate) fn spawn_skybox(display: &impl Facade, app_state: &mut AppState) -> (Object, Texture) {
+    if let Some(current_skybox_object) = app_state.get_skybox().clone() {
+        // If we have an existing skybox, try to get its texture
+        if let Some(texture_uuid) = current_skybox_object.get_materials().first() {
+            if let Some(material) = app_state.get_material(texture_uuid) {
+                if let Some(texture) = &material.albedo {
+                    // Successfully found texture, clone it and return with the existing object
+                    return (
+                        current_skybox_object,
+                        texture.get_texture_clone(display)
+                    );
+                }
+            }
+        }
+
+        // If we reached here, we couldn't get the texture from the existing skybox
+        let mut logger = EnigmaWarning::new(None, true);
+        logger.extent("Failed to get texture from existing skybox. Creating default skybox...");
+        logger.log();
+    }
+
+    let mut material = crate::material::Material::unlit(display.get_context().clone(), false);
+    material.set_name("INTERNAL::SkyBox");
+
+    material.set_texture_from_resource(resources::skybox_texture(), crate::material::TextureType::Albedo);
+
+    // create a default object
+    let mut object = Object::load_from_gltf_resource(resources::skybox(), None);
+
+    // set the material
+    object.add_material(material.uuid);
+    object.get_shapes_mut()[0].set_material_from_object_list(0);
+
+    object.name = "Skybox".to_string();
+
+    object.transform.set_scale([1.0, 1.0, 1.0]);
+
+    app_state.add_material(material);
+    // skybox texture
+    let skybox_texture = Texture::from_resource(display, resources::skybox_texture());
+    (object, skybox_texture)
+}
+
+/// The material uniforms with `surface_pass` switched on. The material sets it to false itself, this is
+/// visited afterwards and overrides it.
+struct SurfacePass<'u, U: Uniforms>(&'u U);
+
+impl<'u, U: Uniforms> Uniforms for SurfacePass<'u, U> {
+    fn visit_values<'a, F: FnMut(&str, UniformValue<'a>)>(&'a self, mut output: F) {
+        self.0.visit_values(&mut output);
+        output("surface_pass", UniformValue::Bool(true));
+    }
+}
+
+/// Writes the current and last frame's model matrix of every instance that is visible in `visibility` into
+/// `buffer`, growing it if necessary, and returns how many were written. Instances without a matrix last
+/// frame, e.g. because the instance count changed, get no object motion.
+fn write_motion_instances(display: &impl Facade, buffer: &mut VertexBuffer<MotionInstanceAttribute>, instance: &ObjectInstance, visibility: &InstanceVisibility, previous: Option<&Vec<[[f32; 4]; 4]>>) -> usize {
+    let previous = previous.filter(|previous| previous.len() == instance.instance_matrices.len());
+    // the visible instances keep the order of `instance_matrices`, so they are matched in a single pass
+    let mut visible = match visibility {
+        InstanceVisibility::Partial(visible) => Some(visible.iter().peekable()),
+        _ => None,
+    };
+    let data: Vec<MotionInstanceAttribute> = instance.instance_matrices.iter().enumerate()
+        .filter(|(_, matrix)| match visible.as_mut() {
+            Some(visible) => visible.next_if(|attribute| attribute.model_matrix == **matrix).is_some(),
+            None => true,
+        })
+        .map(|(index, matrix)| MotionInstanceAttribute {
+            model_matrix: *matrix,
+            previous_model_matrix: previous.map_or(*matrix, |previous| previous[index]),
+        })
+        .collect();
+    if data.is_empty() {
+        return 0;
+    }
+    if buffer.len() < data.len() {
+        *buffer = VertexBuffer::empty_dynamic(display, data.len().next_power_of_two()).expect("Failed to grow motion instance buffer");
+    }
+    buffer.slice_mut(0..data.len()).expect("Motion instance buffer is too small").write(&data);
+    data.len()
+}
+
+/// Writes the visible instances into `buffer`, growing it if necessary.
+fn write_culled_instances(display: &impl Facade, buffer: &mut VertexBuffer<InstanceAttribute>, visibility: &InstanceVisibility) {
+    if let InstanceVisibility::Partial(visible) = visibility {
+        if buffer.len() < visible.len() {
+            *buffer = VertexBuffer::empty_dynamic(display, visible.len().next_power_of_two()).expect("Failed to grow culled instance buffer");
+        }
+        buffer.slice_mut(0..visible.len()).expect("Culled instance buffer is too small").write(visible);
+    }
+}
+
+/// The instance attributes to draw `instance` with, after `write_culled_instances` was called for `visibility`.
+/// Bind the result to a local, the per instance source of the draw call borrows it.
+fn instance_source<'a>(visibility: &InstanceVisibility, instance: &'a ObjectInstance, culled_instances: &'a VertexBuffer<InstanceAttribute>) -> glium::vertex::VertexBufferSlice<'a, InstanceAttribute> {
+    match visibility {
+        InstanceVisibility::Partial(visible) => culled_instances.slice(0..visible.len())
+            .expect("Culled instance buffer is too small"),
+        _ => instance.instance_slice(),
+    }
+}
+
+impl Renderer {
+    /// `width` and `height` are the output size in pixels; the internal targets are scaled by
+    /// the app state's render scale.
+    pub fn new(display: &impl Facade, app_state: &AppState, width: u32, height: u32, skybox_texture: Texture) -> Self {
+        let scaled_width = width * app_state.render_scale;
+        let scaled_height = height * app_state.render_scale;
+
+        // the scene and post-processing targets are floating point, the tonemapping stage maps them into the displayable range
+        let texture = Texture2d::empty_with_format(display, HDR_FORMAT, MipmapsOption::NoMipmap, scaled_width, scaled_height).expect("Failed to create texture");
+        let depth_texture = DepthTexture2d::empty(display, scaled_width, scaled_height).expect("Failed to create depth texture");
+        let surface_texture = Texture2d::empty_with_format(display, HDR_FORMAT, MipmapsOption::NoMipmap, scaled_width, scaled_height).expect("Failed to create surface texture");
+
+        let mut buffer_textures: Vec<Texture2d> = Vec::new();
+        for _ in 0..app_state.max_buffers {
+            buffer_textures.push(Texture2d::empty_with_format(display, HDR_FORMAT, MipmapsOption::NoMipmap, scaled_width, scaled_height).expect("Failed to create texture"));
+        }
+
+        let shadow_maps = ShadowMaps::new(display, app_state.shadow_resolution);
+
+        let shadow_dir_program = glium::Program::from_source(
+            display,
+            resources::shadow_depth_vert_shader(),
+            resources::shadow_depth_dir_frag_shader(),
+            None,
+        ).expect("Failed to compile directional shadow shader");
+
+        let shadow_point_program = glium::Program::from_source(
+            display,
+            resources::shadow_depth_vert_shader(),
+            resources::shadow_depth_point_frag_shader(),
+            None,
+        ).expect("Failed to compile point shadow shader");
+
+        Self {
+            texture,
+            depth_texture,
+            surface_texture,
+            buffer_textures,
+            shadow_maps,
+            shadow_dir_program,
+            shadow_point_program,
+            screen_vert_rect: postprocessing::get_screen_vert_rect(display),
+            screen_indices_rect: postprocessing::get_screen_indices_rect(display),
+            screen_program: postprocessing::get_screen_program(display),
+            tone_mapper: ToneMapper::new(display),
+            temporal: TemporalResolve::new(display, scaled_width, scaled_height),
+            previous_model_matrices: HashMap::new(),
+            motion_instances: VertexBuffer::empty_dynamic(display, 64).expect("Failed to create motion instance buffer"),
+            skybox_texture,
+            ibl: Ibl::disabled(display),
+            ibl_source: None,
+            mesh_cache: MeshCache::new(),
+            culled_instances: VertexBuffer::empty_dynamic(display, 64).expect("Failed to create culled instance buffer"),
+            lighting: LightingData::new(display, app_state.light_clusters, [scaled_width as f32, scaled_height as f32]),
+        }
+    }
+
+    /// The final color target after all passes and post-processing effects.
+    pub fn get_texture(&self) -> &Texture2d {
+        &self.texture
+    }
+
+    /// The GPU buffers of all object instances, kept between frames.
+    pub fn get_mesh_cache(&self) -> &MeshCache {
+        &self.mesh_cache
+    }
+
+    pub fn get_mesh_cache_mut(&mut self) -> &mut MeshCache {
+        &mut self.mesh_cache
+    }
+
+    pub fn get_depth_texture(&self) -> &DepthTexture2d {
+        &self.depth_texture
+    }
+
+    /// Renders one frame of `app_state` into the internal color target.
+    pub fn render_frame(&mut self, display: &impl Facade, app_state: &mut AppState) {
+        let light = app_state.light.clone();
+        let ambient_light = app_state.ambient_light.clone();
+        let mut camera = app_state.camera.clone();
+        let taa = app_state.get_taa();
+        let (width, height) = self.texture.dimensions();
+        if let Some(camera) = camera.as_mut() {
+            // every frame samples a different spot inside the pixels, the resolve pass accumulates them
+            let jitter = match &taa {
+                Some(settings) => jitter_offset(self.temporal.frame, width, height, settings.jitter_scale),
+                None => [0.0, 0.0],
+            };
+            camera.set_jitter(jitter);
+        }
+        if taa.is_none() {
+            self.temporal.reset();
+        }
+
+        let environment_changed = match (&self.ibl_source, &app_state.environment_lighting) {
+            (Some(current), Some(new)) => !Arc::ptr_eq(current, new),
+            (None, None) => false,
+            _ => true,
+        };
+        if environment_changed {
+            self.ibl = match &app_state.environment_lighting {
+                Some(data) => Ibl::new(display, data),
+                None => Ibl::disabled(display),
+            };
+            self.ibl_source = app_state.environment_lighting.clone();
+        }
+        self.ibl.intensity = app_state.environment_intensity;
+
+        let mut framebuffer = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(display, &self.texture, &self.depth_texture).expect("Failed to create framebuffer");
+        let render_target = &mut framebuffer;
+        render_target.clear_color_and_depth((0.0, 0.0, 0.0, 1.0), 1.0);
+        app_state.update_hierarchy();
+        let model_matrices: HashMap<Uuid, [[f32; 4]; 4]> = app_state.objects.iter().map(|x| (x.get_unique_id(), x.transform.get_world_matrix())).collect();
+        let bone_uniform_buffers: HashMap<Uuid, UniformBuffer<BoneTransforms>> = app_state.objects.iter_mut().map(|x| (x.get_unique_id(), x.get_bone_transform_buffer(display))).collect();
+        app_state.setup_instances(display, &model_matrices, &mut self.mesh_cache);
+        let cam_pos: [f32; 3] = match camera {
+            Some(ref c) => { let p = c.transform.get_position(); [p.x, p.y, p.z] }
+            None => [0.0, 0.0, 0.0],
+        };
+
+        //First get the matrix outside of the closure
+        let skybox_model_matrix = match app_state.get_skybox_mut() {
+            Some(obj) => {
+                // skybox should always be relative to the camera
+                obj.transform.set_position(cam_pos);
+                Some(obj.transform.get_matrix().clone())
+            },
+            None => None
+        };
+        app_state.setup_skybox_instance(display, &skybox_model_matrix, &mut self.mesh_cache);
+
+        let object_instances = &self.mesh_cache;
+        let culling_enabled = app_state.frustum_culling;
+        let mut frame_stats = FrameStats {
+            gpu_ms: app_state.get_profiler_mut().begin_gpu_frame(display),
+            ..Default::default()
+        };
+        // the renderer times its own draws with the query of the running GPU pass, the terrain and the
+        // effects draw without it and end the pass
+        let gpu_query = app_state.get_profiler().get_gpu_query();
+        self.lighting.update(display, &light, camera.as_ref(), app_state.light_clusters, [width as f32, height as f32]);
+
+        // --- Shadow pass ---
+        let shadow_start = Instant::now();
+        if self.shadow_maps.resolution != app_state.shadow_resolution {
+            self.shadow_maps = ShadowMaps::new(display, app_state.shadow_resolution);
+        }
+        let shadow_maps = &mut self.shadow_maps;
+        shadow_maps.clear();
+
+        let shadow_draw_params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: true,
+                ..Default::default()
+            },
+            backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
+            time_elapsed_query: gpu_query,
+            ..Default::default()
+        };
+
+        // shadow map i belongs to slot light i
+        for (light_index, light_item) in self.lighting.get_slot_lights().iter().enumerate() {
+            if !light_item.cast_shadow { continue; }
+
+            if light_item.is_directional() || light_item.is_spot() {
+                // --- Directional or spot shadow map, the first directional light gets cascades ---
+                let res = shadow_maps.resolution;
+                let (passes, program, far_plane): (Vec<ShadowPass>, _, f32) = match (light_item.spot, camera.as_ref()) {
+                    (Some(cone), _) => {
+                        let far_plane = light_item.get_effective_range().min(SPOT_SHADOW_MAX_DISTANCE);
+                        let lsm = spot_light_space_matrix(light_item.position, light_item.direction, cone.outer_angle, far_plane);
+                        (vec![(lsm, None)], &self.shadow_point_program, far_plane)
+                    }
+                    (None, Some(cam)) if shadow_maps.cascade_slot.is_none() => {
+                        let near = cam.get_near();
+                        let far = app_state.shadow_distance.min(cam.get_far());
+                        let count = app_state.shadow_cascades.clamp(1, MAX_CASCADES);
+                        let splits = cascade_splits(near, far, count, app_state.shadow_cascade_lambda);
+                        let (view, projection) = (cam.get_view_matrix(), cam.get_projection_matrix());
+                        let mut passes = Vec::with_capacity(count);
+                        let mut split_near = near;
+                        for (cascade, split_far) in splits.into_iter().enumerate() {
+                            let lsm = cascade_light_space_matrix(light_item.direction, view, projection, split_near, split_far, res, app_state.shadow_distance);
+                            shadow_maps.cascade_matrices[cascade] = lsm;
+                            shadow_maps.cascade_splits[cascade] = split_far;
+                            passes.push((lsm, Some(glium::Rect { left: cascade as u32 * res, bottom: 0, width: res, height: res })));
+                            split_near = split_far;
+                        }
+                        shadow_maps.cascade_slot = Some(light_index);
+                        shadow_maps.cascade_count = count;
+                        (passes, &self.shadow_dir_program, far)
+                    }
+                    (None, _) => {
+                        let half = app_state.shadow_distance;
+                        (vec![(directional_light_space_matrix(light_item.direction, cam_pos, half), None)], &self.shadow_dir_program, half * 2.0)
+                    }
+                };
+                let cascaded = shadow_maps.cascade_slot == Some(light_index);
+                shadow_maps.light_space_matrices[light_index] = passes[0].0;
+                shadow_maps.point_far_planes[light_index] = far_plane;
+
+                // cascades are rendered side by side into one texture
+                let shadow_tex = glium::texture::Texture2d::empty_with_format(
+                    display,
+                    glium::texture::UncompressedFloatFormat::F32,
+                    glium::texture::MipmapsOption::NoMipmap,
+                    if cascaded { res * MAX_CASCADES as u32 } else { res },
+                    res,
+                ).expect("Failed to create directional shadow texture");
+
+                {
+                    let mut fb = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
+                        display,
+                        &shadow_tex,
+                        if cascaded { &shadow_maps.cascade_depth_rb } else { &shadow_maps.dir_depth_rb },
+                    ).expect("Failed to create directional shadow framebuffer");
+                    fb.clear_color_and_depth((1.0, 0.0, 0.0, 1.0), 1.0);
+
+                    for (lsm, viewport) in passes.iter() {
+                        frame_stats.shadow_passes += 1;
+                        let pass_draw_params = glium::DrawParameters { viewport: *viewport, ..shadow_draw_params.clone() };
+                        let light_frustum = culling_enabled.then(|| Frustum::from_matrix(*lsm));
+
+                        for (instance_id, object_instance) in object_instances.iter() {
+                            if let Some(object) = app_state.get_object_by_uuid(instance_id) {
+                                if object.get_materials().is_empty() { continue; }
+                                let visibility = culling::cull_instances(light_frustum.as_ref(), object_instance);
+                                let (drawn, culled) = visibility.counts(object_instance.instance_matrices.len());
+                                frame_stats.culling.shadow_casters_drawn += drawn;
+                                frame_stats.culling.shadow_casters_culled += culled;
+                                if let InstanceVisibility::Hidden = visibility { continue; }
+                                write_culled_instances(display, &mut self.culled_instances, &visibility);
+                                let has_skeleton = object.get_skeleton().is_some();
+                                let bone_transform = bone_uniform_buffers.get(&object.get_unique_id())
+                                    .expect("Missing bone transforms in shadow pass");
+                                let morph_weights = object.get_morph_weight_uniforms();
+                                for (((buffer, _mat_index), indices), morph_targets) in object_instance.vertex_buffers.iter()
+                                    .zip(object_instance.index_buffers.iter())
+                                    .zip(object_instance.morph_buffers.iter())
+                                {
+                                    let uniforms = glium::uniform! {
+                                        light_space_matrix: *lsm,
+                                        has_skeleton: has_skeleton,
+                                        BoneTransforms: bone_transform,
+                                        morph_targets: morph_targets.texture.sampled().minify_filter(MinifySamplerFilter::Nearest).magnify_filter(MagnifySamplerFilter::Nearest),
+                                        morph_target_count: morph_targets.target_count,
+                                        morph_vertex_count: morph_targets.vertex_count,
+                                        morph_weights_0: [morph_weights[0], morph_weights[1], morph_weights[2], morph_weights[3]],
+                                        morph_weights_1: [morph_weights[4], morph_weights[5], morph_weights[6], morph_weights[7]],
+                                        light_pos: light_item.position,
+                                        far_plane: far_plane,
+                                    };
+                                    frame_stats.add_draw(indices.len(), drawn);
+                                    let instances = instance_source(&visibility, object_instance, &self.culled_instances);
+                                    fb.draw(
+                                        (buffer, instances.per_instance().expect("Error, unwrapping per instance")),
+                                        indices,
+                                        program,
+                                        &uniforms,
+                                        &pass_draw_params,
+                                    ).expect("Failed to draw shadow pass");
+                                }
+                            }
+                        }
+                    }
+                }
+                shadow_maps.directional_maps[light_index] = Some(shadow_tex);
+
+            } else {
+                // --- Point light shadow map (atlas) ---
+                let far_plane = 100.0f32;
+                shadow_maps.point_far_planes[light_index] = far_plane;
+                let res = shadow_maps.resolution;
+
+                let atlas_tex = glium::texture::Texture2d::empty_with_format(
+                    display,
+                    glium::texture::UncompressedFloatFormat::F32,
+                    glium::texture::MipmapsOption::NoMipmap,
+                    res * 2,
+                    res * 3,
+                ).expect("Failed to create point shadow atlas texture");
+
+                // Clear entire atlas to 1.0 (no shadow) before rendering faces
+                {
+                    let mut clear_fb = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
+                        display,
+                        &atlas_tex,
+                        &shadow_maps.point_depth_rb,
+                    ).expect("Failed to create atlas clear framebuffer");
+                    clear_fb.clear_color_and_depth((1.0, 0.0, 0.0, 1.0), 1.0);
+                }
+
+                let near = 0.1f32;
+                let proj = perspective_90_matrix(near, far_plane);
+                let lp = light_item.position;
+
+                for face in 0..6usize {
+                    let (dir, up) = CUBE_FACE_DIRS[face];
+                    let view = view_matrix(&lp, &dir, &up);
+                    let lsm = mat4_mul(proj, view);
+                    let viewport = face_viewport(face, res);
+                    frame_stats.shadow_passes += 1;
+
+                    let face_draw_params = glium::DrawParameters {
+                        depth: glium::Depth {
+                            test: glium::draw_parameters::DepthTest::IfLess,
+                            write: true,
+                            ..Default::default()
+                        },
+                        backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
+                        viewport: Some(viewport),
+                        time_elapsed_query: gpu_query,
+                        ..Default::default()
+                    };
+
+                    let mut fb = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
+                        display,
+                        &atlas_tex,
+                        &shadow_maps.point_depth_rb,
+                    ).expect("Failed to create point shadow framebuffer");
+                    let face_frustum = culling_enabled.then(|| Frustum::from_matrix(lsm));
+
+                    for (instance_id, object_instance) in object_instances.iter() {
+                        if let Some(object) = app_state.get_object_by_uuid(instance_id) {
+                            if object.get_materials().is_empty() { continue; }
+                            let visibility = culling::cull_instances(face_frustum.as_ref(), object_instance);
+                            let (drawn, culled) = visibility.counts(object_instance.instance_matrices.len());
+                            frame_stats.culling.shadow_casters_drawn += drawn;
+                            frame_stats.culling.shadow_casters_culled += culled;
+                            if let InstanceVisibility::Hidden = visibility { continue; }
+                            write_culled_instances(display, &mut self.culled_instances, &visibility);
+                            let has_skeleton = object.get_skeleton().is_some();
+                            let bone_transform = bone_uniform_buffers.get(&object.get_unique_id())
+                                .expect("Missing bone transforms in shadow pass");
+                            let morph_weights = object.get_morph_weight_uniforms();
+                            for (((buffer, _mat_index), indices), morph_targets) in object_instance.vertex_buffers.iter()
+                                .zip(object_instance.index_buffers.iter())
+                                .zip(object_instance.morph_buffers.iter())
+                            {
+                                let uniforms = glium::uniform! {
+                                    light_space_matrix: lsm,
+                                    has_skeleton: has_skeleton,
+                                    BoneTransforms: bone_transform,
+                                    morph_targets: morph_targets.texture.sampled().minify_filter(MinifySamplerFilter::Nearest).magnify_filter(MagnifySamplerFilter::Nearest),
+                                    morph_target_count: morph_targets.target_count,
+                                    morph_vertex_count: morph_targets.vertex_count,
+                                    morph_weights_0: [morph_weights[0], morph_weights[1], morph_weights[2], morph_weights[3]],
+                                    morph_weights_1: [morph_weights[4], morph_weights[5], morph_weights[6], morph_weights[7]],
+                                    light_pos: lp,
+                                    far_plane: far_plane,
+                                };
+                                frame_stats.add_draw(indices.len(), drawn);
+                                let instances = instance_source(&visibility, object_instance, &self.culled_instances);
+                                fb.draw(
+                                    (buffer, instances.per_instance().expect("Error, unwrapping per instance")),
+                                    indices,
+                                    &self.shadow_point_program,
+                                    &uniforms,
+                                    &face_draw_params,
+                                ).expect("Failed to draw shadow pass");
+                            }
+                        }
+                    }
+                }
+                shadow_maps.point_maps[light_index] = Some(atlas_tex);
+            }
+        }
+        frame_stats.shadow_ms = profiler::elapsed_ms(shadow_start);
+        // --- End shadow pass ---
+        let scene_start = Instant::now();
+        let scene_lighting = SceneLighting {
+            lighting: &self.lighting,
+            ambient_light: ambient_light.as_ref(),
+            skybox: &self.skybox_texture,
+            ibl: &self.ibl,
+            shadow_maps: &self.shadow_maps,
+        };
+
+        // render objects opaque
+        let opaque_rendering_parameter = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: true,
+                ..Default::default()
+            },
+            backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
+            time_elapsed_query: gpu_query,
+            ..Default::default()
+        };
+
+        let camera_frustum = match (culling_enabled, camera.as_ref()) {
+            (true, Some(camera)) => Some(Frustum::from_camera(camera)),
+            _ => None,
+        };
+        let surface_pass = app_state.get_post_processes().iter().any(|entry| entry.enabled && entry.effect.needs_surface_buffer());
+        let mut surface_framebuffer = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(display, &self.surface_texture, &self.depth_texture).expect("Failed to create surface framebuffer");
+        // w = 0 marks pixels without surface data
+        surface_framebuffer.clear_color(0.0, 0.0, 1.0, 0.0);
+        let surface_rendering_parameter = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLessOrEqual,
+                write: false,
+                ..Default::default()
+            },
+            backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
+            time_elapsed_query: gpu_query,
+            ..Default::default()
+        };
+
+        // screen space motion of opaque objects for the TAA resolve, pixels without it only move with the camera
+        let mut velocity_framebuffer = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(display, &self.temporal.velocity_texture, &self.depth_texture).expect("Failed to create velocity framebuffer");
+        velocity_framebuffer.clear_color(0.0, 0.0, 0.0, 0.0);
+        let (view_projection, unjittered_view_projection) = match camera.as_ref() {
+            Some(camera) => (
+                mat4_mul(camera.get_projection_matrix(), camera.get_view_matrix()),
+                mat4_mul(camera.get_unjittered_projection_matrix(), camera.get_view_matrix()),
+            ),
+            None => ([[0.0; 4]; 4], [[0.0; 4]; 4]),
+        };
+        let motion_uniforms = uniform! {
+            view_projection: view_projection,
+            current_view_projection: unjittered_view_projection,
+            previous_view_projection: self.temporal.previous_view_projection(unjittered_view_projection),
+        };
+
+        let mut visibilities: HashMap<Uuid, InstanceVisibility> = HashMap::new();
+        for (instance_id, object_instance) in object_instances.iter() {
+            let visibility = culling::cull_instances(camera_frustum.as_ref(), object_instance);
+            let (drawn, culled) = visibility.counts(object_instance.instance_matrices.len());
+            frame_stats.culling.objects_drawn += drawn;
+            frame_stats.culling.objects_culled += culled;
+            visibilities.insert(*instance_id, visibility);
+        }
+
+        for (instance_id, object_instance) in object_instances.iter() {
+            let visibility = &visibilities[instance_id];
+            if let InstanceVisibility::Hidden = visibility { continue; }
+            write_culled_instances(display, &mut self.culled_instances, visibility);
+            let drawn = visibility.counts(object_instance.instance_matrices.len()).0;
+            let motion_count = match taa {
+                Some(_) => write_motion_instances(display, &mut self.motion_instances, object_instance, visibility, self.previous_model_matrices.get(instance_id)),
+                None => 0,
+            };
+            let object_option = app_state.get_object_by_uuid(&instance_id);
+            match object_option {
+                Some(object) => {
+                    let has_skeleton = object.get_skeleton().is_some();
+                    let bone_transform = bone_uniform_buffers.get(&object.get_unique_id()).expect("Missing Bone Transform Uniforms for Object");
+                    let morph_weights = object.get_morph_weight_uniforms();
+                    for (((buffer, mat_index), indices), morph_targets) in object_instance.vertex_buffers.iter().zip(object_instance.index_buffers.iter()).zip(object_instance.morph_buffers.iter()) {
+                        let mat_uuid: &Uuid = &object.get_materials()[*mat_index];
+                        match app_state.get_material(mat_uuid) {
+                            Some(material) => {
+                                if material.render_transparent {
+                                    continue;
+                                }
+                                let uniforms = &material.get_uniforms(&scene_lighting, camera.as_ref(), &bone_transform, has_skeleton, morph_targets, morph_weights);
+                                frame_stats.add_draw(indices.len(), drawn);
+                                let instances = instance_source(visibility, object_instance, &self.culled_instances);
+                                render_target.draw((buffer, instances.per_instance().expect("Error, unwrapping per instance")), indices, &material.program, uniforms, &opaque_rendering_parameter).expect("Failed to draw object");
+                                // drawn right after the color, so closer objects drawn later overwrite it like they overwrite the color
+                                if surface_pass && material.supports_surface_pass() {
+                                    frame_stats.add_draw(indices.len(), drawn);
+                                    surface_framebuffer.draw((buffer, instances.per_instance().expect("Error, unwrapping per instance")), indices, &material.program, &SurfacePass(uniforms), &surface_rendering_parameter).expect("Failed to draw object surface");
+                                }
+                                // skinning and morph targets are ignored, their motion only comes from the model matrix
+                                if motion_count > 0 {
+                                    let motion_instances = self.motion_instances.slice(0..motion_count).expect("Motion instance buffer is too small");
+                                    frame_stats.add_draw(indices.len(), motion_count);
+                                    velocity_framebuffer.draw((buffer, motion_instances.per_instance().expect("Error, unwrapping per instance of motion instances")), indices, &self.temporal.motion_program, &motion_uniforms, &surface_rendering_parameter).expect("Failed to draw object motion");
+                                }
+                            }
+                            None => ()
+                        }
+                    }
+                }
+                None => EnigmaError::new(Some(smart_format!("Error, instancing the Object Instance with the instance id {}, because no Object with that Id could be found", instance_id).as_str()), true).log()
+            }
+        }
+
+        // render terrain
+        if let Some(terrain) = &app_state.terrain {
+            if let Some(cam) = camera.as_ref() {
+                let (drawn, culled) = terrain.draw(render_target, cam, &scene_lighting, camera_frustum.as_ref());
+                frame_stats.culling.terrain_tiles_drawn += drawn;
+                frame_stats.culling.terrain_tiles_culled += culled;
+                frame_stats.draw_calls += drawn;
+                frame_stats.instances += drawn;
+                frame_stats.triangles += drawn * terrain.get_tile_triangle_count();
+            }
+        }
+        if frame_stats.culling.terrain_tiles_drawn > 0 {
+            app_state.get_profiler_mut().end_gpu_pass();
+            app_state.get_profiler_mut().resume_gpu_pass(display);
+        }
+        let gpu_query = app_state.get_profiler().get_gpu_query();
+
+        // render skybox
+        let skybox_rendering_parameter = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: false,
+                ..Default::default()
+            },
+            backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
+            time_elapsed_query: gpu_query,
+            ..Default::default()
+        };
+
+        let skybox_instance = self.mesh_cache.get_skybox();
+
+        match skybox_instance {
+            Some((skybox_id, instance)) => {
+                let object_option = app_state.get_skybox();
+                match object_option {
+                    Some(skybox) => {
+                        let skybox_bone_buffer = skybox.get_bone_transform_buffer(display);
+                        let morph_weights = skybox.get_morph_weight_uniforms();
+                        for (((buffer, mat_index), indices), morph_targets) in instance.vertex_buffers.iter().zip(instance.index_buffers.iter()).zip(instance.morph_buffers.iter()) {
+                            let mat_uuid: &Uuid = &skybox.get_materials()[*mat_index];
+                            match app_state.get_material(mat_uuid) {
+                                Some(material) => {
+                                    let uniforms = &material.get_uniforms(&scene_lighting, camera.as_ref(), &skybox_bone_buffer, false, morph_targets, morph_weights);
+                                    frame_stats.add_draw(indices.len(), 1);
+                                    let instances = instance.instance_slice();
+                                    render_target.draw((buffer, instances.per_instance().expect("Error, unwrapping per instance in skybox draw")), indices, &material.program, uniforms, &skybox_rendering_parameter).expect("Failed to draw object");
+                                }
+                                None => ()
+                            }
+                        }
+                    }
+                    None => EnigmaError::new(Some(smart_format!("Error, instancing the Skybox Instance with the instance id {}, because no Object with that Id could be found", skybox_id).as_str()), true).log()
+                }
+            }
+            None => {}
+        }
+
+        // render objects transparent
+        let transparent_rendering_parameter = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: true,
+                ..Default::default()
+            },
+            backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
+            blend: glium::Blend::alpha_blending(),
+            time_elapsed_query: gpu_query,
+            ..Default::default()
+        };
+        for (instance_id, object_instance) in self.mesh_cache.iter() {
+            let visibility = &visibilities[instance_id];
+            if let InstanceVisibility::Hidden = visibility { continue; }
+            write_culled_instances(display, &mut self.culled_instances, visibility);
+            let drawn = visibility.counts(object_instance.instance_matrices.len()).0;
+            let object_option = app_state.get_object_by_uuid(&instance_id);
+            match object_option {
+                Some(object) => {
+                    let has_skeleton = object.get_skeleton().is_some();
+                    let bone_transform = bone_uniform_buffers.get(&object.get_unique_id()).expect("Missing Bone Transform Uniforms for Object");
+                    let morph_weights = object.get_morph_weight_uniforms();
+                    for (((buffer, mat_index), indices), morph_targets) in object_instance.vertex_buffers.iter().zip(object_instance.index_buffers.iter()).zip(object_instance.morph_buffers.iter()) {
+                        let mat_uuid: &Uuid = &object.get_materials()[*mat_index];
+                        match app_state.get_material(mat_uuid) {
+                            Some(material) => {
+                                if !material.render_transparent {
+                                    continue;
+                                }
+                                let uniforms = &material.get_uniforms(&scene_lighting, camera.as_ref(), &bone_transform, has_skeleton, morph_targets, morph_weights);
+                                frame_stats.add_draw(indices.len(), drawn);
+                                let instances = instance_source(visibility, object_instance, &self.culled_instances);
+                                render_target.draw((buffer, instances.per_instance().expect("Error, unwrapping per instance")), indices, &material.program, uniforms, &transparent_rendering_parameter).expect("Failed to draw object");
+                            }
+                            None => ()
+                        }
+                    }
+                }
+                None => EnigmaError::new(Some(smart_format!("Error, instancing the Transparent Object Instance with the instance id {}, because no Object with that Id could be found", instance_id).as_str()), true).log()
+            }
+        }
+
+        drop(velocity_framebuffer);
+        self.previous_model_matrices = match taa {
+            Some(_) => self.mesh_cache.iter().map(|(id, instance)| (*id, instance.instance_matrices.clone())).collect(),
+            None => HashMap::new(),
+        };
+
+        frame_stats.scene_ms = profiler::elapsed_ms(scene_start);
+        app_state.set_culling_stats(frame_stats.culling);
+
+        // execute post processing
+        // Each effect reads from a ping-pong buffer (not from `texture` directly),
+        // because `framebuffer` is backed by `texture` — sampling from a texture
+        // that is simultaneously attached as a render target is undefined in OpenGL.
+        let post_process_start = Instant::now();
+        let pp_src_idx = self.buffer_textures.len() - 1;
+        // TAA, tonemapping and the effects draw without the GPU query and end the pass, the copy after them starts a new one
+        // the resolved frame replaces the jittered one before any effect sees it
+        if taa.is_some() {
+            frame_stats.post_process_passes += 1;
+            self.copy_to_ping_pong(display, pp_src_idx, app_state.get_profiler().get_gpu_query());
+            let sources = PostProcessSources {
+                source: &self.buffer_textures[pp_src_idx],
+                depth: &self.depth_texture,
+                surface: &self.surface_texture,
+                buffer_textures: &self.buffer_textures,
+            };
+            let settings = taa.unwrap_or_default();
+            self.temporal.resolve(&settings, unjittered_view_projection, &self.screen_vert_rect, &self.screen_indices_rect, &mut framebuffer, &sources);
+            self.temporal.store_history(display, &self.screen_vert_rect, &self.screen_indices_rect, &self.texture);
+            app_state.get_profiler_mut().end_gpu_pass();
+        }
+        // tonemapping runs before the effect at `tonemap_position`, or after all of them
+        let tonemap_position = app_state.tonemap_position.min(app_state.get_post_processes().len());
+        for index in 0..=app_state.get_post_processes().len() {
+            if index == tonemap_position {
+                frame_stats.post_process_passes += 1;
+                app_state.get_profiler_mut().resume_gpu_pass(display);
+                self.copy_to_ping_pong(display, pp_src_idx, app_state.get_profiler().get_gpu_query());
+                self.tone_mapper.render(app_state, &self.screen_vert_rect, &self.screen_indices_rect, &mut framebuffer, &self.buffer_textures[pp_src_idx]);
+                app_state.get_profiler_mut().end_gpu_pass();
+            }
+            let Some(entry) = app_state.get_post_processes().get(index) else { break };
+            if !entry.enabled || entry.weight <= 0.0 {
+                continue;
+            }
+            frame_stats.post_process_passes += 1;
+            app_state.get_profiler_mut().resume_gpu_pass(display);
+            self.copy_to_ping_pong(display, pp_src_idx, app_state.get_profiler().get_gpu_query());
+            let entry = &app_state.get_post_processes()[index];
+            let sources = PostProcessSources {
+                source: &self.buffer_textures[pp_src_idx],
+                depth: &self.depth_texture,
+                surface: &self.surface_texture,
+                buffer_textures: &self.buffer_textures,
+            };
+            entry.effect.render_with_surface(app_state, &self.screen_vert_rect, &self.screen_indices_rect, &mut framebuffer, &sources);
+            let weight = entry.weight;
+            app_state.get_profiler_mut().end_gpu_pass();
+            if weight < 1.0 {
+                app_state.get_profiler_mut().resume_gpu_pass(display);
+                self.blend_with_ping_pong(&mut framebuffer, pp_src_idx, weight, app_state.get_profiler().get_gpu_query());
+            }
+        }
+        app_state.set_current_exposure(self.tone_mapper.get_exposure());
+        frame_stats.post_process_ms = profiler::elapsed_ms(post_process_start);
+
+        app_state.get_profiler_mut().current_mut().merge_render(&frame_stats);
+    }
+
+    /// Copies the color target into the ping-pong buffer at `index`, so a pass can read the scene while drawing into it.
+    fn copy_to_ping_pong(&self, display: &impl Facade, index: usize, gpu_query: Option<&TimeElapsedQuery>) {
+        let mut pp_fb = glium::framebuffer::SimpleFrameBuffer::new(display, &self.buffer_textures[index]).expect("Failed to create post-process ping-pong framebuffer");
+        let copy_uniforms = uniform! { scene: &self.texture };
+        let params = glium::DrawParameters {
+            time_elapsed_query: gpu_query,
+            ..Default::default()
+        };
+        pp_fb.draw(&self.screen_vert_rect, &self.screen_indices_rect, &self.screen_program, &copy_uniforms, &params).expect("Failed to copy to ping-pong buffer");
+    }
+
+    /// Mixes the image before the last effect, still in the ping-pong buffer at `index`, back into `target`,
+    /// so only `weight` of the effect remains.
+    fn blend_with_ping_pong(&self, target: &mut glium::framebuffer::SimpleFrameBuffer, index: usize, weight: f32, gpu_query: Option<&TimeElapsedQuery>) {
+        let blending_function = glium::BlendingFunction::Addition {
+            source: glium::LinearBlendingFactor::ConstantAlpha,
+            destination: glium::LinearBlendingFactor::OneMinusConstantAlpha,
+        };
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::DepthTest::Overwrite,
+                write: false,
+                ..Default::default()
+            },
+            blend: glium::Blend {
+                color: blending_function,
+                alpha: blending_function,
+                constant_value: (0.0, 0.0, 0.0, 1.0 - weight),
+            },
+            time_elapsed_query: gpu_query,
+            ..Default::default()
+        };
+        let uniforms = uniform! { scene: &self.buffer_textures[index] };
+        target.draw(&self.screen_vert_rect, &self.screen_indices_rect, &self.screen_program, &uniforms, &params).expect("Failed to blend post-process pass");
+    }
+
+    /// Draws the final color target as a fullscreen quad onto `target`.
+    pub fn present<S: Surface>(&self, target: &mut S) {
+        let screen_uniforms = uniform! {
+            scene: &self.texture,
+        };
+        target.draw(
+            &self.screen_vert_rect,
+            &self.screen_indices_rect,
+            &self.screen_program,
+            &screen_uniforms,
+            &Default::default(),
+        ).expect("Failed to draw screen");
+    }
+}
@@ -26,6 +26,34 @@ uniform mat4 shadow_light_space_2;
 uniform mat4 shadow_light_space_3;
 uniform vec4 shadow_far_planes;
 uniform vec4 light_cast_shadow;
+// per slot shadow filter (0 none, 1 PCF, 2 PCSS), its kernel or light size and the biases
+uniform ivec4 light_shadow_filter;
+uniform vec4 light_shadow_filter_size;
+uniform vec4 light_shadow_depth_bias;
+uniform vec4 light_shadow_normal_bias;
+// cascades of the first directional light, side by side in its shadow map
+uniform int shadow_cascade_slot;
+uniform int shadow_cascade_count;
+uniform vec4 shadow_cascade_splits;
+uniform float shadow_cascade_blend;
+uniform mat4 shadow_cascade_0;
+uniform mat4 shadow_cascade_1;
+uniform mat4 shadow_cascade_2;
+uniform mat4 shadow_cascade_3;
+uniform vec4 light_range;
+uniform vec4 light_cone_inner;
+uniform vec4 light_cone_outer;
+
+// clustered lights, everything that doesn't fit into the four slots above
+uniform sampler2D clustered_lights;
+uniform sampler2D light_clusters;
+uniform sampler2D light_indices;
+uniform int clustered_light_count;
+uniform ivec3 cluster_grid;
+uniform float cluster_near;
+uniform float cluster_far;
+uniform vec2 cluster_screen_size;
+uniform mat4 view_matrix;
 
 //attributes
 in vec3 world_position;
@@ -51,6 +79,17 @@ uniform float mat_emissive_strength;
 uniform float mat_transparency_strength;
 uniform sampler2D skybox;
 
+// image based lighting
+uniform bool ibl_enabled;
+uniform float ibl_intensity;
+uniform samplerCube ibl_irradiance;
+uniform samplerCube ibl_specular;
+uniform float ibl_specular_max_lod;
+uniform sampler2D ibl_brdf_lut;
+
+// when set, the fragment writes surface data for screen-space effects instead of its color
+uniform bool surface_pass;
+
 // fragment outputs
 out vec4 color;
 
@@ -100,7 +139,85 @@ vec3 fresnelSchlick(float cosTheta, vec3 F0) {
     return F0 + (1.0 - F0) * pow(1.0 - cosTheta, 5.0);
 }
 
-float dir_shadow(sampler2D shadow_map, mat4 light_space, vec3 world_pos, vec3 normal, vec3 light_dir) {
+vec3 fresnelSchlickRoughness(float cosTheta, vec3 F0, float roughness) {
+    return F0 + (max(vec3(1.0 - roughness), F0) - F0) * pow(clamp(1.0 - cosTheta, 0.0, 1.0), 5.0);
+}
+
+// Split sum image based lighting: diffuse from the irradiance cubemap, specular from the prefiltered
+// cubemap whose mip levels hold increasing roughness, scaled by the BRDF lookup table.
+vec3 image_based_lighting(vec3 normal, vec3 viewDir, vec3 albedo, float roughness, float metallic, vec3 F0) {
+    float NdotV = max(dot(normal, viewDir), 0.0);
+    vec3 F = fresnelSchlickRoughness(NdotV, F0, roughness);
+    vec3 kD = (1.0 - F) * (1.0 - metallic);
+
+    vec3 diffuse = texture(ibl_irradiance, normal).rgb * albedo;
+    vec3 reflectionVector = reflect(-viewDir, normal);
+    vec3 prefiltered = textureLod(ibl_specular, reflectionVector, clamp(roughness, 0.0, 1.0) * ibl_specular_max_lod).rgb;
+    vec2 brdf = texture(ibl_brdf_lut, vec2(NdotV, clamp(roughness, 0.0, 1.0))).rg;
+    vec3 specular = prefiltered * (F * brdf.x + brdf.y);
+
+    return (kD * diffuse + specular) * ibl_intensity;
+}
+
+const vec2 POISSON_DISK[16] = vec2[](
+    vec2(-0.94201624, -0.39906216), vec2(0.94558609, -0.76890725), vec2(-0.09418410, -0.92938870), vec2(0.34495938, 0.29387760),
+    vec2(-0.91588581, 0.45771432), vec2(-0.81544232, -0.87912464), vec2(-0.38277543, 0.27676845), vec2(0.97484398, 0.75648379),
+    vec2(0.44323325, -0.97511554), vec2(0.53742981, -0.47373420), vec2(-0.26496911, -0.41893023), vec2(0.79197514, 0.19090188),
+    vec2(-0.24188840, 0.99706507), vec2(-0.81409955, 0.91437590), vec2(0.19984126, 0.78641367), vec2(0.14383161, -0.14100790)
+);
+
+// slope scaled depth bias of slot i
+float shadow_bias(int i, float n_dot_l) {
+    return max(light_shadow_depth_bias[i] * (1.0 - n_dot_l), light_shadow_depth_bias[i] * 0.04);
+}
+
+// Compares `current` with the shadow map around `uv` using the filter of slot i. Samples are kept
+// inside [uv_min, uv_max] so they don't leak into neighbouring cascades or cube faces.
+float filter_shadow(sampler2D shadow_map, vec2 uv, float current, float bias, int i, vec2 uv_min, vec2 uv_max) {
+    int mode = light_shadow_filter[i];
+    if (mode == 0) {
+        return (current - bias > texture(shadow_map, uv).r) ? 0.0 : 1.0;
+    }
+    vec2 texel = 1.0 / vec2(textureSize(shadow_map, 0));
+    vec2 lo = uv_min + texel * 0.5;
+    vec2 hi = uv_max - texel * 0.5;
+    if (mode == 1) {
+        // PCF, size is the kernel width in texels
+        int radius = int(light_shadow_filter_size[i]) / 2;
+        float lit = 0.0;
+        for (int x = -radius; x <= radius; x++) {
+            for (int y = -radius; y <= radius; y++) {
+                float stored = texture(shadow_map, clamp(uv + vec2(x, y) * texel, lo, hi)).r;
+                lit += (current - bias > stored) ? 0.0 : 1.0;
+            }
+        }
+        float width = float(radius * 2 + 1);
+        return lit / (width * width);
+    }
+    // PCSS, size is the light size in texels. Average the blockers, then widen the filter with the
+    // distance between blocker and receiver
+    float light_size = light_shadow_filter_size[i];
+    float blockers = 0.0;
+    float blocker_depth = 0.0;
+    for (int s = 0; s < 16; s++) {
+        float stored = texture(shadow_map, clamp(uv + POISSON_DISK[s] * light_size * texel, lo, hi)).r;
+        if (current - bias > stored) {
+            blockers += 1.0;
+            blocker_depth += stored;
+        }
+    }
+    if (blockers == 0.0) return 1.0;
+    blocker_depth /= blockers;
+    float penumbra = max(light_size * (current - blocker_depth) / max(blocker_depth, 0.0001), 1.0);
+    float lit = 0.0;
+    for (int s = 0; s < 16; s++) {
+        float stored = texture(shadow_map, clamp(uv + POISSON_DISK[s] * penumbra * texel, lo, hi)).r;
+        lit += (current - bias > stored) ? 0.0 : 1.0;
+    }
+    return lit / 16.0;
+}
+
+float dir_shadow(int i, sampler2D shadow_map, mat4 light_space, vec3 world_pos, vec3 normal, vec3 light_dir) {
     vec4 ls = light_space * vec4(world_pos, 1.0);
     vec3 proj = ls.xyz / ls.w;
     proj = proj * 0.5 + 0.5;
@@ -108,12 +225,48 @@ float dir_shadow(sampler2D shadow_map, mat4 light_space, vec3 world_pos, vec3 no
         return 1.0;
     }
     float n_dot_l = max(dot(normal, normalize(light_dir)), 0.0);
-    float bias = max(0.005 * (1.0 - n_dot_l), 0.0002);
-    float stored = texture(shadow_map, proj.xy).r;
-    return (proj.z - bias > stored) ? 0.0 : 1.0;
+    float bias = shadow_bias(i, n_dot_l);
+    return filter_shadow(shadow_map, proj.xy, proj.z, bias, i, vec2(0.0), vec2(1.0));
+}
+
+float cascade_sample(int i, sampler2D atlas, int cascade, vec3 world_pos, vec3 normal, vec3 light_dir) {
+    mat4 light_space = cascade == 0 ? shadow_cascade_0 : cascade == 1 ? shadow_cascade_1 : cascade == 2 ? shadow_cascade_2 : shadow_cascade_3;
+    vec4 ls = light_space * vec4(world_pos, 1.0);
+    vec3 proj = ls.xyz / ls.w * 0.5 + 0.5;
+    if (proj.x < 0.0 || proj.x > 1.0 || proj.y < 0.0 || proj.y > 1.0 || proj.z < 0.0 || proj.z > 1.0) {
+        return 1.0;
+    }
+    float n_dot_l = max(dot(normal, normalize(light_dir)), 0.0);
+    float bias = shadow_bias(i, n_dot_l);
+    // the atlas is always MAX_CASCADES (4) maps wide
+    vec2 uv_min = vec2(float(cascade) / 4.0, 0.0);
+    vec2 uv_max = vec2(float(cascade + 1) / 4.0, 1.0);
+    return filter_shadow(atlas, vec2((proj.x + float(cascade)) / 4.0, proj.y), proj.z, bias, i, uv_min, uv_max);
 }
 
-vec2 cube_atlas_uv(vec3 dir) {
+// picks the cascade by view depth and blends into the next one towards the end of each cascade
+float cascade_shadow(int i, sampler2D atlas, vec3 world_pos, vec3 normal, vec3 light_dir) {
+    float depth = -(view_matrix * vec4(world_pos, 1.0)).z;
+    if (depth > shadow_cascade_splits[shadow_cascade_count - 1]) return 1.0;
+    int cascade = 0;
+    for (int c = 0; c < shadow_cascade_count - 1; c++) {
+        if (depth > shadow_cascade_splits[c]) cascade = c + 1;
+    }
+    float shadow = cascade_sample(i, atlas, cascade, world_pos, normal, light_dir);
+    if (cascade < shadow_cascade_count - 1) {
+        float start = cascade == 0 ? 0.0 : shadow_cascade_splits[cascade - 1];
+        float end = shadow_cascade_splits[cascade];
+        float band = max((end - start) * shadow_cascade_blend, 0.0001);
+        float t = clamp((depth - (end - band)) / band, 0.0, 1.0);
+        if (t > 0.0) {
+            shadow = mix(shadow, cascade_sample(i, atlas, cascade + 1, world_pos, normal, light_dir), t);
+        }
+    }
+    return shadow;
+}
+
+// uv of `dir` in the 2x3 face atlas, `tile_min` is the lower corner of the face
+vec2 cube_atlas_uv(vec3 dir, out vec2 tile_min) {
     vec3 a = abs(dir);
     float s, t, major, col, row;
     if (a.x >= a.y && a.x >= a.z) {
@@ -130,34 +283,115 @@ vec2 cube_atlas_uv(vec3 dir) {
         else              { col = 1.0; row = 2.0; s = -dir.x; t = -dir.y; }
     }
     vec2 face_uv = (vec2(s, t) / major + 1.0) * 0.5;
+    tile_min = vec2(col * 0.5, row / 3.0);
     return vec2((col + face_uv.x) * 0.5, (row + face_uv.y) / 3.0);
 }
 
-float point_shadow(sampler2D atlas, vec3 world_pos, vec3 light_pos, float far_plane, vec3 normal) {
+float point_shadow(int i, sampler2D atlas, vec3 world_pos, vec3 light_pos, float far_plane, vec3 normal) {
     vec3 dir = world_pos - light_pos;
     float current = length(dir) / far_plane;
     float n_dot_l = max(dot(normal, normalize(-dir)), 0.0);
-    float bias = max(0.005 * (1.0 - n_dot_l), 0.0002);
-    vec2 uv = cube_atlas_uv(dir);
-    float stored = texture(atlas, uv).r;
-    return (current - bias > stored) ? 0.0 : 1.0;
+    float bias = shadow_bias(i, n_dot_l);
+    vec2 tile_min;
+    vec2 uv = cube_atlas_uv(dir, tile_min);
+    return filter_shadow(atlas, uv, current, bias, i, tile_min, tile_min + vec2(0.5, 1.0 / 3.0));
+}
+
+// spot lights store the linear distance to the light like point lights, looked up through their perspective matrix
+float spot_shadow(int i, sampler2D shadow_map, mat4 light_space, vec3 world_pos, vec3 light_pos, float far_plane, vec3 normal) {
+    vec4 ls = light_space * vec4(world_pos, 1.0);
+    if (ls.w <= 0.0) return 1.0;
+    vec2 uv = ls.xy / ls.w * 0.5 + 0.5;
+    if (uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0) return 1.0;
+    vec3 dir = world_pos - light_pos;
+    float current = length(dir) / far_plane;
+    float n_dot_l = max(dot(normal, normalize(-dir)), 0.0);
+    float bias = shadow_bias(i, n_dot_l);
+    return filter_shadow(shadow_map, uv, current, bias, i, vec2(0.0), vec2(1.0));
 }
 
 float compute_shadow(int i, vec3 world_pos, vec3 light_pos, vec3 normal) {
     if (light_cast_shadow[i] < 0.5) return 1.0;
+    // normal offset against acne on surfaces at grazing angles
+    world_pos += normal * light_shadow_normal_bias[i];
+    if (light_direction[i].w == 2.0) {
+        if (i == 0) return spot_shadow(0, shadow_map_0, shadow_light_space_0, world_pos, light_pos, shadow_far_planes[0], normal);
+        if (i == 1) return spot_shadow(1, shadow_map_1, shadow_light_space_1, world_pos, light_pos, shadow_far_planes[1], normal);
+        if (i == 2) return spot_shadow(2, shadow_map_2, shadow_light_space_2, world_pos, light_pos, shadow_far_planes[2], normal);
+        if (i == 3) return spot_shadow(3, shadow_map_3, shadow_light_space_3, world_pos, light_pos, shadow_far_planes[3], normal);
+    }
     bool is_dir = (light_direction[i].w == 1.0);
     vec3 ldir = is_dir ? light_direction[i].xyz : normalize(light_pos - world_pos);
-    if (i == 0) return is_dir ? dir_shadow(shadow_map_0, shadow_light_space_0, world_pos, normal, ldir)
-                              : point_shadow(shadow_point_0, world_pos, light_pos, shadow_far_planes[0], normal);
-    if (i == 1) return is_dir ? dir_shadow(shadow_map_1, shadow_light_space_1, world_pos, normal, ldir)
-                              : point_shadow(shadow_point_1, world_pos, light_pos, shadow_far_planes[1], normal);
-    if (i == 2) return is_dir ? dir_shadow(shadow_map_2, shadow_light_space_2, world_pos, normal, ldir)
-                              : point_shadow(shadow_point_2, world_pos, light_pos, shadow_far_planes[2], normal);
-    if (i == 3) return is_dir ? dir_shadow(shadow_map_3, shadow_light_space_3, world_pos, normal, ldir)
-                              : point_shadow(shadow_point_3, world_pos, light_pos, shadow_far_planes[3], normal);
+    if (is_dir && i == shadow_cascade_slot) {
+        if (i == 0) return cascade_shadow(0, shadow_map_0, world_pos, normal, ldir);
+        if (i == 1) return cascade_shadow(1, shadow_map_1, world_pos, normal, ldir);
+        if (i == 2) return cascade_shadow(2, shadow_map_2, world_pos, normal, ldir);
+        if (i == 3) return cascade_shadow(3, shadow_map_3, world_pos, normal, ldir);
+    }
+    if (i == 0) return is_dir ? dir_shadow(0, shadow_map_0, shadow_light_space_0, world_pos, normal, ldir)
+                              : point_shadow(0, shadow_point_0, world_pos, light_pos, shadow_far_planes[0], normal);
+    if (i == 1) return is_dir ? dir_shadow(1, shadow_map_1, shadow_light_space_1, world_pos, normal, ldir)
+                              : point_shadow(1, shadow_point_1, world_pos, light_pos, shadow_far_planes[1], normal);
+    if (i == 2) return is_dir ? dir_shadow(2, shadow_map_2, shadow_light_space_2, world_pos, normal, ldir)
+                              : point_shadow(2, shadow_point_2, world_pos, light_pos, shadow_far_planes[2], normal);
+    if (i == 3) return is_dir ? dir_shadow(3, shadow_map_3, shadow_light_space_3, world_pos, normal, ldir)
+                              : point_shadow(3, shadow_point_3, world_pos, light_pos, shadow_far_planes[3], normal);
     return 1.0;
 }
 
+// Smoothly fades a light to zero at its range, a range of 0 means unbounded
+float range_window(float distance, float range) {
+    if (range <= 0.0) return 1.0;
+    float r = distance / range;
+    float window = clamp(1.0 - r * r * r * r, 0.0, 1.0);
+    return window * window;
+}
+
+// Spot lights shine along their direction and fade out between the inner and outer cone, other lights return 1
+float spot_factor(float light_type, vec3 spot_direction, vec3 light_pos, float cos_inner, float cos_outer) {
+    if (light_type != 2.0) return 1.0;
+    float cos_angle = dot(normalize(world_position - light_pos), normalize(spot_direction));
+    return smoothstep(cos_outer, max(cos_inner, cos_outer + 0.0001), cos_angle);
+}
+
+// Cook-Torrance BRDF for one light
+vec3 evaluate_light(vec3 lightDir, vec3 radiance, vec3 normal, vec3 viewDir, vec3 albedo, float roughness, float metallic, vec3 F0) {
+    vec3 halfDir = normalize(lightDir + viewDir);
+    float NDF = DistributionGGX(normal, halfDir, roughness);
+    float G = GeometrySmith(normal, viewDir, lightDir, roughness);
+    vec3 F = fresnelSchlick(max(dot(halfDir, viewDir), 0.0), F0);
+
+    vec3 kS = F;
+    vec3 kD = vec3(1.0) - kS;
+    kD *= 1.0 - metallic;
+
+    float NdotL = max(dot(normal, lightDir), 0.0);
+
+    // Combine terms
+    vec3 numerator = NDF * G * F;
+    float denominator = 4.0 * max(dot(normal, viewDir), 0.0) * NdotL + 0.0001;
+    vec3 specular = numerator / denominator;
+
+    vec3 diffuse = kD * albedo / PI;
+    return (diffuse + specular) * radiance * NdotL;
+}
+
+// Texel of the light cluster this fragment lies in, depth slices are exponential between near and far
+ivec2 fragment_cluster() {
+    vec2 screen_uv = gl_FragCoord.xy / cluster_screen_size;
+    float depth = -(view_matrix * vec4(world_position, 1.0)).z;
+    float slice = log(max(depth, cluster_near) / cluster_near) / log(cluster_far / cluster_near) * float(cluster_grid.z);
+    ivec3 cluster = clamp(ivec3(ivec2(screen_uv * vec2(cluster_grid.xy)), int(slice)), ivec3(0), cluster_grid - 1);
+    return ivec2(cluster.x, cluster.y + cluster.z * cluster_grid.y);
+}
+
+// Light indices are packed four per texel, 1024 texels per row
+int clustered_light_index(int i) {
+    int texel = i / 4;
+    vec4 indices = texelFetch(light_indices, ivec2(texel % 1024, texel / 1024), 0);
+    return int(indices[i % 4]);
+}
+
 // Main PBR calculation function
 // PBR calculations including skybox lighting
 vec4 calculatePBRColor(vec3 viewDir) {
@@ -183,34 +417,39 @@ vec4 calculatePBRColor(vec3 viewDir) {
         if(lightDirUniform.w == 1.0) {
             lightDir = normalize(lightDirUniform.xyz);
         }
-        vec3 halfDir = normalize(lightDir + viewDir);
         float distance = length(light_position[i].xyz - world_position);
-        float attenuation = 1.0 / (distance * distance);
+        float attenuation = range_window(distance, light_range[i]) / (distance * distance);
+        attenuation *= spot_factor(lightDirUniform.w, lightDirUniform.xyz, light_position[i].xyz, light_cone_inner[i], light_cone_outer[i]);
         vec3 radiance = light_color[i].xyz * light_intensity[i] * attenuation;
 
-        // Cook-Torrance BRDF
-        float NDF = DistributionGGX(normal, halfDir, roughness);
-        float G = GeometrySmith(normal, viewDir, lightDir, roughness);
-        vec3 F = fresnelSchlick(max(dot(halfDir, viewDir), 0.0), F0);
-
-        vec3 kS = F;
-        vec3 kD = vec3(1.0) - kS;
-        kD *= 1.0 - metallic;
-
-        float NdotL = max(dot(normal, lightDir), 0.0);
-
-        // Combine terms
-        vec3 numerator = NDF * G * F;
-        float denominator = 4.0 * max(dot(normal, viewDir), 0.0) * NdotL + 0.0001;
-        vec3 specular = numerator / denominator;
-
-        vec3 diffuse = kD * albedo / PI;
-        vec3 reflection = (diffuse + specular) * radiance * NdotL;
-
+        vec3 reflection = evaluate_light(lightDir, radiance, normal, viewDir, albedo, roughness, metallic, F0);
         float shadow = compute_shadow(i, world_position, light_position[i].xyz, normal);
         result += reflection * shadow;
     }
 
+    if (clustered_light_count > 0) {
+        vec4 cluster = texelFetch(light_clusters, fragment_cluster(), 0);
+        int offset = int(cluster.r);
+        int count = int(cluster.g);
+        for (int i = 0; i < count; i++) {
+            int light = clustered_light_index(offset + i);
+            vec4 position_range = texelFetch(clustered_lights, ivec2(0, light), 0);
+            vec4 radiance_intensity = texelFetch(clustered_lights, ivec2(1, light), 0);
+            vec4 direction_type = texelFetch(clustered_lights, ivec2(2, light), 0);
+            vec4 cone = texelFetch(clustered_lights, ivec2(3, light), 0);
+            vec3 lightDir = direction_type.w == 1.0 ? normalize(direction_type.xyz) : normalize(position_range.xyz - world_position);
+            float distance = length(position_range.xyz - world_position);
+            float attenuation = range_window(distance, position_range.w) / (distance * distance);
+            attenuation *= spot_factor(direction_type.w, direction_type.xyz, position_range.xyz, cone.x, cone.y);
+            result += evaluate_light(lightDir, radiance_intensity.rgb * attenuation, normal, viewDir, albedo, roughness, metallic, F0);
+        }
+    }
+
+    if (ibl_enabled) {
+        vec3 finalColor = result + emissive + image_based_lighting(normal, viewDir, albedo, roughness, metallic, F0);
+        return vec4(finalColor, albedo_alpha * mat_transparency_strength);
+    }
+
     // Calculate reflection vector for environmental lighting
     vec3 reflectionVector = reflect(-viewDir, normal);
     vec2 uv = getSphereMapUV(reflectionVector);
@@ -226,6 +465,20 @@ vec4 calculatePBRColor(vec3 viewDir) {
     return vec4(finalColor, albedo_alpha * mat_transparency_strength);
 }
 
+// view space normal (xy), roughness and linear depth, read by screen-space effects like reflections
+vec4 surfaceData() {
+    vec3 normal = normalize(vertex_normal + (texture(mat_normal, vertex_texcoord).rgb - 0.5) * mat_normal_strength);
+    float roughness = texture(mat_roughness, vertex_texcoord).r * mat_roughness_strength;
+    // the vertex shader already outputs view space normals
+    vec3 view_normal = normal;
+    float linear_depth = -(view_matrix * vec4(world_position, 1.0)).z;
+    return vec4(view_normal.xy, roughness, linear_depth);
+}
+
 void main() {
+    if (surface_pass) {
+        color = surfaceData();
+        return;
+    }
     color = calculatePBRColor(normalize(modelView_pos));
 }
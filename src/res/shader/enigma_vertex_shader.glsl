@@ -42,18 +42,40 @@ layout(std140) uniform BoneTransforms {
     mat4 bone_transforms[128];
 };
 
+// morph targets: two texels (position, normal delta) per vertex and target
+uniform sampler2D morph_targets;
+uniform int morph_target_count;
+uniform int morph_vertex_count;
+uniform vec4 morph_weights_0;
+uniform vec4 morph_weights_1;
+
+vec4 morph_texel(int index) {
+    int width = textureSize(morph_targets, 0).x;
+    return texelFetch(morph_targets, ivec2(index % width, index / width), 0);
+}
+
 void main() {
     vec3 local_position = position;
     vec3 local_normal = normal;
 
+    for (int t = 0; t < morph_target_count; t++) {
+        float weight = t < 4 ? morph_weights_0[t] : morph_weights_1[t - 4];
+        if (weight == 0.0) continue;
+        int texel = (t * morph_vertex_count + gl_VertexID) * 2;
+        local_position += morph_texel(texel).xyz * weight;
+        local_normal += morph_texel(texel + 1).xyz * weight;
+    }
+    local_normal = length(local_normal) > 0.0001 ? normalize(local_normal) : normal;
+    vec3 morphed_normal = local_normal;
+
     if (has_skeleton) {
         mat4 skin_matrix = bone_transforms[bone_indices.x] * bone_weights.x
                          + bone_transforms[bone_indices.y] * bone_weights.y
                          + bone_transforms[bone_indices.z] * bone_weights.z
                          + bone_transforms[bone_indices.w] * bone_weights.w;
-        local_position = (skin_matrix * vec4(position, 1.0)).xyz;
-        vec3 skinned_n = (skin_matrix * vec4(normal, 0.0)).xyz;
-        local_normal = length(skinned_n) > 0.0001 ? normalize(skinned_n) : normal;
+        local_position = (skin_matrix * vec4(local_position, 1.0)).xyz;
+        vec3 skinned_n = (skin_matrix * vec4(morphed_normal, 0.0)).xyz;
+        local_normal = length(skinned_n) > 0.0001 ? normalize(skinned_n) : morphed_normal;
     }
 
     mat4 modelview = view_matrix * model_matrix;
@@ -0,0 +1,33 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+uniform sampler2D scene;
+uniform sampler3D lut_a;
+uniform float lut_a_size;
+uniform vec3 lut_a_domain_min;
+uniform vec3 lut_a_domain_max;
+uniform sampler3D lut_b;
+uniform float lut_b_size;
+uniform vec3 lut_b_domain_min;
+uniform vec3 lut_b_domain_max;
+// 0 is only lut a, 1 only lut b
+uniform float blend;
+uniform float intensity;
+
+vec3 applyLut(sampler3D lut, float size, vec3 domain_min, vec3 domain_max, vec3 c) {
+    vec3 t = clamp((c - domain_min) / max(domain_max - domain_min, vec3(1e-5)), 0.0, 1.0);
+    // the outer texels hold the ends of the domain, so sample between their centers
+    vec3 uvw = t * (size - 1.0) / size + 0.5 / size;
+    return texture(lut, uvw).rgb;
+}
+
+void main() {
+    vec4 scene_color = texture(scene, TEXCOORD);
+    vec3 graded = applyLut(lut_a, lut_a_size, lut_a_domain_min, lut_a_domain_max, scene_color.rgb);
+    if (blend > 0.0) {
+        graded = mix(graded, applyLut(lut_b, lut_b_size, lut_b_domain_min, lut_b_domain_max, scene_color.rgb), blend);
+    }
+    color = vec4(mix(scene_color.rgb, graded, intensity), scene_color.a);
+}
@@ -0,0 +1,53 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+uniform sampler2D scene;
+uniform sampler2D depth;
+uniform float near;
+uniform float far;
+uniform float focus_distance;
+uniform float aperture;
+// largest circle of confusion radius in pixels
+uniform float max_blur;
+uniform int sample_count;
+
+const float GOLDEN_ANGLE = 2.39996323;
+
+float linearizeDepth(float d) {
+    float z = d * 2.0 - 1.0;
+    return (2.0 * near * far) / (far + near - z * (far - near));
+}
+
+// circle of confusion radius in pixels, grows with the distance to the focus plane
+float circleOfConfusion(float linear_depth) {
+    return clamp(aperture * abs(linear_depth - focus_distance) / max(linear_depth, 1e-4), 0.0, 1.0) * max_blur;
+}
+
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(scene, 0));
+    vec4 center = texture(scene, TEXCOORD);
+    float center_depth = linearizeDepth(texture(depth, TEXCOORD).r);
+    float center_coc = circleOfConfusion(center_depth);
+
+    vec3 sum = center.rgb;
+    float total = 1.0;
+    // samples on a golden angle spiral give an evenly filled disc, the bokeh shape
+    for (int i = 1; i < sample_count; i++) {
+        float radius = max_blur * sqrt(float(i) / float(sample_count));
+        float theta = float(i) * GOLDEN_ANGLE;
+        vec2 sample_uv = TEXCOORD + vec2(cos(theta), sin(theta)) * radius * texel;
+        float sample_depth = linearizeDepth(texture(depth, sample_uv).r);
+        float sample_coc = circleOfConfusion(sample_depth);
+        // a blurry background must not bleed over a sharper object in front of it
+        if (sample_depth > center_depth) {
+            sample_coc = min(sample_coc, center_coc);
+        }
+        // a sample contributes when its own blur disc reaches this pixel
+        float weight = smoothstep(radius - 1.0, radius + 1.0, sample_coc);
+        sum += texture(scene, sample_uv).rgb * weight;
+        total += weight;
+    }
+    color = vec4(sum / total, center.a);
+}
@@ -0,0 +1,124 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+uniform sampler2D scene;
+uniform float edge_threshold;
+uniform float edge_threshold_min;
+uniform float subpixel_quality;
+
+const int SEARCH_STEPS = 12;
+
+float luma(vec3 c) {
+    return dot(c, vec3(0.299, 0.587, 0.114));
+}
+
+float lumaAt(vec2 uv) {
+    return luma(texture(scene, uv).rgb);
+}
+
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(scene, 0));
+    vec4 center_color = texture(scene, TEXCOORD);
+    float center = luma(center_color.rgb);
+    float down = lumaAt(TEXCOORD + vec2(0.0, -texel.y));
+    float up = lumaAt(TEXCOORD + vec2(0.0, texel.y));
+    float left = lumaAt(TEXCOORD + vec2(-texel.x, 0.0));
+    float right = lumaAt(TEXCOORD + vec2(texel.x, 0.0));
+
+    float luma_min = min(center, min(min(down, up), min(left, right)));
+    float luma_max = max(center, max(max(down, up), max(left, right)));
+    float range = luma_max - luma_min;
+    if (range < max(edge_threshold_min, luma_max * edge_threshold)) {
+        color = center_color;
+        return;
+    }
+
+    float down_left = lumaAt(TEXCOORD + vec2(-texel.x, -texel.y));
+    float up_right = lumaAt(TEXCOORD + vec2(texel.x, texel.y));
+    float up_left = lumaAt(TEXCOORD + vec2(-texel.x, texel.y));
+    float down_right = lumaAt(TEXCOORD + vec2(texel.x, -texel.y));
+
+    float down_up = down + up;
+    float left_right = left + right;
+    float left_corners = down_left + up_left;
+    float down_corners = down_left + down_right;
+    float right_corners = down_right + up_right;
+    float up_corners = up_right + up_left;
+
+    float edge_horizontal = abs(-2.0 * left + left_corners) + abs(-2.0 * center + down_up) * 2.0 + abs(-2.0 * right + right_corners);
+    float edge_vertical = abs(-2.0 * up + up_corners) + abs(-2.0 * center + left_right) * 2.0 + abs(-2.0 * down + down_corners);
+    bool horizontal = edge_horizontal >= edge_vertical;
+
+    float luma1 = horizontal ? down : left;
+    float luma2 = horizontal ? up : right;
+    float gradient1 = luma1 - center;
+    float gradient2 = luma2 - center;
+    bool steepest1 = abs(gradient1) >= abs(gradient2);
+    float gradient_scaled = 0.25 * max(abs(gradient1), abs(gradient2));
+
+    float step_length = horizontal ? texel.y : texel.x;
+    float local_average;
+    if (steepest1) {
+        step_length = -step_length;
+        local_average = 0.5 * (luma1 + center);
+    } else {
+        local_average = 0.5 * (luma2 + center);
+    }
+
+    vec2 current_uv = TEXCOORD;
+    if (horizontal) {
+        current_uv.y += step_length * 0.5;
+    } else {
+        current_uv.x += step_length * 0.5;
+    }
+
+    // walk along the edge in both directions until its end
+    vec2 offset = horizontal ? vec2(texel.x, 0.0) : vec2(0.0, texel.y);
+    vec2 uv1 = current_uv - offset;
+    vec2 uv2 = current_uv + offset;
+    float luma_end1 = lumaAt(uv1) - local_average;
+    float luma_end2 = lumaAt(uv2) - local_average;
+    bool reached1 = abs(luma_end1) >= gradient_scaled;
+    bool reached2 = abs(luma_end2) >= gradient_scaled;
+    for (int i = 0; i < SEARCH_STEPS && !(reached1 && reached2); i++) {
+        float quality = i < 4 ? 1.0 : (i < 8 ? 2.0 : 4.0);
+        if (!reached1) {
+            uv1 -= offset * quality;
+            luma_end1 = lumaAt(uv1) - local_average;
+            reached1 = abs(luma_end1) >= gradient_scaled;
+        }
+        if (!reached2) {
+            uv2 += offset * quality;
+            luma_end2 = lumaAt(uv2) - local_average;
+            reached2 = abs(luma_end2) >= gradient_scaled;
+        }
+    }
+
+    float distance1 = horizontal ? (TEXCOORD.x - uv1.x) : (TEXCOORD.y - uv1.y);
+    float distance2 = horizontal ? (uv2.x - TEXCOORD.x) : (uv2.y - TEXCOORD.y);
+    bool direction1 = distance1 < distance2;
+    float distance_final = min(distance1, distance2);
+    float edge_thickness = distance1 + distance2;
+    float pixel_offset = -distance_final / edge_thickness + 0.5;
+
+    bool center_smaller = center < local_average;
+    bool correct_variation = ((direction1 ? luma_end1 : luma_end2) < 0.0) != center_smaller;
+    float final_offset = correct_variation ? pixel_offset : 0.0;
+
+    // sub-pixel aliasing, e.g. thin lines
+    float luma_average = (1.0 / 12.0) * (2.0 * (down_up + left_right) + left_corners + right_corners);
+    float sub_pixel_offset1 = clamp(abs(luma_average - center) / range, 0.0, 1.0);
+    float sub_pixel_offset2 = (-2.0 * sub_pixel_offset1 + 3.0) * sub_pixel_offset1 * sub_pixel_offset1;
+    float sub_pixel_offset = sub_pixel_offset2 * sub_pixel_offset2 * subpixel_quality;
+    final_offset = max(final_offset, sub_pixel_offset);
+
+    vec2 final_uv = TEXCOORD;
+    if (horizontal) {
+        final_uv.y += final_offset * step_length;
+    } else {
+        final_uv.x += final_offset * step_length;
+    }
+    color = vec4(texture(scene, final_uv).rgb, center_color.a);
+}
@@ -0,0 +1,34 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+uniform sampler2D scene;
+uniform sampler2D depth;
+// current clip space to the previous frame's clip space
+uniform mat4 reprojection;
+uniform float intensity;
+// longest blur in uv units
+uniform float max_blur;
+uniform int sample_count;
+
+void main() {
+    vec4 center = texture(scene, TEXCOORD);
+    float d = texture(depth, TEXCOORD).r;
+    vec4 previous = reprojection * vec4(TEXCOORD * 2.0 - 1.0, d * 2.0 - 1.0, 1.0);
+    vec2 previous_uv = previous.xy / previous.w * 0.5 + 0.5;
+
+    vec2 velocity = (TEXCOORD - previous_uv) * intensity;
+    float speed = length(velocity);
+    if (speed > max_blur) {
+        velocity *= max_blur / speed;
+    }
+
+    // samples are spread over the path the pixel moved, centered on the pixel
+    vec3 sum = vec3(0.0);
+    for (int i = 0; i < sample_count; i++) {
+        float t = float(i) / float(max(sample_count - 1, 1)) - 0.5;
+        sum += texture(scene, clamp(TEXCOORD + velocity * t, vec2(0.0), vec2(1.0))).rgb;
+    }
+    color = vec4(sum / float(max(sample_count, 1)), center.a);
+}
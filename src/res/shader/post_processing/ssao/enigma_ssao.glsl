@@ -0,0 +1,86 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+uniform sampler2D depth;
+uniform mat4 projection_matrix;
+uniform float near;
+uniform float far;
+uniform float radius;
+uniform float bias;
+uniform int sample_count;
+// hemisphere offsets around +z, scaled to at most 1, see Ssao::sample_kernel
+uniform sampler1D kernel;
+
+const float PI = 3.14159265359;
+
+float linearizeDepth(float d) {
+    float z = d * 2.0 - 1.0;
+    return (2.0 * near * far) / (far + near - z * (far - near));
+}
+
+// view space position of the pixel at uv, the camera looks down -z
+vec3 viewPosition(vec2 uv, float d) {
+    float z = linearizeDepth(d);
+    vec2 ndc = uv * 2.0 - 1.0;
+    return vec3(ndc.x * z / projection_matrix[0][0], ndc.y * z / projection_matrix[1][1], -z);
+}
+
+vec3 viewPositionAt(ivec2 pixel) {
+    ivec2 size = textureSize(depth, 0);
+    pixel = clamp(pixel, ivec2(0), size - 1);
+    vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+    return viewPosition(uv, texelFetch(depth, pixel, 0).r);
+}
+
+// normal from the neighbours with the smaller depth difference, which keeps silhouettes clean
+vec3 reconstructNormal(ivec2 pixel, vec3 center) {
+    vec3 left = viewPositionAt(pixel - ivec2(1, 0));
+    vec3 right = viewPositionAt(pixel + ivec2(1, 0));
+    vec3 down = viewPositionAt(pixel - ivec2(0, 1));
+    vec3 up = viewPositionAt(pixel + ivec2(0, 1));
+    vec3 dx = abs(right.z - center.z) < abs(center.z - left.z) ? right - center : center - left;
+    vec3 dy = abs(up.z - center.z) < abs(center.z - down.z) ? up - center : center - down;
+    return normalize(cross(dx, dy));
+}
+
+float hash(vec2 p) {
+    return fract(sin(dot(p, vec2(12.9898, 78.233))) * 43758.5453);
+}
+
+void main() {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    float d = texelFetch(depth, pixel, 0).r;
+    if (d >= 1.0) {
+        color = vec4(1.0);
+        return;
+    }
+    vec3 position = viewPosition(TEXCOORD, d);
+    vec3 normal = reconstructNormal(pixel, position);
+
+    vec3 helper = abs(normal.z) < 0.999 ? vec3(0.0, 0.0, 1.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(helper, normal));
+    vec3 bitangent = cross(normal, tangent);
+    float rotation = hash(gl_FragCoord.xy) * 2.0 * PI;
+
+    float occlusion = 0.0;
+    int count = clamp(sample_count, 1, textureSize(kernel, 0));
+    mat2 spin = mat2(cos(rotation), sin(rotation), -sin(rotation), cos(rotation));
+    for (int i = 0; i < count; i++) {
+        vec3 offset = texelFetch(kernel, i, 0).xyz;
+        offset.xy = spin * offset.xy;
+        vec3 sample_position = position + (tangent * offset.x + bitangent * offset.y + normal * offset.z) * radius;
+
+        vec4 clip = projection_matrix * vec4(sample_position, 1.0);
+        vec2 uv = clip.xy / clip.w * 0.5 + 0.5;
+        if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0)))) {
+            continue;
+        }
+        float scene_z = -linearizeDepth(texture(depth, uv).r);
+        float range = smoothstep(0.0, 1.0, radius / max(abs(position.z - scene_z), 1e-4));
+        occlusion += (scene_z >= sample_position.z + bias ? 1.0 : 0.0) * range;
+    }
+    float ao = 1.0 - occlusion / float(count);
+    color = vec4(ao, linearizeDepth(d), 0.0, 1.0);
+}
@@ -0,0 +1,26 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+// r holds the occlusion, g the linear depth
+uniform sampler2D ao;
+uniform int blur_radius;
+
+void main() {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    ivec2 size = textureSize(ao, 0);
+    vec2 center = texelFetch(ao, pixel, 0).rg;
+    float sum = 0.0;
+    float weight = 0.0;
+    for (int x = -blur_radius; x <= blur_radius; x++) {
+        for (int y = -blur_radius; y <= blur_radius; y++) {
+            vec2 s = texelFetch(ao, clamp(pixel + ivec2(x, y), ivec2(0), size - 1), 0).rg;
+            // don't blur across depth discontinuities
+            float w = 1.0 / (1.0 + 100.0 * abs(s.g - center.g) / max(center.g, 1e-4));
+            sum += s.r * w;
+            weight += w;
+        }
+    }
+    color = vec4(sum / max(weight, 1e-4), center.g, 0.0, 1.0);
+}
@@ -0,0 +1,22 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+uniform sampler2D scene;
+uniform sampler2D ao;
+uniform float intensity;
+uniform bool ambient_only;
+uniform vec3 ambient_light;
+
+void main() {
+    vec4 scene_color = texture(scene, TEXCOORD);
+    float occlusion = (1.0 - texture(ao, TEXCOORD).r) * intensity;
+    if (ambient_only) {
+        // without a separate ambient buffer, remove at most what the ambient light contributes to a white surface
+        vec3 ambient = min(scene_color.rgb, ambient_light);
+        color = vec4(scene_color.rgb - ambient * occlusion, scene_color.a);
+    } else {
+        color = vec4(scene_color.rgb * (1.0 - occlusion), scene_color.a);
+    }
+}
@@ -0,0 +1,129 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+uniform sampler2D scene;
+uniform sampler2D depth;
+// view space normal xy, roughness, linear depth (0 where nothing was written)
+uniform sampler2D surface;
+uniform sampler2D skybox;
+uniform bool has_skybox;
+uniform mat4 projection_matrix;
+uniform mat4 view_matrix;
+uniform float near;
+uniform float far;
+uniform float max_distance;
+uniform int max_steps;
+uniform float thickness;
+uniform float intensity;
+uniform float max_roughness;
+
+const float PI = 3.14159265359;
+
+float linearizeDepth(float d) {
+    float z = d * 2.0 - 1.0;
+    return (2.0 * near * far) / (far + near - z * (far - near));
+}
+
+vec3 viewPosition(vec2 uv, float linear_depth) {
+    vec2 ndc = uv * 2.0 - 1.0;
+    return vec3(ndc.x * linear_depth / projection_matrix[0][0], ndc.y * linear_depth / projection_matrix[1][1], -linear_depth);
+}
+
+vec2 projectToUv(vec3 position) {
+    vec4 clip = projection_matrix * vec4(position, 1.0);
+    return clip.xy / clip.w * 0.5 + 0.5;
+}
+
+// same mapping as the sky reflection of the lit shader
+vec2 getSphereMapUV(vec3 dir) {
+    float u = atan(dir.z, dir.x) / (2.0 * PI) + 0.5;
+    float v = asin(clamp(dir.y, -1.0, 1.0)) / PI + 0.5;
+    return vec2(u, v);
+}
+
+// a few taps around the hit, spread by roughness, stand in for a cone trace
+vec3 blurredHit(vec2 uv, float spread) {
+    const vec2 taps[5] = vec2[](vec2(0.0), vec2(1.0, 0.0), vec2(-1.0, 0.0), vec2(0.0, 1.0), vec2(0.0, -1.0));
+    vec3 sum = vec3(0.0);
+    for (int i = 0; i < 5; i++) {
+        sum += texture(scene, clamp(uv + taps[i] * spread, vec2(0.0), vec2(1.0))).rgb;
+    }
+    return sum / 5.0;
+}
+
+void main() {
+    vec4 scene_color = texture(scene, TEXCOORD);
+    vec4 surface_data = texture(surface, TEXCOORD);
+    float scene_depth = linearizeDepth(texture(depth, TEXCOORD).r);
+    float roughness = clamp(surface_data.z, 0.0, 1.0);
+    // no surface data, or it belongs to an object that is now hidden behind one without it
+    if (surface_data.w <= 0.0 || abs(surface_data.w - scene_depth) > 0.01 * scene_depth + 0.01 || roughness >= max_roughness) {
+        color = scene_color;
+        return;
+    }
+
+    vec3 normal = vec3(surface_data.xy, sqrt(max(1.0 - dot(surface_data.xy, surface_data.xy), 0.0)));
+    vec3 position = viewPosition(TEXCOORD, scene_depth);
+    vec3 view_dir = normalize(position);
+    vec3 ray_dir = normalize(reflect(view_dir, normal));
+
+    // march in view space with steps growing with the distance
+    int steps = max(max_steps, 1);
+    float step_size = max_distance / float(steps);
+    vec3 ray = position + normal * 0.01 * scene_depth;
+    bool hit = false;
+    vec2 hit_uv = vec2(0.0);
+    float travelled = 0.0;
+    for (int i = 0; i < steps; i++) {
+        vec3 previous = ray;
+        ray += ray_dir * step_size;
+        travelled += step_size;
+        if (ray.z > -near) {
+            break;
+        }
+        vec2 uv = projectToUv(ray);
+        if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0)))) {
+            break;
+        }
+        float difference = -ray.z - linearizeDepth(texture(depth, uv).r);
+        if (difference > 0.0 && difference < thickness) {
+            // refine between the last two positions
+            vec3 a = previous;
+            vec3 b = ray;
+            for (int j = 0; j < 5; j++) {
+                vec3 middle = (a + b) * 0.5;
+                vec2 middle_uv = projectToUv(middle);
+                if (-middle.z - linearizeDepth(texture(depth, middle_uv).r) > 0.0) {
+                    b = middle;
+                } else {
+                    a = middle;
+                }
+            }
+            hit_uv = projectToUv(b);
+            hit = true;
+            break;
+        }
+    }
+
+    vec3 world_reflection = transpose(mat3(view_matrix)) * ray_dir;
+    vec3 environment = has_skybox ? textureLod(skybox, getSphereMapUV(world_reflection), roughness).rgb : vec3(0.0);
+    vec3 reflection = environment;
+    float confidence = 0.0;
+    if (hit) {
+        // fade out at the screen border, towards the maximum distance and for rays pointing at the camera
+        vec2 border = smoothstep(vec2(0.0), vec2(0.1), hit_uv) * (1.0 - smoothstep(vec2(0.9), vec2(1.0), hit_uv));
+        confidence = border.x * border.y;
+        confidence *= 1.0 - clamp(travelled / max_distance, 0.0, 1.0);
+        confidence *= 1.0 - smoothstep(0.0, 0.5, ray_dir.z);
+        reflection = mix(environment, blurredHit(hit_uv, roughness * 0.02), confidence);
+    }
+
+    // the scene already contains the sky reflection, replace it where a hit was found
+    float n_dot_v = max(dot(normal, -view_dir), 0.0);
+    float fresnel = 0.04 + 0.96 * pow(1.0 - n_dot_v, 5.0);
+    float roughness_fade = 1.0 - smoothstep(max_roughness * 0.5, max_roughness, roughness);
+    vec3 added = (reflection - environment) * fresnel * roughness_fade * intensity;
+    color = vec4(scene_color.rgb + added, scene_color.a);
+}
@@ -0,0 +1,57 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+uniform sampler2D scene;
+uniform sampler2D history;
+uniform sampler2D velocity;
+uniform sampler2D depth;
+// current clip space to previous clip space
+uniform mat4 reprojection;
+uniform float feedback;
+
+// weights bright pixels down so HDR highlights don't dominate the blend
+vec3 tonemap(vec3 c) {
+    return c / (1.0 + max(c.r, max(c.g, c.b)));
+}
+
+vec3 untonemap(vec3 c) {
+    return c / max(1.0 - max(c.r, max(c.g, c.b)), 1e-4);
+}
+
+void main() {
+    vec4 current = texture(scene, TEXCOORD);
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    ivec2 size = textureSize(scene, 0);
+
+    // moving objects wrote their own motion, everything else only moves with the camera
+    vec4 motion = texelFetch(velocity, pixel, 0);
+    vec2 history_uv;
+    if (motion.w > 0.0) {
+        history_uv = TEXCOORD - motion.xy;
+    } else {
+        float d = texelFetch(depth, pixel, 0).r;
+        vec4 previous = reprojection * vec4(TEXCOORD * 2.0 - 1.0, d * 2.0 - 1.0, 1.0);
+        history_uv = previous.xy / previous.w * 0.5 + 0.5;
+    }
+
+    // clamp the history to the neighbourhood of the current pixel to reject disoccluded and changed pixels
+    vec3 minimum = vec3(1e10);
+    vec3 maximum = vec3(-1e10);
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            vec3 neighbour = tonemap(texelFetch(scene, clamp(pixel + ivec2(x, y), ivec2(0), size - 1), 0).rgb);
+            minimum = min(minimum, neighbour);
+            maximum = max(maximum, neighbour);
+        }
+    }
+    vec3 history_color = clamp(tonemap(texture(history, history_uv).rgb), minimum, maximum);
+
+    float weight = feedback;
+    if (any(lessThan(history_uv, vec2(0.0))) || any(greaterThan(history_uv, vec2(1.0)))) {
+        weight = 0.0;
+    }
+    vec3 resolved = mix(tonemap(current.rgb), history_color, weight);
+    color = vec4(untonemap(resolved), current.a);
+}
@@ -0,0 +1,16 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+uniform sampler2D scene;
+uniform float min_log_luminance;
+uniform float max_log_luminance;
+
+void main() {
+    vec3 hdr = texture(scene, TEXCOORD).rgb;
+    float luminance = dot(hdr, vec3(0.2126, 0.7152, 0.0722));
+    float log_luminance = log2(max(luminance, 1e-6));
+    float normalized = (log_luminance - min_log_luminance) / max(max_log_luminance - min_log_luminance, 1e-4);
+    color = vec4(clamp(normalized, 0.0, 1.0), 0.0, 0.0, 1.0);
+}
@@ -0,0 +1,77 @@
+#version 330 core
+
+in vec2 TEXCOORD;
+out vec4 color;
+
+uniform sampler2D scene;
+uniform float exposure;
+// 0 = none, 1 = Reinhard, 2 = ACES, 3 = AgX
+uniform int tonemap_operator;
+
+vec3 reinhard(vec3 x) {
+    float luminance = dot(x, vec3(0.2126, 0.7152, 0.0722));
+    return x / (1.0 + luminance);
+}
+
+// ACES fitted curve by Stephen Hill, including the sRGB to ACEScg conversion
+vec3 aces(vec3 x) {
+    const mat3 input_matrix = mat3(
+        0.59719, 0.07600, 0.02840,
+        0.35458, 0.90834, 0.13383,
+        0.04823, 0.01566, 0.83777
+    );
+    const mat3 output_matrix = mat3(
+        1.60475, -0.10208, -0.00327,
+        -0.53108, 1.10813, -0.07276,
+        -0.07367, -0.00605, 1.07602
+    );
+    vec3 v = input_matrix * x;
+    vec3 a = v * (v + 0.0245786) - 0.000090537;
+    vec3 b = v * (0.983729 * v + 0.4329510) + 0.238081;
+    return clamp(output_matrix * (a / b), 0.0, 1.0);
+}
+
+// minimal AgX with a polynomial fit of the default contrast curve
+vec3 agx_contrast(vec3 x) {
+    vec3 x2 = x * x;
+    vec3 x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+vec3 agx(vec3 x) {
+    const mat3 inset = mat3(
+        0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+        0.0784335999999992, 0.878468636469772, 0.0784336,
+        0.0792237451477643, 0.0791661274605434, 0.879142973793104
+    );
+    const mat3 outset = mat3(
+        1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+        -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+        -0.0990297440797205, -0.0989611768448433, 1.15107367264116
+    );
+    const float min_ev = -12.47393;
+    const float max_ev = 4.026069;
+    vec3 v = inset * x;
+    v = clamp(log2(max(v, vec3(1e-10))), min_ev, max_ev);
+    v = (v - min_ev) / (max_ev - min_ev);
+    v = agx_contrast(v);
+    v = outset * v;
+    // back to the linear space the rest of the pipeline works in
+    return pow(max(v, vec3(0.0)), vec3(2.2));
+}
+
+void main() {
+    vec4 hdr = texture(scene, TEXCOORD);
+    vec3 exposed = hdr.rgb * exposure;
+    vec3 mapped;
+    if (tonemap_operator == 1) {
+        mapped = reinhard(exposed);
+    } else if (tonemap_operator == 2) {
+        mapped = aces(exposed);
+    } else if (tonemap_operator == 3) {
+        mapped = agx(exposed);
+    } else {
+        mapped = exposed;
+    }
+    color = vec4(clamp(mapped, 0.0, 1.0), hdr.a);
+}
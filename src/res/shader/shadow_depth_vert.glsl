@@ -12,17 +12,30 @@ layout(std140) uniform BoneTransforms {
     mat4 bone_transforms[128];
 };
 
+uniform sampler2D morph_targets;
+uniform int morph_target_count;
+uniform int morph_vertex_count;
+uniform vec4 morph_weights_0;
+uniform vec4 morph_weights_1;
+
 out vec3 v_world_pos;
 
 void main() {
     vec3 local_pos = position;
 
+    int morph_width = textureSize(morph_targets, 0).x;
+    for (int t = 0; t < morph_target_count; t++) {
+        float weight = t < 4 ? morph_weights_0[t] : morph_weights_1[t - 4];
+        int texel = (t * morph_vertex_count + gl_VertexID) * 2;
+        local_pos += texelFetch(morph_targets, ivec2(texel % morph_width, texel / morph_width), 0).xyz * weight;
+    }
+
     if (has_skeleton) {
         mat4 skin = bone_transforms[bone_indices.x] * bone_weights.x
                   + bone_transforms[bone_indices.y] * bone_weights.y
                   + bone_transforms[bone_indices.z] * bone_weights.z
                   + bone_transforms[bone_indices.w] * bone_weights.w;
-        local_pos = (skin * vec4(position, 1.0)).xyz;
+        local_pos = (skin * vec4(local_pos, 1.0)).xyz;
     }
 
     vec4 world = model_matrix * vec4(local_pos, 1.0);
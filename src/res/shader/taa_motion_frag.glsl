@@ -0,0 +1,13 @@
+#version 330 core
+
+in vec4 current_clip;
+in vec4 previous_clip;
+
+out vec4 color;
+
+void main() {
+    // screen space motion in uv units from the previous frame to this one, w marks written pixels
+    vec2 current_uv = current_clip.xy / current_clip.w * 0.5 + 0.5;
+    vec2 previous_uv = previous_clip.xy / previous_clip.w * 0.5 + 0.5;
+    color = vec4(current_uv - previous_uv, 0.0, 1.0);
+}
@@ -0,0 +1,21 @@
+#version 330 core
+
+in vec3 position;
+in mat4 model_matrix;
+in mat4 previous_model_matrix;
+
+// jittered, so the depth test against the scene depth matches the scene pass
+uniform mat4 view_projection;
+// unjittered, so the motion contains no jitter
+uniform mat4 current_view_projection;
+uniform mat4 previous_view_projection;
+
+out vec4 current_clip;
+out vec4 previous_clip;
+
+void main() {
+    vec4 world_position = model_matrix * vec4(position, 1.0);
+    current_clip = current_view_projection * world_position;
+    previous_clip = previous_view_projection * (previous_model_matrix * vec4(position, 1.0));
+    gl_Position = view_projection * world_position;
+}
@@ -41,7 +41,59 @@ pub fn post_processing_lens_dirt_fragment() -> &'static str {
     include_str!("res/shader/post_processing/lens_dirt/enigma_lens_dirt.glsl")
 }
 
+pub fn post_processing_ssao_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/ssao/enigma_ssao.glsl")
+}
+
+pub fn post_processing_ssao_blur_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/ssao/enigma_ssao_blur.glsl")
+}
+
+pub fn post_processing_ssao_combine_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/ssao/enigma_ssao_combine.glsl")
+}
+
+pub fn post_processing_ssr_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/ssr/enigma_ssr.glsl")
+}
+
+pub fn post_processing_color_grade_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/color_grade/enigma_color_grade.glsl")
+}
+
+pub fn post_processing_depth_of_field_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/depth_of_field/enigma_depth_of_field.glsl")
+}
+
+pub fn post_processing_motion_blur_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/motion_blur/enigma_motion_blur.glsl")
+}
+
+pub fn post_processing_fxaa_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/fxaa/enigma_fxaa.glsl")
+}
+
+pub fn post_processing_taa_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/taa/enigma_taa.glsl")
+}
+
+pub fn post_processing_tonemapping_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/tonemapping/enigma_tonemapping.glsl")
+}
+
+pub fn post_processing_luminance_fragment() -> &'static str {
+    include_str!("res/shader/post_processing/tonemapping/enigma_luminance.glsl")
+}
+
 //// other
+pub fn taa_motion_vertex() -> &'static str {
+    include_str!("res/shader/taa_motion_vert.glsl")
+}
+
+pub fn taa_motion_fragment() -> &'static str {
+    include_str!("res/shader/taa_motion_frag.glsl")
+}
+
 pub fn fragment_shader() -> &'static str {
     include_str!("res/shader/enigma_fragment_shader.glsl")
 }
@@ -1,15 +1,29 @@
-use glium::{Display, Texture2d};
+use glium::{Texture2d};
 use glium::framebuffer::DepthRenderBuffer;
-use glium::glutin::surface::WindowSurface;
+use glium::backend::Facade;
 use glium::texture::RawImage2d;
 
+/// Maximum number of cascades of the cascaded directional shadow map.
+pub const MAX_CASCADES: usize = 4;
+/// Fraction of each cascade over which it is blended into the next one.
+pub const CASCADE_BLEND: f32 = 0.1;
+
+/// Shadow maps of the four light slots. `directional_maps` also hold the perspective maps of spot lights,
+/// which store the distance to the light divided by `point_far_planes` like the point light atlases.
 pub struct ShadowMaps {
     pub directional_maps: [Option<Texture2d>; 4],
     pub point_maps: [Option<Texture2d>; 4],
     pub light_space_matrices: [[[f32; 4]; 4]; 4],
     pub point_far_planes: [f32; 4],
+    /// Slot of the directional light using cascades, its `directional_maps` entry holds the cascades side by side.
+    pub cascade_slot: Option<usize>,
+    pub cascade_matrices: [[[f32; 4]; 4]; MAX_CASCADES],
+    /// View space depth at which each cascade ends.
+    pub cascade_splits: [f32; MAX_CASCADES],
+    pub cascade_count: usize,
     pub resolution: u32,
     pub dir_depth_rb: DepthRenderBuffer,
+    pub cascade_depth_rb: DepthRenderBuffer,
     pub point_depth_rb: DepthRenderBuffer,
     pub dummy: Texture2d,
 }
@@ -22,7 +36,7 @@ const IDENTITY: [[f32; 4]; 4] = [
 ];
 
 impl ShadowMaps {
-    pub fn new(display: &Display<WindowSurface>, resolution: u32) -> Self {
+    pub fn new(display: &impl Facade, resolution: u32) -> Self {
         let dir_depth_rb = DepthRenderBuffer::new(
             display,
             glium::texture::DepthFormat::F32,
@@ -37,6 +51,13 @@ impl ShadowMaps {
             resolution * 3,
         ).expect("Failed to create point shadow depth renderbuffer");
 
+        let cascade_depth_rb = DepthRenderBuffer::new(
+            display,
+            glium::texture::DepthFormat::F32,
+            resolution * MAX_CASCADES as u32,
+            resolution,
+        ).expect("Failed to create cascaded shadow depth renderbuffer");
+
         let dummy = Texture2d::new(
             display,
             RawImage2d::from_raw_rgba_reversed(&[255u8, 0, 0, 255], (1, 1)),
@@ -47,8 +68,13 @@ impl ShadowMaps {
             point_maps: [None, None, None, None],
             light_space_matrices: [IDENTITY; 4],
             point_far_planes: [100.0; 4],
+            cascade_slot: None,
+            cascade_matrices: [IDENTITY; MAX_CASCADES],
+            cascade_splits: [0.0; MAX_CASCADES],
+            cascade_count: 0,
             resolution,
             dir_depth_rb,
+            cascade_depth_rb,
             point_depth_rb,
             dummy,
         }
@@ -59,6 +85,8 @@ impl ShadowMaps {
             self.directional_maps[i] = None;
             self.point_maps[i] = None;
         }
+        self.cascade_slot = None;
+        self.cascade_count = 0;
     }
 }
 
@@ -115,6 +143,17 @@ pub fn perspective_90_matrix(near: f32, far: f32) -> [[f32; 4]; 4] {
     ]
 }
 
+/// Perspective projection with a vertical field of view of `fov` radians and a square aspect ratio.
+pub fn perspective_matrix(fov: f32, near: f32, far: f32) -> [[f32; 4]; 4] {
+    let f = 1.0 / (fov / 2.0).tan();
+    [
+        [f, 0.0, 0.0, 0.0],
+        [0.0, f, 0.0, 0.0],
+        [0.0, 0.0, (far+near)/(near-far), -1.0],
+        [0.0, 0.0, (2.0*far*near)/(near-far), 0.0],
+    ]
+}
+
 pub fn mat4_mul(a: [[f32;4];4], b: [[f32;4];4]) -> [[f32;4];4] {
     let mut r = [[0.0f32;4];4];
     for col in 0..4 {
@@ -144,6 +183,97 @@ pub fn directional_light_space_matrix(
     mat4_mul(ortho, view)
 }
 
+/// Light space matrix of a spot light, the frustum covers the outer cone (`outer_angle` in degrees) up to `far`.
+pub fn spot_light_space_matrix(position: [f32; 3], direction: [f32; 3], outer_angle: f32, far: f32) -> [[f32; 4]; 4] {
+    let len = (direction[0]*direction[0] + direction[1]*direction[1] + direction[2]*direction[2]).sqrt();
+    let up = if (direction[1] / len).abs() < 0.99 { [0.0f32, 1.0, 0.0] } else { [1.0f32, 0.0, 0.0] };
+    let view = view_matrix(&position, &direction, &up);
+    let fov = (outer_angle.clamp(1.0, 85.0) * 2.0).to_radians();
+    mat4_mul(perspective_matrix(fov, 0.1, far), view)
+}
+
+/// Far distances of `count` cascades between `near` and `far`. `lambda` blends between uniform (0.0)
+/// and logarithmic (1.0) splits, logarithmic splits give the cascades near the camera more resolution.
+pub fn cascade_splits(near: f32, far: f32, count: usize, lambda: f32) -> Vec<f32> {
+    let lambda = lambda.clamp(0.0, 1.0);
+    (1..=count)
+        .map(|i| {
+            let t = i as f32 / count as f32;
+            let logarithmic = near * (far / near).powf(t);
+            let uniform = near + (far - near) * t;
+            lambda * logarithmic + (1.0 - lambda) * uniform
+        })
+        .collect()
+}
+
+/// Light space matrix of one cascade, covering the part of the camera frustum between `split_near` and `split_far`.
+/// The cascade is fitted around the bounding sphere of the frustum slice, so its size doesn't change when the camera
+/// rotates, and its center is snapped to whole shadow map texels, so shadows don't shimmer when the camera moves.
+/// `depth_padding` extends the cascade towards the light to catch casters outside the camera frustum.
+pub fn cascade_light_space_matrix(
+    light_dir: [f32; 3],
+    camera_view: [[f32; 4]; 4],
+    camera_projection: [[f32; 4]; 4],
+    split_near: f32,
+    split_far: f32,
+    resolution: u32,
+    depth_padding: f32,
+) -> [[f32; 4]; 4] {
+    // camera basis from the rows of the view matrix
+    let v = camera_view;
+    let right = [v[0][0], v[1][0], v[2][0]];
+    let up = [v[0][1], v[1][1], v[2][1]];
+    let back = [v[0][2], v[1][2], v[2][2]];
+    let translation = [v[3][0], v[3][1], v[3][2]];
+    let position = [
+        -(right[0] * translation[0] + up[0] * translation[1] + back[0] * translation[2]),
+        -(right[1] * translation[0] + up[1] * translation[1] + back[1] * translation[2]),
+        -(right[2] * translation[0] + up[2] * translation[1] + back[2] * translation[2]),
+    ];
+    let tan_x = 1.0 / camera_projection[0][0];
+    let tan_y = 1.0 / camera_projection[1][1];
+
+    let mut corners = Vec::with_capacity(8);
+    for depth in [split_near, split_far] {
+        for (sx, sy) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+            let x = sx * tan_x * depth;
+            let y = sy * tan_y * depth;
+            corners.push([
+                position[0] + right[0] * x + up[0] * y - back[0] * depth,
+                position[1] + right[1] * x + up[1] * y - back[1] * depth,
+                position[2] + right[2] * x + up[2] * y - back[2] * depth,
+            ]);
+        }
+    }
+    let mut center = [0.0f32; 3];
+    for corner in corners.iter() {
+        for (sum, value) in center.iter_mut().zip(corner) {
+            *sum += value / 8.0;
+        }
+    }
+    let radius = corners.iter()
+        .map(|c| ((c[0] - center[0]).powi(2) + (c[1] - center[1]).powi(2) + (c[2] - center[2]).powi(2)).sqrt())
+        .fold(0.0f32, f32::max);
+    // rounded so floating point noise doesn't change the texel size
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let len = (light_dir[0]*light_dir[0] + light_dir[1]*light_dir[1] + light_dir[2]*light_dir[2]).sqrt();
+    let dir = [light_dir[0]/len, light_dir[1]/len, light_dir[2]/len];
+    let light_up = if dir[1].abs() < 0.99 { [0.0f32, 1.0, 0.0] } else { [1.0f32, 0.0, 0.0] };
+    let light_view = view_matrix(&[0.0, 0.0, 0.0], &dir, &light_up);
+
+    // one texel of margin for the snapping below
+    let extent = radius * resolution as f32 / (resolution as f32 - 2.0);
+    let texel = extent * 2.0 / resolution as f32;
+    let lv = light_view;
+    let cx = ((lv[0][0] * center[0] + lv[1][0] * center[1] + lv[2][0] * center[2]) / texel).floor() * texel;
+    let cy = ((lv[0][1] * center[0] + lv[1][1] * center[1] + lv[2][1] * center[2]) / texel).floor() * texel;
+    let cz = lv[0][2] * center[0] + lv[1][2] * center[1] + lv[2][2] * center[2];
+
+    let ortho = ortho_matrix(cx - extent, cx + extent, cy - extent, cy + extent, -cz - radius - depth_padding, -cz + radius);
+    mat4_mul(ortho, light_view)
+}
+
 // Cube face directions and ups, ordered: +X -X +Y -Y +Z -Z
 pub const CUBE_FACE_DIRS: [([f32;3], [f32;3]); 6] = [
     ([1.0, 0.0, 0.0],  [0.0, -1.0, 0.0]),
@@ -165,3 +295,63 @@ pub fn face_viewport(face: usize, res: u32) -> glium::Rect {
         height: res,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(m: [[f32; 4]; 4], p: [f32; 3]) -> [f32; 3] {
+        let clip: Vec<f32> = (0..4).map(|row| m[0][row] * p[0] + m[1][row] * p[1] + m[2][row] * p[2] + m[3][row]).collect();
+        [clip[0] / clip[3], clip[1] / clip[3], clip[2] / clip[3]]
+    }
+
+    #[test]
+    fn cascade_splits_blend_uniform_and_logarithmic() {
+        let uniform = cascade_splits(1.0, 101.0, 4, 0.0);
+        assert_eq!(uniform, vec![26.0, 51.0, 76.0, 101.0]);
+        let logarithmic = cascade_splits(1.0, 100.0, 2, 1.0);
+        assert!((logarithmic[0] - 10.0).abs() < 1e-4);
+        assert!((logarithmic[1] - 100.0).abs() < 1e-3);
+        let mixed = cascade_splits(1.0, 100.0, 2, 0.5);
+        assert!(mixed[0] > logarithmic[0] && mixed[0] < 50.5);
+    }
+
+    #[test]
+    fn cascade_covers_the_slice_and_snaps_to_texels() {
+        let projection = perspective_matrix(90f32.to_radians(), 0.1, 100.0);
+        let view_at = |x: f32| view_matrix(&[x, 2.0, 0.0], &[0.0, 0.0, -1.0], &[0.0, 1.0, 0.0]);
+        let light_dir = [0.3, -1.0, 0.2];
+        let m = cascade_light_space_matrix(light_dir, view_at(0.0), projection, 1.0, 10.0, 1024, 20.0);
+        // corners of the far end of the slice are inside the cascade
+        for corner in [[-10.0, 12.0, -10.0], [10.0, -8.0, -10.0], [0.0, 2.0, -5.0]] {
+            let p = project(m, corner);
+            assert!(p.iter().all(|v| v.abs() <= 1.0), "{:?} outside {:?}", corner, p);
+        }
+
+        // moving the camera only ever moves the cascade by whole texels
+        let texel = 2.0 / 1024.0;
+        let reference = project(m, [0.0, 0.0, -5.0]);
+        for x in [0.001, 0.3, 0.75] {
+            let moved = project(cascade_light_space_matrix(light_dir, view_at(x), projection, 1.0, 10.0, 1024, 20.0), [0.0, 0.0, -5.0]);
+            for axis in 0..2 {
+                let texels = (reference[axis] - moved[axis]) / texel;
+                assert!((texels - texels.round()).abs() < 1e-2, "moved {} texels", texels);
+            }
+        }
+    }
+
+    #[test]
+    fn spot_frustum_covers_the_cone() {
+        // spot at 10 m height pointing down with a 30° cone
+        let m = spot_light_space_matrix([0.0, 10.0, 0.0], [0.0, -1.0, 0.0], 30.0, 20.0);
+        let center = project(m, [0.0, 0.0, 0.0]);
+        assert!(center[0].abs() < 1e-4 && center[1].abs() < 1e-4);
+        assert!(center[2] > -1.0 && center[2] < 1.0);
+        // the edge of the cone on the ground lands on the edge of the map
+        let edge = project(m, [10.0 * 30f32.to_radians().tan(), 0.0, 0.0]);
+        assert!((edge[0].abs().max(edge[1].abs()) - 1.0).abs() < 1e-3);
+        // outside the cone
+        let outside = project(m, [10.0, 0.0, 0.0]);
+        assert!(outside[0].abs() > 1.0 || outside[1].abs() > 1.0);
+    }
+}
@@ -1,13 +1,13 @@
-use glium::glutin::surface::WindowSurface;
-use glium::Display;
+use std::rc::Rc;
+use glium::backend::{Context, Facade};
 use glium::Surface;
+use nalgebra::Vector3;
 use crate::resources;
 use crate::camera::Camera;
-use crate::geometry::{BoneTransforms, InstanceAttribute};
-use crate::light::Light;
+use crate::culling::Frustum;
+use crate::geometry::{BoneTransforms, BoundingBox, InstanceAttribute, MorphTargetBuffer, MAX_MORPH_TARGETS};
+use crate::lighting::SceneLighting;
 use crate::material::Material;
-use crate::shadow::ShadowMaps;
-use crate::texture;
 
 // ── Config ────────────────────────────────────────────────────────────────────
 
@@ -288,6 +288,8 @@ glium::implement_vertex!(TerrainVertex, position, normal, color, texcoord, bone_
 struct TerrainTile {
     vertex_buffer: glium::VertexBuffer<TerrainVertex>,
     index_buffer:  glium::IndexBuffer<u32>,
+    // relative to the terrain position
+    bounds:        BoundingBox,
     #[allow(dead_code)]
     tile_x: i32,
     #[allow(dead_code)]
@@ -302,13 +304,14 @@ pub struct Terrain {
     position:              [f32; 3],
     pub material:          Option<Material>,
     #[allow(dead_code)]
-    display:               Display<WindowSurface>,
+    display:               Rc<Context>,
     dummy_bone_transforms: glium::uniforms::UniformBuffer<BoneTransforms>,
+    dummy_morph_targets:   MorphTargetBuffer,
     instance_buffer:       glium::VertexBuffer<InstanceAttribute>,
 }
 
 impl Terrain {
-    pub fn new(display: &Display<WindowSurface>, config: TerrainConfig) -> Self {
+    pub fn new(display: &impl Facade, config: TerrainConfig) -> Self {
         assert!(config.tile_count > 0, "tile_count must be >= 1");
         assert_eq!(
             config.resolution % config.tile_count, 0,
@@ -328,6 +331,7 @@ impl Terrain {
             let bt = BoneTransforms { bone_transforms: identity };
             glium::uniforms::UniformBuffer::new(display, bt).expect("Failed to create dummy bone transforms")
         };
+        let dummy_morph_targets = MorphTargetBuffer::empty(display);
         let instance_buffer = glium::VertexBuffer::dynamic(display, &[InstanceAttribute {
             model_matrix: [[1.0,0.0,0.0,0.0],[0.0,1.0,0.0,0.0],[0.0,0.0,1.0,0.0],[0.0,0.0,0.0,1.0]],
         }]).expect("Failed to create terrain instance buffer");
@@ -388,7 +392,8 @@ impl Terrain {
                     &indices,
                 ).expect("Failed to create terrain index buffer");
 
-                tiles.push(TerrainTile { vertex_buffer: vb, index_buffer: ib, tile_x: tx, tile_z: tz });
+                let bounds = tile_bounds(&vertices);
+                tiles.push(TerrainTile { vertex_buffer: vb, index_buffer: ib, bounds, tile_x: tx, tile_z: tz });
             }
         }
 
@@ -399,8 +404,9 @@ impl Terrain {
             program,
             position: [0.0; 3],
             material: None,
-            display: display.clone(),
+            display: display.get_context().clone(),
             dummy_bone_transforms,
+            dummy_morph_targets,
             instance_buffer,
         }
     }
@@ -419,6 +425,11 @@ impl Terrain {
         }]);
     }
 
+    pub fn get_tile_triangle_count(&self) -> usize {
+        let quads = (self.config.resolution / self.config.tile_count) as usize;
+        quads * quads * 2
+    }
+
     pub fn set_material(&mut self, material: Material) {
         self.material = Some(material);
     }
@@ -436,16 +447,27 @@ impl Terrain {
     }
 
     /// Draws all terrain tiles. If a material is set, uses it (full PBR + shadows);
-    /// otherwise falls back to the built-in vertex-color diffuse shader.
+    /// otherwise falls back to the built-in vertex-color diffuse shader, which only sees the slot lights.
+    /// Tiles outside `frustum` are skipped, returns the number of (drawn, culled) tiles.
     pub fn draw(
         &self,
         target: &mut impl Surface,
         camera: &Camera,
-        lights: &Vec<Light>,
-        ambient_light: Option<&Light>,
-        skybox: &texture::Texture,
-        shadow_maps: &ShadowMaps,
-    ) {
+        scene: &SceneLighting,
+        frustum: Option<&Frustum>,
+    ) -> (usize, usize) {
+        let visible_tiles: Vec<&TerrainTile> = self.tiles.iter()
+            .filter(|tile| match frustum {
+                Some(frustum) => {
+                    let mut bounds = tile.bounds;
+                    bounds.center += Vector3::from(self.position);
+                    frustum.intersects_box(&bounds)
+                }
+                None => true,
+            })
+            .collect();
+        let counts = (visible_tiles.len(), self.tiles.len() - visible_tiles.len());
+
         let draw_params = glium::DrawParameters {
             depth: glium::Depth {
                 test:  glium::draw_parameters::DepthTest::IfLess,
@@ -458,15 +480,14 @@ impl Terrain {
 
         if let Some(material) = &self.material {
             let uniforms = material.get_uniforms(
-                lights,
-                ambient_light,
+                scene,
                 Some(camera),
                 &self.dummy_bone_transforms,
                 false,
-                skybox,
-                shadow_maps,
+                &self.dummy_morph_targets,
+                [0.0; MAX_MORPH_TARGETS],
             );
-            for tile in &self.tiles {
+            for tile in visible_tiles.iter() {
                 target.draw(
                     (&tile.vertex_buffer, self.instance_buffer.per_instance().unwrap()),
                     &tile.index_buffer,
@@ -486,6 +507,7 @@ impl Terrain {
             let view_matrix       = camera.get_view_matrix();
             let projection_matrix = camera.get_projection_matrix();
 
+            let lights = scene.lighting.get_slot_lights();
             let n = lights.len().min(4);
             let mut light_pos_arr: [[f32; 4]; 4] = [[0.0; 4]; 4];
             let mut light_col_arr: [[f32; 4]; 4] = [[0.0; 4]; 4];
@@ -495,12 +517,12 @@ impl Terrain {
                 light_col_arr[i] = [lights[i].color[0],    lights[i].color[1],    lights[i].color[2],    0.0];
                 light_int_arr[i] = lights[i].intensity;
             }
-            let (amb_color, amb_intensity) = match ambient_light {
+            let (amb_color, amb_intensity) = match scene.ambient_light {
                 Some(l) => (l.color, l.intensity),
                 None    => ([0.0f32; 3], 0.0f32),
             };
 
-            for tile in &self.tiles {
+            for tile in visible_tiles.iter() {
                 let uniforms = glium::uniform! {
                     model_matrix:            model_matrix,
                     view_matrix:             view_matrix,
@@ -521,9 +543,22 @@ impl Terrain {
                 ).expect("Failed to draw terrain tile");
             }
         }
+        counts
     }
 }
 
+fn tile_bounds(vertices: &[TerrainVertex]) -> BoundingBox {
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for vertex in vertices {
+        for i in 0..3 {
+            min[i] = min[i].min(vertex.position[i]);
+            max[i] = max[i].max(vertex.position[i]);
+        }
+    }
+    BoundingBox::new(min, max)
+}
+
 /// Bilinear lookup into a row-major (Z-then-X) heightmap. `lx`/`lz` are
 /// floating-point grid coordinates; clamped to prevent out-of-bounds access.
 pub(crate) fn bilinear_lookup(heightmap: &[f32], verts: usize, lx: f32, lz: f32) -> f32 {
@@ -592,6 +627,21 @@ mod tests {
         for h in &hm { assert_eq!(*h, 0.0); }
     }
 
+    #[test]
+    fn tile_bounds_enclose_vertices() {
+        let vertex = |position: [f32; 3]| TerrainVertex {
+            position,
+            normal: [0.0, 1.0, 0.0],
+            color: [1.0; 3],
+            texcoord: [0.0; 2],
+            bone_indices: [0; 4],
+            bone_weights: [0.0; 4],
+        };
+        let bounds = tile_bounds(&[vertex([-2.0, 0.0, 0.0]), vertex([2.0, 3.0, 4.0])]);
+        assert_eq!(bounds.min_point(), Vector3::new(-2.0, 0.0, 0.0));
+        assert_eq!(bounds.max_point(), Vector3::new(2.0, 3.0, 4.0));
+    }
+
     #[test]
     fn heightmap_size() {
         let cfg = TerrainConfig { resolution: 8, tile_count: 1, ..TerrainConfig::default() };
@@ -1,11 +1,10 @@
-use glium::glutin::surface::WindowSurface;
+use glium::backend::Facade;
 use std::path::Path;
 use std::vec::Vec;
 use serde::{Deserialize, Serialize};
 
 use std::cell::RefCell;
 use image::{DynamicImage, RgbaImage};
-use glium::Display;
 use glium::texture::{RawImage2d, SrgbTexture2d, MipmapsOption};
 use lru::LruCache;
 use std::num::NonZeroUsize;
@@ -36,7 +35,7 @@ pub struct Texture {
 }
 
 impl Texture {
-    pub fn new(display: &glium::Display<WindowSurface>, path: &str) -> Self {
+    pub fn new(display: &impl Facade, path: &str) -> Self {
         let image = image::open(path).unwrap().to_rgba8();
         let image_dimensions = image.dimensions();
         let image = glium::texture::RawImage2d::from_raw_rgba_reversed(&image.into_raw(), image_dimensions);
@@ -58,7 +57,7 @@ impl Texture {
         self.tileable = tileable;
     }
 
-    pub fn from_serializer(serializer: TextureSerializer, display: &glium::Display<WindowSurface>) -> Self {
+    pub fn from_serializer(serializer: TextureSerializer, display: &impl Facade) -> Self {
         let path = Path::new(&serializer.path);
         if !path.is_file() {
             match &serializer.binary_data {
@@ -94,7 +93,7 @@ impl Texture {
         }
     }
 
-    pub fn from_resource(display: &Display<WindowSurface>, data: &[u8]) -> Self {
+    pub fn from_resource(display: &impl Facade, data: &[u8]) -> Self {
 
         let image = IMAGE_CACHE.with(|cache| {
             let mut cache = cache.borrow_mut();
@@ -121,7 +120,7 @@ impl Texture {
         }
     }
 
-    pub fn get_texture_clone(&self, display: &glium::Display<WindowSurface>) -> Self {
+    pub fn get_texture_clone(&self, display: &impl Facade) -> Self {
         let path_str = self.path.clone();
         let path = Path::new(&path_str);
         if !path.is_file() {
@@ -148,7 +147,7 @@ impl Texture {
         }
     }
 
-    pub fn pink_texture(display: &glium::Display<WindowSurface>) -> Self {
+    pub fn pink_texture(display: &impl Facade) -> Self {
         let image = image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 255, 255]));
         let image_dimensions = image.dimensions();
         let image = glium::texture::RawImage2d::from_raw_rgba_reversed(&image.into_raw(), image_dimensions);
@@ -164,7 +163,7 @@ impl Texture {
         }
     }
 
-    pub fn colored_texture(display: &glium::Display<WindowSurface>, color: [u8; 4], name: Option<String>) -> Self {
+    pub fn colored_texture(display: &impl Facade, color: [u8; 4], name: Option<String>) -> Self {
         let image = image::RgbaImage::from_pixel(1, 1, image::Rgba(color));
         let image_dimensions = image.dimensions();
         let image = glium::texture::RawImage2d::from_raw_rgba_reversed(&image.into_raw(), image_dimensions);